@@ -2,6 +2,29 @@ pub mod stockenv;
 pub mod halfcheetahenv;
 pub mod antenv;
 pub mod hopperenv;
+pub mod normalize;
+pub mod vecenv;
+pub mod actionrepeat;
+pub mod timelimit;
+
+#[cfg(feature = "live")]
+pub mod livestockenv;
+
+// element type of a spec, tells downstream code which tensor kind to build
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Dtype {
+    Float,
+    Double,
+}
+
+impl Dtype {
+    pub fn kind(&self) -> tch::Kind {
+        match self {
+            Dtype::Float => tch::Kind::Float,
+            Dtype::Double => tch::Kind::Double,
+        }
+    }
+}
 
 pub struct Spec {
     pub min: f64,
@@ -9,11 +32,40 @@ pub struct Spec {
 
     // no multidimensional spec support yet (just flatten the action space)
     pub shape: u32,
+
+    // per dimension bounds, shape long
+    pub low: Vec<f64>,
+    pub high: Vec<f64>,
+
+    pub dtype: Dtype,
+}
+
+impl Spec {
+    // every dimension shares the bounds [min, max], Float unless with_dtype says otherwise
+    pub fn new(min: f64, max: f64, shape: u32) -> Self {
+        Spec {
+            min,
+            max,
+            shape,
+            low: vec![min; shape as usize],
+            high: vec![max; shape as usize],
+            dtype: Dtype::Float,
+        }
+    }
+
+    pub fn with_dtype(mut self, dtype: Dtype) -> Self {
+        self.dtype = dtype;
+        self
+    }
 }
 
 pub trait Trajectory {
     fn observation(&self) -> Vec<f64>;
     fn reward(&self) -> Option<f64>;
+    // true only for Terminate, as_any still works for anything else
+    fn is_terminal(&self) -> bool;
+    // how much the value of the next state counts, only a true terminal state stops bootstrapping
+    fn discount(&self) -> f64;
     fn as_any(&self) -> &dyn std::any::Any;
 }
 
@@ -21,9 +73,11 @@ pub struct Transition {
     pub observation: Vec<f64>,
     pub reward: f64,
 }
+// discount is 0 for a true terminal state and 1 when the episode was cut off by a time limit
 pub struct Terminate {
     pub observation: Vec<f64>,
     pub reward: f64,
+    pub discount: f64,
 }
 pub struct Restart {
     pub observation: Vec<f64>,
@@ -51,6 +105,12 @@ impl Trajectory for Transition {
     fn reward(&self) -> Option<f64> {
         Some(self.reward)
     }
+    fn is_terminal(&self) -> bool {
+        false
+    }
+    fn discount(&self) -> f64 {
+        1f64
+    }
     fn as_any(&self) -> &dyn std::any::Any {
         self
     }
@@ -63,6 +123,12 @@ impl Trajectory for Terminate {
     fn reward(&self) -> Option<f64> {
         Some(self.reward)
     }
+    fn is_terminal(&self) -> bool {
+        true
+    }
+    fn discount(&self) -> f64 {
+        self.discount
+    }
     fn as_any(&self) -> &dyn std::any::Any {
         self
     }
@@ -75,7 +141,31 @@ impl Trajectory for Restart {
     fn reward(&self) -> Option<f64> {
         None
     }
+    fn is_terminal(&self) -> bool {
+        false
+    }
+    fn discount(&self) -> f64 {
+        1f64
+    }
     fn as_any(&self) -> &dyn std::any::Any {
         self
     }
 }
+
+// rebuilds a trajectory of the same kind around a new observation, used by environment wrappers
+pub fn with_observation(ts: Box<dyn Trajectory>, observation: Vec<f64>) -> Box<dyn Trajectory> {
+    if ts.as_any().downcast_ref::<Restart>().is_some() {
+        return Box::new(Restart { observation });
+    }
+
+    let reward = ts.reward().unwrap_or(0f64);
+
+    match ts.is_terminal() {
+        true => Box::new(Terminate {
+            observation,
+            reward,
+            discount: ts.discount(),
+        }),
+        false => Box::new(Transition { observation, reward }),
+    }
+}
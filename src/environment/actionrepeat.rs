@@ -0,0 +1,64 @@
+use crate::environment::{Environment, Spec, Terminate, Trajectory, Transition};
+
+// frame skip for any environment, every action is applied repeats times and the rewards summed
+// the last observation is returned, a terminal step ends the repeat early
+pub struct ActionRepeat {
+    pub env: Box<dyn Environment>,
+    pub repeats: u32,
+
+    // inner steps taken by the last step, below repeats only when the episode ended
+    pub last_repeats: u32,
+}
+
+impl ActionRepeat {
+    pub fn new(env: Box<dyn Environment>, repeats: u32) -> Self {
+        assert!(repeats > 0, "repeats must be positive");
+
+        ActionRepeat {
+            env,
+            repeats,
+            last_repeats: 0,
+        }
+    }
+}
+
+impl Environment for ActionRepeat {
+    fn action_spec(&self) -> Spec {
+        self.env.action_spec()
+    }
+
+    fn observation_spec(&self) -> Spec {
+        self.env.observation_spec()
+    }
+
+    fn step(&mut self, action: Vec<f64>) -> Box<dyn Trajectory> {
+        let mut reward = 0f64;
+        self.last_repeats = 0;
+
+        loop {
+            let ts = self.env.step(action.clone());
+            reward += ts.reward().unwrap_or(0f64);
+            self.last_repeats += 1;
+
+            if ts.is_terminal() {
+                return Box::new(Terminate {
+                    observation: ts.observation(),
+                    reward,
+                    discount: ts.discount(),
+                });
+            }
+
+            if self.last_repeats == self.repeats {
+                return Box::new(Transition {
+                    observation: ts.observation(),
+                    reward,
+                });
+            }
+        }
+    }
+
+    fn reset(&mut self) -> Box<dyn Trajectory> {
+        self.last_repeats = 0;
+        self.env.reset()
+    }
+}
@@ -25,23 +25,16 @@ pub struct AntEnv {
     pub episode_length: u32,
     pub step: u32,
     pub episode_ended: bool,
+    pub rng: rand::prelude::StdRng,
 }
 
 impl Environment for AntEnv {
     fn action_spec(&self) -> Spec {
-        Spec {
-            min: -1f64,
-            max: 1f64,
-            shape: 8,
-        }
+        Spec::new(-1f64, 1f64, 8)
     }
 
     fn observation_spec(&self) -> Spec {
-        Spec {
-            min: f64::NEG_INFINITY,
-            max: f64::INFINITY,
-            shape: 107,
-        }
+        Spec::new(f64::NEG_INFINITY, f64::INFINITY, 107)
     }
 
     fn step(&mut self, action: Vec<f64>) -> Box<dyn Trajectory> {
@@ -60,11 +53,14 @@ impl Environment for AntEnv {
         let obs = self.observation();
         let reward = self.get_reward(x_velocity, action.clone());
 
-        if self.step >= self.episode_length || (!self.is_healthy() && self.terminate_when_unhealthy){
+        let fell = !self.is_healthy() && self.terminate_when_unhealthy;
+
+        if self.step >= self.episode_length || fell {
             self.episode_ended = true;
             return Box::new(Terminate {
                 observation: obs,
                 reward: reward,
+                discount: if fell { 0f64 } else { 1f64 },
             });
         }
 
@@ -81,7 +77,6 @@ impl Environment for AntEnv {
         let noise_low = -self.reset_noise_scale;
         let noise_high = self.reset_noise_scale;
 
-        let mut rng = <rand::prelude::StdRng as rand::prelude::SeedableRng>::from_entropy();
         let uniform = rand::distributions::Uniform::from(noise_low..noise_high);
         let normal =
             rand_distr::Normal::new(0f64, 1f64).expect("Failed to make normal distribution");
@@ -89,14 +84,14 @@ impl Environment for AntEnv {
         let qpos = (0..self.model.nq)
             .map(|idx| {
                 self.init_qpos[idx as usize]
-                    + rand::prelude::Distribution::sample(&uniform, &mut rng)
+                    + rand::prelude::Distribution::sample(&uniform, &mut self.rng)
             })
             .collect::<Vec<f64>>();
 
         let qvel = (0..self.model.nv)
             .map(|idx| {
                 self.init_qvel[idx as usize]
-                    + rand::prelude::Distribution::sample(&normal, &mut rng)
+                    + rand::prelude::Distribution::sample(&normal, &mut self.rng)
             })
             .collect::<Vec<f64>>();
 
@@ -153,6 +148,9 @@ impl Mujoco for AntEnv {
     }
 }
 
+// SAFETY: same exclusive ownership of model and data as HalfCheetahEnv, see the note on its Send impl
+unsafe impl Send for AntEnv {}
+
 impl Drop for AntEnv {
     fn drop(&mut self) {
         unsafe {
@@ -269,6 +267,7 @@ impl AntEnv {
                 episode_length,
                 step: 0,
                 episode_ended: true,
+                rng: crate::new_rng(),
             }
         }
     }
@@ -2,9 +2,47 @@ extern crate rand;
 extern crate rand_distr;
 
 use crate::environment::{
-    Environment, Mujoco, Restart, Spec, Terminate, Trajectory, Transition,
+    Dtype, Environment, Mujoco, Restart, Spec, Terminate, Trajectory, Transition,
 };
 
+// named settings for HalfCheetahEnv, start from Default and override what you need
+#[derive(Clone, Debug)]
+pub struct HalfCheetahConfig {
+    // reward per unit of forward velocity
+    pub forward_reward_weight: f64,
+    // penalty on the squared magnitude of the action
+    pub ctrl_cost_weight: f64,
+    // half width of the uniform noise added to the initial joint positions
+    pub reset_noise_scale: f64,
+    // viewer resolution
+    pub width: u32,
+    pub height: u32,
+    // mujoco steps per env step
+    pub frame_skip: u32,
+    // the episode is truncated after this many steps
+    pub max_episode_steps: u32,
+    // keep a render frame for every step of the current episode
+    pub record: bool,
+    // seeds the reset noise rng, None falls back to crate::new_rng
+    pub seed: Option<u64>,
+}
+
+impl Default for HalfCheetahConfig {
+    fn default() -> Self {
+        HalfCheetahConfig {
+            forward_reward_weight: 1f64,
+            ctrl_cost_weight: 0.1,
+            reset_noise_scale: 0.1,
+            width: 1920,
+            height: 1080,
+            frame_skip: 5,
+            max_episode_steps: 1000,
+            record: false,
+            seed: None,
+        }
+    }
+}
+
 pub struct HalfCheetahEnv {
     pub model: Box<crate::wrappers::mujoco::mjModel>,
     pub data: Box<crate::wrappers::mujoco::mjData>,
@@ -19,23 +57,19 @@ pub struct HalfCheetahEnv {
     pub episode_length: u32,
     pub step: u32,
     pub episode_ended: bool,
+    pub rng: rand::prelude::StdRng,
+    pub record: bool,
+    // one render frame per step of the current episode, only filled while recording
+    pub frames: Vec<Vec<f64>>,
 }
 
 impl Environment for HalfCheetahEnv {
     fn action_spec(&self) -> Spec {
-        Spec {
-            min: -1f64,
-            max: 1f64,
-            shape: 6,
-        }
+        Spec::new(-1f64, 1f64, 6).with_dtype(Dtype::Float)
     }
 
     fn observation_spec(&self) -> Spec {
-        Spec {
-            min: f64::NEG_INFINITY,
-            max: f64::INFINITY,
-            shape: 18,
-        }
+        Spec::new(f64::NEG_INFINITY, f64::INFINITY, 18).with_dtype(Dtype::Float)
     }
 
     fn step(&mut self, action: Vec<f64>) -> Box<dyn Trajectory> {
@@ -56,11 +90,17 @@ impl Environment for HalfCheetahEnv {
 
         let obs = self.observation();
 
+        if self.record {
+            self.frames.push(self.render());
+        }
+
+        // the cheetah cant fall over, episodes only end on the time limit
         if self.step >= self.episode_length {
             self.episode_ended = true;
             return Box::new(Terminate {
                 observation: obs,
                 reward: forward_reward - ctrl_cost,
+                discount: 1f64,
             });
         }
 
@@ -77,7 +117,6 @@ impl Environment for HalfCheetahEnv {
         let noise_low = -self.reset_noise_scale;
         let noise_high = self.reset_noise_scale;
 
-        let mut rng = <rand::prelude::StdRng as rand::prelude::SeedableRng>::from_entropy();
         let uniform = rand::distributions::Uniform::from(noise_low..noise_high);
         let normal =
             rand_distr::Normal::new(0f64, 1f64).expect("Failed to make normal distribution");
@@ -85,14 +124,14 @@ impl Environment for HalfCheetahEnv {
         let qpos = (0..self.model.nq)
             .map(|idx| {
                 self.init_qpos[idx as usize]
-                    + rand::prelude::Distribution::sample(&uniform, &mut rng)
+                    + rand::prelude::Distribution::sample(&uniform, &mut self.rng)
             })
             .collect::<Vec<f64>>();
 
         let qvel = (0..self.model.nv)
             .map(|idx| {
                 self.init_qvel[idx as usize]
-                    + rand::prelude::Distribution::sample(&normal, &mut rng)
+                    + rand::prelude::Distribution::sample(&normal, &mut self.rng)
             })
             .collect::<Vec<f64>>();
 
@@ -109,6 +148,7 @@ impl Environment for HalfCheetahEnv {
 
         self.step = 0;
         self.episode_ended = false;
+        self.frames.clear();
         Box::new(Restart {
             observation: self.observation(),
         })
@@ -143,6 +183,12 @@ impl Mujoco for HalfCheetahEnv {
     }
 }
 
+// SAFETY: the mujoco envs are only Send because mjModel and mjData hold raw pointers. The invariant they
+// all uphold is that model and data are owned exclusively by the env, allocated for it in its constructor
+// and freed only in its Drop, and that no pointer into them (qpos, qvel, cfrc_ext, ...) outlives the call
+// that read it. Nothing else can alias them, so moving the env to another thread moves every access too
+unsafe impl Send for HalfCheetahEnv {}
+
 impl Drop for HalfCheetahEnv {
     fn drop(&mut self) {
         unsafe {
@@ -153,6 +199,7 @@ impl Drop for HalfCheetahEnv {
 }
 
 impl HalfCheetahEnv {
+    // positional form of HalfCheetahEnv::from_config, None keeps the HalfCheetahConfig default
     pub fn new(
         forward_reward_weight: Option<f64>,
         ctrl_cost_weight: Option<f64>,
@@ -162,17 +209,36 @@ impl HalfCheetahEnv {
         frame_skip: Option<u32>,
         episode_length: Option<u32>,
     ) -> Self {
+        let default = HalfCheetahConfig::default();
+
+        HalfCheetahEnv::from_config(HalfCheetahConfig {
+            forward_reward_weight: forward_reward_weight.unwrap_or(default.forward_reward_weight),
+            ctrl_cost_weight: ctrl_cost_weight.unwrap_or(default.ctrl_cost_weight),
+            reset_noise_scale: reset_noise_scale.unwrap_or(default.reset_noise_scale),
+            width: width.unwrap_or(default.width),
+            height: height.unwrap_or(default.height),
+            frame_skip: frame_skip.unwrap_or(default.frame_skip),
+            max_episode_steps: episode_length.unwrap_or(default.max_episode_steps),
+            record: default.record,
+            seed: default.seed,
+        })
+    }
+
+    pub fn from_config(config: HalfCheetahConfig) -> Self {
         let halfcheetah_xml = include_str!("../mujoco/halfcheetah.xml");
         let halfcheetah_file = "halfcheetah.xml".as_ptr() as *const libc::c_char;
 
-        let width = width.unwrap_or(1920);
-        let height = height.unwrap_or(1080);
-        let frame_skip = frame_skip.unwrap_or(5);
-        let episode_length = episode_length.unwrap_or(1000);
-
-        let forward_reward_weight = forward_reward_weight.unwrap_or(1f64);
-        let ctrl_cost_weight = ctrl_cost_weight.unwrap_or(0.1);
-        let reset_noise_scale = reset_noise_scale.unwrap_or(0.1);
+        let HalfCheetahConfig {
+            forward_reward_weight,
+            ctrl_cost_weight,
+            reset_noise_scale,
+            width,
+            height,
+            frame_skip,
+            max_episode_steps: episode_length,
+            record,
+            seed,
+        } = config;
 
         unsafe {
             let layout = std::alloc::Layout::new::<crate::wrappers::mujoco::mjVFS>();
@@ -240,10 +306,40 @@ impl HalfCheetahEnv {
                 episode_length,
                 step: 0,
                 episode_ended: true,
+                rng: match seed {
+                    Some(seed) => <rand::prelude::StdRng as rand::prelude::SeedableRng>::seed_from_u64(seed),
+                    None => crate::new_rng(),
+                },
+                record,
+                frames: Vec::new(),
             }
         }
     }
 
+    // restarts the reset noise rng, envs with the same seed reset identically from here on
+    pub fn seed(&mut self, seed: u64) {
+        self.rng = <rand::prelude::StdRng as rand::prelude::SeedableRng>::seed_from_u64(seed);
+    }
+
+    // raw simulator state for external viewers, joint positions followed by joint velocities
+    pub fn render(&self) -> Vec<f64> {
+        let mut qpos = vec![0f64; self.model.nq as usize];
+        let mut qvel = vec![0f64; self.model.nv as usize];
+
+        unsafe {
+            qpos.copy_from_slice(core::slice::from_raw_parts(
+                self.data.qpos as *const f64,
+                self.model.nq as usize,
+            ));
+            qvel.copy_from_slice(core::slice::from_raw_parts(
+                self.data.qvel as *const f64,
+                self.model.nv as usize,
+            ));
+        }
+
+        [qpos, qvel].concat()
+    }
+
     pub fn control_cost(&self, action: Vec<f64>) -> f64 {
         self.ctrl_cost_weight * action.iter().map(|x| x.powi(2)).sum::<f64>()
     }
@@ -24,23 +24,16 @@ pub struct HopperEnv {
     pub episode_length: u32,
     pub step: u32,
     pub episode_ended: bool,
+    pub rng: rand::prelude::StdRng,
 }
 
 impl Environment for HopperEnv {
     fn action_spec(&self) -> Spec {
-        Spec {
-            min: -1f64,
-            max: 1f64,
-            shape: 3,
-        }
+        Spec::new(-1f64, 1f64, 3)
     }
 
     fn observation_spec(&self) -> Spec {
-        Spec {
-            min: f64::NEG_INFINITY,
-            max: f64::INFINITY,
-            shape: 12,
-        }
+        Spec::new(f64::NEG_INFINITY, f64::INFINITY, 12)
     }
 
     fn step(&mut self, action: Vec<f64>) -> Box<dyn Trajectory> {
@@ -59,11 +52,14 @@ impl Environment for HopperEnv {
         let obs = self.observation();
         let reward = self.get_reward(x_velocity, action.clone());
 
-        if self.step >= self.episode_length || (!self.is_healthy() && self.terminate_when_unhealthy){
+        let fell = !self.is_healthy() && self.terminate_when_unhealthy;
+
+        if self.step >= self.episode_length || fell {
             self.episode_ended = true;
             return Box::new(Terminate {
                 observation: obs,
                 reward: reward,
+                discount: if fell { 0f64 } else { 1f64 },
             });
         }
 
@@ -80,7 +76,6 @@ impl Environment for HopperEnv {
         let noise_low = -self.reset_noise_scale;
         let noise_high = self.reset_noise_scale;
 
-        let mut rng = <rand::prelude::StdRng as rand::prelude::SeedableRng>::from_entropy();
         let uniform = rand::distributions::Uniform::from(noise_low..noise_high);
         let normal =
             rand_distr::Normal::new(0f64, 1f64).expect("Failed to make normal distribution");
@@ -88,14 +83,14 @@ impl Environment for HopperEnv {
         let qpos = (0..self.model.nq)
             .map(|idx| {
                 self.init_qpos[idx as usize]
-                    + rand::prelude::Distribution::sample(&uniform, &mut rng)
+                    + rand::prelude::Distribution::sample(&uniform, &mut self.rng)
             })
             .collect::<Vec<f64>>();
 
         let qvel = (0..self.model.nv)
             .map(|idx| {
                 self.init_qvel[idx as usize]
-                    + rand::prelude::Distribution::sample(&normal, &mut rng)
+                    + rand::prelude::Distribution::sample(&normal, &mut self.rng)
             })
             .collect::<Vec<f64>>();
 
@@ -147,6 +142,9 @@ impl Mujoco for HopperEnv {
     }
 }
 
+// SAFETY: same exclusive ownership of model and data as HalfCheetahEnv, see the note on its Send impl
+unsafe impl Send for HopperEnv {}
+
 impl Drop for HopperEnv {
     fn drop(&mut self) {
         unsafe {
@@ -264,6 +262,7 @@ impl HopperEnv {
                 episode_length,
                 step: 0,
                 episode_ended: true,
+                rng: crate::new_rng(),
             }
         }
     }
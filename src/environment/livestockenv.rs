@@ -0,0 +1,685 @@
+extern crate anyhow;
+extern crate curl;
+extern crate polars;
+extern crate serde_json;
+
+use crate::environment::stockenv::portfolio_weights;
+use crate::environment::{Environment, Restart, Spec, Terminate, Trajectory, Transition};
+use crate::logger::{event_columns, Logger};
+use crate::stockframe::{stockframe_columns, StockFrame, Timeframe};
+
+// paper trading counterpart of StockEnv, built only with the `live` feature
+// instead of stepping through cached bars every step polls the latest bar of each ticker and
+// realizes the action as market orders through a Broker, the reward is the log return of the
+// account valued at the prices the orders actually filled at
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Side {
+    Buy,
+    Sell,
+}
+
+impl Side {
+    pub fn as_alpaca(&self) -> &'static str {
+        match self {
+            Side::Buy => "buy",
+            Side::Sell => "sell",
+        }
+    }
+}
+
+// whole share market order
+#[derive(Clone, Debug, PartialEq)]
+pub struct Order {
+    pub symbol: String,
+    pub qty: f64,
+    pub side: Side,
+}
+
+// qty can be smaller than the order asked for if it only partially filled
+#[derive(Clone, Debug, PartialEq)]
+pub struct Fill {
+    pub symbol: String,
+    pub qty: f64,
+    pub price: f64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Bar {
+    pub symbol: String,
+    pub timestamp: polars::export::chrono::NaiveDateTime,
+    pub open: f64,
+    pub high: f64,
+    pub low: f64,
+    pub close: f64,
+    pub volume: f64,
+    pub vwap: f64,
+    pub trade_count: f64,
+}
+
+// everything LiveStockEnv needs from the outside world, tests swap in a mock
+pub trait Broker {
+    // most recent bar of every ticker
+    fn latest_bars(&mut self, tickers: &[String]) -> anyhow::Result<Vec<Bar>>;
+    // blocks until the order has filled
+    fn submit_order(&mut self, order: &Order) -> anyhow::Result<Fill>;
+}
+
+// alpaca paper trading account, keys come from the same ALPACA_KEY and ALPACA_SECRET env variables as StockFrame
+pub struct AlpacaBroker {
+    pub trading_url: String,
+    pub data_url: String,
+    // how many times a submitted order is polled before giving up on it
+    pub fill_timeout: u32,
+}
+
+impl AlpacaBroker {
+    pub fn new(
+        trading_url: Option<String>,
+        data_url: Option<String>,
+        fill_timeout: Option<u32>,
+    ) -> Self {
+        AlpacaBroker {
+            trading_url: trading_url.unwrap_or(String::from("https://paper-api.alpaca.markets")),
+            data_url: data_url.unwrap_or(String::from("https://data.alpaca.markets")),
+            fill_timeout: fill_timeout.unwrap_or(30),
+        }
+    }
+
+    fn request(&self, uri: &str, body: Option<String>) -> anyhow::Result<serde_json::Value> {
+        let alpaca_key: String = std::env::var("ALPACA_KEY")?;
+        let alpaca_secret: String = std::env::var("ALPACA_SECRET")?;
+
+        let mut easy = curl::easy::Easy::new();
+
+        let mut data = Vec::new();
+        let mut headers = curl::easy::List::new();
+
+        headers.append(format!("APCA-API-KEY-ID: {}", alpaca_key).as_str())?;
+        headers.append(format!("APCA-API-SECRET-KEY: {}", alpaca_secret).as_str())?;
+        headers.append("Content-Type: application/json")?;
+
+        easy.url(uri)?;
+        easy.http_headers(headers)?;
+
+        if let Some(body) = &body {
+            easy.post(true)?;
+            easy.post_fields_copy(body.as_bytes())?;
+        }
+
+        {
+            let mut transfer = easy.transfer();
+            transfer.write_function(|new_data| {
+                data.extend_from_slice(new_data);
+                Ok(new_data.len())
+            })?;
+
+            transfer.perform()?;
+        }
+
+        let json_string = String::from_utf8(data)?;
+
+        match easy.response_code()? {
+            200..=299 => Ok(serde_json::from_str(json_string.as_str())?),
+            code => anyhow::bail!(format!("Alpaca returned {}: {}", code, json_string)),
+        }
+    }
+}
+
+fn json_f64(value: &serde_json::Value, key: &str) -> anyhow::Result<f64> {
+    match value.get(key) {
+        Some(serde_json::Value::Number(number)) => Ok(number.as_f64().unwrap_or(f64::NAN)),
+        // alpaca sends prices and quantities on orders as strings
+        Some(serde_json::Value::String(string)) => Ok(string.parse()?),
+        _ => anyhow::bail!(format!("Invalid API Response, missing {}: {}", key, value)),
+    }
+}
+
+impl Broker for AlpacaBroker {
+    fn latest_bars(&mut self, tickers: &[String]) -> anyhow::Result<Vec<Bar>> {
+        let uri = format!(
+            "{}/v2/stocks/bars/latest?symbols={}",
+            self.data_url,
+            tickers.join(",")
+        );
+        let json_object = self.request(uri.as_str(), None)?;
+
+        let bars = match json_object.get("bars").and_then(|bars| bars.as_object()) {
+            None => anyhow::bail!(format!("Invalid API Response: {}", json_object)),
+            Some(bars) => bars,
+        };
+
+        let mut latest = Vec::new();
+
+        for ticker in tickers {
+            let bar = match bars.get(ticker) {
+                None => anyhow::bail!(format!("No latest bar for ticker: {}", ticker)),
+                Some(bar) => bar,
+            };
+
+            let timestamp = match bar.get("t").and_then(|t| t.as_str()) {
+                None => anyhow::bail!(format!("Invalid API Response, missing t: {}", bar)),
+                Some(t) => polars::export::chrono::DateTime::parse_from_rfc3339(t)?.naive_utc(),
+            };
+
+            latest.push(Bar {
+                symbol: ticker.clone(),
+                timestamp,
+                open: json_f64(bar, "o")?,
+                high: json_f64(bar, "h")?,
+                low: json_f64(bar, "l")?,
+                close: json_f64(bar, "c")?,
+                volume: json_f64(bar, "v")?,
+                vwap: json_f64(bar, "vw")?,
+                trade_count: json_f64(bar, "n")?,
+            });
+        }
+
+        Ok(latest)
+    }
+
+    fn submit_order(&mut self, order: &Order) -> anyhow::Result<Fill> {
+        let body = serde_json::json!({
+            "symbol": order.symbol,
+            "qty": order.qty.to_string(),
+            "side": order.side.as_alpaca(),
+            "type": "market",
+            "time_in_force": "day",
+        });
+
+        let uri = format!("{}/v2/orders", self.trading_url);
+        let mut status = self.request(uri.as_str(), Some(body.to_string()))?;
+
+        let id = match status.get("id").and_then(|id| id.as_str()) {
+            None => anyhow::bail!(format!("Invalid API Response: {}", status)),
+            Some(id) => String::from(id),
+        };
+
+        for _ in 0..self.fill_timeout {
+            match status.get("status").and_then(|status| status.as_str()) {
+                Some("filled") => {
+                    return Ok(Fill {
+                        symbol: order.symbol.clone(),
+                        qty: json_f64(&status, "filled_qty")?,
+                        price: json_f64(&status, "filled_avg_price")?,
+                    })
+                }
+                Some("canceled") | Some("expired") | Some("rejected") => {
+                    anyhow::bail!(format!(
+                        "Order for {} did not fill: {}",
+                        order.symbol, status
+                    ))
+                }
+                _ => {}
+            }
+
+            std::thread::sleep(core::time::Duration::from_secs(1));
+            status = self.request(
+                format!("{}/v2/orders/{}", self.trading_url, id).as_str(),
+                None,
+            )?;
+        }
+
+        anyhow::bail!(format!(
+            "Order for {} did not fill in time: {}",
+            order.symbol, status
+        ))
+    }
+}
+
+pub struct LiveStockEnv {
+    pub broker: Box<dyn Broker>,
+    // bars polled so far with indicators, the same columns and pipeline as the backtest frame
+    pub stockframe: StockFrame,
+    // bars kept per symbol, enough for the longest indicator window
+    pub max_bars: usize,
+    pub poll_interval: std::time::Duration,
+    // a failed poll is retried this many times, waiting poll_backoff and doubling it before every retry
+    pub poll_retries: u32,
+    pub poll_backoff: std::time::Duration,
+    // how many rounds of poll_with_backoff a reset tries before giving up on the feed
+    pub reset_rounds: u32,
+    // failed orders and polls, see logger::event_columns
+    pub logger: Logger,
+
+    pub tickers: Vec<String>,
+    pub window: u32,
+    pub history: std::collections::VecDeque<Vec<f64>>,
+
+    pub starting_balance: f64,
+    pub balance: f64,
+    pub holdings: Vec<f64>,
+    pub buy_price: Vec<f64>,
+    pub prices: Vec<f64>,
+    pub total_asset: Vec<f64>,
+    pub num_trades: u32,
+    pub reward: f64,
+
+    pub step: u32,
+    pub episode_length: u32,
+    pub episode_ended: bool,
+}
+
+impl LiveStockEnv {
+    // tickers are traded in alphabetical order, matching the symbol sorted features of the backtest env
+    // starting_balance is the cash this env may spend, not the whole paper account
+    // log_path defaults to ./results/live_events.csv
+    pub fn new(
+        broker: Box<dyn Broker>,
+        tickers: Vec<String>,
+        window: usize,
+        timeframe: Option<Timeframe>,
+        starting_balance: Option<f64>,
+        episode_length: Option<u32>,
+        max_bars: Option<usize>,
+        log_path: Option<String>,
+    ) -> anyhow::Result<Self> {
+        assert!(window > 0, "window must span at least one bar");
+        assert!(
+            !tickers.is_empty(),
+            "LiveStockEnv needs at least one ticker"
+        );
+
+        let timeframe = timeframe.unwrap_or(Timeframe::Min);
+        let starting_balance = starting_balance.unwrap_or(10000f64);
+        let episode_length = episode_length.unwrap_or(390);
+        let max_bars = max_bars.unwrap_or(200);
+        let log_path = log_path.unwrap_or(String::from("./results/live_events.csv"));
+
+        let mut tickers = tickers;
+        tickers.sort();
+
+        let columns: Vec<String> = stockframe_columns
+            .iter()
+            .map(|s| String::from(*s))
+            .collect();
+        let frame = polars::prelude::DataFrame::new(
+            columns
+                .iter()
+                .map(|name| match name.as_str() {
+                    "symbol" => {
+                        polars::prelude::Series::new_empty(name, &polars::prelude::DataType::String)
+                    }
+                    "timestamp" => polars::prelude::Series::new_empty(
+                        name,
+                        &polars::prelude::DataType::Datetime(
+                            polars::prelude::TimeUnit::Milliseconds,
+                            None,
+                        ),
+                    ),
+                    _ => polars::prelude::Series::new_empty(
+                        name,
+                        &polars::prelude::DataType::Float64,
+                    ),
+                })
+                .collect(),
+        )?;
+
+        Ok(LiveStockEnv {
+            broker,
+            stockframe: StockFrame {
+                columns,
+                tickers: tickers.clone(),
+                timeframe,
+                frame: std::cell::RefCell::new(frame),
+            },
+            max_bars,
+            poll_interval: timeframe.duration().to_std()?,
+            poll_retries: 5,
+            poll_backoff: std::time::Duration::from_secs(1),
+            reset_rounds: 3,
+            logger: Logger::with_columns(log_path, &event_columns)?,
+            window: window as u32,
+            history: std::collections::VecDeque::new(),
+            starting_balance,
+            balance: starting_balance,
+            holdings: vec![0f64; tickers.len()],
+            buy_price: vec![0f64; tickers.len()],
+            prices: vec![0f64; tickers.len()],
+            total_asset: vec![starting_balance],
+            num_trades: 0,
+            reward: 0f64,
+            step: 0,
+            episode_length,
+            episode_ended: true,
+            tickers,
+        })
+    }
+
+    pub fn holdings_value(&self) -> f64 {
+        self.holdings
+            .iter()
+            .zip(self.prices.iter())
+            .map(|(shares, price)| shares * price)
+            .sum()
+    }
+
+    // balance and unrealized pnl, the stacked market features of the window, then holdings
+    pub fn observation(&self) -> Vec<f64> {
+        let unrealized_pnl: Vec<f64> = (0..self.tickers.len())
+            .map(|idx| (self.prices[idx] - self.buy_price[idx]) * self.holdings[idx])
+            .collect();
+
+        [
+            vec![self.balance],
+            unrealized_pnl,
+            self.history.iter().flatten().copied().collect(),
+            self.holdings.clone(),
+        ]
+        .concat()
+    }
+
+    // appends the latest bars, recomputes indicators and pushes the newest features into the window
+    pub fn poll(&mut self) -> anyhow::Result<()> {
+        let bars = self.broker.latest_bars(&self.tickers)?;
+
+        let series = |name: &str, values: Vec<f64>| {
+            <polars::prelude::Series as polars::prelude::NamedFrom<Vec<f64>, _>>::new(name, values)
+        };
+        let field = |get: fn(&Bar) -> f64| bars.iter().map(get).collect::<Vec<f64>>();
+
+        let mut columns = vec![
+            <polars::prelude::Series as polars::prelude::NamedFrom<Vec<String>, _>>::new(
+                "symbol",
+                bars.iter().map(|bar| bar.symbol.clone()).collect(),
+            ),
+            <polars::prelude::Series as polars::prelude::NamedFrom<Vec<i64>, _>>::new(
+                "timestamp",
+                bars.iter()
+                    .map(|bar| bar.timestamp.and_utc().timestamp_millis())
+                    .collect(),
+            )
+            .cast(&polars::prelude::DataType::Datetime(
+                polars::prelude::TimeUnit::Milliseconds,
+                None,
+            ))?,
+            series("open", field(|bar| bar.open)),
+            series("high", field(|bar| bar.high)),
+            series("low", field(|bar| bar.low)),
+            series("close", field(|bar| bar.close)),
+            series("volume", field(|bar| bar.volume)),
+            series("vwap", field(|bar| bar.vwap)),
+            series("trade_count", field(|bar| bar.trade_count)),
+        ];
+
+        for name in &stockframe_columns[9..] {
+            columns.push(polars::prelude::Series::full_null(
+                name,
+                bars.len(),
+                &polars::prelude::DataType::Float64,
+            ));
+        }
+
+        let new_bars = polars::prelude::DataFrame::new(columns)?;
+
+        // the same bar can come back when polling faster than the timeframe, keep one copy
+        let frame = self.stockframe.frame.borrow().vstack(&new_bars)?;
+        let frame = <polars::prelude::DataFrame as polars::prelude::IntoLazy>::lazy(frame)
+            .unique_stable(
+                Some(vec![String::from("symbol"), String::from("timestamp")]),
+                polars::prelude::UniqueKeepStrategy::Last,
+            )
+            .sort(["symbol", "timestamp"], Default::default())
+            .collect()?;
+
+        let frame = <polars::prelude::DataFrame as polars::prelude::IntoLazy>::lazy(frame)
+            .filter(
+                polars::prelude::col("timestamp")
+                    .cum_count(true)
+                    .over(["symbol"])
+                    .lt_eq(polars::prelude::lit(self.max_bars as u32)),
+            )
+            .collect()?;
+
+        self.stockframe.frame.replace(frame);
+        self.stockframe.calc_technical_indicators(None)?;
+
+        // same warmup handling as the backtest env
+        let features = self
+            .stockframe
+            .frame
+            .borrow()
+            .fill_null(polars::prelude::FillNullStrategy::Zero)?;
+        let latest = <polars::prelude::DataFrame as polars::prelude::IntoLazy>::lazy(features)
+            .group_by_stable([polars::prelude::col("symbol")])
+            .agg([polars::prelude::all().last()])
+            .sort(["symbol"], Default::default())
+            .collect()?;
+
+        self.prices = latest.column("close")?.f64()?.into_no_null_iter().collect();
+
+        let flat_data: Vec<f64> = latest
+            .drop_many(&[String::from("symbol"), String::from("timestamp")])
+            .to_ndarray::<polars::prelude::Float64Type>(polars::prelude::IndexOrder::C)?
+            .iter()
+            .copied()
+            .collect();
+
+        // the first bar of an episode pads the whole window
+        if self.history.is_empty() {
+            self.history = std::iter::repeat(flat_data)
+                .take(self.window as usize)
+                .collect();
+        } else {
+            self.history.push_back(flat_data);
+
+            while self.history.len() > self.window as usize {
+                self.history.pop_front();
+            }
+        }
+
+        Ok(())
+    }
+
+    // submits the order and books whatever filled, failed orders are logged and leave the account untouched
+    fn execute(&mut self, idx: usize, qty: f64, side: Side) {
+        if qty <= 0f64 {
+            return;
+        }
+
+        let order = Order {
+            symbol: self.tickers[idx].clone(),
+            qty,
+            side,
+        };
+
+        let fill = match self.broker.submit_order(&order) {
+            Ok(fill) => fill,
+            Err(err) => {
+                self.log_event("order_failed", format!("{:?}: {}", order, err).as_str());
+                return;
+            }
+        };
+
+        match side {
+            Side::Buy => {
+                let holding = self.holdings[idx] + fill.qty;
+
+                // if theres existing holdings take average price
+                if holding > 0f64 {
+                    self.buy_price[idx] = (self.holdings[idx] * self.buy_price[idx]
+                        + fill.qty * fill.price)
+                        / holding;
+                }
+
+                self.balance -= fill.qty * fill.price;
+                self.holdings[idx] = holding;
+            }
+            Side::Sell => {
+                self.balance += fill.qty * fill.price;
+                self.holdings[idx] -= fill.qty;
+
+                // reset price if thats the last share
+                if self.holdings[idx] <= 0f64 {
+                    self.buy_price[idx] = 0f64;
+                }
+            }
+        }
+
+        self.num_trades += 1;
+    }
+
+    // trades every symbol towards its target weight at the latest prices, sells first to free up cash
+    pub fn rebalance(&mut self, weights: Vec<f64>) {
+        let total_asset = self.balance + self.holdings_value();
+
+        let deltas: Vec<f64> = (0..self.tickers.len())
+            .map(|idx| (weights[idx] * total_asset / self.prices[idx]).floor() - self.holdings[idx])
+            .collect();
+
+        for (idx, delta) in deltas.iter().enumerate() {
+            if *delta < 0f64 {
+                self.execute(idx, delta.abs().min(self.holdings[idx]), Side::Sell);
+            }
+        }
+
+        for (idx, delta) in deltas.iter().enumerate() {
+            if *delta > 0f64 {
+                let affordable = (self.balance / self.prices[idx]).floor();
+                self.execute(idx, delta.min(affordable), Side::Buy);
+            }
+        }
+    }
+
+    // polls until it succeeds or poll_retries retries have failed, every failure is logged
+    pub fn poll_with_backoff(&mut self) -> anyhow::Result<()> {
+        let mut backoff = self.poll_backoff;
+        let mut retries = 0;
+
+        loop {
+            let err = match self.poll() {
+                Ok(()) => return Ok(()),
+                Err(err) => err,
+            };
+
+            self.log_event("poll_failed", format!("attempt {}: {}", retries + 1, err).as_str());
+
+            if retries >= self.poll_retries {
+                return Err(err);
+            }
+
+            if !backoff.is_zero() {
+                std::thread::sleep(backoff);
+            }
+
+            backoff *= 2;
+            retries += 1;
+        }
+    }
+
+    // a broken log must not take the session and its open positions down with it
+    fn log_event(&mut self, event: &str, detail: &str) {
+        let _ = self.logger.event(event, detail);
+    }
+
+    // positions from the previous episode are sold off, then the budget restarts at starting_balance
+    // there is nothing to observe without bars, so this fails once reset_rounds rounds of polling have
+    // failed, bad keys or a ticker without bars never clear up however long it waits
+    pub fn try_reset(&mut self) -> anyhow::Result<Box<dyn Trajectory>> {
+        for idx in 0..self.tickers.len() {
+            self.execute(idx, self.holdings[idx], Side::Sell);
+        }
+
+        // shares a failed sell left over stay on the books, the budget only restarts once the account is all cash
+        if self.holdings.iter().all(|shares| *shares <= 0f64) {
+            self.balance = self.starting_balance;
+        }
+
+        self.num_trades = 0;
+        self.reward = 0f64;
+        self.step = 0;
+        self.episode_ended = true;
+        self.history.clear();
+
+        let mut rounds = 1;
+        while let Err(err) = self.poll_with_backoff() {
+            if rounds >= self.reset_rounds {
+                return Err(err.context(format!("No bars after {} rounds of polling", rounds)));
+            }
+
+            rounds += 1;
+        }
+
+        self.episode_ended = false;
+        self.total_asset = vec![self.balance + self.holdings_value()];
+
+        Ok(Box::new(Restart {
+            observation: self.observation(),
+        }))
+    }
+
+    fn wait(&self) {
+        if !self.poll_interval.is_zero() {
+            std::thread::sleep(self.poll_interval);
+        }
+    }
+}
+
+impl Environment for LiveStockEnv {
+    fn action_spec(&self) -> Spec {
+        Spec::new(-1.0, 1.0, self.tickers.len() as u32)
+    }
+
+    fn observation_spec(&self) -> Spec {
+        let market_features = stockframe_columns.len() - 2;
+        let len = 1
+            + 2 * self.tickers.len()
+            + self.window as usize * self.tickers.len() * market_features;
+
+        Spec::new(f64::NEG_INFINITY, f64::INFINITY, len as u32)
+    }
+
+    fn step(&mut self, action: Vec<f64>) -> Box<dyn Trajectory> {
+        if self.episode_ended {
+            return self.reset();
+        }
+
+        let total_asset_starting = *self.total_asset.last().unwrap();
+
+        self.rebalance(portfolio_weights(&action));
+
+        self.wait();
+        let polled = self.poll_with_backoff();
+        self.step += 1;
+
+        // without fresh bars the holdings are valued at the last prices that came in
+        let total_asset_ending = self.balance + self.holdings_value();
+        self.total_asset.push(total_asset_ending);
+        self.reward = (total_asset_ending / total_asset_starting).ln();
+
+        // the positions only ever change by filled orders, the next reset sells them off
+        if polled.is_err() {
+            self.episode_ended = true;
+            self.log_event("episode_ended", "market data stayed unavailable");
+
+            // losing the feed is not a state of the market, keep bootstrapping
+            return Box::new(Terminate {
+                observation: self.observation(),
+                reward: self.reward,
+                discount: 1f64,
+            });
+        }
+
+        if self.step >= self.episode_length {
+            self.episode_ended = true;
+
+            // the session running out is a time limit, keep bootstrapping
+            return Box::new(Terminate {
+                observation: self.observation(),
+                reward: self.reward,
+                discount: 1f64,
+            });
+        }
+
+        Box::new(Transition {
+            observation: self.observation(),
+            reward: self.reward,
+        })
+    }
+
+    // a feed that stays down through every round of a reset is not coming back on its own, see try_reset
+    fn reset(&mut self) -> Box<dyn Trajectory> {
+        self.try_reset()
+            .unwrap_or_else(|err| panic!("Failed to reset LiveStockEnv: {:#}", err))
+    }
+}
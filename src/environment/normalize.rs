@@ -0,0 +1,142 @@
+use crate::environment::{with_observation, Environment, Spec, Trajectory};
+
+// standardizes observations with a running mean and variance (welford)
+pub struct NormalizeObservation {
+    pub env: Box<dyn Environment>,
+    pub count: f64,
+    pub mean: Vec<f64>,
+    pub m2: Vec<f64>,
+    pub epsilon: f64,
+
+    // clamps standardized observations to [-obs_clip, obs_clip], None leaves them unbounded
+    pub obs_clip: Option<f64>,
+
+    // stop updating the statistics, e.g. for evaluation
+    pub frozen: bool,
+}
+
+impl NormalizeObservation {
+    pub fn new(env: Box<dyn Environment>, epsilon: Option<f64>, obs_clip: Option<f64>) -> Self {
+        if let Some(obs_clip) = obs_clip {
+            assert!(obs_clip > 0f64, "obs_clip must be positive");
+        }
+
+        let shape = env.observation_spec().shape as usize;
+
+        NormalizeObservation {
+            env,
+            count: 0f64,
+            mean: vec![0f64; shape],
+            m2: vec![0f64; shape],
+            epsilon: epsilon.unwrap_or(1e-8),
+            obs_clip,
+            frozen: false,
+        }
+    }
+
+    pub fn variance(&self) -> Vec<f64> {
+        match self.count > 1f64 {
+            true => self.m2.iter().map(|m2| m2 / self.count).collect(),
+            false => vec![1f64; self.m2.len()],
+        }
+    }
+
+    pub fn update(&mut self, observation: &[f64]) {
+        self.count += 1f64;
+
+        for idx in 0..observation.len() {
+            let delta = observation[idx] - self.mean[idx];
+            self.mean[idx] += delta / self.count;
+            self.m2[idx] += delta * (observation[idx] - self.mean[idx]);
+        }
+    }
+
+    pub fn normalize(&self, observation: &[f64]) -> Vec<f64> {
+        observation
+            .iter()
+            .zip(self.mean.iter().zip(self.variance().iter()))
+            .map(|(obs, (mean, var))| (obs - mean) / (var + self.epsilon).sqrt())
+            .map(|obs| match self.obs_clip {
+                Some(clip) => obs.clamp(-clip, clip),
+                None => obs,
+            })
+            .collect()
+    }
+
+    fn process(&mut self, ts: Box<dyn Trajectory>) -> Box<dyn Trajectory> {
+        let observation = ts.observation();
+
+        if !self.frozen {
+            self.update(&observation);
+        }
+
+        let normalized = self.normalize(&observation);
+        with_observation(ts, normalized)
+    }
+}
+
+impl Environment for NormalizeObservation {
+    fn action_spec(&self) -> Spec {
+        self.env.action_spec()
+    }
+
+    fn observation_spec(&self) -> Spec {
+        let inner = self.env.observation_spec();
+        let bound = self.obs_clip.unwrap_or(f64::INFINITY);
+        Spec::new(-bound, bound, inner.shape).with_dtype(inner.dtype)
+    }
+
+    fn step(&mut self, action: Vec<f64>) -> Box<dyn Trajectory> {
+        let ts = self.env.step(action);
+        self.process(ts)
+    }
+
+    fn reset(&mut self) -> Box<dyn Trajectory> {
+        let ts = self.env.reset();
+        self.process(ts)
+    }
+}
+
+// lets the policy act in [-1, 1] regardless of the inner action bounds
+pub struct NormalizeAction {
+    pub env: Box<dyn Environment>,
+}
+
+impl NormalizeAction {
+    pub fn new(env: Box<dyn Environment>) -> Self {
+        NormalizeAction { env }
+    }
+
+    pub fn rescale(&self, action: Vec<f64>) -> Vec<f64> {
+        let spec = self.env.action_spec();
+
+        action
+            .iter()
+            .enumerate()
+            .map(|(idx, act)| {
+                let (low, high) = (spec.low[idx], spec.high[idx]);
+                low + (act.clamp(-1f64, 1f64) + 1f64) * 0.5 * (high - low)
+            })
+            .collect()
+    }
+}
+
+impl Environment for NormalizeAction {
+    fn action_spec(&self) -> Spec {
+        let inner = self.env.action_spec();
+        Spec::new(-1f64, 1f64, inner.shape).with_dtype(inner.dtype)
+    }
+
+    fn observation_spec(&self) -> Spec {
+        self.env.observation_spec()
+    }
+
+    fn step(&mut self, action: Vec<f64>) -> Box<dyn Trajectory> {
+        let action = self.rescale(action);
+        self.env.step(action)
+    }
+
+    fn reset(&mut self) -> Box<dyn Trajectory> {
+        self.env.reset()
+    }
+}
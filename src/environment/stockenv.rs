@@ -1,6 +1,7 @@
+extern crate anyhow;
 extern crate polars;
 
-use crate::environment::{Environment, Restart, Spec, Terminate, Trajectory, Transition};
+use crate::environment::{Dtype, Environment, Restart, Spec, Terminate, Trajectory, Transition};
 use crate::stockframe::StockFrame;
 
 #[derive(Clone)]
@@ -9,6 +10,7 @@ pub struct StockEnv {
     pub data: polars::prelude::DataFrame,
 
     pub iteration: u32,
+    pub step: u32,
     pub feature_length: u32,
     pub train_start: polars::export::chrono::NaiveDateTime,
     pub train_end: polars::export::chrono::NaiveDateTime,
@@ -25,91 +27,132 @@ pub struct StockEnv {
     pub portfolio_value: f64,
     pub state: Vec<f64>,
     pub reward: f64,
-}
 
-fn calc_returns(series: polars::prelude::Series) -> polars::prelude::Series {
-    let period_return = series.clone() / series.clone().shift(1) - 1;
-    period_return.slice(1, period_return.len()).clone()
+    // simple return of the total asset value for every step of the episode
+    pub returns: Vec<f64>,
+    pub num_trades: u32,
+    pub stats: Option<EpisodeStats>,
+
+    // stockframe columns the observation is built from, in this order
+    pub feature_columns: Vec<String>,
+    // market features of the last `window` bars, oldest first
+    pub window: u32,
+    pub history: std::collections::VecDeque<Vec<f64>>,
+
+    pub tickers: Vec<String>,
+    pub weights: Vec<f64>,
+    pub commission_fixed: f64,
+    pub commission_pct: f64,
+    pub slippage: f64,
+
+    // cash the account starts every episode with
+    pub initial_cash: f64,
+    // largest fraction of the total asset value a single symbol may hold
+    pub max_position: f64,
+
+    pub reward_kind: RewardKind,
+    // running estimates of the first and second moment of the step returns, for the differential sharpe reward
+    pub return_moments: (f64, f64),
+
+    // audit trail of the current episode, one record per symbol per step
+    pub record_trades: bool,
+    pub trades: Vec<TradeRecord>,
+
+    // symbol and timestamp millis of the bars fill_date_range padded in, they carry a stale close and are never traded on
+    pub padded_bars: std::collections::HashSet<(String, i64)>,
 }
 
-fn calc_gain_to_pain(series: polars::prelude::Series) -> f64 {
-    let returns = calc_returns(series.drop_nulls().tail(Some(30)));
-    let sum_returns: f64 = returns.sum().unwrap();
+// what the policy asked for on a step and where it left the account
+#[derive(Clone, Debug, PartialEq)]
+pub struct TradeRecord {
+    pub timestamp: polars::export::chrono::NaiveDateTime,
+    pub symbol: String,
+    pub action: f64,
+    pub price: f64,
+    pub position: f64,
+    pub reward: f64,
+}
 
-    let sum_neg_returns: f64 = returns
-        .iter()
-        .map(|f| {
-            let x = match f {
-                polars::prelude::AnyValue::Float64(z) => z,
-                _ => 0f64,
-            };
-
-            if x < 0f64 {
-                x.abs()
-            } else {
-                0f64
-            }
-        })
-        .collect::<Vec<f64>>()
-        .iter()
-        .sum();
+pub const trade_columns: [&str; 6] = [
+    "timestamp",
+    "symbol",
+    "action",
+    "price",
+    "position",
+    "reward",
+];
 
-    sum_returns / (sum_neg_returns + 1f64)
+// how a step's return of the total asset value is turned into the reward
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RewardKind {
+    // log return of the step
+    LogReturn,
+    // change of the episode's sharpe ratio the step caused
+    SharpeIncremental,
+    // moody and saffell's differential sharpe ratio, eta is the decay of the moment estimates
+    DifferentialSharpe { eta: f64 },
 }
 
-fn calc_lake_ratio(series: polars::prelude::Series) -> f64 {
-    let mut water = 0f64;
-    let mut earth = 0f64;
-    let mut peak: f64;
-    let mut waterlevel: Vec<f64> = vec![];
-    let mut s = calc_returns(series.clone());
-    s = s
-        .add_to(&<polars::prelude::Series as polars::prelude::NamedFrom<
-            Vec<f64>,
-            _,
-        >>::new("_", vec![1f64; s.len()]))
-        .unwrap();
-    s = polars::prelude::cum_prod(&s, false).unwrap().drop_nulls();
-
-    for (idx, f) in s.iter().enumerate() {
-        let x = match f {
-            polars::prelude::AnyValue::Float64(z) => z,
-            _ => 0f64,
-        };
-
-        if idx == 0 {
-            peak = x;
-        } else {
-            peak = s.slice(0, idx).max().unwrap().unwrap();
-        }
+impl Default for RewardKind {
+    fn default() -> Self {
+        RewardKind::LogReturn
+    }
+}
 
-        waterlevel.push(peak);
+// sharpe ratio of the returns, not annualized, 0 while it is undefined
+fn sharpe_ratio(returns: &[f64]) -> f64 {
+    if returns.len() < 2 {
+        return 0f64;
+    }
 
-        if (x) < peak {
-            water += peak - x
-        }
+    let n = returns.len() as f64;
+    let mean = returns.iter().sum::<f64>() / n;
+    let variance = returns.iter().map(|r| (r - mean).powi(2)).sum::<f64>() / (n - 1f64);
 
-        earth += x;
+    match variance > 0f64 {
+        true => mean / variance.sqrt(),
+        false => 0f64,
     }
+}
 
-    water / earth
+#[derive(Clone, Debug, PartialEq)]
+pub struct EpisodeStats {
+    pub total_return: f64,
+    pub sharpe: f64,
+    pub max_drawdown: f64,
+    pub num_trades: u32,
+}
+
+// softmax over the raw action, every symbol gets a share of the portfolio and the shares sum to 1
+pub fn portfolio_weights(action: &[f64]) -> Vec<f64> {
+    let max = action.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    let exp: Vec<f64> = action.iter().map(|a| (a - max).exp()).collect();
+    let sum: f64 = exp.iter().sum();
+
+    exp.iter().map(|e| e / sum).collect()
+}
+
+// the named columns of every symbol's row, flattened symbol by symbol
+pub fn market_features(
+    data: &polars::prelude::DataFrame,
+    feature_columns: &[String],
+) -> Vec<f64> {
+    data.select(feature_columns.iter().map(|name| name.as_str()))
+        .unwrap()
+        .to_ndarray::<polars::prelude::Float64Type>(polars::prelude::IndexOrder::C)
+        .unwrap()
+        .iter()
+        .copied()
+        .collect()
 }
 
 impl Environment for StockEnv {
     fn action_spec(&self) -> Spec {
-        Spec {
-            min: -1.0,
-            max: 1.0,
-            shape: tickers.len() as u32,
-        }
+        Spec::new(-1.0, 1.0, self.tickers.len() as u32).with_dtype(Dtype::Float)
     }
 
     fn observation_spec(&self) -> Spec {
-        Spec {
-            min: f64::NEG_INFINITY,
-            max: f64::INFINITY,
-            shape: self.state.len() as u32,
-        }
+        Spec::new(f64::NEG_INFINITY, f64::INFINITY, self.observation().len() as u32).with_dtype(Dtype::Float)
     }
 
     fn step(&mut self, action: Vec<f64>) -> Box<dyn Trajectory> {
@@ -117,7 +160,7 @@ impl Environment for StockEnv {
             return self.reset();
         }
 
-        let mut new_ts = self.timestamp + polars::export::chrono::Duration::minutes(1);
+        let mut new_ts = self.timestamp + self.stockframe.timeframe.duration();
         let mut data: polars::prelude::DataFrame;
 
         loop {
@@ -134,106 +177,47 @@ impl Environment for StockEnv {
             if data.shape().0 != 0 {
                 break;
             } else {
-                new_ts += polars::export::chrono::Duration::minutes(1);
+                new_ts += self.stockframe.timeframe.duration();
 
                 if new_ts.timestamp_millis() > self.train_end.timestamp_millis() {
                     self.episode_ended = true;
+                    self.stats = Some(self.calc_episode_stats());
+                    // running out of bars is a time limit, keep bootstrapping
                     return Box::new(Terminate {
-                        observation: self.state.clone(),
+                        observation: self.observation(),
                         reward: 0.0,
+                        discount: 1f64,
                     });
                 }
             }
         }
 
-        let flat_data: Vec<f64> = data
-            .clone()
-            .drop_many(&[String::from("symbol"), String::from("timestamp")])
-            .to_ndarray::<polars::prelude::Float64Type>(polars::prelude::IndexOrder::C)
-            .unwrap()
-            .iter()
-            .copied()
-            .collect();
+        let flat_data = market_features(&data, &self.feature_columns);
         self.data = data.clone();
         self.timestamp = new_ts;
+        self.step += 1;
 
-        self.portfolio_value = (0..tickers.len())
-            .collect::<Vec<usize>>()
-            .iter()
-            .map(|idx| {
-                let symbol = tickers[*idx];
-                let df = self.data.clone();
-                let ticker_df = polars::prelude::IntoLazy::lazy(df)
-                    .filter(polars::prelude::col("symbol").eq(polars::prelude::lit(symbol)))
-                    .collect()
-                    .unwrap();
-
-                assert_ne!(ticker_df.shape().0, 0); // data must exist nulls are bad
-
-                ticker_df["close"].f64().unwrap().get(0).unwrap()
-            })
-            .collect::<Vec<f64>>()
-            .iter()
-            .sum();
-
-        let total_asset_starting = self.state[0] + self.portfolio_value;
+        self.rebalance(portfolio_weights(&action));
 
-        // we do all the sell order before buy orders to free up cash
-        let mut indices: Vec<usize> = (0..action.len()).collect();
-        indices.sort_by(|&i, &j| action[i].partial_cmp(&action[j]).unwrap());
-
-        for idx in indices {
-            if action[idx] < 0f64 {
-                self.sell(idx as u32, action[idx]);
-            } else if action[idx] > 0f64 {
-                self.buy(idx as u32, action[idx]);
-            }
-        }
+        let total_asset_starting = *self.total_asset.last().unwrap();
 
-        self.unrealized_pnl = (0..tickers.len())
-            .collect::<Vec<usize>>()
-            .iter()
+        self.unrealized_pnl = (0..self.tickers.len())
             .map(|idx| {
-                let symbol = tickers[*idx];
-                let df = self.data.clone();
-                let ticker_df = polars::prelude::IntoLazy::lazy(df)
-                    .filter(polars::prelude::col("symbol").eq(polars::prelude::lit(symbol)))
-                    .collect()
-                    .unwrap();
-
-                assert_ne!(ticker_df.shape().0, 0); // data must exist nulls are bad
-
-                (ticker_df["close"].f64().unwrap().get(0).unwrap() - self.buy_price[*idx])
-                    * self.state[*idx + self.feature_length as usize]
+                (self.price(idx as u32) - self.buy_price[idx])
+                    * self.state[idx + self.feature_length as usize]
             })
             .collect::<Vec<f64>>();
 
         self.state = [
             vec![self.state[0]],
             self.unrealized_pnl.clone(),
-            flat_data,
+            flat_data.clone(),
             self.state[(self.feature_length as usize)..].to_vec(),
         ]
         .concat();
+        self.push_bar(flat_data);
 
-        self.portfolio_value = (0..tickers.len())
-            .collect::<Vec<usize>>()
-            .iter()
-            .map(|idx| {
-                let symbol = tickers[*idx];
-                let df = self.data.clone();
-                let ticker_df = polars::prelude::IntoLazy::lazy(df)
-                    .filter(polars::prelude::col("symbol").eq(polars::prelude::lit(symbol)))
-                    .collect()
-                    .unwrap();
-
-                assert_ne!(ticker_df.shape().0, 0); // data must exist nulls are bad
-
-                ticker_df["close"].f64().unwrap().get(0).unwrap()
-            })
-            .collect::<Vec<f64>>()
-            .iter()
-            .sum();
+        self.portfolio_value = self.holdings_value();
 
         let total_asset_ending = self.state[0] + self.portfolio_value;
 
@@ -241,33 +225,37 @@ impl Environment for StockEnv {
         self.portfolio_asset.push(self.portfolio_value);
         self.total_asset.push(total_asset_ending);
         self.timeline.push(self.timestamp);
+        self.returns
+            .push(total_asset_ending / total_asset_starting - 1f64);
 
-        if self.total_asset.len() > 29 {
-            let total_asset = <polars::prelude::Series as polars::prelude::NamedFrom<
-                Vec<f64>,
-                _,
-            >>::new("_", self.total_asset.clone());
-            self.reward = total_asset_ending - total_asset_starting
-                + (100f64 * calc_gain_to_pain(total_asset.clone()))
-                - (500f64 * calc_lake_ratio(total_asset.clone()));
-        } else {
-            self.reward = total_asset_ending - total_asset_starting;
+        // every reward is computed from returns of the whole portfolio, commissions and slippage already left the balance
+        // being returns they read the same whatever initial_cash the account started with
+        self.reward = self.step_reward(total_asset_ending / total_asset_starting - 1f64);
+
+        if self.record_trades {
+            self.record_step(&action);
         }
 
         Box::new(Transition {
-            observation: self.state.clone(),
+            observation: self.observation(),
             reward: self.reward,
         })
     }
 
     fn reset(&mut self) -> Box<dyn Trajectory> {
         self.episode_ended = false;
-        self.acc_balance = vec![10000f64];
-        self.total_asset = vec![10000f64];
+        self.acc_balance = vec![self.initial_cash];
+        self.total_asset = vec![self.initial_cash];
         self.portfolio_asset = vec![0f64];
-        self.buy_price = vec![0f64; tickers.len()];
-        self.unrealized_pnl = vec![0f64];
+        self.buy_price = vec![0f64; self.tickers.len()];
+        self.unrealized_pnl = vec![0f64; self.tickers.len()];
+        self.weights = vec![0f64; self.tickers.len()];
+        self.returns = vec![];
+        self.return_moments = (0f64, 0f64);
+        self.num_trades = 0;
+        self.trades = vec![];
         self.portfolio_value = 0.0;
+        self.step = 0;
 
         self.timestamp = self.train_start;
         self.timeline = vec![self.timestamp];
@@ -282,26 +270,20 @@ impl Environment for StockEnv {
             .collect()
             .unwrap();
 
-        let flat_data: Vec<f64> = self
-            .data
-            .clone()
-            .drop_many(&[String::from("symbol"), String::from("timestamp")])
-            .to_ndarray::<polars::prelude::Float64Type>(polars::prelude::IndexOrder::C)
-            .unwrap()
-            .iter()
-            .copied()
-            .collect();
+        let flat_data = market_features(&self.data, &self.feature_columns);
         self.state = [
             self.acc_balance.clone(),
             self.unrealized_pnl.clone(),
-            flat_data,
-            vec![0f64; tickers.len()],
+            flat_data.clone(),
+            vec![0f64; self.tickers.len()],
         ]
         .concat();
+        self.history.clear();
+        self.push_bar(flat_data);
         self.iteration += 1;
 
         Box::new(Restart {
-            observation: self.state.clone(),
+            observation: self.observation(),
         })
     }
 }
@@ -316,21 +298,106 @@ impl StockEnv {
     pub fn new(
         start: polars::export::chrono::NaiveDateTime,
         end: polars::export::chrono::NaiveDateTime,
+        window: usize,
+        commission_fixed: Option<f64>,
+        commission_pct: Option<f64>,
+        slippage: Option<f64>,
+        initial_cash: Option<f64>,
+        max_position: Option<f64>,
+        feature_columns: Option<Vec<String>>,
+        reward_kind: Option<RewardKind>,
     ) -> Self {
         let mut stockframe = StockFrame::new(
             Some(tickers.iter().map(|s| String::from(*s)).collect()),
             Some(start),
             Some(end),
+            None,
+            None,
+            None,
         );
 
-        stockframe.parse_dt_column();
-        stockframe.fill_date_range();
-        stockframe.fill_nulls();
+        stockframe.parse_dt_column().expect("Failed to parse date time index");
+
+        StockEnv::from_stockframe(
+            stockframe,
+            window,
+            commission_fixed,
+            commission_pct,
+            slippage,
+            initial_cash,
+            max_position,
+            feature_columns,
+            reward_kind,
+        )
+    }
+
+    // builds the environment from bars that are already loaded with a parsed timestamp column
+    // window is how many bars of market features each observation stacks
+    // commission_fixed is charged per order, commission_pct scales with the order
+    // slippage is the fraction the fill price moves against the order per dollar of notional, so a dollar
+    // amount costs the same whatever the share price
+    // max_position caps the weight of every symbol, whatever the cap cuts off stays in cash
+    // feature_columns picks the market features of the observation, None uses every column but symbol and timestamp
+    // reward_kind defaults to the log return of every step
+    pub fn from_stockframe(
+        mut stockframe: StockFrame,
+        window: usize,
+        commission_fixed: Option<f64>,
+        commission_pct: Option<f64>,
+        slippage: Option<f64>,
+        initial_cash: Option<f64>,
+        max_position: Option<f64>,
+        feature_columns: Option<Vec<String>>,
+        reward_kind: Option<RewardKind>,
+    ) -> Self {
+        assert!(window > 0, "window must span at least one bar");
+
+        let commission_fixed = commission_fixed.unwrap_or(0f64);
+        let commission_pct = commission_pct.unwrap_or(0.0005);
+        let slippage = slippage.unwrap_or(0.0000001);
+        let initial_cash = initial_cash.unwrap_or(10000f64);
+        let max_position = max_position.unwrap_or(1f64);
+        let reward_kind = reward_kind.unwrap_or_default();
 
-        unsafe {
-            stockframe.calc_technical_indicators();
+        if let RewardKind::DifferentialSharpe { eta } = reward_kind {
+            assert!(eta > 0f64 && eta <= 1f64, "eta must be in (0, 1]");
         }
 
+        assert!(
+            initial_cash > 0f64 && initial_cash.is_finite(),
+            "initial_cash must be positive"
+        );
+        assert!(
+            max_position > 0f64 && max_position <= 1f64,
+            "max_position must be a fraction of the portfolio in (0, 1]"
+        );
+
+        stockframe.fill_date_range().expect("Failed to fill date range");
+
+        // fill_nulls only carries the last close over the gaps, remember where they were before it does
+        let padded = polars::prelude::IntoLazy::lazy(stockframe.frame.borrow().clone())
+            .filter(polars::prelude::col("close").is_null())
+            .select([
+                polars::prelude::col("symbol"),
+                polars::prelude::col("timestamp")
+                    .dt()
+                    .timestamp(polars::datatypes::TimeUnit::Milliseconds),
+            ])
+            .collect()
+            .expect("Failed to find padded bars");
+        let padded_bars = padded["symbol"]
+            .str()
+            .unwrap()
+            .into_no_null_iter()
+            .zip(padded["timestamp"].i64().unwrap().into_no_null_iter())
+            .map(|(symbol, ts)| (String::from(symbol), ts))
+            .collect::<std::collections::HashSet<(String, i64)>>();
+
+        stockframe.fill_nulls().expect("Failed to fill null bars");
+        stockframe
+            .calc_technical_indicators(None)
+            .expect("Failed to calculate technical indicators");
+
         // fill volume, vwap, and trade_count with zeros
         stockframe.frame = std::cell::RefCell::new(
             stockframe
@@ -340,10 +407,10 @@ impl StockEnv {
                 .fill_null(polars::prelude::FillNullStrategy::Zero)
                 .unwrap(),
         );
-        stockframe.clean();
+        stockframe.clean(None).expect("Failed to clean bars");
 
         // sort
-        stockframe.update_symbol_groups();
+        stockframe.update_symbol_groups().expect("Failed to group bars by symbol");
         stockframe.frame = std::cell::RefCell::new(
             stockframe
                 .clone()
@@ -353,14 +420,38 @@ impl StockEnv {
                 .unwrap(),
         );
 
-        let acc_balance = vec![10000f64];
-        let total_asset = vec![10000f64];
+        let env_tickers = stockframe.tickers.clone();
+
+        let market_columns: Vec<String> = stockframe
+            .frame
+            .borrow()
+            .get_column_names()
+            .iter()
+            .filter(|name| **name != "symbol" && **name != "timestamp")
+            .map(|name| String::from(*name))
+            .collect();
+        let feature_columns = feature_columns.unwrap_or(market_columns.clone());
+
+        assert!(
+            !feature_columns.is_empty(),
+            "feature_columns must name at least one column"
+        );
+        for name in &feature_columns {
+            assert!(
+                market_columns.contains(name),
+                "{} is not a market feature of the stockframe",
+                name
+            );
+        }
+
+        let acc_balance = vec![initial_cash];
+        let total_asset = vec![initial_cash];
         let portfolio_asset = vec![0f64];
-        let buy_price = vec![0f64; tickers.len()];
-        let unrealized_pnl = vec![0f64];
+        let buy_price = vec![0f64; env_tickers.len()];
+        let unrealized_pnl = vec![0f64; env_tickers.len()];
 
         let mut df_start = stockframe.get_min_timestamp();
-        let df_end = stockframe.get_min_timestamp();
+        let df_end = stockframe.get_max_timestamp();
 
         // search for next valid timestamp
         let mut data: polars::prelude::DataFrame;
@@ -379,25 +470,21 @@ impl StockEnv {
             if data.shape().0 != 0 {
                 break;
             } else {
-                df_start += polars::export::chrono::Duration::minutes(1);
+                df_start += stockframe.timeframe.duration();
             }
         }
 
         let timeline = vec![df_start];
 
-        let flat_data: Vec<f64> = data
-            .clone()
-            .drop_many(&[String::from("symbol"), String::from("timestamp")])
-            .to_ndarray::<polars::prelude::Float64Type>(polars::prelude::IndexOrder::C)
-            .unwrap()
-            .iter()
-            .copied()
-            .collect();
-        let feature_length = 2 + flat_data.len();
+        let flat_data = market_features(&data, &feature_columns);
+
+        // holdings start after the balance, per ticker unrealized pnl and market features
+        let feature_length = 1 + env_tickers.len() + flat_data.len();
 
         StockEnv {
             stockframe: Box::new(stockframe),
             iteration: 0,
+            step: 0,
             feature_length: feature_length as u32,
             train_start: df_start,
             train_end: df_end,
@@ -414,16 +501,160 @@ impl StockEnv {
             state: [
                 acc_balance,
                 unrealized_pnl,
-                flat_data,
-                vec![0f64; tickers.len()],
+                flat_data.clone(),
+                vec![0f64; env_tickers.len()],
             ]
             .concat(),
             reward: 0.0,
+            returns: vec![],
+            num_trades: 0,
+            stats: None,
+            feature_columns,
+            window: window as u32,
+            history: std::iter::repeat(flat_data).take(window).collect(),
+            weights: vec![0f64; env_tickers.len()],
+            tickers: env_tickers,
+            commission_fixed,
+            commission_pct,
+            slippage,
+            initial_cash,
+            max_position,
+            reward_kind,
+            return_moments: (0f64, 0f64),
+            record_trades: true,
+            trades: vec![],
+            padded_bars,
+        }
+    }
+
+    // expects the step's simple return to already be pushed onto self.returns
+    fn step_reward(&mut self, step_return: f64) -> f64 {
+        match self.reward_kind {
+            RewardKind::LogReturn => step_return.ln_1p(),
+            RewardKind::SharpeIncremental => {
+                let previous = &self.returns[..self.returns.len() - 1];
+                sharpe_ratio(&self.returns) - sharpe_ratio(previous)
+            }
+            RewardKind::DifferentialSharpe { eta } => {
+                let (a, b) = self.return_moments;
+                let delta_a = step_return - a;
+                let delta_b = step_return.powi(2) - b;
+
+                let denominator = (b - a.powi(2)).powf(1.5);
+                let reward = match denominator > 0f64 {
+                    true => (b * delta_a - 0.5 * a * delta_b) / denominator,
+                    false => 0f64,
+                };
+
+                self.return_moments = (a + eta * delta_a, b + eta * delta_b);
+                reward
+            }
+        }
+    }
+
+    fn record_step(&mut self, action: &[f64]) {
+        for idx in 0..self.tickers.len() {
+            self.trades.push(TradeRecord {
+                timestamp: self.timestamp,
+                symbol: self.tickers[idx].clone(),
+                action: action[idx],
+                price: self.price(idx as u32),
+                position: self.state[idx + self.feature_length as usize],
+                reward: self.reward,
+            });
         }
     }
 
-    pub fn buy(&mut self, idx: u32, action: f64) {
-        let symbol = tickers[idx as usize];
+    // writes the trade log of the current episode as csv, timestamps in the same format StockFrame::from_csv reads
+    pub fn export_trades(&self, path: &str) -> anyhow::Result<()> {
+        if let Some(parent) = std::path::Path::new(path).parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let mut writer = std::io::BufWriter::new(std::fs::File::create(path)?);
+        std::io::Write::write_all(&mut writer, trade_columns.join(",").as_bytes())?;
+        std::io::Write::write_all(&mut writer, b"\n")?;
+
+        for trade in &self.trades {
+            let row = format!(
+                "{},{},{},{},{},{}\n",
+                trade
+                    .timestamp
+                    .and_utc()
+                    .to_rfc3339_opts(polars::export::chrono::SecondsFormat::Secs, true),
+                trade.symbol,
+                trade.action,
+                trade.price,
+                trade.position,
+                trade.reward
+            );
+            std::io::Write::write_all(&mut writer, row.as_bytes())?;
+        }
+
+        std::io::Write::flush(&mut writer)?;
+        Ok(())
+    }
+
+    // stats of the last finished episode, set when Terminate is emitted
+    pub fn episode_stats(&self) -> Option<EpisodeStats> {
+        self.stats.clone()
+    }
+
+    // sharpe is annualized with the bar timeframe, max_drawdown is the worst fall from a peak as a fraction
+    pub fn calc_episode_stats(&self) -> EpisodeStats {
+        let total_return =
+            self.total_asset.last().unwrap() / self.total_asset.first().unwrap() - 1f64;
+
+        let sharpe =
+            sharpe_ratio(&self.returns) * self.stockframe.timeframe.periods_per_year().sqrt();
+
+        let mut peak = f64::NEG_INFINITY;
+        let mut max_drawdown = 0f64;
+
+        for asset in &self.total_asset {
+            peak = peak.max(*asset);
+            max_drawdown = max_drawdown.max((peak - asset) / peak);
+        }
+
+        EpisodeStats {
+            total_return,
+            sharpe,
+            max_drawdown,
+            num_trades: self.num_trades,
+        }
+    }
+
+    // balance and unrealized pnl, the stacked market features of the window, then holdings
+    pub fn observation(&self) -> Vec<f64> {
+        let market_start = 1 + self.tickers.len();
+
+        [
+            self.state[..market_start].to_vec(),
+            self.history.iter().flatten().copied().collect(),
+            self.state[(self.feature_length as usize)..].to_vec(),
+        ]
+        .concat()
+    }
+
+    // the first bar of an episode pads the whole window
+    pub fn push_bar(&mut self, flat_data: Vec<f64>) {
+        if self.history.is_empty() {
+            self.history = std::iter::repeat(flat_data)
+                .take(self.window as usize)
+                .collect();
+            return;
+        }
+
+        self.history.push_back(flat_data);
+
+        while self.history.len() > self.window as usize {
+            self.history.pop_front();
+        }
+    }
+
+    // close of the current bar
+    pub fn price(&self, idx: u32) -> f64 {
+        let symbol = self.tickers[idx as usize].as_str();
         let df = self.data.clone();
         let ticker_df = polars::prelude::IntoLazy::lazy(df)
             .filter(polars::prelude::col("symbol").eq(polars::prelude::lit(symbol)))
@@ -432,11 +663,112 @@ impl StockEnv {
 
         assert_ne!(ticker_df.shape().0, 0); // data must exist nulls are bad
 
-        let price = ticker_df["close"].f64().unwrap().get(0).unwrap();
-        let available_unit = (self.state[0] / price).floor();
-        let num_share = (action * available_unit).floor();
+        ticker_df["close"].f64().unwrap().get(0).unwrap()
+    }
+
+    // false while the symbol has no real bar at the current timestamp
+    pub fn tradable(&self, idx: u32) -> bool {
+        let key = (self.tickers[idx as usize].clone(), self.timestamp.timestamp_millis());
+        !self.padded_bars.contains(&key)
+    }
+
+    pub fn holdings_value(&self) -> f64 {
+        (0..self.tickers.len())
+            .map(|idx| self.price(idx as u32) * self.state[idx + self.feature_length as usize])
+            .sum()
+    }
+
+    // slippage moves the fill price against the order in proportion to its notional at the quoted price
+    pub fn fill_price(&self, price: f64, num_share: f64, buying: bool) -> f64 {
+        let impact = self.slippage * price * num_share;
+
+        match buying {
+            true => price * (1f64 + impact),
+            false => (price * (1f64 - impact)).max(0f64),
+        }
+    }
 
-        self.state[0] -= num_share * price;
+    pub fn commission(&self, notional: f64) -> f64 {
+        self.commission_fixed + self.commission_pct * notional
+    }
+
+    // what the account pays for a buy, slippage and commission included
+    pub fn buy_cost(&self, price: f64, num_share: f64) -> f64 {
+        let notional = num_share * self.fill_price(price, num_share, true);
+        notional + self.commission(notional)
+    }
+
+    // most whole shares the cash pays for at this price
+    // with x = price * num_share the bill is (slippage * x^2 + x) * (1 + commission_pct) + commission_fixed,
+    // x is the positive root of that against the cash, written so it stays stable as slippage goes to 0
+    pub fn affordable_shares(&self, price: f64) -> f64 {
+        let budget = (self.state[0] - self.commission_fixed) / (1f64 + self.commission_pct);
+
+        if budget <= 0f64 || price <= 0f64 {
+            return 0f64;
+        }
+
+        let notional = 2f64 * budget / (1f64 + (1f64 + 4f64 * self.slippage * budget).sqrt());
+        let mut num_share = (notional / price).floor();
+
+        // rounding can leave the root a share above what the cash covers
+        while num_share > 0f64 && self.buy_cost(price, num_share) > self.state[0] {
+            num_share -= 1f64;
+        }
+
+        num_share
+    }
+
+    // trades every symbol towards its target weight of the total asset value at the current bar
+    // weights above max_position are clamped to it, buys are further limited by the cash on hand
+    // symbols without a real bar keep their position, the padded close is not a price anyone could fill at
+    pub fn rebalance(&mut self, weights: Vec<f64>) {
+        let weights: Vec<f64> = weights
+            .iter()
+            .map(|weight| weight.min(self.max_position))
+            .collect();
+        let total_asset = self.state[0] + self.holdings_value();
+
+        let deltas: Vec<f64> = (0..self.tickers.len())
+            .map(|idx| {
+                if !self.tradable(idx as u32) {
+                    return 0f64;
+                }
+
+                let target = (weights[idx] * total_asset / self.price(idx as u32)).floor();
+                target - self.state[idx + self.feature_length as usize]
+            })
+            .collect();
+
+        // we do all the sell order before buy orders to free up cash
+        for (idx, delta) in deltas.iter().enumerate() {
+            if *delta < 0f64 {
+                self.sell(idx as u32, delta.abs());
+            }
+        }
+
+        for (idx, delta) in deltas.iter().enumerate() {
+            if *delta > 0f64 {
+                self.buy(idx as u32, *delta);
+            }
+        }
+
+        self.weights = weights;
+    }
+
+    pub fn buy(&mut self, idx: u32, num_share: f64) {
+        let price = self.price(idx);
+
+        // slippage and fees grow the bill, shrink the order until the account can pay
+        let num_share = num_share.floor().min(self.affordable_shares(price));
+
+        if num_share <= 0f64 {
+            return;
+        }
+
+        let fill_price = self.fill_price(price, num_share, true);
+        let total_cost = self.buy_cost(price, num_share);
+        self.state[0] -= total_cost;
 
         // if theres existing holdings take average price
         if self.state[(idx + self.feature_length) as usize] > 0f64 {
@@ -444,31 +776,28 @@ impl StockEnv {
             let previous_buy_price = self.buy_price[idx as usize];
             let new_holding = existing_holdings + num_share;
             self.buy_price[idx as usize] =
-                ((existing_holdings * previous_buy_price) + (price * num_share)) / new_holding;
+                ((existing_holdings * previous_buy_price) + (fill_price * num_share)) / new_holding;
         } else if self.state[(idx + self.feature_length) as usize] == 0.0 {
-            self.buy_price[idx as usize] = price;
+            self.buy_price[idx as usize] = fill_price;
         }
 
         self.state[(idx + self.feature_length) as usize] += num_share;
+        self.num_trades += 1;
     }
 
-    pub fn sell(&mut self, idx: u32, action: f64) {
-        let num_share = (action.abs() * self.state[(idx + self.feature_length) as usize]).floor();
-
-        let symbol = tickers[idx as usize];
-        let df = self.data.clone();
-        let ticker_df = polars::prelude::IntoLazy::lazy(df)
-            .filter(polars::prelude::col("symbol").eq(polars::prelude::lit(symbol)))
-            .collect()
-            .unwrap();
-
-        assert_ne!(ticker_df.shape().0, 0); // data must exist nulls are bad
+    pub fn sell(&mut self, idx: u32, num_share: f64) {
+        let num_share = num_share
+            .floor()
+            .min(self.state[(idx + self.feature_length) as usize]);
+        let price = self.price(idx);
 
-        let price = ticker_df["close"].f64().unwrap().get(0).unwrap();
+        if num_share > 0f64 {
+            let notional = num_share * self.fill_price(price, num_share, false);
+            let proceeds = notional - self.commission(notional);
 
-        if self.state[(idx + self.feature_length) as usize] > 0f64 {
-            self.state[0] += price * num_share;
+            self.state[0] += proceeds;
             self.state[(idx + self.feature_length) as usize] -= num_share;
+            self.num_trades += 1;
 
             // reset price if thats the last share
             if self.state[(idx + self.feature_length) as usize] == 0f64 {
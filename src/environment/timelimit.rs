@@ -0,0 +1,50 @@
+use crate::environment::{Environment, Spec, Terminate, Trajectory};
+
+// ends the episode after max_steps steps with a truncation, a Terminate with discount 1
+// so the cut off state still bootstraps, a true terminal from the inner env passes through unchanged
+pub struct TimeLimit {
+    pub env: Box<dyn Environment>,
+    pub max_steps: u32,
+    pub step: u32,
+}
+
+impl TimeLimit {
+    pub fn new(env: Box<dyn Environment>, max_steps: u32) -> Self {
+        assert!(max_steps > 0, "max_steps must be positive");
+
+        TimeLimit {
+            env,
+            max_steps,
+            step: 0,
+        }
+    }
+}
+
+impl Environment for TimeLimit {
+    fn action_spec(&self) -> Spec {
+        self.env.action_spec()
+    }
+
+    fn observation_spec(&self) -> Spec {
+        self.env.observation_spec()
+    }
+
+    fn step(&mut self, action: Vec<f64>) -> Box<dyn Trajectory> {
+        let ts = self.env.step(action);
+        self.step += 1;
+
+        match self.step >= self.max_steps && !ts.is_terminal() {
+            true => Box::new(Terminate {
+                observation: ts.observation(),
+                reward: ts.reward().unwrap_or(0f64),
+                discount: 1f64,
+            }),
+            false => ts,
+        }
+    }
+
+    fn reset(&mut self) -> Box<dyn Trajectory> {
+        self.step = 0;
+        self.env.reset()
+    }
+}
@@ -0,0 +1,112 @@
+extern crate rayon;
+
+use crate::environment::{Environment, Spec};
+use rayon::prelude::{
+    IndexedParallelIterator, IntoParallelIterator, IntoParallelRefMutIterator, ParallelIterator,
+};
+
+// one step of every sub env, index i belongs to envs[i]
+pub struct VecStep {
+    // the observation after an auto reset if the sub env finished its episode
+    pub observations: Vec<Vec<f64>>,
+    pub rewards: Vec<f64>,
+    pub dones: Vec<bool>,
+    pub discounts: Vec<f64>,
+    // the last observation of a finished episode, it is replaced by the reset observation above
+    pub final_observations: Vec<Option<Vec<f64>>>,
+}
+
+// steps N independent envs in parallel, finished envs are reset automatically
+pub struct VecEnv<E: Environment + Send> {
+    pub envs: Vec<E>,
+    // steps taken in the current episode of each env
+    pub steps: Vec<u32>,
+}
+
+impl<E: Environment + Send> VecEnv<E> {
+    pub fn new(envs: Vec<E>) -> Self {
+        assert!(!envs.is_empty(), "VecEnv needs at least one env");
+
+        let steps = vec![0; envs.len()];
+        VecEnv { envs, steps }
+    }
+
+    pub fn len(&self) -> usize {
+        self.envs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.envs.is_empty()
+    }
+
+    pub fn action_spec(&self) -> Spec {
+        self.envs[0].action_spec()
+    }
+
+    pub fn observation_spec(&self) -> Spec {
+        self.envs[0].observation_spec()
+    }
+
+    pub fn reset(&mut self) -> Vec<Vec<f64>> {
+        self.steps.iter_mut().for_each(|step| *step = 0);
+
+        self.envs
+            .par_iter_mut()
+            .map(|env| env.reset().observation())
+            .collect()
+    }
+
+    pub fn step(&mut self, actions: Vec<Vec<f64>>) -> VecStep {
+        assert_eq!(actions.len(), self.envs.len());
+
+        // trajectories are not Send, so each thread flattens its own before handing it back
+        let results: Vec<(Vec<f64>, f64, bool, f64, Option<Vec<f64>>)> = self
+            .envs
+            .par_iter_mut()
+            .zip(actions.into_par_iter())
+            .map(|(env, action)| {
+                let ts = env.step(action);
+                let reward = ts.reward().unwrap_or(0f64);
+
+                match ts.is_terminal() {
+                    true => {
+                        let final_observation = ts.observation();
+                        (
+                            env.reset().observation(),
+                            reward,
+                            true,
+                            ts.discount(),
+                            Some(final_observation),
+                        )
+                    }
+                    false => (ts.observation(), reward, false, ts.discount(), None),
+                }
+            })
+            .collect();
+
+        let mut vec_step = VecStep {
+            observations: Vec::with_capacity(results.len()),
+            rewards: Vec::with_capacity(results.len()),
+            dones: Vec::with_capacity(results.len()),
+            discounts: Vec::with_capacity(results.len()),
+            final_observations: Vec::with_capacity(results.len()),
+        };
+
+        for (idx, (observation, reward, done, discount, final_observation)) in
+            results.into_iter().enumerate()
+        {
+            self.steps[idx] = match done {
+                true => 0,
+                false => self.steps[idx] + 1,
+            };
+
+            vec_step.observations.push(observation);
+            vec_step.rewards.push(reward);
+            vec_step.dones.push(done);
+            vec_step.discounts.push(discount);
+            vec_step.final_observations.push(final_observation);
+        }
+
+        vec_step
+    }
+}
@@ -0,0 +1,169 @@
+// Safe wrappers over the TA-Lib functions StockFrame uses
+// TA-Lib only sees raw pointers, so every wrapper checks that its inputs share one length and hands
+// TA-Lib output buffers of that same length. For the range [0, len - 1] it never reads or writes
+// past them, which is the only thing the unsafe blocks below rely on.
+
+use crate::wrappers::talib;
+
+// TA-Lib writes the output for input index begin into out[0], this moves the count valid values
+// back under the rows they belong to and leaves the warmup window as None
+fn align(values: Vec<f64>, begin: libc::c_int, count: libc::c_int) -> Vec<Option<f64>> {
+    let mut aligned = vec![None; values.len()];
+
+    for idx in 0..count as usize {
+        aligned[begin as usize + idx] = Some(values[idx]);
+    }
+
+    aligned
+}
+
+fn check_inputs(inputs: &[&[f64]]) -> usize {
+    let len = inputs[0].len();
+    assert!(inputs.iter().all(|input| input.len() == len), "TA-Lib inputs differ in length");
+    assert!(len > 0, "TA-Lib inputs are empty");
+
+    len
+}
+
+pub fn adx(high: &[f64], low: &[f64], close: &[f64], period: i32) -> Vec<Option<f64>> {
+    let len = check_inputs(&[high, low, close]);
+    let (mut begin, mut count) = (0, 0);
+    let mut out = vec![0f64; len];
+
+    let ret = unsafe {
+        talib::TA_ADX(0, (len - 1) as libc::c_int, high.as_ptr(), low.as_ptr(), close.as_ptr(), period, &mut begin, &mut count, out.as_mut_ptr())
+    };
+
+    assert_eq!(ret, talib::TA_RetCode_TA_SUCCESS);
+    align(out, begin, count)
+}
+
+pub fn atr(high: &[f64], low: &[f64], close: &[f64], period: i32) -> Vec<Option<f64>> {
+    let len = check_inputs(&[high, low, close]);
+    let (mut begin, mut count) = (0, 0);
+    let mut out = vec![0f64; len];
+
+    let ret = unsafe {
+        talib::TA_ATR(0, (len - 1) as libc::c_int, high.as_ptr(), low.as_ptr(), close.as_ptr(), period, &mut begin, &mut count, out.as_mut_ptr())
+    };
+
+    assert_eq!(ret, talib::TA_RetCode_TA_SUCCESS);
+    align(out, begin, count)
+}
+
+// returns (aroon down, aroon up)
+pub fn aroon(high: &[f64], low: &[f64], period: i32) -> (Vec<Option<f64>>, Vec<Option<f64>>) {
+    let len = check_inputs(&[high, low]);
+    let (mut begin, mut count) = (0, 0);
+    let mut down = vec![0f64; len];
+    let mut up = vec![0f64; len];
+
+    let ret = unsafe {
+        talib::TA_AROON(0, (len - 1) as libc::c_int, high.as_ptr(), low.as_ptr(), period, &mut begin, &mut count, down.as_mut_ptr(), up.as_mut_ptr())
+    };
+
+    assert_eq!(ret, talib::TA_RetCode_TA_SUCCESS);
+    (align(down, begin, count), align(up, begin, count))
+}
+
+pub fn aroonosc(high: &[f64], low: &[f64], period: i32) -> Vec<Option<f64>> {
+    let len = check_inputs(&[high, low]);
+    let (mut begin, mut count) = (0, 0);
+    let mut out = vec![0f64; len];
+
+    let ret = unsafe {
+        talib::TA_AROONOSC(0, (len - 1) as libc::c_int, high.as_ptr(), low.as_ptr(), period, &mut begin, &mut count, out.as_mut_ptr())
+    };
+
+    assert_eq!(ret, talib::TA_RetCode_TA_SUCCESS);
+    align(out, begin, count)
+}
+
+// simple moving average bands, returns (upper, middle, lower)
+pub fn bbands(
+    close: &[f64],
+    period: i32,
+    dev: f64,
+) -> (Vec<Option<f64>>, Vec<Option<f64>>, Vec<Option<f64>>) {
+    let len = check_inputs(&[close]);
+    let (mut begin, mut count) = (0, 0);
+    let mut upper = vec![0f64; len];
+    let mut middle = vec![0f64; len];
+    let mut lower = vec![0f64; len];
+
+    let ret = unsafe {
+        talib::TA_BBANDS(0, (len - 1) as libc::c_int, close.as_ptr(), period, dev, dev, talib::TA_MAType_TA_MAType_SMA, &mut begin, &mut count, upper.as_mut_ptr(), middle.as_mut_ptr(), lower.as_mut_ptr())
+    };
+
+    assert_eq!(ret, talib::TA_RetCode_TA_SUCCESS);
+    (align(upper, begin, count), align(middle, begin, count), align(lower, begin, count))
+}
+
+// returns (macd, signal, histogram)
+pub fn macd(
+    close: &[f64],
+    fast: i32,
+    slow: i32,
+    signal: i32,
+) -> (Vec<Option<f64>>, Vec<Option<f64>>, Vec<Option<f64>>) {
+    let len = check_inputs(&[close]);
+    let (mut begin, mut count) = (0, 0);
+    let mut macd = vec![0f64; len];
+    let mut macdsignal = vec![0f64; len];
+    let mut macdhist = vec![0f64; len];
+
+    let ret = unsafe {
+        talib::TA_MACD(0, (len - 1) as libc::c_int, close.as_ptr(), fast, slow, signal, &mut begin, &mut count, macd.as_mut_ptr(), macdsignal.as_mut_ptr(), macdhist.as_mut_ptr())
+    };
+
+    assert_eq!(ret, talib::TA_RetCode_TA_SUCCESS);
+    (align(macd, begin, count), align(macdsignal, begin, count), align(macdhist, begin, count))
+}
+
+pub fn rsi(close: &[f64], period: i32) -> Vec<Option<f64>> {
+    let len = check_inputs(&[close]);
+    let (mut begin, mut count) = (0, 0);
+    let mut out = vec![0f64; len];
+
+    let ret = unsafe {
+        talib::TA_RSI(0, (len - 1) as libc::c_int, close.as_ptr(), period, &mut begin, &mut count, out.as_mut_ptr())
+    };
+
+    assert_eq!(ret, talib::TA_RetCode_TA_SUCCESS);
+    align(out, begin, count)
+}
+
+// slow stochastic with simple moving averages, returns (slow k, slow d)
+pub fn stoch(
+    high: &[f64],
+    low: &[f64],
+    close: &[f64],
+    fastk_period: i32,
+    slowk_period: i32,
+    slowd_period: i32,
+) -> (Vec<Option<f64>>, Vec<Option<f64>>) {
+    let len = check_inputs(&[high, low, close]);
+    let (mut begin, mut count) = (0, 0);
+    let mut slowk = vec![0f64; len];
+    let mut slowd = vec![0f64; len];
+
+    let ret = unsafe {
+        talib::TA_STOCH(0, (len - 1) as libc::c_int, high.as_ptr(), low.as_ptr(), close.as_ptr(), fastk_period, slowk_period, talib::TA_MAType_TA_MAType_SMA, slowd_period, talib::TA_MAType_TA_MAType_SMA, &mut begin, &mut count, slowk.as_mut_ptr(), slowd.as_mut_ptr())
+    };
+
+    assert_eq!(ret, talib::TA_RetCode_TA_SUCCESS);
+    (align(slowk, begin, count), align(slowd, begin, count))
+}
+
+pub fn sma(close: &[f64], period: i32) -> Vec<Option<f64>> {
+    let len = check_inputs(&[close]);
+    let (mut begin, mut count) = (0, 0);
+    let mut out = vec![0f64; len];
+
+    let ret = unsafe {
+        talib::TA_SMA(0, (len - 1) as libc::c_int, close.as_ptr(), period, &mut begin, &mut count, out.as_mut_ptr())
+    };
+
+    assert_eq!(ret, talib::TA_RetCode_TA_SUCCESS);
+    align(out, begin, count)
+}
@@ -0,0 +1,63 @@
+// appends one csv row of training metrics per train call, flushed every row so runs can be watched live
+// LiveStockEnv writes its failed orders and polls through the same type, see event
+pub struct Logger {
+    pub path: String,
+    writer: std::io::BufWriter<std::fs::File>,
+}
+
+pub const logger_columns: [&str; 4] = ["total_it", "critic_loss", "actor_loss", "target_q"];
+
+// columns of a log written through event instead of log
+pub const event_columns: [&str; 3] = ["timestamp", "event", "detail"];
+
+impl Logger {
+    pub fn new(path: String) -> anyhow::Result<Self> {
+        Logger::with_columns(path, &logger_columns)
+    }
+
+    // creates any missing parent directories and truncates an existing file
+    pub fn with_columns(path: String, columns: &[&str]) -> anyhow::Result<Self> {
+        if let Some(parent) = std::path::Path::new(path.as_str()).parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let file = std::fs::File::create(path.as_str())?;
+        let mut logger = Logger {
+            path,
+            writer: std::io::BufWriter::new(file),
+        };
+
+        logger.write_row(columns.join(",").as_str())?;
+        Ok(logger)
+    }
+
+    // actor_loss is left empty on steps where the actor was not updated
+    pub fn log(
+        &mut self,
+        total_it: i64,
+        critic_loss: f64,
+        actor_loss: Option<f64>,
+        target_q: f64,
+    ) -> anyhow::Result<()> {
+        let actor_loss = actor_loss.map_or(String::new(), |loss| loss.to_string());
+        let row = format!("{},{},{},{}", total_it, critic_loss, actor_loss, target_q);
+
+        self.write_row(row.as_str())
+    }
+
+    // one row of an event_columns log, the detail is quoted since error messages can hold commas
+    pub fn event(&mut self, event: &str, detail: &str) -> anyhow::Result<()> {
+        let timestamp = polars::export::chrono::Utc::now().naive_utc();
+        let row = format!("{},{},\"{}\"", timestamp, event, detail.replace('"', "\"\""));
+
+        self.write_row(row.as_str())
+    }
+
+    fn write_row(&mut self, row: &str) -> anyhow::Result<()> {
+        std::io::Write::write_all(&mut self.writer, row.as_bytes())?;
+        std::io::Write::write_all(&mut self.writer, b"\n")?;
+        std::io::Write::flush(&mut self.writer)?;
+
+        Ok(())
+    }
+}
@@ -2,15 +2,19 @@
 #![allow(dead_code)]
 
 mod environment;
+mod indicators;
+mod logger;
 mod optimizer;
 mod replay_buffer;
+mod sac;
 mod stockframe;
 mod td3;
 mod tests;
+mod trainer;
 mod viewer;
 mod wrappers;
 
-use crate::environment::{Environment, Mujoco, Terminate};
+use crate::environment::{Environment, Mujoco};
 use crate::environment::halfcheetahenv::HalfCheetahEnv;
 use crate::environment::antenv::AntEnv;
 use crate::environment::hopperenv::HopperEnv;
@@ -18,11 +22,25 @@ use crate::environment::hopperenv::HopperEnv;
 use crate::environment::stockenv::StockEnv;
 use crate::replay_buffer::ReplayBuffer;
 
-use crate::td3::TD3;
+use crate::td3::{TD3, TD3Builder};
 use crate::viewer::Viewer;
 
 lazy_static::lazy_static! {
     static ref device: std::sync::Arc<tch::Device> = std::sync::Arc::new(tch::Device::cuda_if_available());
+    static ref global_seed: std::sync::Mutex<Option<u64>> = std::sync::Mutex::new(None);
+}
+
+// seeds libtorch and every rng created through new_rng afterwards, makes runs reproducible
+fn seed(seed: u64) {
+    tch::manual_seed(seed as i64);
+    *global_seed.lock().expect("Failed to lock global seed") = Some(seed);
+}
+
+fn new_rng() -> rand::prelude::StdRng {
+    match *global_seed.lock().expect("Failed to lock global seed") {
+        Some(seed) => <rand::prelude::StdRng as rand::prelude::SeedableRng>::seed_from_u64(seed),
+        None => <rand::prelude::StdRng as rand::prelude::SeedableRng>::from_entropy(),
+    }
 }
 
 #[derive(clap::Parser)]
@@ -50,6 +68,23 @@ enum Commands {
         eval_freq: Option<u32>,
         #[arg(long)]
         save_policy: Option<bool>,
+        #[arg(long)]
+        actor_lr: Option<f64>,
+        #[arg(long)]
+        critic_lr: Option<f64>,
+        #[arg(long)]
+        seed: Option<u64>,
+        #[arg(long)]
+        reward_scale: Option<f64>,
+        // symmetric bound, rewards are clamped to [-reward_clip, reward_clip] after scaling
+        #[arg(long)]
+        reward_clip: Option<f64>,
+        // stop after this many evaluations without a new best return
+        #[arg(long)]
+        patience: Option<u32>,
+        // save the agent every checkpoint_freq timesteps under ./models/checkpoints, keeping the last 5
+        #[arg(long)]
+        checkpoint_freq: Option<u32>,
     },
 
     Run {
@@ -58,24 +93,6 @@ enum Commands {
     },
 }
 
-fn eval_td3(policy: &TD3, env: &mut Box<dyn Environment>, eval_episodes: Option<u32>) -> f64 {
-    let eval_episodes = eval_episodes.unwrap_or(10);
-    let mut ts = env.reset();
-    let mut avg_reward = 0f64;
-
-    for _ in 0..eval_episodes {
-        while ts.as_any().downcast_ref::<Terminate>().is_none() {
-            let action = policy.select_action(ts.observation());
-            ts = env.step(action);
-
-            avg_reward += ts.reward().unwrap_or(0f64);
-        }
-    }
-
-    avg_reward /= eval_episodes as f64;
-    avg_reward
-}
-
 fn run_td3(
     env: &str,
     filename: &str,
@@ -86,6 +103,12 @@ fn run_td3(
     save_policy: bool,
     actor_opt: &str,
     critic_opt: &str,
+    actor_lr: Option<f64>,
+    critic_lr: Option<f64>,
+    reward_scale: Option<f64>,
+    reward_clip: Option<f64>,
+    patience: Option<u32>,
+    checkpoint_freq: Option<u32>,
 ) {
     if !std::path::Path::new("./results").exists() {
         std::fs::create_dir_all("./results").expect("Failed to create results directory");
@@ -139,7 +162,9 @@ fn run_td3(
                 .unwrap();
             let start = end - polars::export::chrono::Duration::days(15);
 
-            let train_env = Box::new(StockEnv::new(start, end));
+            let train_env = Box::new(StockEnv::new(
+                start, end, 10, None, None, None, None, None, None, None,
+            ));
             let eval_env = train_env.clone();
 
             (train_env, eval_env)
@@ -157,97 +182,31 @@ fn run_td3(
     let action_dim = train_env.action_spec().shape;
     let max_action = train_env.action_spec().max;
 
-    let mut policy = TD3::new(
-        state_dim as i64,
-        action_dim as i64,
-        max_action,
-        actor_opt,
-        critic_opt,
-        None,
-        None,
-        None,
-        None,
-        None,
-        None,
-        None,
-        None,
-    )
-    .expect("Failed to create TD3 Policy");
+    let mut policy = TD3Builder { actor_lr, critic_lr, ..TD3::builder() }
+        .state_dim(state_dim as i64)
+        .action_dim(action_dim as i64)
+        .max_action(max_action)
+        .actor_opt(actor_opt)
+        .critic_opt(critic_opt)
+        .build()
+        .expect("Failed to create TD3 Policy");
 
     let mut replaybuffer = ReplayBuffer::new(state_dim as i64, action_dim as i64, None);
-    let mut evals = vec![eval_td3(&policy, &mut eval_env, None)];
-
-    let mut ts = train_env.reset();
-    let mut episode_reward = 0f64;
-    let mut episode_timesteps = 0;
-    let mut episode_num = 0;
-
-    let mut rng = <rand::prelude::StdRng as rand::prelude::SeedableRng>::from_entropy();
-    let uniform = rand::distributions::Uniform::from(0f64..1f64);
-    let normal = rand_distr::Normal::new(0f64, max_action * expl_noise)
-        .expect("Failed to make normal distribution");
-
-    let mut action: Vec<f64>;
-    for t in 0..max_timesteps {
-        episode_timesteps += 1;
-
-        if t < start_timesteps {
-            action = (0..train_env.action_spec().shape)
-                .map(|_| rand::prelude::Distribution::sample(&uniform, &mut rng))
-                .collect();
-        } else {
-            action = policy.select_action(
-                ts.observation()
-                    .iter()
-                    .map(|act| act + rand::prelude::Distribution::sample(&normal, &mut rng))
-                    .collect(),
-            )
-        }
-
-        let next_ts = train_env.step(action.clone());
-        let done = next_ts
-            .as_ref()
-            .as_any()
-            .downcast_ref::<Terminate>()
-            .is_some();
-
-        let done_bool = match done {
-            true => 1f64,
-            false => 0f64,
-        };
-
-        replaybuffer.add(
-            ts.observation(),
-            action,
-            next_ts.observation(),
-            next_ts.reward().unwrap_or(0f64),
-            done_bool,
-        );
-
-        episode_reward += next_ts.reward().unwrap_or(0f64);
-        ts = next_ts;
-
-        if t >= start_timesteps {
-            policy.train(&replaybuffer, None);
-        }
-
-        if done {
-            println!(
-                "Total T: {} Episode Num: {} Episode T: {} Reward: {:.3}",
-                t + 1,
-                episode_num + 1,
-                episode_timesteps,
-                episode_reward
-            );
-
-            ts = train_env.step(Vec::new());
-            episode_reward = 0f64;
-            episode_timesteps = 0;
-            episode_num += 1;
-        }
-
-        if (t + 1) % eval_freq == 0 {
-            evals.push(eval_td3(&policy, &mut eval_env, None));
+    replaybuffer.set_n_step(policy.n_step, policy.discount);
+    replaybuffer.set_reward_transform(reward_scale, reward_clip.map(|clip| (-clip, clip)));
+
+    let mut stopper = patience.map(|patience| crate::trainer::PatienceStopper::new(patience, None));
+
+    crate::trainer::train_agent(
+        train_env.as_mut(),
+        eval_env.as_mut(),
+        &mut policy,
+        &mut replaybuffer,
+        max_timesteps,
+        start_timesteps,
+        eval_freq,
+        expl_noise,
+        |t, policy, evals| {
             let mut file = std::fs::OpenOptions::new()
                 .write(true)
                 .create(true)
@@ -257,37 +216,27 @@ fn run_td3(
 
             std::io::Write::write_all(
                 &mut file,
-                serde_json::to_string_pretty(&evals)
+                serde_json::to_string_pretty(evals)
                     .expect("Failed to convert vals to string")
                     .as_bytes(),
             )
             .expect("Failed to write result");
 
             if save_policy {
-                let mut file = std::fs::OpenOptions::new()
-                    .write(true)
-                    .create(true)
-                    .truncate(true)
-                    .open(format!("./models/{}_{}_steps.banan", filename, t + 1))
-                    .expect("Failed to open file to save model");
-
-                std::io::Write::write_all(
-                    &mut file,
-                    serde_json::to_string_pretty(&policy)
-                        .expect("Failed to serialize td3 to json")
-                        .as_bytes(),
-                )
-                .expect("Failed to write td3 to file");
+                policy
+                    .save(format!("./models/{}_{}_steps.bin", filename, t))
+                    .expect("Failed to write td3 to file");
             }
-        }
-    }
-}
-
-fn load_td3(filename: String) -> TD3 {
-    let data = std::fs::read_to_string(filename.clone())
-        .unwrap_or_else(|_| panic!("Failed to read file: {}", filename.clone()));
-    serde_json::from_str(data.as_str())
-        .unwrap_or_else(|_| panic!("Failed to parse td3 from file: {}", filename.clone()))
+        },
+        |eval_return, t| match stopper.as_mut() {
+            Some(stopper) => stopper.should_stop(eval_return, t),
+            None => false,
+        },
+        checkpoint_freq.map(|freq| {
+            crate::trainer::Checkpoint::new(freq, format!("./models/checkpoints/{}", filename), None)
+        }),
+        Some(format!("./models/{}", filename)),
+    );
 }
 
 fn main() {
@@ -304,7 +253,18 @@ fn main() {
             start_timesteps,
             eval_freq,
             save_policy,
+            actor_lr,
+            critic_lr,
+            seed,
+            reward_scale,
+            reward_clip,
+            patience,
+            checkpoint_freq,
         } => {
+            if let Some(seed) = seed {
+                crate::seed(seed);
+            }
+
             let expl_noise = expl_noise.unwrap_or(0.1);
             let max_timesteps = max_timesteps.unwrap_or(100000);
             let start_timesteps = start_timesteps.unwrap_or(25000);
@@ -328,11 +288,18 @@ fn main() {
                 save_policy,
                 actor_opt.as_str(),
                 critic_opt.as_str(),
+                actor_lr,
+                critic_lr,
+                reward_scale,
+                reward_clip,
+                patience,
+                checkpoint_freq,
             );
         }
 
         Commands::Run { savefile } => {
-            let td3 = load_td3(savefile);
+            let td3 = TD3::load(savefile.clone())
+                .unwrap_or_else(|_| panic!("Failed to load td3 from file: {}", savefile));
 
             let env: Box<dyn Mujoco> = match args.env.as_str() {
                 "halfcheetah" => Box::new(HalfCheetahEnv::new(
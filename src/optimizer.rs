@@ -5,6 +5,74 @@ extern crate tch;
 
 pub type RefVs = std::rc::Rc<std::cell::RefCell<tch::nn::VarStore>>;
 
+// dynamic loss scaling for fp16 autocast, small gradients would underflow to zero without it
+// the loss is scaled up before backward and the gradients scaled back down before the update,
+// a step with non-finite gradients is skipped and the scale backed off, a long clean run grows it again
+#[derive(Clone, Debug, PartialEq)]
+pub struct GradScaler {
+    pub scale: f64,
+    pub growth_factor: f64,
+    pub backoff_factor: f64,
+    pub growth_interval: i64,
+    // clean steps since the scale last changed
+    pub growth_tracker: i64,
+}
+
+impl GradScaler {
+    // same defaults as torch.cuda.amp.GradScaler
+    pub fn new() -> Self {
+        Self {
+            scale: 65536f64,
+            growth_factor: 2f64,
+            backoff_factor: 0.5,
+            growth_interval: 2000,
+            growth_tracker: 0,
+        }
+    }
+
+    pub fn scale_loss(&self, loss: &tch::Tensor) -> tch::Tensor {
+        loss * self.scale
+    }
+
+    // divides the gradients by the scale in place, false when any of them is not finite
+    pub fn unscale(&self, grads: &mut [tch::Tensor]) -> bool {
+        tch::no_grad(|| {
+            let mut finite = true;
+
+            for grad in grads.iter_mut() {
+                let _ = grad.g_div_scalar_(self.scale);
+                finite &= bool::try_from(grad.isfinite().all()).unwrap_or(false);
+            }
+
+            finite
+        })
+    }
+
+    // call once per optimizer step with whether the unscaled gradients were finite
+    pub fn update(&mut self, finite: bool) {
+        match finite {
+            true => {
+                self.growth_tracker += 1;
+
+                if self.growth_tracker == self.growth_interval {
+                    self.scale *= self.growth_factor;
+                    self.growth_tracker = 0;
+                }
+            }
+            false => {
+                self.scale *= self.backoff_factor;
+                self.growth_tracker = 0;
+            }
+        }
+    }
+}
+
+impl Default for GradScaler {
+    fn default() -> Self {
+        GradScaler::new()
+    }
+}
+
 pub trait MilkshakeOptimizer {
     fn ask(&mut self) -> Vec<RefVs>;
     fn tell(&mut self, solutions: Vec<RefVs>, losses: Vec<tch::Tensor>);
@@ -20,4 +88,28 @@ pub trait MilkshakeOptimizer {
     fn grads(&mut self) -> bool {
         false
     }
+
+    // gradient accumulation for optimizers with grads, backward adds to the stored gradients
+    // and step applies them once, tell is zero_grad, backward and step in one go
+    fn zero_grad(&mut self) {}
+
+    fn backward(&mut self, _loss: &tch::Tensor) {}
+
+    fn step(&mut self) {}
+
+    // optimizers without a learning rate ignore schedules
+    fn lr(&self) -> Option<f64> {
+        None
+    }
+
+    fn set_lr(&mut self, _lr: f64) {}
+
+    // optimizers without internal state have nothing to persist
+    fn save_state(&self) -> anyhow::Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+
+    fn load_state(&mut self, _state: &[u8]) -> anyhow::Result<()> {
+        Ok(())
+    }
 }
@@ -1,19 +1,118 @@
+use crate::optimizer::GradScaler;
 use crate::optimizer::MilkshakeOptimizer;
 use crate::optimizer::RefVs;
-use std::ops::Deref;
 
+// Adam with the moment estimates kept on this side so they can be saved and restored,
+// the update matches torch.optim.Adam with default betas and no weight decay
 pub struct ADAM {
     pub vs: RefVs,
-    pub opt: tch::nn::Optimizer,
+    pub lr: f64,
+    pub beta1: f64,
+    pub beta2: f64,
+    pub eps: f64,
+    pub max_grad_norm: Option<f64>,
+    // scales the loss for mixed precision training, None backprops the loss as is
+    pub grad_scaler: Option<GradScaler>,
+
+    // one first and second moment per trainable variable, in varstore order
+    pub m: Vec<tch::Tensor>,
+    pub v: Vec<tch::Tensor>,
+    pub step: i64,
 }
 
 impl ADAM {
-    pub fn new(lr: f64, vs: RefVs) -> Self {
-        let opt =
-            tch::nn::OptimizerConfig::build(tch::nn::Adam::default(), vs.borrow().deref(), lr)
-                .expect("Failed to construct Adam Optimizer");
+    pub fn new(lr: f64, vs: RefVs, max_grad_norm: Option<f64>) -> Self {
+        let variables = vs.borrow().trainable_variables();
+        let m = variables.iter().map(|var| var.zeros_like()).collect();
+        let v = variables.iter().map(|var| var.zeros_like()).collect();
+
+        Self {
+            vs,
+            lr,
+            beta1: 0.9,
+            beta2: 0.999,
+            eps: 1e-8,
+            max_grad_norm,
+            grad_scaler: None,
+            m,
+            v,
+            step: 0,
+        }
+    }
 
-        Self { vs, opt }
+    // unscales the gradients, false when they are not finite and the step has to be skipped
+    fn unscale_grads(&mut self) -> bool {
+        let grad_scaler = match self.grad_scaler.as_mut() {
+            Some(grad_scaler) => grad_scaler,
+            None => return true,
+        };
+
+        let mut grads: Vec<tch::Tensor> = self
+            .vs
+            .borrow()
+            .trainable_variables()
+            .iter()
+            .filter(|var| var.requires_grad())
+            .map(|var| var.grad())
+            .filter(|grad| grad.defined())
+            .collect();
+
+        let finite = grad_scaler.unscale(&mut grads);
+        grad_scaler.update(finite);
+        finite
+    }
+
+    fn clip_grad_norm(&self, max: f64) {
+        tch::no_grad(|| {
+            let grads: Vec<tch::Tensor> = self
+                .vs
+                .borrow()
+                .trainable_variables()
+                .iter()
+                .filter(|var| var.requires_grad())
+                .map(|var| var.grad())
+                .filter(|grad| grad.defined())
+                .collect();
+
+            let total_norm = grads
+                .iter()
+                .map(|grad| grad.norm().double_value(&[]).powi(2))
+                .sum::<f64>()
+                .sqrt();
+            let clip_coef = max / (total_norm + 1e-6);
+
+            if clip_coef < 1f64 {
+                for mut grad in grads {
+                    let _ = grad.g_mul_scalar_(clip_coef);
+                }
+            }
+        })
+    }
+
+    fn update(&mut self) {
+        self.step += 1;
+
+        let bias_correction1 = 1f64 - self.beta1.powi(self.step as i32);
+        let bias_correction2 = 1f64 - self.beta2.powi(self.step as i32);
+
+        tch::no_grad(|| {
+            let variables = self.vs.borrow().trainable_variables();
+
+            for (idx, mut var) in variables.into_iter().enumerate() {
+                // frozen variables can still hold a zeroed grad, momentum alone would move them
+                let grad = var.grad();
+                if !var.requires_grad() || !grad.defined() {
+                    continue;
+                }
+
+                self.m[idx] = self.beta1 * &self.m[idx] + (1f64 - self.beta1) * &grad;
+                self.v[idx] = self.beta2 * &self.v[idx] + (1f64 - self.beta2) * (&grad * &grad);
+
+                let denom = self.v[idx].sqrt() / bias_correction2.sqrt() + self.eps;
+                let updated = &var - (self.lr / bias_correction1) * &self.m[idx] / denom;
+                var.copy_(&updated);
+            }
+        })
     }
 }
 
@@ -27,9 +126,35 @@ impl MilkshakeOptimizer for ADAM {
         assert_eq!(losses.len(), 1);
         assert!(std::rc::Rc::ptr_eq(solutions.first().unwrap(), &self.vs));
 
-        self.opt.zero_grad();
-        losses.first().unwrap().backward();
-        self.opt.step();
+        self.zero_grad();
+        self.backward(losses.first().unwrap());
+        self.step();
+    }
+
+    fn zero_grad(&mut self) {
+        for mut var in self.vs.borrow().trainable_variables() {
+            var.zero_grad();
+        }
+    }
+
+    fn backward(&mut self, loss: &tch::Tensor) {
+        match &self.grad_scaler {
+            Some(grad_scaler) => grad_scaler.scale_loss(loss).backward(),
+            None => loss.backward(),
+        }
+    }
+
+    fn step(&mut self) {
+        if !self.unscale_grads() {
+            self.zero_grad();
+            return;
+        }
+
+        if let Some(max_grad_norm) = self.max_grad_norm {
+            self.clip_grad_norm(max_grad_norm);
+        }
+
+        self.update();
     }
 
     fn result(&mut self) -> RefVs {
@@ -39,4 +164,57 @@ impl MilkshakeOptimizer for ADAM {
     fn grads(&mut self) -> bool {
         true
     }
+
+    fn lr(&self) -> Option<f64> {
+        Some(self.lr)
+    }
+
+    fn set_lr(&mut self, lr: f64) {
+        self.lr = lr;
+    }
+
+    fn save_state(&self) -> anyhow::Result<Option<Vec<u8>>> {
+        let mut named_tensors = vec![(String::from("step"), tch::Tensor::from(self.step))];
+
+        for (idx, (m, v)) in self.m.iter().zip(self.v.iter()).enumerate() {
+            named_tensors.push((format!("m.{}", idx), m.shallow_clone()));
+            named_tensors.push((format!("v.{}", idx), v.shallow_clone()));
+        }
+
+        let mut cursor = std::io::Cursor::new(Vec::<u8>::new());
+        tch::Tensor::save_multi_to_stream(named_tensors.as_slice(), &mut cursor)?;
+
+        Ok(Some(cursor.into_inner()))
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> anyhow::Result<()> {
+        let device = self.vs.borrow().device();
+        let named_tensors =
+            tch::Tensor::load_multi_from_stream_with_device(std::io::Cursor::new(state), device)?;
+        let named_tensors: std::collections::HashMap<String, tch::Tensor> =
+            named_tensors.into_iter().collect();
+
+        let get = |name: String| -> anyhow::Result<tch::Tensor> {
+            match named_tensors.get(&name) {
+                Some(tensor) => Ok(tensor.shallow_clone()),
+                None => anyhow::bail!("Adam state is missing {}", name),
+            }
+        };
+
+        for idx in 0..self.m.len() {
+            let m = get(format!("m.{}", idx))?;
+            let v = get(format!("v.{}", idx))?;
+
+            if m.size() != self.m[idx].size() || v.size() != self.v[idx].size() {
+                anyhow::bail!("Adam state does not match the shape of variable {}", idx);
+            }
+
+            self.m[idx] = m;
+            self.v[idx] = v;
+        }
+
+        self.step = get(String::from("step"))?.int64_value(&[]);
+
+        Ok(())
+    }
 }
@@ -1,4 +1,3 @@
-use crate::device;
 use crate::optimizer::MilkshakeOptimizer;
 use crate::optimizer::RefVs;
 
@@ -41,6 +40,8 @@ impl CMAES {
     pub fn new(vs: RefVs, sigma: Option<f64>, popsize: Option<i64>) -> Self {
         let sigma = sigma.unwrap_or(1.25f64);
 
+        // every tensor lives on the same device as the optimized varstore
+        let device = vs.borrow().device();
         let xmean = Self::vs_to_flattensor(vs.clone());
 
         let N = xmean.size()[0];
@@ -56,7 +57,7 @@ impl CMAES {
 
         let weights = tch::Tensor::from_slice(weights.as_slice());
         let weights = weights.copy() / weights.sum(Some(tch::Kind::Float));
-        let weights = weights.totype(tch::Kind::Float).to_device(**device);
+        let weights = weights.totype(tch::Kind::Float).to_device(device);
 
         let mueff = (weights.sum(Some(tch::Kind::Float)).pow_(2)
             / weights.copy().pow_(2).sum(Some(tch::Kind::Float)))
@@ -75,15 +76,15 @@ impl CMAES {
         let chiN = (N as f64).sqrt()
             * (1f64 - 1f64 / (4f64 * N as f64) + 1f64 / (21f64 * (N as f64).powi(2)));
 
-        let B = tch::Tensor::eye(N, (tch::Kind::Float, **device));
-        let D = tch::Tensor::eye(N, (tch::Kind::Float, **device));
+        let B = tch::Tensor::eye(N, (tch::Kind::Float, device));
+        let D = tch::Tensor::eye(N, (tch::Kind::Float, device));
         let C = tch::Tensor::matmul(&B.matmul(&D), &B.matmul(&D).t_());
 
-        let z = tch::Tensor::zeros([lambda, N], (tch::Kind::Float, **device));
-        let s = tch::Tensor::zeros([lambda, N], (tch::Kind::Float, **device));
+        let z = tch::Tensor::zeros([lambda, N], (tch::Kind::Float, device));
+        let s = tch::Tensor::zeros([lambda, N], (tch::Kind::Float, device));
 
-        let pc = tch::Tensor::zeros([N], (tch::Kind::Float, **device));
-        let ps = tch::Tensor::zeros([N], (tch::Kind::Float, **device));
+        let pc = tch::Tensor::zeros([N], (tch::Kind::Float, device));
+        let ps = tch::Tensor::zeros([N], (tch::Kind::Float, device));
 
         let eigeneval = 0;
         let counteval = 0;
@@ -136,7 +137,7 @@ impl CMAES {
     }
 
     fn flattensor_to_vs(layout: RefVs, tensor: tch::Tensor) -> tch::nn::VarStore {
-        let newvs = tch::nn::VarStore::new(**device);
+        let newvs = tch::nn::VarStore::new(layout.borrow().device());
 
         let binding = layout.borrow().variables();
         let mut names_sorted = binding.keys().collect::<Vec<_>>();
@@ -167,7 +168,7 @@ impl CMAES {
 
 impl MilkshakeOptimizer for CMAES {
     fn ask(&mut self) -> Vec<RefVs> {
-        self.z = tch::Tensor::randn([self.N, self.lambda], (tch::Kind::Float, **device));
+        self.z = tch::Tensor::randn([self.N, self.lambda], (tch::Kind::Float, self.vs.borrow().device()));
         self.s = self.xmean.view([-1, 1]) + self.sigma * self.B.matmul(&self.D.matmul(&self.z));
 
         let candidates = tch::Tensor::unbind(&self.s.copy().t_(), 0);
@@ -207,7 +208,7 @@ impl MilkshakeOptimizer for CMAES {
 
         let z = self.z.index_select(
             1,
-            &tch::Tensor::from_slice(elite_indices).to_device(**device),
+            &tch::Tensor::from_slice(elite_indices).to_device(self.vs.borrow().device()),
         );
 
         // recombination
@@ -1,30 +1,185 @@
 use crate::device;
 
-#[derive(Clone)]
+pub struct Batch {
+    pub states: tch::Tensor,
+    pub actions: tch::Tensor,
+    pub next_states: tch::Tensor,
+    pub rewards: tch::Tensor,
+    pub not_dones: tch::Tensor,
+
+    // transitions summed into each reward, targets bootstrap with discount^n_steps
+    pub n_steps: tch::Tensor,
+    pub indices: Vec<usize>,
+    pub weights: tch::Tensor,
+}
+
+impl From<Batch> for Vec<tch::Tensor> {
+    fn from(batch: Batch) -> Self {
+        vec![
+            batch.states,
+            batch.actions,
+            batch.next_states,
+            batch.rewards,
+            batch.not_dones,
+            batch.n_steps,
+        ]
+    }
+}
+
 pub struct ReplayBuffer {
     pub max_size: usize,
     pub ptr: usize,
     pub size: usize,
-    pub state: Vec<Vec<f64>>,
-    pub action: Vec<Vec<f64>>,
-    pub next_state: Vec<Vec<f64>>,
-    pub reward: Vec<f64>,
-    pub not_done: Vec<f64>,
+    pub state_dim: usize,
+    pub action_dim: usize,
+    pub rng: std::cell::RefCell<rand::prelude::StdRng>,
+    pub priorities: Option<Vec<f64>>,
+    pub alpha: f64,
+    pub beta: f64,
+    pub max_priority: f64,
+
+    // uniform batches drawn without replacement hold no duplicates, while the buffer has enough slots
+    pub with_replacement: bool,
+    pub n_step: usize,
+    pub discount: f64,
+    pub pending: std::collections::VecDeque<(Vec<f64>, Vec<f64>, Vec<f64>, f64, f64)>,
+
+    // applied to every per step reward as it is added, before n-step sums
+    pub reward_scale: f64,
+    pub reward_clip: Option<(f64, f64)>,
+
+    // every slot is one float row (state, action, next_state, reward, not_done, n_steps) of the storage on device,
+    // rows added since the last read are staged on the host and copied over in one go when the buffer is read
+    pub device: tch::Device,
+    pub pin_memory: bool,
+    storage: tch::Tensor,
+    staged: std::cell::RefCell<Vec<(i64, Vec<f64>)>>,
+}
+
+impl Clone for ReplayBuffer {
+    fn clone(&self) -> Self {
+        self.flush_staged();
+
+        ReplayBuffer {
+            max_size: self.max_size,
+            ptr: self.ptr,
+            size: self.size,
+            state_dim: self.state_dim,
+            action_dim: self.action_dim,
+            rng: self.rng.clone(),
+            priorities: self.priorities.clone(),
+            alpha: self.alpha,
+            beta: self.beta,
+            max_priority: self.max_priority,
+            with_replacement: self.with_replacement,
+            n_step: self.n_step,
+            discount: self.discount,
+            pending: self.pending.clone(),
+            reward_scale: self.reward_scale,
+            reward_clip: self.reward_clip,
+            device: self.device,
+            pin_memory: self.pin_memory,
+            storage: self.storage.copy(),
+            staged: std::cell::RefCell::new(Vec::new()),
+        }
+    }
 }
 
 impl ReplayBuffer {
     pub fn new(state_dim: i64, action_dim: i64, max_size: Option<i64>) -> Self {
+        ReplayBuffer::with_device(state_dim, action_dim, max_size, None, None)
+    }
+
+    // pin_memory only applies to cpu storage, it speeds up the copy of sampled batches to the cuda training device
+    pub fn with_device(
+        state_dim: i64,
+        action_dim: i64,
+        max_size: Option<i64>,
+        storage_device: Option<tch::Device>,
+        pin_memory: Option<bool>,
+    ) -> Self {
         let max_size = max_size.unwrap_or(1e6 as i64) as usize;
+        let storage_device = storage_device.unwrap_or(**device);
+        let pin_memory = pin_memory.unwrap_or(false);
+
+        let row_len = 2 * state_dim + action_dim + 3;
+        let storage = ReplayBuffer::allocate(max_size, row_len, storage_device, pin_memory);
 
         ReplayBuffer {
             max_size,
-            state: vec![vec![0f64; state_dim as usize]; max_size],
-            action: vec![vec![0f64; action_dim as usize]; max_size],
-            next_state: vec![vec![0f64; state_dim as usize]; max_size],
-            reward: vec![0f64; max_size],
-            not_done: vec![0f64; max_size],
             ptr: 0,
             size: 0,
+            state_dim: state_dim as usize,
+            action_dim: action_dim as usize,
+            rng: std::cell::RefCell::new(crate::new_rng()),
+            priorities: None,
+            alpha: 0.6,
+            beta: 0.4,
+            max_priority: 1f64,
+            with_replacement: true,
+            n_step: 1,
+            discount: 0.99,
+            pending: std::collections::VecDeque::new(),
+            reward_scale: 1f64,
+            reward_clip: None,
+            device: storage_device,
+            pin_memory,
+            storage,
+            staged: std::cell::RefCell::new(Vec::new()),
+        }
+    }
+
+    fn allocate(rows: usize, row_len: i64, storage_device: tch::Device, pin_memory: bool) -> tch::Tensor {
+        let storage = tch::Tensor::zeros([rows as i64, row_len], (tch::Kind::Float, storage_device));
+
+        match (pin_memory, storage_device, **device) {
+            (true, tch::Device::Cpu, tch::Device::Cuda(_)) => storage.pin_memory(**device),
+            _ => storage,
+        }
+    }
+
+    pub fn prioritized(
+        state_dim: i64,
+        action_dim: i64,
+        max_size: Option<i64>,
+        alpha: Option<f64>,
+        beta: Option<f64>,
+    ) -> Self {
+        let mut replay_buffer = ReplayBuffer::new(state_dim, action_dim, max_size);
+
+        replay_buffer.priorities = Some(vec![0f64; replay_buffer.max_size]);
+        replay_buffer.alpha = alpha.unwrap_or(0.6);
+        replay_buffer.beta = beta.unwrap_or(0.4);
+
+        replay_buffer
+    }
+
+    // discount should match the policy, only the rewards are discounted here, the window length of every
+    // slot is stored so the target can bootstrap with discount^n_steps
+    pub fn set_n_step(&mut self, n_step: i64, discount: f64) {
+        assert!(n_step >= 1);
+
+        self.n_step = n_step as usize;
+        self.discount = discount;
+        self.pending.clear();
+    }
+
+    // rewards are scaled first and then clamped to reward_clip
+    pub fn set_reward_transform(&mut self, reward_scale: Option<f64>, reward_clip: Option<(f64, f64)>) {
+        if let Some((low, high)) = reward_clip {
+            assert!(low <= high);
+        }
+
+        self.reward_scale = reward_scale.unwrap_or(1f64);
+        self.reward_clip = reward_clip;
+    }
+
+    fn transform_reward(&self, reward: f64) -> f64 {
+        let reward = reward * self.reward_scale;
+
+        match self.reward_clip {
+            Some((low, high)) => reward.clamp(low, high),
+            None => reward,
         }
     }
 
@@ -36,57 +191,350 @@ impl ReplayBuffer {
         reward: f64,
         done: f64,
     ) {
-        self.state[self.ptr] = state;
-        self.action[self.ptr] = action;
-        self.next_state[self.ptr] = next_state;
-        self.reward[self.ptr] = reward;
-        self.not_done[self.ptr] = 1f64 - done;
+        let reward = self.transform_reward(reward);
+
+        if self.n_step == 1 {
+            self.store(state, action, next_state, reward, done, 1f64);
+            return;
+        }
+
+        self.pending.push_back((state, action, next_state, reward, done));
+
+        if self.pending.len() == self.n_step {
+            self.flush_pending();
+        }
+
+        // windows starting late in the episode are cut short at the terminal state
+        if done > 0f64 {
+            self.end_episode();
+        }
+    }
+
+    // flushes the n-step windows of an episode that ended without a done flag, like a time limit
+    pub fn end_episode(&mut self) {
+        while !self.pending.is_empty() {
+            self.flush_pending();
+        }
+    }
+
+    fn flush_pending(&mut self) {
+        let reward = self
+            .pending
+            .iter()
+            .enumerate()
+            .map(|(idx, transition)| self.discount.powi(idx as i32) * transition.3)
+            .sum::<f64>();
+
+        // windows cut short at the end of an episode bootstrap from fewer steps ahead
+        let n_steps = self.pending.len() as f64;
+        let (_, _, next_state, _, done) = self.pending.back().cloned().unwrap();
+        let (state, action, _, _, _) = self.pending.pop_front().unwrap();
+
+        self.store(state, action, next_state, reward, done, n_steps);
+    }
+
+    fn store(
+        &mut self,
+        state: Vec<f64>,
+        action: Vec<f64>,
+        next_state: Vec<f64>,
+        reward: f64,
+        done: f64,
+        n_steps: f64,
+    ) {
+        // a full ring of staged rows would start overwriting its own slots
+        if self.staged.borrow().len() == self.max_size {
+            self.flush_staged();
+        }
+
+        let row = [state.as_slice(), action.as_slice(), next_state.as_slice(), &[reward, 1f64 - done, n_steps]].concat();
+        self.staged.borrow_mut().push((self.ptr as i64, row));
+
+        // new transitions take the highest priority seen so far until their td error is known
+        if let Some(priorities) = self.priorities.as_mut() {
+            priorities[self.ptr] = self.max_priority;
+        }
 
         self.ptr = (self.ptr + 1) % self.max_size;
         self.size = std::cmp::min(self.size + 1, self.max_size);
     }
 
+    // one host to device copy for every row added since the last read
+    fn flush_staged(&self) {
+        let staged = self.staged.take();
+        if staged.is_empty() {
+            return;
+        }
+
+        let index = tch::Tensor::from_slice(staged.iter().map(|(idx, _)| *idx).collect::<Vec<i64>>().as_slice())
+            .to_device(self.device);
+        let rows = staged.into_iter().flat_map(|(_, row)| row).collect::<Vec<f64>>();
+        let rows = tch::Tensor::from_slice(rows.as_slice())
+            .view([index.size()[0], self.storage.size()[1]])
+            .totype(tch::Kind::Float)
+            .to_device(self.device);
+
+        // the shallow clone shares its data with the storage, so the copy lands in the buffer itself
+        let _ = self.storage.shallow_clone().index_copy_(0, &index, &rows);
+    }
+
+    fn column(&self, column: i64) -> Vec<f64> {
+        self.flush_staged();
+
+        Vec::<f64>::try_from(&self.storage.select(1, column).to_device(tch::Device::Cpu)).unwrap()
+    }
+
+    // every slot, filled or not
+    pub fn rewards(&self) -> Vec<f64> {
+        self.column((2 * self.state_dim + self.action_dim) as i64)
+    }
+
+    pub fn not_dones(&self) -> Vec<f64> {
+        self.column((2 * self.state_dim + self.action_dim + 1) as i64)
+    }
+
+    pub fn n_steps(&self) -> Vec<f64> {
+        self.column((2 * self.state_dim + self.action_dim + 2) as i64)
+    }
+
+    // (state, action, next_state, reward, not_done) of slot idx
+    pub fn transition(&self, idx: usize) -> (Vec<f64>, Vec<f64>, Vec<f64>, f64, f64) {
+        self.flush_staged();
+
+        let row = Vec::<f64>::try_from(&self.storage.get(idx as i64).to_device(tch::Device::Cpu)).unwrap();
+        let (state_dim, action_dim) = (self.state_dim, self.action_dim);
+
+        (
+            row[..state_dim].to_vec(),
+            row[state_dim..state_dim + action_dim].to_vec(),
+            row[state_dim + action_dim..2 * state_dim + action_dim].to_vec(),
+            row[2 * state_dim + action_dim],
+            row[2 * state_dim + action_dim + 1],
+        )
+    }
+
+    pub fn len(&self) -> usize {
+        self.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.size == self.max_size
+    }
+
+    // changes the capacity in place, the newest min(len, new_capacity) transitions are kept and move to the
+    // front of the ring in chronological order so the next add goes to the first free slot
+    // pending n-step windows are untouched, they are stored as usual on the next add
+    pub fn resize(&mut self, new_capacity: usize) {
+        assert!(new_capacity > 0, "replay buffer capacity must be positive");
+
+        self.flush_staged();
+
+        let kept = std::cmp::min(self.size, new_capacity);
+
+        // oldest first, the ring only wraps once it is full
+        let start = match self.size == self.max_size {
+            true => self.ptr,
+            false => 0,
+        };
+        let order = (self.size - kept..self.size)
+            .map(|offset| (start + offset) % self.max_size)
+            .collect::<Vec<usize>>();
+
+        self.priorities = self.priorities.as_ref().map(|priorities| {
+            let mut resized = order.iter().map(|idx| priorities[*idx]).collect::<Vec<f64>>();
+            resized.resize(new_capacity, 0f64);
+            resized
+        });
+
+        let storage = ReplayBuffer::allocate(new_capacity, self.storage.size()[1], self.device, self.pin_memory);
+
+        if kept > 0 {
+            let index = tch::Tensor::from_slice(order.iter().map(|idx| *idx as i64).collect::<Vec<i64>>().as_slice())
+                .to_device(self.device);
+            storage.narrow(0, 0, kept as i64).copy_(&self.storage.index_select(0, &index));
+        }
+
+        self.storage = storage;
+        self.max_size = new_capacity;
+        self.size = kept;
+        self.ptr = kept % new_capacity;
+    }
+
     pub fn sample(&self, batch_size: i64) -> Vec<tch::Tensor> {
-        let mut rng = <rand::prelude::StdRng as rand::prelude::SeedableRng>::from_entropy();
+        self.sample_batch(batch_size).into()
+    }
+
+    // weights are all one unless the buffer is prioritized
+    pub fn sample_batch(&self, batch_size: i64) -> Batch {
+        let (indices, weights) = self.sample_indices(batch_size);
+        let mut samples = self.gather(&indices).into_iter();
 
-        let mut sample_state = Vec::with_capacity(batch_size as usize);
-        let mut sample_action = Vec::with_capacity(batch_size as usize);
-        let mut sample_next_state = Vec::with_capacity(batch_size as usize);
-        let mut sample_reward = Vec::with_capacity(batch_size as usize);
-        let mut sample_not_done = Vec::with_capacity(batch_size as usize);
+        Batch {
+            states: samples.next().unwrap(),
+            actions: samples.next().unwrap(),
+            next_states: samples.next().unwrap(),
+            rewards: samples.next().unwrap(),
+            not_dones: samples.next().unwrap(),
+            n_steps: samples.next().unwrap(),
+            indices,
+            weights: tch::Tensor::from_slice(weights.as_slice())
+                .totype(tch::Kind::Float)
+                .to_device(self.device),
+        }
+    }
 
-        for _ in 0..batch_size {
-            let id = rand::prelude::Rng::gen_range(&mut rng, 0..self.size);
+    pub fn update_priorities(&mut self, indices: &[usize], td_errors: &[f64]) {
+        assert_eq!(indices.len(), td_errors.len());
 
-            sample_state.push(self.state[id].as_slice());
-            sample_action.push(self.action[id].as_slice());
-            sample_next_state.push(self.next_state[id].as_slice());
-            sample_reward.push(self.reward[id]);
-            sample_not_done.push(self.not_done[id]);
+        if let Some(priorities) = self.priorities.as_mut() {
+            for (idx, td_error) in indices.iter().zip(td_errors.iter()) {
+                // a zero priority would never be sampled again
+                let priority = td_error.abs() + 1e-6;
+                priorities[*idx] = priority;
+                self.max_priority = self.max_priority.max(priority);
+            }
         }
+    }
 
-        let sample_state_tensor = tch::Tensor::from_slice2(sample_state.as_slice())
-            .totype(tch::Kind::Float)
-            .to_device(**device);
-        let sample_action_tensor = tch::Tensor::from_slice2(sample_action.as_slice())
-            .totype(tch::Kind::Float)
-            .to_device(**device);
-        let sample_next_state_tensor = tch::Tensor::from_slice2(sample_next_state.as_slice())
-            .totype(tch::Kind::Float)
-            .to_device(**device);
-        let sample_reward_tensor = tch::Tensor::from_slice(sample_reward.as_slice())
-            .totype(tch::Kind::Float)
-            .to_device(**device);
-        let sample_not_done_tensor = tch::Tensor::from_slice(sample_not_done.as_slice())
-            .totype(tch::Kind::Float)
-            .to_device(**device);
+    fn sample_indices(&self, batch_size: i64) -> (Vec<usize>, Vec<f64>) {
+        let mut rng = self.rng.borrow_mut();
+
+        match &self.priorities {
+            // batches larger than the buffer fall back to drawing with replacement
+            None if !self.with_replacement && batch_size as usize <= self.size => {
+                let indices = rand::seq::index::sample(&mut *rng, self.size, batch_size as usize).into_vec();
+
+                (indices, vec![1f64; batch_size as usize])
+            }
+
+            None => {
+                let indices = (0..batch_size)
+                    .map(|_| rand::prelude::Rng::gen_range(&mut *rng, 0..self.size))
+                    .collect();
+
+                (indices, vec![1f64; batch_size as usize])
+            }
+
+            Some(priorities) => {
+                let scaled = priorities[..self.size]
+                    .iter()
+                    .map(|priority| priority.powf(self.alpha))
+                    .collect::<Vec<f64>>();
+                let total = scaled.iter().sum::<f64>();
+
+                let distribution = rand::distributions::WeightedIndex::new(&scaled)
+                    .expect("Failed to make priority distribution");
+
+                let indices = (0..batch_size)
+                    .map(|_| rand::prelude::Distribution::sample(&distribution, &mut *rng))
+                    .collect::<Vec<usize>>();
+
+                let weights = indices
+                    .iter()
+                    .map(|idx| (self.size as f64 * scaled[*idx] / total).powf(-self.beta))
+                    .collect::<Vec<f64>>();
+
+                // normalized so the weights only ever scale the loss down
+                let max_weight = weights.iter().cloned().fold(f64::MIN, f64::max);
+                let weights = weights.iter().map(|weight| weight / max_weight).collect();
+
+                (indices, weights)
+            }
+        }
+    }
+
+    fn gather(&self, indices: &[usize]) -> Vec<tch::Tensor> {
+        self.flush_staged();
+
+        let state_dim = self.state_dim as i64;
+        let action_dim = self.action_dim as i64;
+
+        let index = tch::Tensor::from_slice(
+            indices.iter().map(|idx| *idx as i64).collect::<Vec<i64>>().as_slice(),
+        )
+        .to_device(self.device);
+        let rows = self.storage.index_select(0, &index);
 
         vec![
-            sample_state_tensor,
-            sample_action_tensor,
-            sample_next_state_tensor,
-            sample_reward_tensor,
-            sample_not_done_tensor,
+            rows.narrow(1, 0, state_dim).contiguous(),
+            rows.narrow(1, state_dim, action_dim).contiguous(),
+            rows.narrow(1, state_dim + action_dim, state_dim).contiguous(),
+            rows.select(1, 2 * state_dim + action_dim),
+            rows.select(1, 2 * state_dim + action_dim + 1),
+            rows.select(1, 2 * state_dim + action_dim + 2),
         ]
     }
+
+    // flat little endian file: max_size, ptr, size, state_dim, action_dim as u64 then every filled slot as f64
+    pub fn save(&self, path: String) -> anyhow::Result<()> {
+        self.flush_staged();
+
+        let mut bytes = Vec::new();
+        for header in [self.max_size, self.ptr, self.size, self.state_dim, self.action_dim] {
+            bytes.extend_from_slice(&(header as u64).to_le_bytes());
+        }
+
+        // slots past size have never been written, the buffer only wraps once it is full
+        let rows = self.storage.narrow(0, 0, self.size as i64).to_device(tch::Device::Cpu).view([-1]);
+        for val in Vec::<f64>::try_from(&rows)? {
+            bytes.extend_from_slice(&val.to_le_bytes());
+        }
+
+        if let Some(parent) = std::path::Path::new(path.as_str()).parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        std::fs::write(path, bytes)?;
+        Ok(())
+    }
+
+    pub fn load(path: String) -> anyhow::Result<Self> {
+        let bytes = std::fs::read(path)?;
+        let words: Vec<[u8; 8]> = bytes
+            .chunks_exact(8)
+            .map(|chunk| <[u8; 8]>::try_from(chunk).unwrap())
+            .collect();
+
+        if words.len() < 5 {
+            anyhow::bail!("Replay buffer file is missing its header");
+        }
+
+        let max_size = u64::from_le_bytes(words[0]) as usize;
+        let ptr = u64::from_le_bytes(words[1]) as usize;
+        let size = u64::from_le_bytes(words[2]) as usize;
+        let state_dim = u64::from_le_bytes(words[3]) as usize;
+        let action_dim = u64::from_le_bytes(words[4]) as usize;
+
+        // files from before the window length was stored have one column less and bootstrap one step ahead
+        let row_len = match words.len() == 5 + size * (2 * state_dim + action_dim + 2) {
+            true => 2 * state_dim + action_dim + 2,
+            false => 2 * state_dim + action_dim + 3,
+        };
+        if words.len() != 5 + size * row_len || size > max_size || ptr >= max_size {
+            anyhow::bail!("Replay buffer file does not match its header");
+        }
+
+        let mut replay_buffer =
+            ReplayBuffer::new(state_dim as i64, action_dim as i64, Some(max_size as i64));
+
+        let rows: Vec<f64> = words[5..].iter().map(|word| f64::from_le_bytes(*word)).collect();
+        let rows = tch::Tensor::from_slice(rows.as_slice())
+            .view([size as i64, row_len as i64])
+            .totype(tch::Kind::Float)
+            .to_device(replay_buffer.device);
+        replay_buffer.storage.narrow(0, 0, size as i64).narrow(1, 0, row_len as i64).copy_(&rows);
+
+        if row_len == 2 * state_dim + action_dim + 2 {
+            let _ = replay_buffer.storage.narrow(0, 0, size as i64).select(1, row_len as i64).fill_(1f64);
+        }
+
+        replay_buffer.ptr = ptr;
+        replay_buffer.size = size;
+
+        Ok(replay_buffer)
+    }
 }
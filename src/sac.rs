@@ -0,0 +1,350 @@
+extern crate anyhow;
+extern crate tch;
+
+use crate::device;
+use crate::environment::Environment;
+use crate::replay_buffer::ReplayBuffer;
+use crate::td3::{soft_update, Activation, Critic, LossKind, MilkshakeLayer, MilkshakeNetwork};
+
+use crate::optimizer::adam::ADAM;
+use crate::optimizer::MilkshakeOptimizer;
+
+// bounds on the predicted log standard deviation, keeps the policy from collapsing or exploding
+const LOG_STD_MIN: f64 = -20f64;
+const LOG_STD_MAX: f64 = 2f64;
+
+// tanh squashed gaussian policy, a shared trunk feeds separate mean and log std heads
+pub struct GaussianActor {
+    pub vs: std::rc::Rc<std::cell::RefCell<tch::nn::VarStore>>,
+    pub trunk: MilkshakeNetwork,
+    pub mean: MilkshakeLayer,
+    pub log_std: MilkshakeLayer,
+    pub max_action: f64,
+}
+
+impl GaussianActor {
+    pub fn new(
+        state_dim: i64,
+        action_dim: i64,
+        nn_shape: Vec<i64>,
+        max_action: f64,
+        activation: Option<Activation>,
+        compute_device: Option<tch::Device>,
+    ) -> Self {
+        let activation = activation.unwrap_or(Activation::Relu);
+        let compute_device = compute_device.unwrap_or(**device);
+        let vs = std::rc::Rc::new(std::cell::RefCell::new(tch::nn::VarStore::new(
+            compute_device,
+        )));
+
+        let mut shape = nn_shape.clone();
+        shape.insert(0, state_dim);
+
+        let mut layers = Vec::new();
+
+        for x in 1..shape.len() {
+            layers.push(MilkshakeLayer {
+                layer: tch::nn::linear(
+                    vs.borrow().root(),
+                    shape[x - 1],
+                    shape[x],
+                    Default::default(),
+                ),
+
+                input: shape[x - 1],
+                output: shape[x],
+            });
+        }
+
+        let hidden = *shape.last().unwrap();
+
+        let mean = MilkshakeLayer {
+            layer: tch::nn::linear(vs.borrow().root(), hidden, action_dim, Default::default()),
+            input: hidden,
+            output: action_dim,
+        };
+
+        let log_std = MilkshakeLayer {
+            layer: tch::nn::linear(vs.borrow().root(), hidden, action_dim, Default::default()),
+            input: hidden,
+            output: action_dim,
+        };
+
+        let trunk = MilkshakeNetwork {
+            layers,
+            activation,
+            dropout_p: 0f64,
+        };
+
+        GaussianActor {
+            vs,
+            trunk,
+            mean,
+            log_std,
+            max_action,
+        }
+    }
+
+    // unsquashed mean and clamped log std of the action distribution
+    pub fn forward(&self, xs: &tch::Tensor) -> (tch::Tensor, tch::Tensor) {
+        // the trunk's last layer is linear, so its output still needs the hidden activation
+        let features =
+            self.trunk
+                .activation
+                .apply(&<MilkshakeNetwork as tch::nn::Module>::forward(
+                    &self.trunk,
+                    xs,
+                ));
+
+        let mean = <MilkshakeLayer as tch::nn::Module>::forward(&self.mean, &features);
+        let log_std = <MilkshakeLayer as tch::nn::Module>::forward(&self.log_std, &features)
+            .clamp(LOG_STD_MIN, LOG_STD_MAX);
+
+        (mean, log_std)
+    }
+
+    // reparameterized action sample and its log probability, shaped [batch, action_dim] and [batch, 1]
+    pub fn sample(&self, xs: &tch::Tensor) -> (tch::Tensor, tch::Tensor) {
+        let (mean, log_std) = self.forward(xs);
+
+        let eps = mean.randn_like();
+        let pre_tanh = &mean + log_std.exp() * &eps;
+        let squashed = pre_tanh.tanh();
+
+        let gaussian_log_prob =
+            -0.5 * eps.pow_tensor_scalar(2) - &log_std - 0.5 * (2f64 * std::f64::consts::PI).ln();
+
+        // change of variables through tanh and the max_action scale
+        let log_prob = (gaussian_log_prob
+            - (1f64 - squashed.pow_tensor_scalar(2) + 1e-6).log()
+            - self.max_action.ln())
+        .sum_dim_intlist(&[1i64][..], true, Some(tch::Kind::Float));
+
+        (self.max_action * squashed, log_prob)
+    }
+
+    // mean of the squashed distribution, used for evaluation
+    pub fn deterministic(&self, xs: &tch::Tensor) -> tch::Tensor {
+        self.max_action * self.forward(xs).0.tanh()
+    }
+}
+
+pub struct SAC {
+    pub actor: GaussianActor,
+    pub critic: Critic,
+    pub critic_target: Critic,
+
+    actor_opt: Box<dyn MilkshakeOptimizer>,
+    critic_opt: Box<dyn MilkshakeOptimizer>,
+    alpha_opt: Box<dyn MilkshakeOptimizer>,
+
+    // temperature is learned in log space so it stays positive
+    pub log_alpha_vs: std::rc::Rc<std::cell::RefCell<tch::nn::VarStore>>,
+    pub log_alpha: tch::Tensor,
+    pub target_entropy: f64,
+
+    pub action_dim: i64,
+    pub state_dim: i64,
+    pub max_action: f64,
+    pub tau: f64,
+    pub discount: f64,
+    pub total_it: i64,
+    pub actor_lr: f64,
+    pub critic_lr: f64,
+    pub alpha_lr: f64,
+    pub n_step: i64,
+
+    pub device: tch::Device,
+}
+
+impl SAC {
+    pub fn new(
+        state_dim: i64,
+        action_dim: i64,
+        max_action: f64,
+        actor_shape: Option<Vec<i64>>,
+        q1_shape: Option<Vec<i64>>,
+        q2_shape: Option<Vec<i64>>,
+        tau: Option<f64>,
+        discount: Option<f64>,
+        actor_lr: Option<f64>,
+        critic_lr: Option<f64>,
+        alpha_lr: Option<f64>,
+        init_alpha: Option<f64>,
+        target_entropy: Option<f64>,
+        n_step: Option<i64>,
+        compute_device: Option<tch::Device>,
+    ) -> anyhow::Result<Self> {
+        let actor_shape = actor_shape.unwrap_or(vec![64, 64]);
+        let q1_shape = q1_shape.unwrap_or(vec![64, 64]);
+        let q2_shape = q2_shape.unwrap_or(vec![64, 64]);
+
+        let tau = tau.unwrap_or(0.005);
+        let discount = discount.unwrap_or(0.99);
+        let actor_lr = actor_lr.unwrap_or(0.0003);
+        let critic_lr = critic_lr.unwrap_or(0.0003);
+        let alpha_lr = alpha_lr.unwrap_or(0.0003);
+        let init_alpha = init_alpha.unwrap_or(1f64);
+        // the usual heuristic, one nat of entropy per action dimension
+        let target_entropy = target_entropy.unwrap_or(-action_dim as f64);
+        let n_step = n_step.unwrap_or(1);
+        let compute_device = compute_device.unwrap_or(**device);
+
+        let actor = GaussianActor::new(
+            state_dim,
+            action_dim,
+            actor_shape,
+            max_action,
+            None,
+            Some(compute_device),
+        );
+
+        let critic = Critic::new(
+            state_dim,
+            action_dim,
+            vec![q1_shape.clone(), q2_shape.clone()],
+            None,
+            None,
+            Some(compute_device),
+        );
+        let critic_target = Critic::new(
+            state_dim,
+            action_dim,
+            vec![q1_shape.clone(), q2_shape.clone()],
+            None,
+            None,
+            Some(compute_device),
+        );
+
+        critic_target.vs.borrow_mut().copy(&critic.vs.borrow())?;
+
+        let log_alpha_vs = std::rc::Rc::new(std::cell::RefCell::new(tch::nn::VarStore::new(
+            compute_device,
+        )));
+        let log_alpha = log_alpha_vs.borrow().root().var(
+            "log_alpha",
+            &[1],
+            tch::nn::Init::Const(init_alpha.ln()),
+        );
+
+        let actor_opt: Box<dyn MilkshakeOptimizer> =
+            Box::new(ADAM::new(actor_lr, actor.vs.clone(), None));
+        let critic_opt: Box<dyn MilkshakeOptimizer> =
+            Box::new(ADAM::new(critic_lr, critic.vs.clone(), None));
+        let alpha_opt: Box<dyn MilkshakeOptimizer> =
+            Box::new(ADAM::new(alpha_lr, log_alpha_vs.clone(), None));
+
+        Ok(SAC {
+            actor,
+            critic,
+            critic_target,
+            actor_opt,
+            critic_opt,
+            alpha_opt,
+            log_alpha_vs,
+            log_alpha,
+            target_entropy,
+            action_dim,
+            state_dim,
+            max_action,
+            tau,
+            discount,
+            total_it: 0,
+            actor_lr,
+            critic_lr,
+            alpha_lr,
+            n_step,
+            device: compute_device,
+        })
+    }
+
+    pub fn alpha(&self) -> f64 {
+        self.log_alpha.exp().double_value(&[0])
+    }
+
+    fn act(&self, state: Vec<f64>, deterministic: bool) -> Vec<f64> {
+        let state = tch::Tensor::from_slice(state.as_slice())
+            .view([1, -1])
+            .to_device(self.device);
+
+        let tensor = tch::no_grad(|| match deterministic {
+            true => self.actor.deterministic(&state),
+            false => self.actor.sample(&state).0,
+        })
+        .to_device(tch::Device::Cpu);
+
+        let mut vec = vec![0f32; self.action_dim as usize];
+        tensor.copy_data(vec.as_mut_slice(), self.action_dim as usize);
+
+        vec.iter().map(|x| *x as f64).collect()
+    }
+
+    pub fn select_action(&self, state: Vec<f64>) -> Vec<f64> {
+        self.act(state, true)
+    }
+
+    // exploration comes from the policy's own distribution, no external noise is added
+    pub fn sample_action(&mut self, state: Vec<f64>) -> Vec<f64> {
+        self.act(state, false)
+    }
+
+    // see trainer::evaluate_policy
+    pub fn evaluate(&self, env: &mut dyn Environment, episodes: i64) -> f64 {
+        crate::trainer::evaluate_policy(env, episodes, |state| self.select_action(state))
+    }
+
+    pub fn train(&mut self, replay_buffer: &ReplayBuffer, batch_size: Option<i64>) {
+        self.total_it += 1;
+
+        let batch_size = batch_size.unwrap_or(256);
+        let samples = replay_buffer
+            .sample(batch_size)
+            .iter()
+            .map(|sample| sample.to_device(self.device))
+            .collect::<Vec<tch::Tensor>>();
+
+        let state = &samples[0];
+        let action = &samples[1];
+        let next_state = &samples[2];
+        let reward = &samples[3];
+        let not_done = &samples[4];
+        let n_steps = &samples[5];
+
+        let alpha = self.alpha();
+
+        let target_q = tch::no_grad(|| {
+            let (next_action, next_log_prob) = self.actor.sample(next_state);
+            let q = self.critic_target.forward(next_state, &next_action);
+
+            let soft_q = q.amin(&[1i64][..], true) - alpha * next_log_prob;
+
+            // soft value of the state the window ends in, discounted by as many steps as the window really spans
+            let discount = tch::Tensor::pow_scalar(self.discount, &n_steps.unsqueeze(1));
+            reward.unsqueeze(1) + not_done.unsqueeze(1) * soft_q * discount
+        });
+
+        let critic_loss = self
+            .critic
+            .loss(state, action, &target_q, LossKind::Mse, true, None);
+        let solutions = self.critic_opt.ask();
+        self.critic_opt.tell(solutions, vec![critic_loss]);
+
+        let (pi, log_pi) = self.actor.sample(state);
+        let q = self.critic.forward(state, &pi);
+        let actor_loss = (alpha * &log_pi - q.amin(&[1i64][..], true)).mean(tch::Kind::Float);
+        let solutions = self.actor_opt.ask();
+        self.actor_opt.tell(solutions, vec![actor_loss]);
+
+        // moves the temperature so the policy entropy tracks target_entropy
+        let alpha_loss =
+            -(&self.log_alpha * (log_pi.detach() + self.target_entropy)).mean(tch::Kind::Float);
+        let solutions = self.alpha_opt.ask();
+        self.alpha_opt.tell(solutions, vec![alpha_loss]);
+
+        soft_update(
+            &self.critic.vs.borrow(),
+            &mut self.critic_target.vs.borrow_mut(),
+            self.tau,
+        );
+    }
+}
@@ -6,11 +6,292 @@ extern crate serde_json;
 // in order to use it you must have alpaca api keys set as env variables
 // ALPACA_KEY={your api key}
 // ALPACA_SECRET={your secret key}
+// or load bars from a csv with StockFrame::from_csv instead
+
+pub const stockframe_columns: [&str; 24] = [
+    "symbol",
+    "timestamp",
+    "open",
+    "high",
+    "low",
+    "close",
+    "volume",
+    "vwap",
+    "trade_count",
+    "adx",
+    "atr",
+    "aroonosc",
+    "aroonu",
+    "aroond",
+    "bband_up",
+    "bband_mid",
+    "bband_low",
+    "macd",
+    "macdsignal",
+    "macdhist",
+    "rsi",
+    "stoch_slowk",
+    "stoch_slowd",
+    "sma",
+];
+
+// periods of every indicator calc_technical_indicators computes, start from Default and override what you need
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IndicatorConfig {
+    pub adx_period: i32,
+    pub atr_period: i32,
+    // shared by aroonosc, aroonu and aroond
+    pub aroon_period: i32,
+    // bollinger band period and standard deviation multiplier
+    pub bband_period: i32,
+    pub bband_dev: f64,
+    // fast and slow ema periods of the macd line and the ema period of its signal line
+    pub macd_fast: i32,
+    pub macd_slow: i32,
+    pub macd_signal: i32,
+    pub rsi_period: i32,
+    pub stoch_fastk_period: i32,
+    pub stoch_slowk_period: i32,
+    pub stoch_slowd_period: i32,
+    pub sma_period: i32,
+}
+
+impl Default for IndicatorConfig {
+    fn default() -> Self {
+        IndicatorConfig {
+            adx_period: 14,
+            atr_period: 14,
+            aroon_period: 14,
+            bband_period: 20,
+            bband_dev: 2f64,
+            macd_fast: 12,
+            macd_slow: 26,
+            macd_signal: 9,
+            rsi_period: 14,
+            stoch_fastk_period: 5,
+            stoch_slowk_period: 3,
+            stoch_slowd_period: 3,
+            sma_period: 30,
+        }
+    }
+}
+
+// how clean treats the leading bars of each symbol whose indicators are still null
+// because the rolling windows have not filled yet
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WarmupStrategy {
+    // drop every bar with a null indicator
+    DropNullRows,
+    // carry the last indicator value forward, the leading bars have nothing to carry and are zero
+    // filling them with a later value would leak the future into the warmup bars
+    ForwardFill,
+    // replace null indicators with zero and keep every bar
+    ZeroFill,
+}
+
+// (value - mean) / std over a trailing window, a window without variance scores zero instead of nan
+fn rolling_zscore(values: &[Option<f64>], window: usize) -> Vec<Option<f64>> {
+    (0..values.len())
+        .map(|idx| {
+            if idx + 1 < window {
+                return None;
+            }
+
+            let window_values: Vec<f64> = values[(idx + 1 - window)..=idx]
+                .iter()
+                .copied()
+                .collect::<Option<Vec<f64>>>()?;
+
+            let n = window as f64;
+            let mean = window_values.iter().sum::<f64>() / n;
+            let variance =
+                window_values.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / (n - 1f64);
+            let std = variance.sqrt();
+
+            // rounding leaves a flat window with a tiny std rather than exactly zero
+            match std > 1e-12 * (1f64 + mean.abs()) {
+                true => Some((window_values[window - 1] - mean) / std),
+                false => Some(0f64),
+            }
+        })
+        .collect()
+}
+
+// runs f on the bars of one symbol where every input is present, in bar order, and puts each output back on
+// the bars it came from, bars with a null input get null outputs
+// the indicator sees the remaining bars as contiguous, fill_nulls first to keep gaps from shortening its windows
+fn indicator_values<F>(
+    series: &[polars::prelude::Series],
+    outputs: usize,
+    f: &F,
+) -> polars::prelude::PolarsResult<Vec<Vec<Option<f64>>>>
+where
+    F: Fn(&[Vec<f64>]) -> Vec<Vec<Option<f64>>>,
+{
+    let inputs = series
+        .iter()
+        .map(|input| Ok(input.f64()?.into_iter().collect::<Vec<Option<f64>>>()))
+        .collect::<polars::prelude::PolarsResult<Vec<Vec<Option<f64>>>>>()?;
+
+    let len = inputs[0].len();
+    let present = (0..len)
+        .filter(|row| inputs.iter().all(|input| input[*row].is_some()))
+        .collect::<Vec<usize>>();
+    let values = inputs
+        .iter()
+        .map(|input| present.iter().map(|row| input[*row].unwrap()).collect::<Vec<f64>>())
+        .collect::<Vec<Vec<f64>>>();
+
+    // TA-Lib rejects empty inputs
+    if present.is_empty() {
+        return Ok(vec![vec![None; len]; outputs]);
+    }
+
+    Ok(f(&values)
+        .into_iter()
+        .map(|output| {
+            let mut full = vec![None; len];
+            for (row, value) in present.iter().zip(output) {
+                full[*row] = value;
+            }
+            full
+        })
+        .collect())
+}
+
+// one indicator column as a lazy expression evaluated over each symbol's window,
+// f gets the null free inputs of a single symbol in bar order, see indicator_values
+fn indicator_expr<F>(name: &str, inputs: &[&str], f: F) -> polars::prelude::Expr
+where
+    F: Fn(&[Vec<f64>]) -> Vec<Option<f64>> + Send + Sync + 'static,
+{
+    let output = String::from(name);
+
+    polars::prelude::apply_multiple(
+        move |series: &mut [polars::prelude::Series]| {
+            let indicator = indicator_values(series, 1, &|x: &[Vec<f64>]| vec![f(x)])?.remove(0);
+
+            Ok(Some(<polars::prelude::Series as polars::prelude::NamedFrom<
+                Vec<Option<f64>>,
+                _,
+            >>::new(output.as_str(), indicator)))
+        },
+        inputs
+            .iter()
+            .map(|input| polars::prelude::col(input))
+            .collect::<Vec<polars::prelude::Expr>>(),
+        polars::prelude::GetOutput::from_type(polars::prelude::DataType::Float64),
+        false,
+    )
+    .over(["symbol"])
+    .alias(name)
+}
+
+// indicators with several outputs as one struct column named name, TA-Lib runs once per symbol for all of them
+// unnest the struct to get a column per output, f returns the outputs in the order of outputs
+fn indicator_struct_expr<F>(name: &str, outputs: &[&str], inputs: &[&str], f: F) -> polars::prelude::Expr
+where
+    F: Fn(&[Vec<f64>]) -> Vec<Vec<Option<f64>>> + Send + Sync + 'static,
+{
+    let struct_name = String::from(name);
+    let outputs = outputs.iter().map(|output| String::from(*output)).collect::<Vec<String>>();
+    let fields = outputs
+        .iter()
+        .map(|output| polars::prelude::Field::new(output, polars::prelude::DataType::Float64))
+        .collect::<Vec<polars::prelude::Field>>();
+
+    polars::prelude::apply_multiple(
+        move |series: &mut [polars::prelude::Series]| {
+            let columns = indicator_values(series, outputs.len(), &f)?
+                .into_iter()
+                .zip(outputs.iter())
+                .map(|(values, output)| {
+                    <polars::prelude::Series as polars::prelude::NamedFrom<Vec<Option<f64>>, _>>::new(output, values)
+                })
+                .collect::<Vec<polars::prelude::Series>>();
+
+            Ok(Some(polars::prelude::IntoSeries::into_series(polars::prelude::StructChunked::new(
+                struct_name.as_str(),
+                &columns,
+            )?)))
+        },
+        inputs
+            .iter()
+            .map(|input| polars::prelude::col(input))
+            .collect::<Vec<polars::prelude::Expr>>(),
+        polars::prelude::GetOutput::from_type(polars::prelude::DataType::Struct(fields)),
+        false,
+    )
+    .over(["symbol"])
+    .alias(name)
+}
+
+// bar granularity requested from alpaca and used for the expected timestamp grid
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Timeframe {
+    Min,
+    FiveMin,
+    FifteenMin,
+    Hour,
+    Day,
+}
+
+impl Timeframe {
+    // name alpaca expects in the timeframe query parameter
+    pub fn as_alpaca(&self) -> &'static str {
+        match self {
+            Timeframe::Min => "1Min",
+            Timeframe::FiveMin => "5Min",
+            Timeframe::FifteenMin => "15Min",
+            Timeframe::Hour => "1Hour",
+            Timeframe::Day => "1Day",
+        }
+    }
+
+    // bars in a year of regular trading hours, 252 sessions of 6.5 hours
+    pub fn periods_per_year(&self) -> f64 {
+        match self {
+            Timeframe::Min => 252f64 * 390f64,
+            Timeframe::FiveMin => 252f64 * 78f64,
+            Timeframe::FifteenMin => 252f64 * 26f64,
+            Timeframe::Hour => 252f64 * 6.5,
+            Timeframe::Day => 252f64,
+        }
+    }
+
+    pub fn duration(&self) -> polars::export::chrono::Duration {
+        match self {
+            Timeframe::Min => polars::export::chrono::Duration::minutes(1),
+            Timeframe::FiveMin => polars::export::chrono::Duration::minutes(5),
+            Timeframe::FifteenMin => polars::export::chrono::Duration::minutes(15),
+            Timeframe::Hour => polars::export::chrono::Duration::hours(1),
+            Timeframe::Day => polars::export::chrono::Duration::days(1),
+        }
+    }
+}
+
+// split or dividend that rescales every earlier bar of a symbol so prices are continuous across its ex date
+#[derive(Clone, Debug, PartialEq)]
+pub enum CorporateAction {
+    // new shares per old share, 2.0 for a 2:1 split
+    Split {
+        symbol: String,
+        ex_date: polars::export::chrono::NaiveDateTime,
+        ratio: f64,
+    },
+    // cash paid per share, in the price units of the frame
+    Dividend {
+        symbol: String,
+        ex_date: polars::export::chrono::NaiveDateTime,
+        amount: f64,
+    },
+}
 
 #[derive(Clone)]
 pub struct StockFrame {
     pub columns: Vec<String>,
     pub tickers: Vec<String>,
+    pub timeframe: Timeframe,
     pub frame: std::cell::RefCell<polars::prelude::DataFrame>,
 }
 
@@ -88,6 +369,7 @@ impl StockFrame {
         start: polars::export::chrono::NaiveDateTime,
         end: polars::export::chrono::NaiveDateTime,
         tickers: &Vec<String>,
+        timeframe: Timeframe,
     ) -> polars::prelude::DataFrame {
         let mut df = polars::prelude::DataFrame::default();
 
@@ -107,7 +389,7 @@ impl StockFrame {
                         end.and_utc()
                             .to_rfc3339_opts(polars::export::chrono::SecondsFormat::Secs, true)
                     ),
-                    "timeframe=1Min"
+                    format!("timeframe={}", timeframe.as_alpaca())
                 );
                 let entire_json = StockFrame::grab_entire_json(ticker, &uri, None)?;
                 let json_tree = serde_json::to_string(&entire_json)?;
@@ -161,11 +443,48 @@ impl StockFrame {
         df
     }
 
+    // timeframe is the bar granularity, 1 minute bars by default
+    // fetched bars are cached as parquet under cache_dir (./cache by default) keyed by tickers, range and timeframe
+    // force_refresh skips the cached file and fetches from alpaca again
     pub fn new(
+        tickers: Option<Vec<String>>,
+        start: Option<polars::export::chrono::NaiveDateTime>,
+        end: Option<polars::export::chrono::NaiveDateTime>,
+        timeframe: Option<Timeframe>,
+        cache_dir: Option<String>,
+        force_refresh: Option<bool>,
+    ) -> Self {
+        StockFrame::with_fetcher(
+            tickers,
+            start,
+            end,
+            timeframe,
+            cache_dir,
+            force_refresh,
+            StockFrame::grab_latest_data,
+        )
+    }
+
+    // same as StockFrame::new but bars that miss the cache come from fetch instead of alpaca
+    pub fn with_fetcher<F>(
         mut tickers: Option<Vec<String>>,
         mut start: Option<polars::export::chrono::NaiveDateTime>,
         mut end: Option<polars::export::chrono::NaiveDateTime>,
-    ) -> Self {
+        timeframe: Option<Timeframe>,
+        cache_dir: Option<String>,
+        force_refresh: Option<bool>,
+        fetch: F,
+    ) -> Self
+    where
+        F: FnOnce(
+            polars::export::chrono::NaiveDateTime,
+            polars::export::chrono::NaiveDateTime,
+            &Vec<String>,
+            Timeframe,
+        ) -> polars::prelude::DataFrame,
+    {
+        let timeframe = timeframe.unwrap_or(Timeframe::Min);
+
         if tickers.is_none() {
             tickers = Some(["AAPL", "TSLA"].iter().map(|s| String::from(*s)).collect());
         }
@@ -182,63 +501,215 @@ impl StockFrame {
 
         assert!(tickers.is_some());
 
-        let columns_list: Vec<String> = vec![
-            "symbol",
-            "timestamp",
-            "open",
-            "high",
-            "low",
-            "close",
-            "volume",
-            "vwap",
-            "trade_count",
-            "adx",
-            "atr",
-            "aroonosc",
-            "aroonu",
-            "aroond",
-            "bband_up",
-            "bband_mid",
-            "bband_low",
-            "macd",
-            "macdsignal",
-            "macdhist",
-            "rsi",
-            "stoch_slowk",
-            "stoch_slowd",
-            "sma",
-        ]
-        .iter()
-        .map(|s| String::from(*s))
-        .collect();
+        let columns_list: Vec<String> = stockframe_columns
+            .iter()
+            .map(|s| String::from(*s))
+            .collect();
         let tickers_list = tickers.unwrap();
 
-        let dataframe = <polars::prelude::DataFrame as polars::prelude::IntoLazy>::lazy(
-            StockFrame::grab_latest_data(start.unwrap(), end.unwrap(), &tickers_list),
-        )
-        .with_columns(
-            columns_list[9..]
-                .iter()
-                .map(|s| polars::prelude::lit(polars::prelude::NULL).alias(s))
-                .collect::<Vec<_>>()
-                .as_slice(),
-        )
-        .collect()
-        .unwrap()
-        .select(&columns_list)
-        .unwrap()
-        .to_owned();
+        let cache_path = std::path::Path::new(&cache_dir.unwrap_or(String::from("./cache"))).join(
+            StockFrame::cache_key(&tickers_list, start.unwrap(), end.unwrap(), timeframe),
+        );
+
+        let cached = match force_refresh.unwrap_or(false) || !cache_path.exists() {
+            true => None,
+            false => match StockFrame::read_cache(&cache_path) {
+                Ok(bars) => Some(bars),
+                Err(err) => {
+                    println!(
+                        "Failed to read cached bars at {}: {}",
+                        cache_path.display(),
+                        err
+                    );
+                    None
+                }
+            },
+        };
+
+        let bars = match cached {
+            Some(bars) => bars,
+            None => {
+                let mut bars = fetch(start.unwrap(), end.unwrap(), &tickers_list, timeframe);
+
+                // an empty frame means every request failed, dont cache that
+                if bars.shape().0 != 0 {
+                    if let Err(err) = StockFrame::write_cache(&cache_path, &mut bars) {
+                        println!("Failed to cache bars at {}: {}", cache_path.display(), err);
+                    }
+                }
+
+                bars
+            }
+        };
+
+        let dataframe = <polars::prelude::DataFrame as polars::prelude::IntoLazy>::lazy(bars)
+            .with_columns(
+                columns_list[9..]
+                    .iter()
+                    .map(|s| polars::prelude::lit(polars::prelude::NULL).alias(s))
+                    .collect::<Vec<_>>()
+                    .as_slice(),
+            )
+            .collect()
+            .unwrap()
+            .select(&columns_list)
+            .unwrap()
+            .to_owned();
 
         let dataframe_box = std::cell::RefCell::new(dataframe);
 
         StockFrame {
             columns: columns_list,
             tickers: tickers_list,
+            timeframe,
             frame: dataframe_box,
         }
     }
 
-    pub fn parse_dt_column(&mut self) {
+    pub fn cache_key(
+        tickers: &[String],
+        start: polars::export::chrono::NaiveDateTime,
+        end: polars::export::chrono::NaiveDateTime,
+        timeframe: Timeframe,
+    ) -> String {
+        format!(
+            "{}_{}_{}_{}.parquet",
+            tickers.join("-"),
+            start.and_utc().timestamp(),
+            end.and_utc().timestamp(),
+            timeframe.as_alpaca()
+        )
+    }
+
+    fn read_cache(path: &std::path::Path) -> anyhow::Result<polars::prelude::DataFrame> {
+        let file = std::fs::File::open(path)?;
+        let reader =
+            <polars::prelude::ParquetReader<std::fs::File> as polars::prelude::SerReader<
+                std::fs::File,
+            >>::new(file);
+        let bars = <polars::prelude::ParquetReader<std::fs::File> as polars::prelude::SerReader<
+            std::fs::File,
+        >>::finish(reader)?;
+
+        Ok(bars)
+    }
+
+    fn write_cache(
+        path: &std::path::Path,
+        bars: &mut polars::prelude::DataFrame,
+    ) -> anyhow::Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let file = std::fs::File::create(path)?;
+        polars::prelude::ParquetWriter::new(file).finish(bars)?;
+        Ok(())
+    }
+
+    // loads bars from a csv instead of alpaca, the result goes through the same pipeline as StockFrame::new
+    // expected header: timestamp,symbol,open,high,low,close,volume with optional vwap and trade_count columns
+    // timestamps are rfc3339 strings like the ones alpaca returns (2024-01-02T14:30:00Z)
+    // symbols keeps only those tickers, None keeps every symbol in the file
+    // timeframe is the spacing of the bars in the file, 1 minute by default
+    pub fn from_csv(
+        path: &str,
+        symbols: Option<Vec<String>>,
+        timeframe: Option<Timeframe>,
+    ) -> anyhow::Result<Self> {
+        let reader = polars::prelude::CsvReadOptions::default()
+            .with_has_header(true)
+            .try_into_reader_with_file_path(Some(path.into()))?;
+        let raw_df = <polars::prelude::CsvReader<std::fs::File> as polars::prelude::SerReader<
+            std::fs::File,
+        >>::finish(reader)?;
+
+        let raw_columns: Vec<String> = raw_df
+            .get_column_names()
+            .iter()
+            .map(|s| String::from(*s))
+            .collect();
+        let missing: Vec<&str> = stockframe_columns[..7]
+            .iter()
+            .copied()
+            .filter(|s| !raw_columns.iter().any(|column| column == s))
+            .collect();
+
+        if !missing.is_empty() {
+            anyhow::bail!(format!(
+                "{} is missing columns: {}",
+                path,
+                missing.join(", ")
+            ));
+        }
+
+        let columns_list: Vec<String> = stockframe_columns
+            .iter()
+            .map(|s| String::from(*s))
+            .collect();
+
+        let mut lazy_df = <polars::prelude::DataFrame as polars::prelude::IntoLazy>::lazy(raw_df)
+            .with_columns([
+                polars::prelude::col("symbol").cast(polars::prelude::DataType::String),
+                polars::prelude::col("timestamp").cast(polars::prelude::DataType::String),
+            ]);
+
+        // alpaca always sends vwap and trade_count, a csv might not
+        for name in &columns_list[2..9] {
+            lazy_df = match raw_columns.contains(name) {
+                true => lazy_df.with_column(
+                    polars::prelude::col(name).cast(polars::prelude::DataType::Float64),
+                ),
+                false => lazy_df.with_column(
+                    polars::prelude::lit(polars::prelude::NULL)
+                        .cast(polars::prelude::DataType::Float64)
+                        .alias(name),
+                ),
+            };
+        }
+
+        if let Some(symbols) = &symbols {
+            let keep = symbols
+                .iter()
+                .map(|symbol| {
+                    polars::prelude::col("symbol").eq(polars::prelude::lit(symbol.as_str()))
+                })
+                .reduce(|acc, expr| acc.or(expr))
+                .unwrap_or(polars::prelude::lit(false));
+            lazy_df = lazy_df.filter(keep);
+        }
+
+        let dataframe = lazy_df
+            .with_columns(
+                columns_list[9..]
+                    .iter()
+                    .map(|s| polars::prelude::lit(polars::prelude::NULL).alias(s))
+                    .collect::<Vec<_>>()
+                    .as_slice(),
+            )
+            .collect()?
+            .select(&columns_list)?;
+
+        let tickers_list = match symbols {
+            Some(symbols) => symbols,
+            None => dataframe
+                .column("symbol")?
+                .unique_stable()?
+                .str()?
+                .into_no_null_iter()
+                .map(String::from)
+                .collect(),
+        };
+
+        Ok(StockFrame {
+            columns: columns_list,
+            tickers: tickers_list,
+            timeframe: timeframe.unwrap_or(Timeframe::Min),
+            frame: std::cell::RefCell::new(dataframe),
+        })
+    }
+
+    pub fn parse_dt_column(&mut self) -> anyhow::Result<()> {
         let lazy_df = <polars::prelude::DataFrame as polars::prelude::IntoLazy>::lazy(
             self.frame.borrow().clone(),
         );
@@ -256,10 +727,10 @@ impl StockFrame {
                 strptimeoptions,
                 polars::prelude::lit("1970-01-01T00:00:00+00:00"),
             )])
-            .collect()
-            .expect("Failed to parse date time index");
+            .collect()?;
 
         self.frame.replace(new_df);
+        Ok(())
     }
 
     pub fn get_min_timestamp(&self) -> polars::export::chrono::NaiveDateTime {
@@ -310,16 +781,17 @@ impl StockFrame {
         .naive_utc()
     }
 
-    pub fn fill_date_range(&mut self) {
+    pub fn fill_date_range(&mut self) -> anyhow::Result<()> {
         let df = self.frame.borrow().clone();
 
         let max = self.get_max_timestamp().timestamp_millis();
         let mut min = self.get_min_timestamp().timestamp_millis();
+        let step = self.timeframe.duration().num_milliseconds();
         let mut date_range: Vec<i64> = vec![];
 
         while min <= max {
             date_range.push(min);
-            min += 60000;
+            min += step;
         }
 
         let ts_range: Vec<polars::export::chrono::NaiveDateTime> = date_range
@@ -340,27 +812,29 @@ impl StockFrame {
             [polars::export::chrono::NaiveDateTime],
         >>::new("timestamp", ts_range.as_slice());
         let new_rows = <polars::prelude::DataFrame as polars::prelude::IntoLazy>::lazy(
-            polars::prelude::DataFrame::new(vec![ts_range_series]).unwrap(),
+            polars::prelude::DataFrame::new(vec![ts_range_series])?,
         );
 
         let lazy_df = <polars::prelude::DataFrame as polars::prelude::IntoLazy>::lazy(df.clone());
         let symbol_df = lazy_df
             .select([polars::prelude::col("symbol")])
             .unique(None, polars::prelude::UniqueKeepStrategy::First);
-        let new_index = symbol_df.cross_join(new_rows).collect().unwrap();
+        let new_index = symbol_df.cross_join(new_rows).collect()?;
 
-        let new_df = <polars::prelude::DataFrame as polars::prelude::DataFrameJoinOps>::outer_join(
+        let new_df = <polars::prelude::DataFrame as polars::prelude::DataFrameJoinOps>::join(
             &df.clone(),
             &new_index,
             ["symbol", "timestamp"],
             ["symbol", "timestamp"],
-        )
-        .unwrap();
+            polars::prelude::JoinArgs::new(polars::prelude::JoinType::Full)
+                .with_coalesce(polars::prelude::JoinCoalesce::CoalesceColumns),
+        )?;
 
         self.frame.replace(new_df);
+        Ok(())
     }
 
-    pub fn fill_nulls(&mut self) {
+    pub fn fill_nulls(&mut self) -> anyhow::Result<()> {
         let lazy_df = <polars::prelude::DataFrame as polars::prelude::IntoLazy>::lazy(
             self.frame.borrow().clone(),
         );
@@ -370,8 +844,7 @@ impl StockFrame {
                 .forward_fill(None)
                 .backward_fill(None)
                 .over(["symbol"])])
-            .collect()
-            .unwrap();
+            .collect()?;
 
         let new_df = <polars::prelude::DataFrame as polars::prelude::IntoLazy>::lazy(ffill_df)
             .with_columns([
@@ -379,22 +852,313 @@ impl StockFrame {
                 polars::prelude::col("high").fill_null(polars::prelude::col("close")),
                 polars::prelude::col("low").fill_null(polars::prelude::col("close")),
             ])
-            .collect()
-            .unwrap();
+            .collect()?;
+
+        self.frame.replace(new_df);
+        Ok(())
+    }
+
+    // aggregates the bars into coarser timeframe buckets per symbol and recomputes the indicators
+    // open and close are the first and last non null values in a bucket, high, low, volume and trade_count
+    // skip nulls, a bucket with no bars at all keeps null ohlcv so fill_nulls can still handle it
+    // expects a parsed timestamp column, buckets are aligned to multiples of the timeframe since the epoch
+    pub fn resample(&mut self, timeframe: Timeframe) -> anyhow::Result<()> {
+        assert!(
+            timeframe.duration() >= self.timeframe.duration(),
+            "Cannot resample {} bars into finer {} bars",
+            self.timeframe.as_alpaca(),
+            timeframe.as_alpaca()
+        );
+
+        let step = timeframe.duration().num_milliseconds();
+        let columns = self.columns.clone();
+
+        let bucket = polars::prelude::col("timestamp")
+            .cast(polars::prelude::DataType::Datetime(polars::prelude::TimeUnit::Milliseconds, None))
+            .cast(polars::prelude::DataType::Int64)
+            .floor_div(polars::prelude::lit(step))
+            * polars::prelude::lit(step);
+
+        // sum over a bucket, null instead of zero when every value in it is null
+        let null_sum = |name: &str| {
+            polars::prelude::when(polars::prelude::col(name).count().eq(polars::prelude::lit(0)))
+                .then(polars::prelude::lit(polars::prelude::NULL).cast(polars::prelude::DataType::Float64))
+                .otherwise(polars::prelude::col(name).sum())
+                .alias(name)
+        };
+
+        let vwap = (polars::prelude::col("vwap") * polars::prelude::col("volume")).sum()
+            / polars::prelude::col("volume")
+                .filter(polars::prelude::col("vwap").is_not_null())
+                .sum();
+
+        let new_df = <polars::prelude::DataFrame as polars::prelude::IntoLazy>::lazy(
+            self.frame.borrow().clone(),
+        )
+        .sort(["symbol", "timestamp"], Default::default())
+        .with_column(
+            bucket
+                .cast(polars::prelude::DataType::Datetime(polars::prelude::TimeUnit::Milliseconds, None))
+                .alias("timestamp"),
+        )
+        .group_by_stable([polars::prelude::col("symbol"), polars::prelude::col("timestamp")])
+        .agg([
+            polars::prelude::col("open").drop_nulls().first(),
+            polars::prelude::col("high").max(),
+            polars::prelude::col("low").min(),
+            polars::prelude::col("close").drop_nulls().last(),
+            null_sum("volume"),
+            polars::prelude::when(polars::prelude::col("vwap").count().eq(polars::prelude::lit(0)))
+                .then(polars::prelude::lit(polars::prelude::NULL).cast(polars::prelude::DataType::Float64))
+                .otherwise(vwap)
+                .alias("vwap"),
+            null_sum("trade_count"),
+        ])
+        .with_columns(
+            columns[9..]
+                .iter()
+                .map(|s| {
+                    polars::prelude::lit(polars::prelude::NULL)
+                        .cast(polars::prelude::DataType::Float64)
+                        .alias(s)
+                })
+                .collect::<Vec<_>>()
+                .as_slice(),
+        )
+        .sort(["symbol", "timestamp"], Default::default())
+        .collect()?
+        .select(&columns)?;
 
         self.frame.replace(new_df);
+        self.timeframe = timeframe;
+
+        self.calc_technical_indicators(None)
     }
 
-    pub fn update_symbol_groups(&mut self) -> Box<polars::prelude::GroupBy> {
-        return Box::new(self.frame.get_mut().group_by(["symbol"]).unwrap());
+    // chronological split for backtesting, bars before cutoff go to the first frame and the rest to the second
+    // every symbol keeps its rows in order, both halves keep the tickers and timeframe of this frame
+    pub fn split_by_date(
+        &self,
+        cutoff: polars::export::chrono::NaiveDateTime,
+    ) -> (StockFrame, StockFrame) {
+        let before = polars::prelude::col("timestamp")
+            .dt()
+            .timestamp(polars::datatypes::TimeUnit::Milliseconds)
+            .lt(polars::prelude::lit(cutoff.and_utc().timestamp_millis()));
+
+        let split = |predicate: polars::prelude::Expr| {
+            let frame = <polars::prelude::DataFrame as polars::prelude::IntoLazy>::lazy(
+                self.frame.borrow().clone(),
+            )
+            .filter(predicate)
+            .sort(["symbol", "timestamp"], Default::default())
+            .collect()
+            .expect("Failed to split bars");
+
+            StockFrame {
+                columns: self.columns.clone(),
+                tickers: self.tickers.clone(),
+                timeframe: self.timeframe,
+                frame: std::cell::RefCell::new(frame),
+            }
+        };
+
+        (split(before.clone()), split(before.not()))
     }
 
-    // bad TA-Lib wrapper
-    pub unsafe fn calc_technical_indicators(&mut self) {
+    // back adjusts open, high, low, close and vwap of the bars before each ex date, call it before the indicators
+    // a split divides those prices by its ratio and multiplies volume by it, a dividend multiplies prices by
+    // 1 - amount / the last close before the ex date, actions are applied in the order given
+    // expects a parsed timestamp column
+    pub fn adjust_prices(&mut self, actions: &[CorporateAction]) -> anyhow::Result<()> {
+        let prices = ["open", "high", "low", "close", "vwap"];
+
+        for action in actions {
+            let (symbol, ex_date) = match action {
+                CorporateAction::Split { symbol, ex_date, .. } => (symbol, ex_date),
+                CorporateAction::Dividend { symbol, ex_date, .. } => (symbol, ex_date),
+            };
+
+            let before = polars::prelude::col("symbol")
+                .eq(polars::prelude::lit(symbol.as_str()))
+                .and(
+                    polars::prelude::col("timestamp")
+                        .dt()
+                        .timestamp(polars::datatypes::TimeUnit::Milliseconds)
+                        .lt(polars::prelude::lit(ex_date.and_utc().timestamp_millis())),
+                );
+
+            let lazy_df = <polars::prelude::DataFrame as polars::prelude::IntoLazy>::lazy(
+                self.frame.borrow().clone(),
+            );
+
+            let (price_factor, volume_factor) = match action {
+                CorporateAction::Split { ratio, .. } => {
+                    if *ratio <= 0f64 {
+                        anyhow::bail!("Split ratio for {} must be positive, got {}", symbol, ratio);
+                    }
+
+                    (1f64 / ratio, *ratio)
+                }
+                CorporateAction::Dividend { amount, .. } => {
+                    let prior_close = lazy_df
+                        .clone()
+                        .filter(before.clone())
+                        .sort(["timestamp"], Default::default())
+                        .select([polars::prelude::col("close").drop_nulls().last()])
+                        .collect()?
+                        .column("close")?
+                        .f64()?
+                        .get(0);
+
+                    match prior_close {
+                        Some(close) if close > *amount => (1f64 - amount / close, 1f64),
+                        Some(close) => anyhow::bail!(
+                            "Dividend of {} for {} is not below the prior close of {}",
+                            amount,
+                            symbol,
+                            close
+                        ),
+                        None => anyhow::bail!("No close for {} before the dividend ex date {}", symbol, ex_date),
+                    }
+                }
+            };
+
+            let scale = |name: &str, factor: f64| {
+                polars::prelude::when(before.clone())
+                    .then(polars::prelude::col(name) * polars::prelude::lit(factor))
+                    .otherwise(polars::prelude::col(name))
+                    .alias(name)
+            };
+
+            let mut columns: Vec<polars::prelude::Expr> =
+                prices.iter().map(|name| scale(name, price_factor)).collect();
+            columns.push(scale("volume", volume_factor));
+
+            let new_df = lazy_df.with_columns(columns).collect()?;
+            self.frame.replace(new_df);
+        }
+
+        Ok(())
+    }
+
+    // [num_bars, columns.len()] float tensor on the training device, one row per bar of every symbol
+    // rows are ordered by symbol then timestamp and features follow the order of columns
+    // null values are an error, clean or fill the frame first
+    pub fn to_tensor(&self, columns: &[&str]) -> anyhow::Result<tch::Tensor> {
+        let sorted = self
+            .frame
+            .borrow()
+            .sort(["symbol", "timestamp"], vec![false, false], false)?;
+
+        let mut features = Vec::with_capacity(columns.len());
+        for name in columns {
+            let column = sorted.column(name)?.cast(&polars::prelude::DataType::Float64)?;
+
+            if column.null_count() > 0 {
+                anyhow::bail!("Column {} has {} null values", name, column.null_count());
+            }
+
+            features.push(column.f64()?.into_no_null_iter().collect::<Vec<f64>>());
+        }
+
+        let rows = sorted.height();
+        let matrix = (0..rows)
+            .flat_map(|row| features.iter().map(move |feature| feature[row]))
+            .collect::<Vec<f64>>();
+
+        Ok(tch::Tensor::from_slice(matrix.as_slice())
+            .view([rows as i64, columns.len() as i64])
+            .totype(tch::Kind::Float)
+            .to_device(**crate::device))
+    }
+
+    pub fn update_symbol_groups(&mut self) -> anyhow::Result<Box<polars::prelude::GroupBy>> {
+        Ok(Box::new(self.frame.get_mut().group_by(["symbol"])?))
+    }
+
+    // indicators come from TA-Lib through the safe wrappers in crate::indicators
+    // config sets the period of every indicator, None uses IndicatorConfig::default
+    // every indicator is one expression over the symbol window of a single lazy query, so polars
+    // runs them in parallel and collects once, the bars come back sorted by symbol and timestamp
+    // indicators with several outputs are computed once as a struct column and unnested
+    pub fn calc_technical_indicators(&mut self, config: Option<IndicatorConfig>) -> anyhow::Result<()> {
+        let IndicatorConfig {
+            adx_period,
+            atr_period,
+            aroon_period,
+            bband_period,
+            bband_dev,
+            macd_fast,
+            macd_slow,
+            macd_signal,
+            rsi_period,
+            stoch_fastk_period,
+            stoch_slowk_period,
+            stoch_slowd_period,
+            sma_period,
+        } = config.unwrap_or_default();
+
+        let hlc = ["high", "low", "close"];
+        let hl = ["high", "low"];
+        let c = ["close"];
+
+        let indicators = vec![
+            indicator_expr("adx", &hlc, move |x| crate::indicators::adx(&x[0], &x[1], &x[2], adx_period)),
+            indicator_expr("atr", &hlc, move |x| crate::indicators::atr(&x[0], &x[1], &x[2], atr_period)),
+            indicator_expr("aroonosc", &hl, move |x| crate::indicators::aroonosc(&x[0], &x[1], aroon_period)),
+            indicator_struct_expr("aroon", &["aroonu", "aroond"], &hl, move |x| {
+                let (aroond, aroonu) = crate::indicators::aroon(&x[0], &x[1], aroon_period);
+                vec![aroonu, aroond]
+            }),
+            indicator_struct_expr("bbands", &["bband_up", "bband_mid", "bband_low"], &c, move |x| {
+                let (up, mid, low) = crate::indicators::bbands(&x[0], bband_period, bband_dev);
+                vec![up, mid, low]
+            }),
+            indicator_struct_expr("macd_outputs", &["macd", "macdsignal", "macdhist"], &c, move |x| {
+                let (macd, signal, hist) = crate::indicators::macd(&x[0], macd_fast, macd_slow, macd_signal);
+                vec![macd, signal, hist]
+            }),
+            indicator_expr("rsi", &c, move |x| crate::indicators::rsi(&x[0], rsi_period)),
+            indicator_struct_expr("stoch", &["stoch_slowk", "stoch_slowd"], &hlc, move |x| {
+                let (slowk, slowd) =
+                    crate::indicators::stoch(&x[0], &x[1], &x[2], stoch_fastk_period, stoch_slowk_period, stoch_slowd_period);
+                vec![slowk, slowd]
+            }),
+            indicator_expr("sma", &c, move |x| crate::indicators::sma(&x[0], sma_period)),
+        ];
+        let structs = ["aroon", "bbands", "macd_outputs", "stoch"];
+
+        let new_df = <polars::prelude::DataFrame as polars::prelude::IntoLazy>::lazy(
+            self.frame.borrow().clone(),
+        )
+        .sort(["symbol", "timestamp"], Default::default())
+        // the unnested struct fields would clash with the indicators of an earlier calculation
+        .drop_no_validate(&stockframe_columns[9..])
+        .with_columns(indicators)
+        .unnest(structs)
+        .select(
+            self.columns
+                .iter()
+                .map(|name| polars::prelude::col(name))
+                .collect::<Vec<polars::prelude::Expr>>(),
+        )
+        .collect()?;
+
+        self.frame.replace(new_df);
+        Ok(())
+    }
+
+    // reference for calc_technical_indicators in tests, one eager pass per symbol group in frame order
+    // expects the bars of each symbol to be contiguous, in time order and free of nulls
+    #[cfg(test)]
+    pub fn calc_technical_indicators_eager(&mut self, config: Option<IndicatorConfig>) -> anyhow::Result<()> {
+        let config = config.unwrap_or_default();
+
         // force sort by symbol
         let mut concat_df = polars::prelude::DataFrame::default();
         let columns = self.columns.clone();
-        let symbol_groups = self.update_symbol_groups();
+        let symbol_groups = self.update_symbol_groups()?;
 
         for idx in symbol_groups.get_groups().clone().iter() {
             let symbol_df = symbol_groups
@@ -402,276 +1166,147 @@ impl StockFrame {
                 .slice(idx.first() as i64, idx.len())
                 .clone();
             let high: Vec<f64> = symbol_df
-                .column("high")
-                .unwrap()
-                .f64()
-                .unwrap()
+                .column("high")?
+                .f64()?
                 .into_no_null_iter()
                 .collect();
             let low: Vec<f64> = symbol_df
-                .column("low")
-                .unwrap()
-                .f64()
-                .unwrap()
+                .column("low")?
+                .f64()?
                 .into_no_null_iter()
                 .collect();
             let close: Vec<f64> = symbol_df
-                .column("close")
-                .unwrap()
-                .f64()
-                .unwrap()
+                .column("close")?
+                .f64()?
                 .into_no_null_iter()
                 .collect();
 
-            let mut s: libc::c_int = 0;
-            let mut n: libc::c_int = 0;
-
-            let mut adx = vec![0f64; idx.len()];
-            let mut atr = vec![0f64; idx.len()];
-            let mut aroon_up = vec![0f64; idx.len()];
-            let mut aroon_down = vec![0f64; idx.len()];
-            let mut aroonosc = vec![0f64; idx.len()];
-            let mut bband_up = vec![0f64; idx.len()];
-            let mut bband_mid = vec![0f64; idx.len()];
-            let mut bband_low = vec![0f64; idx.len()];
-            let mut macd = vec![0f64; idx.len()];
-            let mut macdsignal = vec![0f64; idx.len()];
-            let mut macdhist = vec![0f64; idx.len()];
-            let mut rsi = vec![0f64; idx.len()];
-            let mut stoch_slowk = vec![0f64; idx.len()];
-            let mut stoch_slowd = vec![0f64; idx.len()];
-            let mut sma = vec![0f64; idx.len()];
-
-            assert_eq!(
-                crate::wrappers::talib::TA_ADX(
-                    0,
-                    (close.len() - 1) as libc::c_int,
-                    high.as_ptr(),
-                    low.as_ptr(),
-                    close.as_ptr(),
-                    14,
-                    &mut s as *mut libc::c_int,
-                    &mut n as *mut libc::c_int,
-                    adx.as_mut_ptr(),
-                ),
-                crate::wrappers::talib::TA_RetCode_TA_SUCCESS
+            let (aroon_down, aroon_up) = crate::indicators::aroon(&high, &low, config.aroon_period);
+            let (bband_up, bband_mid, bband_low) =
+                crate::indicators::bbands(&close, config.bband_period, config.bband_dev);
+            let (macd, macdsignal, macdhist) =
+                crate::indicators::macd(&close, config.macd_fast, config.macd_slow, config.macd_signal);
+            let (stoch_slowk, stoch_slowd) = crate::indicators::stoch(
+                &high,
+                &low,
+                &close,
+                config.stoch_fastk_period,
+                config.stoch_slowk_period,
+                config.stoch_slowd_period,
             );
 
-            assert_eq!(
-                crate::wrappers::talib::TA_ATR(
-                    0,
-                    (close.len() - 1) as libc::c_int,
-                    high.as_ptr(),
-                    low.as_ptr(),
-                    close.as_ptr(),
-                    14,
-                    &mut s as *mut libc::c_int,
-                    &mut n as *mut libc::c_int,
-                    atr.as_mut_ptr(),
-                ),
-                crate::wrappers::talib::TA_RetCode_TA_SUCCESS
-            );
+            let indicators = vec![
+                ("adx", crate::indicators::adx(&high, &low, &close, config.adx_period)),
+                ("atr", crate::indicators::atr(&high, &low, &close, config.atr_period)),
+                ("aroonosc", crate::indicators::aroonosc(&high, &low, config.aroon_period)),
+                ("aroonu", aroon_up),
+                ("aroond", aroon_down),
+                ("bband_up", bband_up),
+                ("bband_mid", bband_mid),
+                ("bband_low", bband_low),
+                ("macd", macd),
+                ("macdsignal", macdsignal),
+                ("macdhist", macdhist),
+                ("rsi", crate::indicators::rsi(&close, config.rsi_period)),
+                ("stoch_slowk", stoch_slowk),
+                ("stoch_slowd", stoch_slowd),
+                ("sma", crate::indicators::sma(&close, config.sma_period)),
+            ];
 
-            assert_eq!(
-                crate::wrappers::talib::TA_AROON(
-                    0,
-                    (close.len() - 1) as libc::c_int,
-                    high.as_ptr(),
-                    low.as_ptr(),
-                    14,
-                    &mut s as *mut libc::c_int,
-                    &mut n as *mut libc::c_int,
-                    aroon_down.as_mut_ptr(),
-                    aroon_up.as_mut_ptr(),
-                ),
-                crate::wrappers::talib::TA_RetCode_TA_SUCCESS
-            );
+            let mut new_df = symbol_df.clone();
+            new_df = new_df.drop_many(columns[9..].as_ref());
 
-            assert_eq!(
-                crate::wrappers::talib::TA_AROONOSC(
-                    0,
-                    (close.len() - 1) as libc::c_int,
-                    high.as_ptr(),
-                    low.as_ptr(),
-                    14,
-                    &mut s as *mut libc::c_int,
-                    &mut n as *mut libc::c_int,
-                    aroonosc.as_mut_ptr(),
-                ),
-                crate::wrappers::talib::TA_RetCode_TA_SUCCESS
-            );
+            for (name, values) in indicators {
+                new_df.with_column(
+                    <polars::prelude::Series as polars::prelude::NamedFrom<Vec<Option<f64>>, _>>::new(name, values),
+                )?;
+            }
 
-            assert_eq!(
-                crate::wrappers::talib::TA_BBANDS(
-                    0,
-                    (close.len() - 1) as libc::c_int,
-                    close.as_ptr(),
-                    5,
-                    2f64,
-                    2f64,
-                    crate::wrappers::talib::TA_MAType_TA_MAType_SMA,
-                    &mut s as *mut libc::c_int,
-                    &mut n as *mut libc::c_int,
-                    bband_up.as_mut_ptr(),
-                    bband_mid.as_mut_ptr(),
-                    bband_low.as_mut_ptr(),
-                ),
-                crate::wrappers::talib::TA_RetCode_TA_SUCCESS
-            );
+            new_df = new_df.select(&columns)?;
+            concat_df = concat_df.vstack(&new_df)?;
+        }
 
-            assert_eq!(
-                crate::wrappers::talib::TA_MACD(
-                    0,
-                    (close.len() - 1) as libc::c_int,
-                    close.as_ptr(),
-                    12,
-                    26,
-                    9,
-                    &mut s as *mut libc::c_int,
-                    &mut n as *mut libc::c_int,
-                    macd.as_mut_ptr(),
-                    macdsignal.as_mut_ptr(),
-                    macdhist.as_mut_ptr(),
-                ),
-                crate::wrappers::talib::TA_RetCode_TA_SUCCESS
-            );
+        self.frame.replace(concat_df);
+        Ok(())
+    }
 
-            assert_eq!(
-                crate::wrappers::talib::TA_RSI(
-                    0,
-                    (close.len() - 1) as libc::c_int,
-                    close.as_ptr(),
-                    14,
-                    &mut s as *mut libc::c_int,
-                    &mut n as *mut libc::c_int,
-                    rsi.as_mut_ptr(),
-                ),
-                crate::wrappers::talib::TA_RetCode_TA_SUCCESS
-            );
+    // adds {column}_zscore_{window}, the value standardized by the mean and sample standard deviation
+    // of the last `window` bars of its symbol, the first window - 1 bars and windows with a null stay null
+    // the new column is not one of self.columns, so recalculating the indicators or resampling drops it
+    // returns the name of the new column
+    pub fn add_rolling_zscore(&mut self, column: &str, window: usize) -> anyhow::Result<String> {
+        assert!(window > 1, "a rolling z-score needs a window of at least two bars");
 
-            assert_eq!(
-                crate::wrappers::talib::TA_STOCH(
-                    0,
-                    (close.len() - 1) as libc::c_int,
-                    high.as_ptr(),
-                    low.as_ptr(),
-                    close.as_ptr(),
-                    5,
-                    3,
-                    crate::wrappers::talib::TA_MAType_TA_MAType_SMA,
-                    3,
-                    crate::wrappers::talib::TA_MAType_TA_MAType_SMA,
-                    &mut s as *mut libc::c_int,
-                    &mut n as *mut libc::c_int,
-                    stoch_slowk.as_mut_ptr(),
-                    stoch_slowd.as_mut_ptr(),
-                ),
-                crate::wrappers::talib::TA_RetCode_TA_SUCCESS
-            );
+        let name = format!("{}_zscore_{}", column, window);
 
-            assert_eq!(
-                crate::wrappers::talib::TA_SMA(
-                    0,
-                    (close.len() - 1) as libc::c_int,
-                    close.as_ptr(),
-                    30,
-                    &mut s as *mut libc::c_int,
-                    &mut n as *mut libc::c_int,
-                    sma.as_mut_ptr(),
-                ),
-                crate::wrappers::talib::TA_RetCode_TA_SUCCESS
-            );
+        let sorted = self
+            .frame
+            .borrow()
+            .sort(["symbol", "timestamp"], vec![false, false], false)?;
+        self.frame.replace(sorted);
 
-            let mut new_df = symbol_df.clone();
-            new_df = new_df.drop_many(columns[9..].as_ref());
+        let mut zscores: Vec<Option<f64>> = Vec::new();
+        let symbol_groups = self.update_symbol_groups()?;
 
-            new_df
-                .with_column(
-                    <polars::prelude::Series as polars::prelude::NamedFromOwned<Vec<f64>>>::from_vec("adx", adx),
-                )
-                .unwrap();
-            new_df
-                .with_column(
-                    <polars::prelude::Series as polars::prelude::NamedFromOwned<Vec<f64>>>::from_vec("atr", atr),
-                )
-                .unwrap();
-            new_df
-                .with_column(
-                    <polars::prelude::Series as polars::prelude::NamedFromOwned<Vec<f64>>>::from_vec("aroonosc", aroonosc),
-                )
-                .unwrap();
-            new_df
-                .with_column(
-                    <polars::prelude::Series as polars::prelude::NamedFromOwned<Vec<f64>>>::from_vec("aroonu", aroon_up),
-                )
-                .unwrap();
-            new_df
-                .with_column(
-                    <polars::prelude::Series as polars::prelude::NamedFromOwned<Vec<f64>>>::from_vec("aroond", aroon_down),
-                )
-                .unwrap();
-            new_df
-                .with_column(
-                    <polars::prelude::Series as polars::prelude::NamedFromOwned<Vec<f64>>>::from_vec("bband_up", bband_up),
-                )
-                .unwrap();
-            new_df
-                .with_column(
-                    <polars::prelude::Series as polars::prelude::NamedFromOwned<Vec<f64>>>::from_vec("bband_mid", bband_mid),
-                )
-                .unwrap();
-            new_df
-                .with_column(
-                    <polars::prelude::Series as polars::prelude::NamedFromOwned<Vec<f64>>>::from_vec("bband_low", bband_low),
-                )
-                .unwrap();
-            new_df
-                .with_column(
-                    <polars::prelude::Series as polars::prelude::NamedFromOwned<Vec<f64>>>::from_vec("macd", macd),
-                )
-                .unwrap();
-            new_df
-                .with_column(
-                    <polars::prelude::Series as polars::prelude::NamedFromOwned<Vec<f64>>>::from_vec("macdsignal", macdsignal),
-                )
-                .unwrap();
-            new_df
-                .with_column(
-                    <polars::prelude::Series as polars::prelude::NamedFromOwned<Vec<f64>>>::from_vec("macdhist", macdhist),
-                )
-                .unwrap();
-            new_df
-                .with_column(
-                    <polars::prelude::Series as polars::prelude::NamedFromOwned<Vec<f64>>>::from_vec("rsi", rsi),
-                )
-                .unwrap();
-            new_df
-                .with_column(
-                    <polars::prelude::Series as polars::prelude::NamedFromOwned<Vec<f64>>>::from_vec("stoch_slowk", stoch_slowk),
-                )
-                .unwrap();
-            new_df
-                .with_column(
-                    <polars::prelude::Series as polars::prelude::NamedFromOwned<Vec<f64>>>::from_vec("stoch_slowd", stoch_slowd),
-                )
-                .unwrap();
-            new_df
-                .with_column(
-                    <polars::prelude::Series as polars::prelude::NamedFromOwned<Vec<f64>>>::from_vec("sma", sma),
-                )
-                .unwrap();
+        for idx in symbol_groups.get_groups().clone().iter() {
+            let values: Vec<Option<f64>> = symbol_groups
+                .df
+                .slice(idx.first() as i64, idx.len())
+                .column(column)?
+                .f64()?
+                .into_iter()
+                .collect();
 
-            concat_df = concat_df.vstack(&new_df).unwrap();
+            zscores.extend(rolling_zscore(&values, window));
         }
 
-        self.frame.replace(concat_df);
+        self.frame
+            .get_mut()
+            .with_column(<polars::prelude::Series as polars::prelude::NamedFrom<
+                Vec<Option<f64>>,
+                _,
+            >>::new(name.as_str(), zscores))?;
+
+        Ok(name)
     }
 
-    // limit to trading hours (not including first 30 mins due to lack of data in that period)
-    pub fn clean(&mut self) {
+    // resolves the indicator warmup region with the chosen strategy (ZeroFill by default),
+    // then limits to trading hours (not including first 30 mins due to lack of data in that period)
+    // daily bars are stamped at midnight eastern so they skip the trading hours filter
+    pub fn clean(&mut self, warmup: Option<WarmupStrategy>) -> anyhow::Result<()> {
+        let warmup = warmup.unwrap_or(WarmupStrategy::ZeroFill);
+
+        let indicators: Vec<polars::prelude::Expr> = stockframe_columns[9..]
+            .iter()
+            .filter(|name| self.frame.borrow().column(name).is_ok())
+            .map(|name| polars::prelude::col(name))
+            .collect();
+
         let lazy = <polars::prelude::DataFrame as polars::prelude::IntoLazy>::lazy(
             self.frame.borrow().clone(),
-        );
+        )
+        .sort(["symbol", "timestamp"], Default::default());
+
+        let lazy = match warmup {
+            WarmupStrategy::DropNullRows => lazy.drop_nulls(Some(indicators)),
+            WarmupStrategy::ForwardFill => lazy.with_columns(
+                indicators
+                    .into_iter()
+                    .map(|indicator| indicator.forward_fill(None).over(["symbol"]).fill_null(polars::prelude::lit(0f64)))
+                    .collect::<Vec<polars::prelude::Expr>>(),
+            ),
+            WarmupStrategy::ZeroFill => lazy.with_columns(
+                indicators
+                    .into_iter()
+                    .map(|indicator| indicator.fill_null(polars::prelude::lit(0f64)))
+                    .collect::<Vec<polars::prelude::Expr>>(),
+            ),
+        };
+
+        if self.timeframe == Timeframe::Day {
+            self.frame.replace(lazy.collect()?);
+            return Ok(());
+        }
 
         let new_df = lazy
             .filter(
@@ -686,9 +1321,9 @@ impl StockFrame {
                             .gt_eq(polars::prelude::lit(14)),
                     ),
             )
-            .collect()
-            .unwrap();
+            .collect()?;
 
         self.frame.replace(new_df);
+        Ok(())
     }
 }
@@ -1,12 +1,18 @@
 extern crate anyhow;
+extern crate rand;
+extern crate rand_distr;
 extern crate serde;
+extern crate serde_json;
 extern crate tch;
 
 use crate::device;
-use crate::replay_buffer::ReplayBuffer;
+use crate::environment::Environment;
+use crate::logger::Logger;
+use crate::replay_buffer::{Batch, ReplayBuffer};
 
 use crate::optimizer::adam::ADAM;
 use crate::optimizer::cmaes::CMAES;
+use crate::optimizer::GradScaler;
 use crate::optimizer::MilkshakeOptimizer;
 
 #[derive(Debug)]
@@ -46,28 +52,266 @@ impl serde::Serialize for MilkshakeLayer {
     }
 }
 
+// applied after every hidden layer, the output layer stays linear
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Activation {
+    Relu,
+    Tanh,
+    Elu,
+    Gelu,
+}
+
+impl Activation {
+    pub fn apply(&self, xs: &tch::Tensor) -> tch::Tensor {
+        match self {
+            Activation::Relu => xs.relu(),
+            Activation::Tanh => xs.tanh(),
+            Activation::Elu => xs.elu(),
+            Activation::Gelu => xs.gelu("none"),
+        }
+    }
+
+    // name used in save files
+    pub fn name(&self) -> &'static str {
+        match self {
+            Activation::Relu => "relu",
+            Activation::Tanh => "tanh",
+            Activation::Elu => "elu",
+            Activation::Gelu => "gelu",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "relu" => Some(Activation::Relu),
+            "tanh" => Some(Activation::Tanh),
+            "elu" => Some(Activation::Elu),
+            "gelu" => Some(Activation::Gelu),
+            _ => None,
+        }
+    }
+}
+
+// picks one of the networks td3 trains, the targets follow them through the polyak update
+// critics are indexed in ensemble order, 0 is the q network the actor learns from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NetworkKind {
+    Actor,
+    Critic(usize),
+}
+
+// l2 norm over every weight and bias of each trained network, one per critic of the ensemble
+#[derive(Clone, Debug, PartialEq)]
+pub struct NetworkNorms {
+    pub actor: f64,
+    pub critics: Vec<f64>,
+}
+
+// tensors without data, like the grad of a variable nothing backpropagated into, count as zero
+fn network_norm(network: &MilkshakeNetwork, tensor: impl Fn(&tch::Tensor) -> tch::Tensor) -> f64 {
+    tch::no_grad(|| {
+        network
+            .layers
+            .iter()
+            .flat_map(|layer| std::iter::once(&layer.layer.ws).chain(layer.layer.bs.as_ref()))
+            .map(tensor)
+            .filter(|tensor| tensor.defined())
+            .map(|tensor| tensor.norm().double_value(&[]).powi(2))
+            .sum::<f64>()
+            .sqrt()
+    })
+}
+
+// regression loss between the critic estimates and the td target
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LossKind {
+    Mse,
+    Huber(f64),
+}
+
+impl LossKind {
+    pub fn apply(&self, xs: &tch::Tensor, target: &tch::Tensor) -> tch::Tensor {
+        match self {
+            LossKind::Mse => xs.mse_loss(target, tch::Reduction::Mean),
+            LossKind::Huber(delta) => xs.smooth_l1_loss(target, tch::Reduction::Mean, *delta),
+        }
+    }
+
+    // per sample loss scaled by its importance sampling weight before the mean, xs and target are [batch, 1]
+    pub fn apply_weighted(&self, xs: &tch::Tensor, target: &tch::Tensor, weights: &tch::Tensor) -> tch::Tensor {
+        let elementwise = match self {
+            LossKind::Mse => xs.mse_loss(target, tch::Reduction::None),
+            LossKind::Huber(delta) => xs.smooth_l1_loss(target, tch::Reduction::None, *delta),
+        };
+
+        (elementwise * weights.view([-1, 1])).mean(tch::Kind::Float)
+    }
+
+    // save files only store the huber delta, none means mse
+    pub fn huber_delta(&self) -> Option<f64> {
+        match self {
+            LossKind::Mse => None,
+            LossKind::Huber(delta) => Some(*delta),
+        }
+    }
+
+    pub fn from_huber_delta(delta: Option<f64>) -> Self {
+        match delta {
+            None => LossKind::Mse,
+            Some(delta) => LossKind::Huber(delta),
+        }
+    }
+}
+
+// exploration noise added to the deterministic action during training,
+// ornstein uhlenbeck noise is correlated across steps and restarts at zero every episode
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NoiseProcess {
+    Gaussian,
+    OrnsteinUhlenbeck { theta: f64, sigma: f64 },
+}
+
+// decays the learning rate from its initial value down to floor * initial over the horizon,
+// the floor is a fraction of the initial rate
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LrSchedule {
+    Constant,
+    Linear(f64),
+    Cosine(f64),
+}
+
+impl LrSchedule {
+    // multiplier on the initial learning rate after step train calls
+    pub fn scale(&self, step: i64, horizon: i64) -> f64 {
+        let progress = (step as f64 / horizon.max(1) as f64).clamp(0f64, 1f64);
+
+        match self {
+            LrSchedule::Constant => 1f64,
+            LrSchedule::Linear(floor) => 1f64 - (1f64 - floor) * progress,
+            LrSchedule::Cosine(floor) => {
+                floor + (1f64 - floor) * 0.5 * (1f64 + (std::f64::consts::PI * progress).cos())
+            }
+        }
+    }
+
+    // name used in save files
+    pub fn name(&self) -> &'static str {
+        match self {
+            LrSchedule::Constant => "constant",
+            LrSchedule::Linear(_) => "linear",
+            LrSchedule::Cosine(_) => "cosine",
+        }
+    }
+
+    pub fn floor(&self) -> f64 {
+        match self {
+            LrSchedule::Constant => 1f64,
+            LrSchedule::Linear(floor) | LrSchedule::Cosine(floor) => *floor,
+        }
+    }
+
+    pub fn from_name(name: &str, floor: f64) -> Option<Self> {
+        match name {
+            "constant" => Some(LrSchedule::Constant),
+            "linear" => Some(LrSchedule::Linear(floor)),
+            "cosine" => Some(LrSchedule::Cosine(floor)),
+            _ => None,
+        }
+    }
+}
+
+// running mean and variance (welford) of the states the agent sees, standardizes them before every forward pass
+#[derive(Clone, Debug, PartialEq)]
+pub struct RunningNormalizer {
+    pub count: f64,
+    pub mean: Vec<f64>,
+    pub m2: Vec<f64>,
+    pub epsilon: f64,
+}
+
+impl RunningNormalizer {
+    pub fn new(dim: usize, epsilon: Option<f64>) -> Self {
+        RunningNormalizer {
+            count: 0f64,
+            mean: vec![0f64; dim],
+            m2: vec![0f64; dim],
+            epsilon: epsilon.unwrap_or(1e-8),
+        }
+    }
+
+    // (count, mean, m2) as they are stored in save files
+    pub fn from_stats(stats: (f64, Vec<f64>, Vec<f64>)) -> Self {
+        let (count, mean, m2) = stats;
+
+        RunningNormalizer {
+            count,
+            mean,
+            m2,
+            epsilon: 1e-8,
+        }
+    }
+
+    pub fn variance(&self) -> Vec<f64> {
+        match self.count > 1f64 {
+            true => self.m2.iter().map(|m2| m2 / self.count).collect(),
+            false => vec![1f64; self.m2.len()],
+        }
+    }
+
+    pub fn update(&mut self, state: &[f64]) {
+        self.count += 1f64;
+
+        for idx in 0..state.len() {
+            let delta = state[idx] - self.mean[idx];
+            self.mean[idx] += delta / self.count;
+            self.m2[idx] += delta * (state[idx] - self.mean[idx]);
+        }
+    }
+
+    pub fn normalize(&self, states: &tch::Tensor) -> tch::Tensor {
+        let mean = tch::Tensor::from_slice(self.mean.as_slice()).totype(states.kind()).to_device(states.device());
+        let std = tch::Tensor::from_slice(
+            self.variance().iter().map(|var| (var + self.epsilon).sqrt()).collect::<Vec<f64>>().as_slice(),
+        )
+        .totype(states.kind())
+        .to_device(states.device());
+
+        (states - mean) / std
+    }
+}
+
 #[derive(Debug)]
 pub struct MilkshakeNetwork {
     pub layers: Vec<MilkshakeLayer>,
+    pub activation: Activation,
+    // dropout probability after every hidden activation, only applied when training
+    pub dropout_p: f64,
 }
 
-impl tch::nn::Module for MilkshakeNetwork {
-    fn forward(&self, xs: &tch::Tensor) -> tch::Tensor {
-        let mut alpha = self
-            .layers
-            .first()
-            .unwrap()
-            .forward(&xs.totype(tch::Kind::Float))
-            .relu();
+impl MilkshakeNetwork {
+    pub fn forward_t(&self, xs: &tch::Tensor, train: bool) -> tch::Tensor {
+        let mut alpha = xs.totype(tch::Kind::Float);
 
-        for layer in &self.layers[1..1] {
-            alpha = layer.forward(&alpha).relu();
+        // every layer but the output layer is a hidden layer
+        for layer in &self.layers[..self.layers.len() - 1] {
+            alpha = self.activation.apply(&layer.forward(&alpha));
+
+            if self.dropout_p > 0f64 {
+                alpha = alpha.dropout(self.dropout_p, train);
+            }
         }
 
         self.layers.last().unwrap().forward(&alpha)
     }
 }
 
+// the plain forward pass is the evaluation pass
+impl tch::nn::Module for MilkshakeNetwork {
+    fn forward(&self, xs: &tch::Tensor) -> tch::Tensor {
+        self.forward_t(xs, false)
+    }
+}
+
 impl serde::Serialize for MilkshakeNetwork {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -176,19 +420,90 @@ impl<'de> serde::Deserialize<'de> for DummyLayer {
     }
 }
 
+// missing activations default to relu, unknown names are an error
+fn parse_activation<E: serde::de::Error>(name: Option<String>) -> Result<Activation, E> {
+    match name {
+        None => Ok(Activation::Relu),
+        Some(name) => Activation::from_name(name.as_str())
+            .ok_or_else(|| E::custom(format!("unknown activation `{}`", name))),
+    }
+}
+
+// gaussian noise is saved without ornstein uhlenbeck parameters
+fn noise_process_from_params(theta: Option<f64>, sigma: Option<f64>) -> NoiseProcess {
+    match (theta, sigma) {
+        (Some(theta), Some(sigma)) => NoiseProcess::OrnsteinUhlenbeck { theta, sigma },
+        _ => NoiseProcess::Gaussian,
+    }
+}
+
+fn parse_lr_schedule<E: serde::de::Error>(name: Option<String>, floor: Option<f64>) -> Result<LrSchedule, E> {
+    match name {
+        None => Ok(LrSchedule::Constant),
+        Some(name) => LrSchedule::from_name(name.as_str(), floor.unwrap_or(1f64))
+            .ok_or_else(|| E::custom(format!("unknown lr schedule `{}`", name))),
+    }
+}
+
+// what the last layer of the actor turns into, discrete heads pick one of n actions like buy, hold or sell
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ActorHead {
+    // tanh scaled to max_action, one value per action dimension
+    Continuous,
+    // softmax over n actions, action_dim is ignored
+    Discrete(i64),
+}
+
+impl ActorHead {
+    // persisted as the number of discrete actions, None for a continuous head
+    pub fn discrete_actions(&self) -> Option<i64> {
+        match self {
+            ActorHead::Continuous => None,
+            ActorHead::Discrete(n) => Some(*n),
+        }
+    }
+
+    pub fn from_discrete_actions(discrete_actions: Option<i64>) -> Self {
+        match discrete_actions {
+            Some(n) => ActorHead::Discrete(n),
+            None => ActorHead::Continuous,
+        }
+    }
+}
+
 pub struct Actor {
     pub vs: std::rc::Rc<std::cell::RefCell<tch::nn::VarStore>>,
     pub actor: MilkshakeNetwork,
     pub max_action: f64,
+    pub head: ActorHead,
 }
 
 impl Actor {
-    pub fn new(state_dim: i64, action_dim: i64, nn_shape: Vec<i64>, max_action: f64) -> Self {
-        let vs = std::rc::Rc::new(std::cell::RefCell::new(tch::nn::VarStore::new(**device)));
+    pub fn new(
+        state_dim: i64,
+        action_dim: i64,
+        nn_shape: Vec<i64>,
+        max_action: f64,
+        activation: Option<Activation>,
+        head: Option<ActorHead>,
+        compute_device: Option<tch::Device>,
+    ) -> Self {
+        let activation = activation.unwrap_or(Activation::Relu);
+        let head = head.unwrap_or(ActorHead::Continuous);
+        let compute_device = compute_device.unwrap_or(**device);
+        let vs = std::rc::Rc::new(std::cell::RefCell::new(tch::nn::VarStore::new(compute_device)));
+
+        let output_dim = match head {
+            ActorHead::Continuous => action_dim,
+            ActorHead::Discrete(n) => {
+                assert!(n > 0, "a discrete actor needs at least one action");
+                n
+            }
+        };
 
         let mut shape = nn_shape.clone();
         shape.insert(0, state_dim);
-        shape.insert(shape.len(), action_dim);
+        shape.insert(shape.len(), output_dim);
 
         let mut layers = Vec::new();
 
@@ -206,17 +521,38 @@ impl Actor {
             });
         }
 
-        let actor = MilkshakeNetwork { layers };
+        let actor = MilkshakeNetwork { layers, activation, dropout_p: 0f64 };
 
         Actor {
             vs,
             actor,
             max_action,
+            head,
         }
     }
 
+    // discrete heads return the probability of every action instead of the action itself
     pub fn forward(&self, xs: &tch::Tensor) -> tch::Tensor {
-        self.max_action * <MilkshakeNetwork as tch::nn::Module>::forward(&self.actor, &xs).tanh()
+        let output = <MilkshakeNetwork as tch::nn::Module>::forward(&self.actor, &xs);
+
+        match self.head {
+            ActorHead::Continuous => self.max_action * output.tanh(),
+            ActorHead::Discrete(_) => output.softmax(-1, output.kind()),
+        }
+    }
+
+    // continuous heads return the deterministic action whatever sample is set to
+    // discrete heads return one int64 action index per state, the most likely one or one drawn from the softmax
+    pub fn select_action(&self, xs: &tch::Tensor, sample: bool) -> tch::Tensor {
+        tch::no_grad(|| {
+            let output = self.forward(xs);
+
+            match (self.head, sample) {
+                (ActorHead::Continuous, _) => output,
+                (ActorHead::Discrete(_), false) => output.argmax(-1, false),
+                (ActorHead::Discrete(_), true) => output.multinomial(1, true).squeeze_dim(-1),
+            }
+        })
     }
 }
 
@@ -228,23 +564,39 @@ impl serde::Serialize for Actor {
         let mut cursor = std::io::Cursor::new(Vec::<u8>::new());
         self.vs.borrow().save_to_stream(&mut cursor).expect("Failed to save actor varstore to byte buffer");
 
-        let mut struct_serializer = serializer.serialize_struct("Actor", 3)?;
+        let mut struct_serializer = serializer.serialize_struct("Actor", 5)?;
 
         <<S as serde::Serializer>::SerializeStruct as serde::ser::SerializeStruct>::serialize_field(&mut struct_serializer, "actor_varstore", cursor.into_inner().as_slice())?;
         <<S as serde::Serializer>::SerializeStruct as serde::ser::SerializeStruct>::serialize_field(&mut struct_serializer, "actor_network", &self.actor)?;
         <<S as serde::Serializer>::SerializeStruct as serde::ser::SerializeStruct>::serialize_field(&mut struct_serializer, "max_action", &self.max_action)?;
+        <<S as serde::Serializer>::SerializeStruct as serde::ser::SerializeStruct>::serialize_field(&mut struct_serializer, "activation", &Some(self.actor.activation.name()))?;
+        <<S as serde::Serializer>::SerializeStruct as serde::ser::SerializeStruct>::serialize_field(&mut struct_serializer, "discrete_actions", &self.head.discrete_actions())?;
 
         <<S as serde::Serializer>::SerializeStruct as serde::ser::SerializeStruct>::end(struct_serializer)
     }
 }
 
+// deserializes an actor with its varstore on the given device, plain deserialize uses the global device
+pub struct ActorSeed(pub tch::Device);
+
 impl<'de> serde::Deserialize<'de> for Actor {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: serde::Deserializer<'de>,
     {
-        enum ActorField { actor_varstore, actor_network, max_action }
-        const ACTOR_FIELDS: &[&str] = &["actor_varstore", "actor_network", "max_action"];
+        serde::de::DeserializeSeed::deserialize(ActorSeed(**device), deserializer)
+    }
+}
+
+impl<'de> serde::de::DeserializeSeed<'de> for ActorSeed {
+    type Value = Actor;
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Actor, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        enum ActorField { actor_varstore, actor_network, max_action, activation, discrete_actions }
+        const ACTOR_FIELDS: &[&str] = &["actor_varstore", "actor_network", "max_action", "activation", "discrete_actions"];
 
         impl<'de> serde::Deserialize<'de> for ActorField {
             fn deserialize<D>(deserializer: D) -> Result<ActorField, D::Error>
@@ -268,6 +620,8 @@ impl<'de> serde::Deserialize<'de> for Actor {
                             "actor_varstore" => Ok(ActorField::actor_varstore),
                             "actor_network" => Ok(ActorField::actor_network),
                             "max_action" => Ok(ActorField::max_action),
+                            "activation" => Ok(ActorField::activation),
+                            "discrete_actions" => Ok(ActorField::discrete_actions),
                             _ => Err(serde::de::Error::unknown_field(value, ACTOR_FIELDS)),
                         }
                     }
@@ -278,7 +632,9 @@ impl<'de> serde::Deserialize<'de> for Actor {
             }
         }
 
-        struct ActorVisitor;
+        struct ActorVisitor {
+            device: tch::Device,
+        }
 
         impl<'de> serde::de::Visitor<'de> for ActorVisitor {
             type Value = Actor;
@@ -298,7 +654,14 @@ impl<'de> serde::Deserialize<'de> for Actor {
                 let max_action = seq.next_element()?
                     .ok_or_else(|| serde::de::Error::invalid_length(2, &self))?;
 
-                let vs = std::rc::Rc::new(std::cell::RefCell::new(tch::nn::VarStore::new(**device)));
+                // files saved before activations were configurable are relu networks
+                let activation: Option<String> = seq.next_element::<Option<String>>()?.flatten();
+                let activation = parse_activation::<V::Error>(activation)?;
+
+                // files saved before discrete heads existed are continuous actors
+                let head = ActorHead::from_discrete_actions(seq.next_element::<Option<i64>>()?.flatten());
+
+                let vs = std::rc::Rc::new(std::cell::RefCell::new(tch::nn::VarStore::new(self.device)));
 
                 let mut layers = Vec::new();
                 for layer in actor_network {
@@ -309,9 +672,9 @@ impl<'de> serde::Deserialize<'de> for Actor {
                 let cursor = std::io::Cursor::new(actor_varstore);
                 vs.borrow_mut().load_from_stream(cursor).expect("Failed to load actor varstore from save file");
 
-                let actor = MilkshakeNetwork { layers };
+                let actor = MilkshakeNetwork { layers, activation, dropout_p: 0f64 };
 
-                Ok(Actor { vs, actor, max_action })
+                Ok(Actor { vs, actor, max_action, head })
             }
 
             fn visit_map<V>(self, mut map: V) -> Result<Actor, V::Error>
@@ -321,6 +684,8 @@ impl<'de> serde::Deserialize<'de> for Actor {
                 let mut max_action = None;
                 let mut actor_network = None;
                 let mut actor_varstore = None;
+                let mut activation: Option<String> = None;
+                let mut discrete_actions: Option<Option<i64>> = None;
 
                 while let Some(key) = map.next_key()? {
                     match key {
@@ -347,14 +712,32 @@ impl<'de> serde::Deserialize<'de> for Actor {
 
                             max_action = Some(map.next_value()?);
                         }
+
+                        ActorField::activation => {
+                            if activation.is_some() {
+                                return Err(serde::de::Error::duplicate_field("activation"));
+                            }
+
+                            activation = Some(map.next_value()?);
+                        }
+
+                        ActorField::discrete_actions => {
+                            if discrete_actions.is_some() {
+                                return Err(serde::de::Error::duplicate_field("discrete_actions"));
+                            }
+
+                            discrete_actions = Some(map.next_value()?);
+                        }
                     }
                 }
 
                 let actor_varstore: Vec<u8> = actor_varstore.ok_or_else(|| serde::de::Error::missing_field("actor_varstore"))?;
                 let actor_network: Vec<DummyLayer> = actor_network.ok_or_else(|| serde::de::Error::missing_field("actor_network"))?;
                 let max_action = max_action.ok_or_else(|| serde::de::Error::missing_field("max_action"))?;
+                let activation = parse_activation::<V::Error>(activation)?;
+                let head = ActorHead::from_discrete_actions(discrete_actions.flatten());
 
-                let vs = std::rc::Rc::new(std::cell::RefCell::new(tch::nn::VarStore::new(**device)));
+                let vs = std::rc::Rc::new(std::cell::RefCell::new(tch::nn::VarStore::new(self.device)));
 
                 let mut layers = Vec::new();
                 for layer in actor_network {
@@ -365,83 +748,142 @@ impl<'de> serde::Deserialize<'de> for Actor {
                 let cursor = std::io::Cursor::new(actor_varstore);
                 vs.borrow_mut().load_from_stream(cursor).expect("Failed to load actor varstore from save file");
 
-                let actor = MilkshakeNetwork { layers };
+                let actor = MilkshakeNetwork { layers, activation, dropout_p: 0f64 };
 
-                Ok(Actor { vs, actor, max_action })
+                Ok(Actor { vs, actor, max_action, head })
             }
         }
 
-        deserializer.deserialize_struct("Actor", ACTOR_FIELDS, ActorVisitor)
+        deserializer.deserialize_struct("Actor", ACTOR_FIELDS, ActorVisitor { device: self.0 })
     }
 }
 
 pub struct Critic {
     pub vs: std::rc::Rc<std::cell::RefCell<tch::nn::VarStore>>,
-    pub q1: MilkshakeNetwork,
-    pub q2: MilkshakeNetwork,
+    // ensemble of q networks sharing one varstore, the actor learns from the first
+    pub qs: Vec<MilkshakeNetwork>,
 }
 
-impl Critic {
-    pub fn new(state_dim: i64, action_dim: i64, q1_shape: Vec<i64>, q2_shape: Vec<i64>) -> Self {
-        let vs = std::rc::Rc::new(std::cell::RefCell::new(tch::nn::VarStore::new(**device)));
-
-        let mut q1_shape = q1_shape.clone();
-        q1_shape.insert(0, state_dim + action_dim);
-        q1_shape.insert(q1_shape.len(), 1);
+// rebuilds the layers of a saved q network on vs, in the order they were created
+fn load_q_network(
+    vs: &std::rc::Rc<std::cell::RefCell<tch::nn::VarStore>>,
+    network: Vec<DummyLayer>,
+    activation: Activation,
+    dropout_p: f64,
+) -> MilkshakeNetwork {
+    let mut layers = Vec::new();
+    for layer in network {
+        let child = tch::nn::linear(vs.borrow().root(), layer.input_dim, layer.output_dim, Default::default());
+        layers.push(MilkshakeLayer {layer: child, input: layer.input_dim, output: layer.output_dim});
+    }
 
-        let mut q1_layers = Vec::new();
+    MilkshakeNetwork { layers, activation, dropout_p }
+}
 
-        for x in 1..q1_shape.len() {
-            q1_layers.push(MilkshakeLayer {
-                layer: tch::nn::linear(
-                    vs.borrow().root(),
-                    q1_shape[x - 1],
-                    q1_shape[x],
-                    Default::default(),
-                ),
+impl Critic {
+    // one q network per hidden layer shape, at least the twin pair
+    pub fn new(
+        state_dim: i64,
+        action_dim: i64,
+        q_shapes: Vec<Vec<i64>>,
+        activation: Option<Activation>,
+        dropout_p: Option<f64>,
+        compute_device: Option<tch::Device>,
+    ) -> Self {
+        assert!(q_shapes.len() >= 2, "a critic needs at least two q networks, got {}", q_shapes.len());
+
+        let activation = activation.unwrap_or(Activation::Relu);
+        let dropout_p = dropout_p.unwrap_or(0f64);
+        let compute_device = compute_device.unwrap_or(**device);
+        let vs = std::rc::Rc::new(std::cell::RefCell::new(tch::nn::VarStore::new(compute_device)));
+
+        let mut qs = Vec::new();
+
+        for mut q_shape in q_shapes {
+            q_shape.insert(0, state_dim + action_dim);
+            q_shape.insert(q_shape.len(), 1);
+
+            let mut q_layers = Vec::new();
+
+            for x in 1..q_shape.len() {
+                q_layers.push(MilkshakeLayer {
+                    layer: tch::nn::linear(
+                        vs.borrow().root(),
+                        q_shape[x - 1],
+                        q_shape[x],
+                        Default::default(),
+                    ),
+
+                    input: q_shape[x - 1],
+                    output: q_shape[x],
+                });
+            }
 
-                input: q1_shape[x - 1],
-                output: q1_shape[x],
-            });
+            qs.push(MilkshakeNetwork { layers: q_layers, activation, dropout_p });
         }
 
-        let mut q2_shape = q2_shape.clone();
-        q2_shape.insert(0, state_dim + action_dim);
-        q2_shape.insert(q2_shape.len(), 1);
-
-        let mut q2_layers = Vec::new();
+        Critic { vs, qs }
+    }
 
-        for x in 1..q2_shape.len() {
-            q2_layers.push(MilkshakeLayer {
-                layer: tch::nn::linear(
-                    vs.borrow().root(),
-                    q2_shape[x - 1],
-                    q2_shape[x],
-                    Default::default(),
-                ),
+    // evaluation pass, used for targets and the actor loss
+    pub fn forward(&self, state: &tch::Tensor, action: &tch::Tensor) -> tch::Tensor {
+        self.forward_t(state, action, false)
+    }
 
-                input: q2_shape[x - 1],
-                output: q2_shape[x],
-            });
-        }
+    // one column of q estimates per network of the ensemble, dropout is only active when train is set
+    pub fn forward_t(&self, state: &tch::Tensor, action: &tch::Tensor, train: bool) -> tch::Tensor {
+        let xs = tch::Tensor::cat(&[state, action], 1);
+        let qs: Vec<tch::Tensor> = self.qs.iter().map(|q| q.forward_t(&xs, train)).collect();
 
-        let q1 = MilkshakeNetwork { layers: q1_layers };
-        let q2 = MilkshakeNetwork { layers: q2_layers };
+        tch::Tensor::cat(&qs, 1)
+    }
 
-        Critic { vs, q1, q2 }
+    pub fn Q1(&self, xs: &tch::Tensor) -> tch::Tensor {
+        <MilkshakeNetwork as tch::nn::Module>::forward(&self.qs[0], &xs)
     }
 
-    pub fn forward(&self, state: &tch::Tensor, action: &tch::Tensor) -> (tch::Tensor, tch::Tensor) {
-        let xs = tch::Tensor::cat(&[state, action], 1);
+    // redq style minimum over a random subset of the ensemble's columns, subset equal to the ensemble size is the plain minimum
+    pub fn min_over_subset(&self, q: &tch::Tensor, subset: usize) -> tch::Tensor {
+        assert!(
+            subset > 0 && subset <= self.qs.len(),
+            "subset must pick between 1 and {} q networks, got {}",
+            self.qs.len(),
+            subset
+        );
 
-        let q1 = <MilkshakeNetwork as tch::nn::Module>::forward(&self.q1, &xs);
-        let q2 = <MilkshakeNetwork as tch::nn::Module>::forward(&self.q2, &xs);
+        let columns = tch::Tensor::randperm(self.qs.len() as i64, (tch::Kind::Int64, q.device()))
+            .narrow(0, 0, subset as i64);
 
-        (q1, q2)
+        q.index_select(1, &columns).amin(&[1i64][..], true)
     }
 
-    pub fn Q1(&self, xs: &tch::Tensor) -> tch::Tensor {
-        <MilkshakeNetwork as tch::nn::Module>::forward(&self.q1, &xs)
+    // scalar sum of every q network's mean squared bellman error, only the first one's when twin is off
+    // weights are per sample importance sampling weights from a prioritized buffer, None is the plain mean
+    pub fn loss(
+        &self,
+        state: &tch::Tensor,
+        action: &tch::Tensor,
+        target_q: &tch::Tensor,
+        loss_kind: LossKind,
+        twin: bool,
+        weights: Option<&tch::Tensor>,
+    ) -> tch::Tensor {
+        let q = self.forward_t(state, action, true);
+
+        let apply = |current_q: &tch::Tensor| match weights {
+            Some(weights) => loss_kind.apply_weighted(current_q, target_q, weights),
+            None => loss_kind.apply(current_q, target_q),
+        };
+
+        let trained = match twin {
+            true => self.qs.len() as i64,
+            false => 1,
+        };
+
+        (0..trained)
+            .map(|idx| apply(&q.narrow(1, idx, 1)))
+            .reduce(|sum, loss| sum + loss)
+            .unwrap()
     }
 }
 
@@ -453,23 +895,40 @@ impl serde::Serialize for Critic {
         let mut cursor = std::io::Cursor::new(Vec::<u8>::new());
         self.vs.borrow().save_to_stream(&mut cursor).expect("Failed to save critic varstore to byte buffer");
 
-        let mut struct_serializer = serializer.serialize_struct("Critic", 3)?;
+        let mut struct_serializer = serializer.serialize_struct("Critic", 6)?;
 
         <<S as serde::Serializer>::SerializeStruct as serde::ser::SerializeStruct>::serialize_field(&mut struct_serializer, "critic_varstore", cursor.into_inner().as_slice())?;
-        <<S as serde::Serializer>::SerializeStruct as serde::ser::SerializeStruct>::serialize_field(&mut struct_serializer, "q1_network", &self.q1)?;
-        <<S as serde::Serializer>::SerializeStruct as serde::ser::SerializeStruct>::serialize_field(&mut struct_serializer, "q2_network", &self.q2)?;
+        <<S as serde::Serializer>::SerializeStruct as serde::ser::SerializeStruct>::serialize_field(&mut struct_serializer, "q1_network", &self.qs[0])?;
+        <<S as serde::Serializer>::SerializeStruct as serde::ser::SerializeStruct>::serialize_field(&mut struct_serializer, "q2_network", &self.qs[1])?;
+        <<S as serde::Serializer>::SerializeStruct as serde::ser::SerializeStruct>::serialize_field(&mut struct_serializer, "activation", &Some(self.qs[0].activation.name()))?;
+        <<S as serde::Serializer>::SerializeStruct as serde::ser::SerializeStruct>::serialize_field(&mut struct_serializer, "dropout_p", &Some(self.qs[0].dropout_p))?;
+        <<S as serde::Serializer>::SerializeStruct as serde::ser::SerializeStruct>::serialize_field(&mut struct_serializer, "extra_q_networks", &Some(&self.qs[2..]))?;
 
         <<S as serde::Serializer>::SerializeStruct as serde::ser::SerializeStruct>::end(struct_serializer)
     }
 }
 
+// deserializes a critic with its varstore on the given device, plain deserialize uses the global device
+pub struct CriticSeed(pub tch::Device);
+
 impl<'de> serde::Deserialize<'de> for Critic {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: serde::Deserializer<'de>,
     {
-        enum CriticField { critic_varstore, q1_network, q2_network }
-        const CRITIC_FIELDS: &[&str] = &["critic_varstore", "q1_network", "q2_network"];
+        serde::de::DeserializeSeed::deserialize(CriticSeed(**device), deserializer)
+    }
+}
+
+impl<'de> serde::de::DeserializeSeed<'de> for CriticSeed {
+    type Value = Critic;
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Critic, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        enum CriticField { critic_varstore, q1_network, q2_network, activation, dropout_p, extra_q_networks }
+        const CRITIC_FIELDS: &[&str] = &["critic_varstore", "q1_network", "q2_network", "activation", "dropout_p", "extra_q_networks"];
 
         impl<'de> serde::Deserialize<'de> for CriticField {
             fn deserialize<D>(deserializer: D) -> Result<CriticField, D::Error>
@@ -493,6 +952,9 @@ impl<'de> serde::Deserialize<'de> for Critic {
                             "critic_varstore" => Ok(CriticField::critic_varstore),
                             "q1_network" => Ok(CriticField::q1_network),
                             "q2_network" => Ok(CriticField::q2_network),
+                            "activation" => Ok(CriticField::activation),
+                            "dropout_p" => Ok(CriticField::dropout_p),
+                            "extra_q_networks" => Ok(CriticField::extra_q_networks),
                             _ => Err(serde::de::Error::unknown_field(value, CRITIC_FIELDS)),
                         }
                     }
@@ -502,7 +964,9 @@ impl<'de> serde::Deserialize<'de> for Critic {
             }
         }
 
-        struct CriticVisitor;
+        struct CriticVisitor {
+            device: tch::Device,
+        }
 
         impl<'de> serde::de::Visitor<'de> for CriticVisitor {
             type Value = Critic;
@@ -522,27 +986,27 @@ impl<'de> serde::Deserialize<'de> for Critic {
                 let q2_network: Vec<DummyLayer> = seq.next_element()?
                     .ok_or_else(|| serde::de::Error::invalid_length(2, &self))?;
 
-                let vs = std::rc::Rc::new(std::cell::RefCell::new(tch::nn::VarStore::new(**device)));
+                // files saved before activations were configurable are relu networks
+                let activation: Option<String> = seq.next_element::<Option<String>>()?.flatten();
+                let activation = parse_activation::<V::Error>(activation)?;
+                let dropout_p: f64 = seq.next_element::<Option<f64>>()?.flatten().unwrap_or(0f64);
 
-                let mut q1_layers = Vec::new();
-                for layer in q1_network {
-                    let child = tch::nn::linear(vs.borrow().root(), layer.input_dim, layer.output_dim, Default::default());
-                    q1_layers.push(MilkshakeLayer {layer: child, input: layer.input_dim, output: layer.output_dim});
-                }
+                // files saved before ensembles are twin critics
+                let extra_q_networks: Vec<Vec<DummyLayer>> =
+                    seq.next_element::<Option<Vec<Vec<DummyLayer>>>>()?.flatten().unwrap_or_default();
 
-                let mut q2_layers = Vec::new();
-                for layer in q2_network {
-                    let child = tch::nn::linear(vs.borrow().root(), layer.input_dim, layer.output_dim, Default::default());
-                    q2_layers.push(MilkshakeLayer {layer: child, input: layer.input_dim, output: layer.output_dim});
-                }
+                let vs = std::rc::Rc::new(std::cell::RefCell::new(tch::nn::VarStore::new(self.device)));
+
+                let qs = [q1_network, q2_network]
+                    .into_iter()
+                    .chain(extra_q_networks)
+                    .map(|network| load_q_network(&vs, network, activation, dropout_p))
+                    .collect();
 
                 let cursor = std::io::Cursor::new(critic_varstore);
                 vs.borrow_mut().load_from_stream(cursor).expect("Failed to load critic varstore from save file");
 
-                let q1 = MilkshakeNetwork { layers: q1_layers };
-                let q2 = MilkshakeNetwork { layers: q2_layers };
-
-                Ok(Critic { vs, q1, q2 })
+                Ok(Critic { vs, qs })
             }
 
             fn visit_map<V>(self, mut map: V) -> Result<Critic, V::Error>
@@ -552,6 +1016,9 @@ impl<'de> serde::Deserialize<'de> for Critic {
                 let mut critic_varstore = None;
                 let mut q1_network = None;
                 let mut q2_network = None;
+                let mut activation: Option<String> = None;
+                let mut dropout_p: Option<f64> = None;
+                let mut extra_q_networks: Option<Option<Vec<Vec<DummyLayer>>>> = None;
 
                 while let Some(key) = map.next_key()? {
                     match key {
@@ -578,292 +1045,1056 @@ impl<'de> serde::Deserialize<'de> for Critic {
 
                             q2_network = Some(map.next_value()?);
                         }
+
+                        CriticField::activation => {
+                            if activation.is_some() {
+                                return Err(serde::de::Error::duplicate_field("activation"));
+                            }
+
+                            activation = Some(map.next_value()?);
+                        }
+
+                        CriticField::dropout_p => {
+                            if dropout_p.is_some() {
+                                return Err(serde::de::Error::duplicate_field("dropout_p"));
+                            }
+
+                            dropout_p = Some(map.next_value()?);
+                        }
+
+                        CriticField::extra_q_networks => {
+                            if extra_q_networks.is_some() {
+                                return Err(serde::de::Error::duplicate_field("extra_q_networks"));
+                            }
+
+                            extra_q_networks = Some(map.next_value()?);
+                        }
                     }
                 }
 
                 let critic_varstore: Vec<u8> = critic_varstore.ok_or_else(|| serde::de::Error::missing_field("critic_varstore"))?;
                 let q1_network: Vec<DummyLayer> = q1_network.ok_or_else(|| serde::de::Error::missing_field("q1_network"))?;
                 let q2_network: Vec<DummyLayer> = q2_network.ok_or_else(|| serde::de::Error::missing_field("q2_network"))?;
+                let activation = parse_activation::<V::Error>(activation)?;
+                let dropout_p: f64 = dropout_p.unwrap_or(0f64);
+                let extra_q_networks: Vec<Vec<DummyLayer>> = extra_q_networks.flatten().unwrap_or_default();
 
-                let vs = std::rc::Rc::new(std::cell::RefCell::new(tch::nn::VarStore::new(**device)));
-
-                let mut q1_layers = Vec::new();
-                for layer in q1_network {
-                    let child = tch::nn::linear(vs.borrow().root(), layer.input_dim, layer.output_dim, Default::default());
-                    q1_layers.push(MilkshakeLayer {layer: child, input: layer.input_dim, output: layer.output_dim});
-                }
+                let vs = std::rc::Rc::new(std::cell::RefCell::new(tch::nn::VarStore::new(self.device)));
 
-                let mut q2_layers = Vec::new();
-                for layer in q2_network {
-                    let child = tch::nn::linear(vs.borrow().root(), layer.input_dim, layer.output_dim, Default::default());
-                    q2_layers.push(MilkshakeLayer {layer: child, input: layer.input_dim, output: layer.output_dim});
-                }
+                let qs = [q1_network, q2_network]
+                    .into_iter()
+                    .chain(extra_q_networks)
+                    .map(|network| load_q_network(&vs, network, activation, dropout_p))
+                    .collect();
 
                 let cursor = std::io::Cursor::new(critic_varstore);
                 vs.borrow_mut().load_from_stream(cursor).expect("Failed to load critic varstore from save file");
 
-                let q1 = MilkshakeNetwork { layers: q1_layers };
-                let q2 = MilkshakeNetwork { layers: q2_layers };
-
-                Ok(Critic { vs, q1, q2 })
+                Ok(Critic { vs, qs })
             }
         }
 
-        deserializer.deserialize_struct("Critic", CRITIC_FIELDS, CriticVisitor)
+        deserializer.deserialize_struct("Critic", CRITIC_FIELDS, CriticVisitor { device: self.0 })
     }
 }
 
-pub struct TD3 {
-    actor: Actor,
-    actor_target: Actor,
-    critic: Critic,
-    critic_target: Critic,
+// polyak update of every trainable variable (weights and biases), target = tau * source + (1 - tau) * target
+pub fn soft_update(source: &tch::nn::VarStore, target: &mut tch::nn::VarStore, tau: f64) {
+    tch::no_grad(|| {
+        for (param, target_param) in source
+            .trainable_variables()
+            .iter()
+            .zip(target.trainable_variables().iter_mut())
+        {
+            target_param.copy_(&(tau * param.copy() + (1f64 - tau) * target_param.copy()));
+        }
+    })
+}
 
-    actor_opt: Box<dyn MilkshakeOptimizer>,
-    critic_opt: Box<dyn MilkshakeOptimizer>,
+// copies of the trainable variables of one varstore, in trainable_variables order
+pub fn snapshot_vs(vs: &tch::nn::VarStore) -> Vec<tch::Tensor> {
+    tch::no_grad(|| vs.trainable_variables().iter().map(|var| var.copy()).collect())
+}
 
-    pub action_dim: i64,
-    pub state_dim: i64,
-    pub max_action: f64,
-    pub tau: f64,
-    pub discount: f64,
-    pub policy_noise: f64,
-    pub noise_clip: f64,
-    pub policy_freq: i64,
-    pub total_it: i64,
+pub fn restore_vs(vs: &mut tch::nn::VarStore, snapshot: &[tch::Tensor]) {
+    tch::no_grad(|| {
+        for (var, saved) in vs.trainable_variables().iter_mut().zip(snapshot.iter()) {
+            var.copy_(saved);
+        }
+    })
 }
 
-impl TD3 {
-    pub fn new(
-        state_dim: i64,
-        action_dim: i64,
-        max_action: f64,
-        actor_opt: &str,
-        critic_opt: &str,
-        actor_shape: Option<Vec<i64>>,
-        q1_shape: Option<Vec<i64>>,
-        q2_shape: Option<Vec<i64>>,
-        tau: Option<f64>,
-        discount: Option<f64>,
-        policy_noise: Option<f64>,
-        noise_clip: Option<f64>,
-        policy_freq: Option<i64>,
-    ) -> anyhow::Result<Self> {
-        let actor_shape = actor_shape.unwrap_or(vec![64, 64]);
-        let q1_shape = q1_shape.unwrap_or(vec![64, 64]);
-        let q2_shape = q2_shape.unwrap_or(vec![64, 64]);
-
-        let tau = tau.unwrap_or(0.005);
-        let discount = discount.unwrap_or(0.99);
-        let policy_noise = policy_noise.unwrap_or(0.2);
-        let noise_clip = noise_clip.unwrap_or(0.5);
-        let policy_freq = policy_freq.unwrap_or(2);
-
-        let actor = Actor::new(state_dim, action_dim, actor_shape.clone(), max_action);
-        let actor_target = Actor::new(state_dim, action_dim, actor_shape.clone(), max_action);
-
-        let critic = Critic::new(state_dim, action_dim, q1_shape.clone(), q2_shape.clone());
-        let critic_target = Critic::new(state_dim, action_dim, q1_shape.clone(), q2_shape.clone());
-
-        let actor_opt: anyhow::Result<Box<dyn MilkshakeOptimizer>> = match actor_opt {
-            "ADAM" => Ok(Box::new(ADAM::new(0.0003f64, actor.vs.clone()))),
-            "CMAES" => Ok(Box::new(CMAES::new(actor.vs.clone(), None, None))),
-            &_ => {
-                anyhow::bail!("Invalid Actor Optimizer Chosen")
-            }
-        };
+// everything a train step changes, restore_on_non_finite rolls back to it as a whole so the adam moments
+// always belong to the weights they are stepping
+pub struct TrainSnapshot {
+    pub actor: Vec<tch::Tensor>,
+    pub actor_target: Vec<tch::Tensor>,
+    pub critic: Vec<tch::Tensor>,
+    pub critic_target: Vec<tch::Tensor>,
+    pub actor_opt_state: Option<Vec<u8>>,
+    pub critic_opt_state: Option<Vec<u8>>,
+}
 
-        let critic_opt: anyhow::Result<Box<dyn MilkshakeOptimizer>> = match critic_opt {
-            "ADAM" => Ok(Box::new(ADAM::new(0.0003f64, critic.vs.clone()))),
-            "CMAES" => Ok(Box::new(CMAES::new(critic.vs.clone(), None, None))),
-            &_ => {
-                anyhow::bail!("Invalid Critic Optimizer Chosen")
+// one sampled minibatch of a train step, moved to the device and with its targets computed
+struct Minibatch {
+    state: tch::Tensor,
+    action: tch::Tensor,
+    target_q: tch::Tensor,
+    weights: Option<tch::Tensor>,
+    td_errors: Option<Vec<f64>>,
+}
+
+// one optimizer step on the mean of the minibatch losses, None when that mean is not finite
+// optimizers with grads backprop every minibatch on its own so only one graph is alive at a time
+fn optimize(
+    opt: &mut dyn MilkshakeOptimizer,
+    vs: &crate::optimizer::RefVs,
+    minibatches: usize,
+    loss: impl Fn(usize) -> tch::Tensor,
+) -> Option<f64> {
+    let grads = opt.grads();
+    let accumulate = grads && minibatches > 1;
+
+    let mut train_closure = || {
+        let solutions = opt.ask();
+        let mut losses = vec![];
+        let mut values = vec![];
+
+        for solution in &solutions {
+            if !std::rc::Rc::ptr_eq(solution, vs) {
+                vs.borrow_mut()
+                    .copy(&solution.borrow())
+                    .expect("Failed to copy test solution to network");
             }
-        };
 
-        let actor_opt = actor_opt?;
-        let critic_opt = critic_opt?;
+            match accumulate {
+                true => {
+                    opt.zero_grad();
 
-        Ok(TD3 {
-            actor,
-            actor_target,
-            critic,
-            critic_target,
-            actor_opt,
-            critic_opt,
-            action_dim,
-            state_dim,
-            max_action,
-            tau,
-            discount,
-            policy_noise,
-            noise_clip,
-            policy_freq,
-            total_it: 0,
-        })
-    }
+                    let mut value = 0f64;
+                    for idx in 0..minibatches {
+                        let minibatch_loss = loss(idx) / minibatches as f64;
+                        value += minibatch_loss.double_value(&[]);
+                        opt.backward(&minibatch_loss);
+                    }
 
-    pub fn select_action(&self, state: Vec<f64>) -> Vec<f64> {
-        let state = tch::Tensor::from_slice(&state).to_device(**device);
-        let tensor = self.actor.forward(&state).to_device(tch::Device::Cpu);
+                    values.push(value);
+                }
+                false => {
+                    let solution_loss = match minibatches {
+                        1 => loss(0),
+                        _ => (0..minibatches).map(&loss).reduce(|sum, minibatch_loss| sum + minibatch_loss).unwrap()
+                            / minibatches as f64,
+                    };
+
+                    values.push(solution_loss.double_value(&[]));
+                    losses.push(solution_loss);
+                }
+            }
+        }
+
+        // a non-finite loss would spread nan through every weight on the backward pass
+        let mean_loss = values.iter().sum::<f64>() / values.len() as f64;
+        let finite = mean_loss.is_finite();
+        if finite {
+            match accumulate {
+                true => opt.step(),
+                false => opt.tell(solutions, losses),
+            }
+        }
+
+        let result = opt.result();
+        if !std::rc::Rc::ptr_eq(&result, vs) {
+            vs.borrow_mut()
+                .copy(&result.borrow())
+                .expect("Failed to copy result to network from optimizer");
+        }
+
+        finite.then_some(mean_loss)
+    };
+
+    match grads {
+        true => train_closure(),
+        false => tch::no_grad(train_closure),
+    }
+}
+
+// every TD3 setting by name, anything left unset takes the default in build
+// state_dim, action_dim and max_action have no default and must be set
+#[derive(Clone, Debug, Default)]
+pub struct TD3Builder {
+    pub state_dim: Option<i64>,
+    pub action_dim: Option<i64>,
+    pub max_action: Option<f64>,
+    pub actor_opt: Option<String>,
+    pub critic_opt: Option<String>,
+    pub actor_shape: Option<Vec<i64>>,
+    pub q1_shape: Option<Vec<i64>>,
+    pub q2_shape: Option<Vec<i64>>,
+    pub tau: Option<f64>,
+    pub discount: Option<f64>,
+    pub policy_noise: Option<f64>,
+    pub noise_clip: Option<f64>,
+    pub policy_freq: Option<i64>,
+    pub actor_lr: Option<f64>,
+    pub critic_lr: Option<f64>,
+    pub max_grad_norm: Option<f64>,
+    pub n_step: Option<i64>,
+    pub critic_loss: Option<LossKind>,
+    pub lr_schedule: Option<LrSchedule>,
+    pub lr_horizon: Option<i64>,
+    pub log_path: Option<String>,
+    pub noise_process: Option<NoiseProcess>,
+    pub use_twin_critic: Option<bool>,
+    pub target_policy_smoothing: Option<bool>,
+    pub normalize_observations: Option<bool>,
+    pub target_update_freq: Option<i64>,
+    pub restore_on_non_finite: Option<bool>,
+    pub snapshot_freq: Option<i64>,
+    pub amp: Option<bool>,
+    pub num_critics: Option<usize>,
+    pub compute_device: Option<tch::Device>,
+}
+
+impl TD3Builder {
+    pub fn new() -> Self {
+        TD3Builder::default()
+    }
+
+    pub fn state_dim(mut self, state_dim: i64) -> Self {
+        self.state_dim = Some(state_dim);
+        self
+    }
+
+    pub fn action_dim(mut self, action_dim: i64) -> Self {
+        self.action_dim = Some(action_dim);
+        self
+    }
+
+    pub fn max_action(mut self, max_action: f64) -> Self {
+        self.max_action = Some(max_action);
+        self
+    }
+
+    // "ADAM" or "CMAES", ADAM by default
+    pub fn actor_opt(mut self, actor_opt: &str) -> Self {
+        self.actor_opt = Some(String::from(actor_opt));
+        self
+    }
+
+    pub fn critic_opt(mut self, critic_opt: &str) -> Self {
+        self.critic_opt = Some(String::from(critic_opt));
+        self
+    }
+
+    pub fn actor_shape(mut self, actor_shape: Vec<i64>) -> Self {
+        self.actor_shape = Some(actor_shape);
+        self
+    }
+
+    pub fn critic_shapes(mut self, q1_shape: Vec<i64>, q2_shape: Vec<i64>) -> Self {
+        self.q1_shape = Some(q1_shape);
+        self.q2_shape = Some(q2_shape);
+        self
+    }
+
+    pub fn tau(mut self, tau: f64) -> Self {
+        self.tau = Some(tau);
+        self
+    }
+
+    pub fn discount(mut self, discount: f64) -> Self {
+        self.discount = Some(discount);
+        self
+    }
+
+    pub fn policy_noise(mut self, policy_noise: f64) -> Self {
+        self.policy_noise = Some(policy_noise);
+        self
+    }
+
+    pub fn noise_clip(mut self, noise_clip: f64) -> Self {
+        self.noise_clip = Some(noise_clip);
+        self
+    }
+
+    pub fn policy_freq(mut self, policy_freq: i64) -> Self {
+        self.policy_freq = Some(policy_freq);
+        self
+    }
+
+    // same learning rate for the actor and the critic
+    pub fn lr(mut self, lr: f64) -> Self {
+        self.actor_lr = Some(lr);
+        self.critic_lr = Some(lr);
+        self
+    }
+
+    pub fn actor_lr(mut self, actor_lr: f64) -> Self {
+        self.actor_lr = Some(actor_lr);
+        self
+    }
+
+    pub fn critic_lr(mut self, critic_lr: f64) -> Self {
+        self.critic_lr = Some(critic_lr);
+        self
+    }
+
+    pub fn max_grad_norm(mut self, max_grad_norm: f64) -> Self {
+        self.max_grad_norm = Some(max_grad_norm);
+        self
+    }
+
+    pub fn n_step(mut self, n_step: i64) -> Self {
+        self.n_step = Some(n_step);
+        self
+    }
+
+    pub fn critic_loss(mut self, critic_loss: LossKind) -> Self {
+        self.critic_loss = Some(critic_loss);
+        self
+    }
+
+    pub fn lr_schedule(mut self, lr_schedule: LrSchedule, lr_horizon: i64) -> Self {
+        self.lr_schedule = Some(lr_schedule);
+        self.lr_horizon = Some(lr_horizon);
+        self
+    }
+
+    pub fn log_path(mut self, log_path: String) -> Self {
+        self.log_path = Some(log_path);
+        self
+    }
+
+    pub fn noise_process(mut self, noise_process: NoiseProcess) -> Self {
+        self.noise_process = Some(noise_process);
+        self
+    }
+
+    pub fn use_twin_critic(mut self, use_twin_critic: bool) -> Self {
+        self.use_twin_critic = Some(use_twin_critic);
+        self
+    }
+
+    pub fn target_policy_smoothing(mut self, target_policy_smoothing: bool) -> Self {
+        self.target_policy_smoothing = Some(target_policy_smoothing);
+        self
+    }
+
+    pub fn normalize_observations(mut self, normalize_observations: bool) -> Self {
+        self.normalize_observations = Some(normalize_observations);
+        self
+    }
+
+    pub fn target_update_freq(mut self, target_update_freq: i64) -> Self {
+        self.target_update_freq = Some(target_update_freq);
+        self
+    }
+
+    pub fn restore_on_non_finite(mut self, restore_on_non_finite: bool) -> Self {
+        self.restore_on_non_finite = Some(restore_on_non_finite);
+        self
+    }
+
+    pub fn snapshot_freq(mut self, snapshot_freq: i64) -> Self {
+        self.snapshot_freq = Some(snapshot_freq);
+        self
+    }
+
+    pub fn amp(mut self, amp: bool) -> Self {
+        self.amp = Some(amp);
+        self
+    }
+
+    pub fn num_critics(mut self, num_critics: usize) -> Self {
+        self.num_critics = Some(num_critics);
+        self
+    }
+
+    pub fn compute_device(mut self, compute_device: tch::Device) -> Self {
+        self.compute_device = Some(compute_device);
+        self
+    }
+
+    pub fn build(self) -> anyhow::Result<TD3> {
+        let state_dim = match self.state_dim {
+            Some(state_dim) => state_dim,
+            None => anyhow::bail!("TD3Builder needs a state_dim"),
+        };
+        let action_dim = match self.action_dim {
+            Some(action_dim) => action_dim,
+            None => anyhow::bail!("TD3Builder needs an action_dim"),
+        };
+        let max_action = match self.max_action {
+            Some(max_action) => max_action,
+            None => anyhow::bail!("TD3Builder needs a max_action"),
+        };
+
+        let actor_opt = self.actor_opt.unwrap_or(String::from("ADAM"));
+        let critic_opt = self.critic_opt.unwrap_or(String::from("ADAM"));
+        let actor_shape = self.actor_shape.unwrap_or(vec![64, 64]);
+        let q1_shape = self.q1_shape.unwrap_or(vec![64, 64]);
+        let q2_shape = self.q2_shape.unwrap_or(vec![64, 64]);
+        // critics past the twin pair are shaped like q2
+        let num_critics = self.num_critics.unwrap_or(2);
+
+        let tau = self.tau.unwrap_or(0.005);
+        let discount = self.discount.unwrap_or(0.99);
+        let policy_noise = self.policy_noise.unwrap_or(0.2);
+        let noise_clip = self.noise_clip.unwrap_or(0.5);
+        let policy_freq = self.policy_freq.unwrap_or(2);
+        let target_update_freq = self.target_update_freq.unwrap_or(policy_freq);
+        let restore_on_non_finite = self.restore_on_non_finite.unwrap_or(false);
+        let snapshot_freq = self.snapshot_freq.unwrap_or(100);
+        let actor_lr = self.actor_lr.unwrap_or(0.0003);
+        let critic_lr = self.critic_lr.unwrap_or(0.0003);
+        let max_grad_norm = self.max_grad_norm;
+        let n_step = self.n_step.unwrap_or(1);
+        let critic_loss = self.critic_loss.unwrap_or(LossKind::Mse);
+        let lr_schedule = self.lr_schedule.unwrap_or(LrSchedule::Constant);
+        let lr_horizon = self.lr_horizon.unwrap_or(1_000_000);
+        let noise_process = self.noise_process.unwrap_or(NoiseProcess::Gaussian);
+        let use_twin_critic = self.use_twin_critic.unwrap_or(true);
+        let target_policy_smoothing = self.target_policy_smoothing.unwrap_or(true);
+        let obs_normalizer = match self.normalize_observations.unwrap_or(false) {
+            true => Some(RunningNormalizer::new(state_dim.max(0) as usize, None)),
+            false => None,
+        };
+        let compute_device = self.compute_device.unwrap_or(**device);
+        // autocast only pays off on cuda, anywhere else training stays in fp32
+        let amp = self.amp.unwrap_or(false) && compute_device.is_cuda();
+
+        if state_dim <= 0 || action_dim <= 0 {
+            anyhow::bail!("state_dim and action_dim must be positive, got {} and {}", state_dim, action_dim);
+        }
+
+        if max_action <= 0f64 || !max_action.is_finite() {
+            anyhow::bail!("max_action must be positive and finite, got {}", max_action);
+        }
+
+        if policy_freq <= 0 || target_update_freq <= 0 {
+            anyhow::bail!(
+                "policy_freq and target_update_freq must be positive, got {} and {}",
+                policy_freq,
+                target_update_freq
+            );
+        }
+
+        if snapshot_freq <= 0 {
+            anyhow::bail!("snapshot_freq must be positive, got {}", snapshot_freq);
+        }
+
+        if num_critics < 2 {
+            anyhow::bail!("num_critics must be at least 2, got {}", num_critics);
+        }
+
+        for (name, shape) in [("actor_shape", &actor_shape), ("q1_shape", &q1_shape), ("q2_shape", &q2_shape)] {
+            if shape.is_empty() || shape.iter().any(|width| *width <= 0) {
+                anyhow::bail!("{} needs at least one hidden layer of positive width, got {:?}", name, shape);
+            }
+        }
+
+        let actor = Actor::new(state_dim, action_dim, actor_shape.clone(), max_action, None, None, Some(compute_device));
+        let actor_target = Actor::new(state_dim, action_dim, actor_shape.clone(), max_action, None, None, Some(compute_device));
+
+        let q_shapes: Vec<Vec<i64>> =
+            std::iter::once(q1_shape.clone()).chain(std::iter::repeat(q2_shape.clone()).take(num_critics - 1)).collect();
+
+        let critic = Critic::new(state_dim, action_dim, q_shapes.clone(), None, None, Some(compute_device));
+        let critic_target = Critic::new(state_dim, action_dim, q_shapes.clone(), None, None, Some(compute_device));
+
+        // targets start as exact copies of the online networks
+        actor_target.vs.borrow_mut().copy(&actor.vs.borrow())?;
+        critic_target.vs.borrow_mut().copy(&critic.vs.borrow())?;
+
+        let actor_opt: anyhow::Result<Box<dyn MilkshakeOptimizer>> = match actor_opt.as_str() {
+            "ADAM" => {
+                let mut adam = ADAM::new(actor_lr, actor.vs.clone(), max_grad_norm);
+                adam.grad_scaler = amp.then(GradScaler::new);
+                Ok(Box::new(adam))
+            }
+            "CMAES" => Ok(Box::new(CMAES::new(actor.vs.clone(), None, None))),
+            &_ => {
+                anyhow::bail!("Invalid Actor Optimizer Chosen")
+            }
+        };
+
+        let critic_opt: anyhow::Result<Box<dyn MilkshakeOptimizer>> = match critic_opt.as_str() {
+            "ADAM" => {
+                let mut adam = ADAM::new(critic_lr, critic.vs.clone(), max_grad_norm);
+                adam.grad_scaler = amp.then(GradScaler::new);
+                Ok(Box::new(adam))
+            }
+            "CMAES" => Ok(Box::new(CMAES::new(critic.vs.clone(), None, None))),
+            &_ => {
+                anyhow::bail!("Invalid Critic Optimizer Chosen")
+            }
+        };
+
+        let actor_opt = actor_opt?;
+        let critic_opt = critic_opt?;
+
+        // opened last so a rejected configuration leaves no empty log behind
+        let logger = self.log_path.map(Logger::new).transpose()?;
+
+        Ok(TD3 {
+            actor,
+            actor_target,
+            critic,
+            critic_target,
+            actor_opt,
+            critic_opt,
+            action_dim,
+            state_dim,
+            max_action,
+            tau,
+            discount,
+            policy_noise,
+            noise_clip,
+            policy_freq,
+            target_update_freq,
+            total_it: 0,
+            actor_lr,
+            critic_lr,
+            max_grad_norm,
+            n_step,
+            critic_loss,
+            lr_schedule,
+            lr_horizon,
+            noise_process,
+            noise_state: vec![0f64; action_dim as usize],
+            use_twin_critic,
+            target_policy_smoothing,
+            obs_normalizer,
+            skipped_steps: 0,
+            restore_on_non_finite,
+            snapshot_freq,
+            last_good: None,
+            amp,
+            device: compute_device,
+            logger,
+        })
+    }
+}
+
+pub struct TD3 {
+    pub actor: Actor,
+    pub actor_target: Actor,
+    pub critic: Critic,
+    pub critic_target: Critic,
+
+    actor_opt: Box<dyn MilkshakeOptimizer>,
+    critic_opt: Box<dyn MilkshakeOptimizer>,
+
+    pub action_dim: i64,
+    pub state_dim: i64,
+    pub max_action: f64,
+    pub tau: f64,
+    pub discount: f64,
+    pub policy_noise: f64,
+    pub noise_clip: f64,
+    pub policy_freq: i64,
+    // train calls between polyak updates of the target networks
+    pub target_update_freq: i64,
+    pub total_it: i64,
+    pub actor_lr: f64,
+    pub critic_lr: f64,
+    pub max_grad_norm: Option<f64>,
+    pub n_step: i64,
+    pub critic_loss: LossKind,
+    pub lr_schedule: LrSchedule,
+    pub lr_horizon: i64,
+    pub noise_process: NoiseProcess,
+
+    // ornstein uhlenbeck state, one value per action dimension in units of max_action
+    pub noise_state: Vec<f64>,
+
+    // turning both off recovers ddpg, a single q target and no noise on the target action
+    pub use_twin_critic: bool,
+    pub target_policy_smoothing: bool,
+
+    // standardizes states before the actor and critic see them, None leaves them raw
+    pub obs_normalizer: Option<RunningNormalizer>,
+
+    // train calls that hit a nan or infinite loss, nothing is updated on those
+    pub skipped_steps: i64,
+    // roll the networks back to the last step that trained cleanly when a loss is not finite
+    pub restore_on_non_finite: bool,
+    // clean steps are snapshotted every snapshot_freq train calls, and on the first one, so a rollback can lose
+    // up to snapshot_freq - 1 steps, not persisted, loaded policies snapshot every 100 train calls
+    pub snapshot_freq: i64,
+    pub last_good: Option<TrainSnapshot>,
+
+    // forward passes of the losses run under fp16 autocast with a scaled loss, only ever true on cuda
+    // not persisted, loaded policies train in fp32
+    pub amp: bool,
+
+    // not persisted, loaded policies go to the device given to TD3::load_on
+    pub device: tch::Device,
+
+    // not persisted, loaded policies do not log until one is attached
+    pub logger: Option<Logger>,
+}
+
+impl TD3 {
+    pub fn builder() -> TD3Builder {
+        TD3Builder::new()
+    }
+
+    // every other setting at its default, TD3::builder configures the rest
+    pub fn new(state_dim: i64, action_dim: i64, max_action: f64) -> anyhow::Result<Self> {
+        TD3::builder().state_dim(state_dim).action_dim(action_dim).max_action(max_action).build()
+    }
+
+    // learning rates the actor and critic optimizers are currently stepping with
+    pub fn current_lr(&self) -> (Option<f64>, Option<f64>) {
+        (self.actor_opt.lr(), self.critic_opt.lr())
+    }
+
+    // actor forward pass without an autograd graph, evaluation interleaved with training stays cheap
+    pub fn infer(&self, state: &tch::Tensor) -> tch::Tensor {
+        tch::no_grad(|| self.actor.forward(state))
+    }
+
+    pub fn select_action(&self, state: Vec<f64>) -> Vec<f64> {
+        self.select_actions_batch(&[state]).remove(0)
+    }
+
+    // deterministic actions for many states with a single forward pass and device transfer
+    pub fn select_actions_batch(&self, states: &[Vec<f64>]) -> Vec<Vec<f64>> {
+        if states.is_empty() {
+            return Vec::new();
+        }
+
+        let state = tch::Tensor::from_slice(&states.concat())
+            .view([states.len() as i64, -1])
+            .to_device(self.device);
+        let tensor = self.infer(&self.normalize_states(&state)).to_device(tch::Device::Cpu);
         let len = tensor.size().iter().fold(1, |sum, val| sum * *val as usize);
 
         let mut vec = vec![0f32; len];
         tensor.copy_data(vec.as_mut_slice(), len);
 
-        vec.iter().map(|x| *x as f64).collect()
+        vec.chunks(self.action_dim as usize)
+            .map(|action| action.iter().map(|x| *x as f64).collect())
+            .collect()
     }
 
-    pub fn train(&mut self, replay_buffer: &ReplayBuffer, batch_size: Option<i64>) {
-        let batch_size = batch_size.unwrap_or(256);
-        let samples = replay_buffer.sample(batch_size);
+    // (q1, q2) critic estimates for a single state action pair
+    pub fn q_value(&self, state: Vec<f64>, action: Vec<f64>) -> (f64, f64) {
+        let state = tch::Tensor::from_slice(&state).view([1, -1]).to_device(self.device);
+        let action = tch::Tensor::from_slice(&action).view([1, -1]).to_device(self.device);
 
-        let state = &samples[0];
-        let action = &samples[1];
-        let next_state = &samples[2];
-        let reward = &samples[3];
-        let not_done = &samples[4];
+        let q = tch::no_grad(|| self.critic.forward(&self.normalize_states(&state), &action)).to_device(tch::Device::Cpu);
 
-        let target_q = tch::no_grad(|| {
-            let noise =
-                (action.rand_like() * self.policy_noise).clamp(-self.noise_clip, self.noise_clip);
+        (q.double_value(&[0, 0]), q.double_value(&[0, 1]))
+    }
 
-            let next_action = (self.actor_target.forward(next_state) + noise)
-                .clamp(-self.max_action, self.max_action);
+    // raw states when the normalizer is off
+    pub fn normalize_states(&self, states: &tch::Tensor) -> tch::Tensor {
+        match &self.obs_normalizer {
+            Some(normalizer) => normalizer.normalize(states),
+            None => states.shallow_clone(),
+        }
+    }
 
-            let q = self.critic_target.forward(next_state, &next_action);
+    // deterministic action plus exploration noise, clamped to [-max_action, max_action]
+    // expl_noise is the gaussian std as a fraction of max_action, ornstein uhlenbeck uses its own sigma
+    // the visited state updates the normalizer, select_action alone only reads it so evaluation leaves it untouched
+    // this is the only update, training reads the statistics so replayed states are not counted again
+    pub fn sample_action(
+        &mut self,
+        state: Vec<f64>,
+        expl_noise: f64,
+        rng: &mut rand::prelude::StdRng,
+    ) -> Vec<f64> {
+        if let Some(normalizer) = self.obs_normalizer.as_mut() {
+            normalizer.update(&state);
+        }
 
-            let target_q1 = &q.0;
-            let target_q2 = &q.1;
+        let noise: Vec<f64> = match self.noise_process {
+            NoiseProcess::Gaussian => {
+                let normal = rand_distr::Normal::new(0f64, self.max_action * expl_noise)
+                    .expect("Failed to make normal distribution");
 
-            let min_q = target_q1.min_other(target_q2);
+                (0..self.action_dim)
+                    .map(|_| rand::prelude::Distribution::sample(&normal, rng))
+                    .collect()
+            }
 
-            reward.unsqueeze(1) + not_done.unsqueeze(1) * min_q * self.discount
-        });
+            NoiseProcess::OrnsteinUhlenbeck { theta, sigma } => {
+                let normal =
+                    rand_distr::Normal::new(0f64, sigma).expect("Failed to make normal distribution");
 
-        let grads = self.critic_opt.grads();
-        let mut critic_train_closure = || {
-            let solutions = self.critic_opt.ask();
-            let mut losses = vec![];
-
-            for solution in &solutions {
-                if !std::rc::Rc::ptr_eq(solution, &self.critic.vs) {
-                    self.critic
-                        .vs
-                        .borrow_mut()
-                        .copy(&solution.borrow())
-                        .expect("Failed to copy test solution to critic");
+                for x in self.noise_state.iter_mut() {
+                    *x += -theta * *x + rand::prelude::Distribution::sample(&normal, rng);
                 }
 
-                let q = self.critic.forward(state, action);
+                self.noise_state.iter().map(|x| x * self.max_action).collect()
+            }
+        };
 
-                let current_q1 = &q.0;
-                let current_q2 = &q.1;
+        self.select_action(state)
+            .iter()
+            .zip(noise.iter())
+            .map(|(act, noise)| (act + noise).clamp(-self.max_action, self.max_action))
+            .collect()
+    }
 
-                let q1_loss = current_q1.mse_loss(&target_q, tch::Reduction::Mean);
-                let q2_loss = current_q2.mse_loss(&target_q, tch::Reduction::Mean);
+    // call at episode boundaries so correlated noise does not carry over
+    pub fn reset_noise(&mut self) {
+        self.noise_state.iter_mut().for_each(|x| *x = 0f64);
+    }
 
-                let critic_loss = q1_loss + q2_loss;
-                losses.push(critic_loss);
-            }
+    // see trainer::evaluate_policy
+    pub fn evaluate(&self, env: &mut dyn Environment, episodes: i64) -> f64 {
+        crate::trainer::evaluate_policy(env, episodes, |state| self.select_action(state))
+    }
+
+    // zero mean gaussian with std policy_noise, clipped to noise_clip, shaped like the actions
+    pub fn smoothing_noise(&self, action: &tch::Tensor) -> tch::Tensor {
+        (action.randn_like() * self.policy_noise).clamp(-self.noise_clip, self.noise_clip)
+    }
 
-            self.critic_opt.tell(solutions, losses);
+    // a prioritized buffer gets importance sampling weighted critic losses and fresh td error priorities back
+    pub fn train(&mut self, replay_buffer: &mut ReplayBuffer, batch_size: Option<i64>) {
+        self.train_accumulated(replay_buffer, batch_size, 1)
+    }
 
-            let critic_result = self.critic_opt.result();
-            if !std::rc::Rc::ptr_eq(&critic_result, &self.critic.vs) {
-                self.critic
-                    .vs
-                    .borrow_mut()
-                    .copy(&critic_result.borrow())
-                    .expect("Failed to copy result to critic from optimizer");
-            }
+    // one update from accumulation_steps minibatches of batch_size, each loss scaled by 1 / accumulation_steps
+    // gradients add up over the minibatches and the optimizers step once, trading time for gpu memory
+    pub fn train_accumulated(&mut self, replay_buffer: &mut ReplayBuffer, batch_size: Option<i64>, accumulation_steps: i64) {
+        assert!(accumulation_steps > 0, "accumulation_steps must be positive");
+
+        self.total_it += 1;
+
+        let lr_scale = self.lr_schedule.scale(self.total_it, self.lr_horizon);
+        self.actor_opt.set_lr(self.actor_lr * lr_scale);
+        self.critic_opt.set_lr(self.critic_lr * lr_scale);
+
+        let batch_size = batch_size.unwrap_or(256);
+        let batches = (0..accumulation_steps)
+            .map(|_| replay_buffer.sample_batch(batch_size))
+            .collect::<Vec<Batch>>();
+        let prioritized = replay_buffer.priorities.is_some();
+
+        let minibatches = batches
+            .iter()
+            .map(|batch| {
+                let state = self.normalize_states(&batch.states.to_device(self.device));
+                let action = batch.actions.to_device(self.device);
+                let next_state = self.normalize_states(&batch.next_states.to_device(self.device));
+                let reward = batch.rewards.to_device(self.device);
+                let not_done = batch.not_dones.to_device(self.device);
+                let n_steps = batch.n_steps.to_device(self.device);
+
+                // the target stays in fp32 whatever amp is set to, bootstrapping would compound fp16 rounding
+                let target_q = tch::no_grad(|| {
+                    let mut next_action = self.actor_target.forward(&next_state);
+
+                    if self.target_policy_smoothing {
+                        next_action = (&next_action + self.smoothing_noise(&action)).clamp(-self.max_action, self.max_action);
+                    }
+
+                    let q = self.critic_target.forward(&next_state, &next_action);
+
+                    // the whole ensemble backs the target, only the first critic when twin is off
+                    let min_q = match self.use_twin_critic {
+                        true => q.amin(&[1i64][..], true),
+                        false => q.narrow(1, 0, 1),
+                    };
+
+                    // rewards are already summed over each window by the replay buffer, windows cut short at the
+                    // end of an episode bootstrap with a smaller power of the discount
+                    let discount = tch::Tensor::pow_scalar(self.discount, &n_steps.unsqueeze(1));
+                    reward.unsqueeze(1) + not_done.unsqueeze(1) * min_q * discount
+                });
+
+                // q1's td error before the update becomes the new priority of each sampled transition
+                let td_errors = match prioritized {
+                    true => {
+                        let td_errors =
+                            tch::no_grad(|| (&target_q - self.critic.forward(&state, &action).narrow(1, 0, 1)).abs().view([-1]))
+                                .to_device(tch::Device::Cpu);
+
+                        Some(Vec::<f64>::try_from(&td_errors.totype(tch::Kind::Double)).expect("Failed to read td errors"))
+                    }
+                    false => None,
+                };
+
+                let weights = match prioritized {
+                    true => Some(batch.weights.to_device(self.device)),
+                    false => None,
+                };
+
+                Minibatch {
+                    state,
+                    action,
+                    target_q,
+                    weights,
+                    td_errors,
+                }
+            })
+            .collect::<Vec<Minibatch>>();
+
+        let amp = self.amp;
+        let critic = &self.critic;
+        let critic_loss = optimize(self.critic_opt.as_mut(), &critic.vs, minibatches.len(), |idx| {
+            let minibatch = &minibatches[idx];
+
+            tch::autocast(amp, || {
+                critic.loss(
+                    &minibatch.state,
+                    &minibatch.action,
+                    &minibatch.target_q,
+                    self.critic_loss,
+                    self.use_twin_critic,
+                    minibatch.weights.as_ref(),
+                )
+            })
+        });
+
+        let critic_loss = match critic_loss {
+            Some(critic_loss) => critic_loss,
+            None => return self.skip_non_finite_step(),
         };
 
-        match grads {
-            true => critic_train_closure(),
-            false => tch::no_grad(critic_train_closure),
+        for (batch, minibatch) in batches.iter().zip(minibatches.iter()) {
+            if let Some(td_errors) = &minibatch.td_errors {
+                replay_buffer.update_priorities(&batch.indices, td_errors);
+            }
         }
 
+        let mut actor_loss = None;
         if self.total_it % self.policy_freq == 0 {
-            let grads = self.actor_opt.grads();
-            let mut actor_train_closure = || {
-                let solutions = self.actor_opt.ask();
-                let mut losses = vec![];
-
-                for solution in &solutions {
-                    if !std::rc::Rc::ptr_eq(solution, &self.actor.vs) {
-                        self.actor
-                            .vs
-                            .borrow_mut()
-                            .copy(&solution.borrow())
-                            .expect("Failed to copy test solution to actor");
-                    }
+            let actor = &self.actor;
+            actor_loss = optimize(self.actor_opt.as_mut(), &actor.vs, minibatches.len(), |idx| {
+                let state = &minibatches[idx].state;
 
-                    let loss = -1 * self
-                        .critic
-                        .Q1(&tch::Tensor::cat(&[state, &self.actor.forward(state)], 1))
-                        .mean(tch::Kind::Float);
+                tch::autocast(amp, || {
+                    -1 * self.critic.Q1(&tch::Tensor::cat(&[state, &actor.forward(state)], 1)).mean(tch::Kind::Float)
+                })
+            });
 
-                    losses.push(loss);
-                }
+            if actor_loss.is_none() {
+                return self.skip_non_finite_step();
+            }
+        }
 
-                self.actor_opt.tell(solutions, losses);
+        if self.total_it % self.target_update_freq == 0 {
+            soft_update(
+                &self.actor.vs.borrow(),
+                &mut self.actor_target.vs.borrow_mut(),
+                self.tau,
+            );
+            soft_update(
+                &self.critic.vs.borrow(),
+                &mut self.critic_target.vs.borrow_mut(),
+                self.tau,
+            );
+        }
 
-                let actor_result = self.actor_opt.result();
-                if !std::rc::Rc::ptr_eq(&actor_result, &self.actor.vs) {
-                    self.actor
-                        .vs
-                        .borrow_mut()
-                        .copy(&actor_result.borrow())
-                        .expect("Failed to copy result to actor from optimizer");
-                }
-            };
+        if self.restore_on_non_finite && (self.last_good.is_none() || self.total_it % self.snapshot_freq == 0) {
+            self.last_good = Some(TrainSnapshot {
+                actor: snapshot_vs(&self.actor.vs.borrow()),
+                actor_target: snapshot_vs(&self.actor_target.vs.borrow()),
+                critic: snapshot_vs(&self.critic.vs.borrow()),
+                critic_target: snapshot_vs(&self.critic_target.vs.borrow()),
+                actor_opt_state: self.actor_opt.save_state().expect("Failed to snapshot actor optimizer"),
+                critic_opt_state: self.critic_opt.save_state().expect("Failed to snapshot critic optimizer"),
+            });
+        }
+
+        if let Some(logger) = self.logger.as_mut() {
+            let mean_target_q = minibatches
+                .iter()
+                .map(|minibatch| minibatch.target_q.mean(tch::Kind::Float).double_value(&[]))
+                .sum::<f64>()
+                / minibatches.len() as f64;
+
+            logger
+                .log(self.total_it, critic_loss, actor_loss, mean_target_q)
+                .expect("Failed to write training metrics");
+        }
+    }
+
+    // counts the step, and rolls the networks and both optimizers back to the last snapshot if asked to
+    // a critic update that already went through in this step is rolled back with the rest when the actor loss
+    // was not finite, keeping only the critic would leave it out of step with the restored critic target
+    fn skip_non_finite_step(&mut self) {
+        self.skipped_steps += 1;
+
+        if !self.restore_on_non_finite {
+            return;
+        }
+
+        if let Some(snapshot) = &self.last_good {
+            restore_vs(&mut self.actor.vs.borrow_mut(), &snapshot.actor);
+            restore_vs(&mut self.actor_target.vs.borrow_mut(), &snapshot.actor_target);
+            restore_vs(&mut self.critic.vs.borrow_mut(), &snapshot.critic);
+            restore_vs(&mut self.critic_target.vs.borrow_mut(), &snapshot.critic_target);
 
-            match grads {
-                true => actor_train_closure(),
-                false => tch::no_grad(actor_train_closure),
+            if let Some(state) = &snapshot.actor_opt_state {
+                self.actor_opt.load_state(state).expect("Failed to restore actor optimizer");
             }
 
-            tch::no_grad(|| {
-                for (param, target_param) in self
-                    .actor
-                    .vs
-                    .borrow_mut()
-                    .trainable_variables()
-                    .iter_mut()
-                    .zip(
-                        self.actor_target
-                            .vs
-                            .borrow_mut()
-                            .trainable_variables()
-                            .iter_mut(),
-                    )
-                {
-                    target_param.copy_(
-                        &(self.tau * param.copy() + (1f64 - self.tau) * target_param.copy()),
-                    );
-                }
+            if let Some(state) = &snapshot.critic_opt_state {
+                self.critic_opt.load_state(state).expect("Failed to restore critic optimizer");
+            }
+        }
+    }
+
+    // .bin files are written with bincode behind a td3_bin_magic header, anything else is pretty printed json
+    fn is_binary(path: &std::path::Path) -> bool {
+        path.extension().map_or(false, |extension| extension == "bin")
+    }
+
+    // creates any missing parent directories, returns the number of bytes written
+    pub fn save(&self, filename: String) -> anyhow::Result<usize> {
+        let path = std::path::Path::new(filename.as_str());
+
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let data = match TD3::is_binary(path) {
+            true => [
+                td3_bin_magic.to_vec(),
+                (td3_fields.len() as u32).to_le_bytes().to_vec(),
+                bincode::serialize(self)?,
+            ]
+            .concat(),
+            false => serde_json::to_string_pretty(self)?.into_bytes(),
+        };
+        let mut file = std::fs::File::create(path)?;
+
+        std::io::Write::write_all(&mut file, data.as_slice())?;
+        std::io::Write::flush(&mut file)?;
+
+        Ok(data.len())
+    }
+
+    pub fn load(filename: String) -> anyhow::Result<Self> {
+        TD3::load_on(filename, None)
+    }
+
+    // networks and optimizer state are restored straight onto compute_device, the global device by default
+    pub fn load_on(filename: String, compute_device: Option<tch::Device>) -> anyhow::Result<Self> {
+        let path = std::path::Path::new(filename.as_str());
+        let data = std::fs::read(path)?;
+        let compute_device = compute_device.unwrap_or(**device);
+
+        // the options bincode::serialize writes with
+        let from_bincode = |body: &[u8], fields: usize| -> bincode::Result<TD3> {
+            let options = bincode::Options::allow_trailing_bytes(bincode::Options::with_fixint_encoding(bincode::DefaultOptions::new()));
+            bincode::Options::deserialize_seed(options, TD3Seed(compute_device, fields), body)
+        };
+
+        let td3: TD3 = match TD3::is_binary(path) {
+            true if data.starts_with(td3_bin_magic) && data.len() >= 8 => {
+                let fields = u32::from_le_bytes([data[4], data[5], data[6], data[7]]) as usize;
+                from_bincode(&data[8..], fields)?
+            }
+            // files from before the header do not say how many fields they hold, a count past the end of the
+            // file runs out of bytes so the longest count that reads is the one it was written with
+            // total_it is the last field every version had
+            true => {
+                let mut result = from_bincode(data.as_slice(), td3_fields.len());
+
+                for fields in (13..td3_fields.len()).rev() {
+                    if result.is_ok() {
+                        break;
+                    }
 
-                for (param, target_param) in self
-                    .critic
-                    .vs
-                    .borrow_mut()
-                    .trainable_variables()
-                    .iter_mut()
-                    .zip(
-                        self.critic_target
-                            .vs
-                            .borrow_mut()
-                            .trainable_variables()
-                            .iter_mut(),
-                    )
-                {
-                    target_param.copy_(
-                        &(self.tau * param.copy() + (1f64 - self.tau) * target_param.copy()),
-                    );
+                    result = from_bincode(data.as_slice(), fields);
                 }
-            })
+
+                result?
+            }
+            false => {
+                let mut deserializer = serde_json::Deserializer::from_slice(data.as_slice());
+                let td3 = serde::de::DeserializeSeed::deserialize(TD3Seed(compute_device, td3_fields.len()), &mut deserializer)?;
+                deserializer.end()?;
+                td3
+            }
+        };
+
+        Ok(td3)
+    }
+
+    // frozen layers keep their weights through gradient updates, cma-es still samples them
+    // not persisted, loaded policies train every layer
+    pub fn set_layer_trainable(&mut self, network: NetworkKind, layer_idx: usize, trainable: bool) {
+        let network = match network {
+            NetworkKind::Actor => &self.actor.actor,
+            NetworkKind::Critic(idx) => {
+                assert!(idx < self.critic.qs.len(), "critic {} is out of range for an ensemble of {}", idx, self.critic.qs.len());
+                &self.critic.qs[idx]
+            }
+        };
+
+        assert!(
+            layer_idx < network.layers.len(),
+            "layer_idx {} is out of range for a network with {} layers",
+            layer_idx,
+            network.layers.len()
+        );
+
+        let linear = &network.layers[layer_idx].layer;
+        let _ = linear.ws.set_requires_grad(trainable);
+        if let Some(bs) = &linear.bs {
+            let _ = bs.set_requires_grad(trainable);
+        }
+    }
+
+    // gradients left by the last backward pass, read only so the next step is unaffected
+    // on steps that update the actor the first critic also holds what the actor loss backpropagated through it
+    pub fn grad_norms(&self) -> NetworkNorms {
+        NetworkNorms {
+            actor: network_norm(&self.actor.actor, |var| var.grad()),
+            critics: self.critic.qs.iter().map(|q| network_norm(q, |var| var.grad())).collect(),
+        }
+    }
+
+    pub fn weight_norms(&self) -> NetworkNorms {
+        NetworkNorms {
+            actor: network_norm(&self.actor.actor, |var| var.shallow_clone()),
+            critics: self.critic.qs.iter().map(|q| network_norm(q, |var| var.shallow_clone())).collect(),
+        }
+    }
+
+    // warm starts the actor and its target from another checkpoint, critics and optimizers stay fresh
+    pub fn load_actor_weights(&mut self, filename: String) -> anyhow::Result<()> {
+        let pretrained = TD3::load_on(filename.clone(), Some(self.device))?;
+
+        if pretrained.state_dim != self.state_dim || pretrained.action_dim != self.action_dim {
+            anyhow::bail!(
+                "Actor in {} maps {} states to {} actions, this policy maps {} states to {} actions",
+                filename,
+                pretrained.state_dim,
+                pretrained.action_dim,
+                self.state_dim,
+                self.action_dim
+            );
         }
+
+        let shape = |actor: &Actor| -> Vec<(i64, i64)> {
+            actor.actor.layers.iter().map(|layer| (layer.input, layer.output)).collect()
+        };
+
+        if shape(&pretrained.actor) != shape(&self.actor) {
+            anyhow::bail!(
+                "Actor in {} has layers {:?}, this policy has layers {:?}",
+                filename,
+                shape(&pretrained.actor),
+                shape(&self.actor)
+            );
+        }
+
+        self.actor.vs.borrow_mut().copy(&pretrained.actor.vs.borrow())?;
+        self.actor_target.vs.borrow_mut().copy(&pretrained.actor.vs.borrow())?;
+
+        Ok(())
     }
 }
 
@@ -872,7 +2103,15 @@ impl serde::Serialize for TD3 {
     where
         S: serde::Serializer,
     {
-        let mut struct_serializer = serializer.serialize_struct("TD3", 13)?;
+        let actor_opt_state = self.actor_opt.save_state().map_err(<S::Error as serde::ser::Error>::custom)?;
+        let critic_opt_state = self.critic_opt.save_state().map_err(<S::Error as serde::ser::Error>::custom)?;
+
+        let ou_params = match self.noise_process {
+            NoiseProcess::Gaussian => None,
+            NoiseProcess::OrnsteinUhlenbeck { theta, sigma } => Some((theta, sigma)),
+        };
+
+        let mut struct_serializer = serializer.serialize_struct("TD3", 31)?;
 
         <<S as serde::Serializer>::SerializeStruct as serde::ser::SerializeStruct>::serialize_field(&mut struct_serializer, "actor", &self.actor)?;
         <<S as serde::Serializer>::SerializeStruct as serde::ser::SerializeStruct>::serialize_field(&mut struct_serializer, "actor_target", &self.actor_target)?;
@@ -888,18 +2127,71 @@ impl serde::Serialize for TD3 {
         <<S as serde::Serializer>::SerializeStruct as serde::ser::SerializeStruct>::serialize_field(&mut struct_serializer, "noise_clip", &self.noise_clip)?;
         <<S as serde::Serializer>::SerializeStruct as serde::ser::SerializeStruct>::serialize_field(&mut struct_serializer, "policy_freq", &self.policy_freq)?;
         <<S as serde::Serializer>::SerializeStruct as serde::ser::SerializeStruct>::serialize_field(&mut struct_serializer, "total_it", &self.total_it)?;
+        <<S as serde::Serializer>::SerializeStruct as serde::ser::SerializeStruct>::serialize_field(&mut struct_serializer, "actor_lr", &self.actor_lr)?;
+        <<S as serde::Serializer>::SerializeStruct as serde::ser::SerializeStruct>::serialize_field(&mut struct_serializer, "critic_lr", &self.critic_lr)?;
+        <<S as serde::Serializer>::SerializeStruct as serde::ser::SerializeStruct>::serialize_field(&mut struct_serializer, "max_grad_norm", &self.max_grad_norm)?;
+        <<S as serde::Serializer>::SerializeStruct as serde::ser::SerializeStruct>::serialize_field(&mut struct_serializer, "n_step", &self.n_step)?;
+        <<S as serde::Serializer>::SerializeStruct as serde::ser::SerializeStruct>::serialize_field(&mut struct_serializer, "actor_opt_state", &actor_opt_state)?;
+        <<S as serde::Serializer>::SerializeStruct as serde::ser::SerializeStruct>::serialize_field(&mut struct_serializer, "critic_opt_state", &critic_opt_state)?;
+        <<S as serde::Serializer>::SerializeStruct as serde::ser::SerializeStruct>::serialize_field(&mut struct_serializer, "huber_delta", &self.critic_loss.huber_delta())?;
+        <<S as serde::Serializer>::SerializeStruct as serde::ser::SerializeStruct>::serialize_field(&mut struct_serializer, "lr_schedule", &Some(self.lr_schedule.name()))?;
+        <<S as serde::Serializer>::SerializeStruct as serde::ser::SerializeStruct>::serialize_field(&mut struct_serializer, "lr_floor", &Some(self.lr_schedule.floor()))?;
+        <<S as serde::Serializer>::SerializeStruct as serde::ser::SerializeStruct>::serialize_field(&mut struct_serializer, "lr_horizon", &Some(self.lr_horizon))?;
+        <<S as serde::Serializer>::SerializeStruct as serde::ser::SerializeStruct>::serialize_field(&mut struct_serializer, "ou_theta", &ou_params.map(|(theta, _)| theta))?;
+        <<S as serde::Serializer>::SerializeStruct as serde::ser::SerializeStruct>::serialize_field(&mut struct_serializer, "ou_sigma", &ou_params.map(|(_, sigma)| sigma))?;
+        <<S as serde::Serializer>::SerializeStruct as serde::ser::SerializeStruct>::serialize_field(&mut struct_serializer, "use_twin_critic", &Some(self.use_twin_critic))?;
+        <<S as serde::Serializer>::SerializeStruct as serde::ser::SerializeStruct>::serialize_field(&mut struct_serializer, "target_policy_smoothing", &Some(self.target_policy_smoothing))?;
+        <<S as serde::Serializer>::SerializeStruct as serde::ser::SerializeStruct>::serialize_field(&mut struct_serializer, "obs_normalizer", &Some(self.obs_normalizer.as_ref().map(|normalizer| (normalizer.count, normalizer.mean.clone(), normalizer.m2.clone()))))?;
+        <<S as serde::Serializer>::SerializeStruct as serde::ser::SerializeStruct>::serialize_field(&mut struct_serializer, "target_update_freq", &Some(self.target_update_freq))?;
+        <<S as serde::Serializer>::SerializeStruct as serde::ser::SerializeStruct>::serialize_field(&mut struct_serializer, "skipped_steps", &Some(self.skipped_steps))?;
+        <<S as serde::Serializer>::SerializeStruct as serde::ser::SerializeStruct>::serialize_field(&mut struct_serializer, "restore_on_non_finite", &Some(self.restore_on_non_finite))?;
 
         <<S as serde::Serializer>::SerializeStruct as serde::ser::SerializeStruct>::end(struct_serializer)
     }
 }
 
+// in serialization order, a .bin file holds a prefix of these
+pub const td3_fields: &[&str] = &["actor", "actor_target", "critic", "critic_target", "action_dim", "state_dim", "max_action", "tau", "discount", "policy_noise", "noise_clip", "policy_freq", "total_it", "actor_lr", "critic_lr", "max_grad_norm", "n_step", "actor_opt_state", "critic_opt_state", "huber_delta", "lr_schedule", "lr_floor", "lr_horizon", "ou_theta", "ou_sigma", "use_twin_critic", "target_policy_smoothing", "obs_normalizer", "target_update_freq", "skipped_steps", "restore_on_non_finite"];
+
+// .bin files start with these bytes and the number of fields that follow as a little endian u32
+// bincode writes no field names or end marker, so a file from before a field was added can only be told apart
+// by its count, the missing trailing fields are then defaulted as they are for older json
+pub const td3_bin_magic: &[u8; 4] = b"TD3B";
+
+// deserializes a policy with every network and optimizer on the given device, plain deserialize uses the
+// global device
+// the second element is how many fields a sequence holds, maps name theirs and ignore it
+pub struct TD3Seed(pub tch::Device, pub usize);
+
+// the next element of a sequence holding only `fields` elements, None for anything past its end
+fn next_field<'de, V, T>(seq: &mut V, idx: usize, fields: usize) -> Result<Option<T>, V::Error>
+where
+    V: serde::de::SeqAccess<'de>,
+    T: serde::Deserialize<'de>,
+{
+    match idx < fields {
+        true => seq.next_element(),
+        false => Ok(None),
+    }
+}
+
 impl<'de> serde::Deserialize<'de> for TD3 {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: serde::Deserializer<'de>,
     {
-        enum TD3Field { actor, actor_target, critic, critic_target, action_dim, state_dim, max_action, tau, discount, policy_noise, noise_clip, policy_freq, total_it }
-        const TD3_FIELDS: &[&str] = &["actor", "actor_target", "critic", "critic_target", "action_dim", "state_dim", "max_action", "tau", "discount", "policy_noise", "noise_clip", "policy_freq", "total_it"];
+        serde::de::DeserializeSeed::deserialize(TD3Seed(**device, td3_fields.len()), deserializer)
+    }
+}
+
+impl<'de> serde::de::DeserializeSeed<'de> for TD3Seed {
+    type Value = TD3;
+
+    fn deserialize<D>(self, deserializer: D) -> Result<TD3, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        enum TD3Field { actor, actor_target, critic, critic_target, action_dim, state_dim, max_action, tau, discount, policy_noise, noise_clip, policy_freq, total_it, actor_lr, critic_lr, max_grad_norm, n_step, actor_opt_state, critic_opt_state, huber_delta, lr_schedule, lr_floor, lr_horizon, ou_theta, ou_sigma, use_twin_critic, target_policy_smoothing, obs_normalizer, target_update_freq, skipped_steps, restore_on_non_finite }
 
         impl<'de> serde::Deserialize<'de> for TD3Field {
             fn deserialize<D>(deserializer: D) -> Result<TD3Field, D::Error>
@@ -934,8 +2226,26 @@ impl<'de> serde::Deserialize<'de> for TD3 {
                             "noise_clip" => Ok(TD3Field::noise_clip),
                             "policy_freq" => Ok(TD3Field::policy_freq),
                             "total_it" => Ok(TD3Field::total_it),
-
-                            _ => Err(serde::de::Error::unknown_field(value, TD3_FIELDS)),
+                            "actor_lr" => Ok(TD3Field::actor_lr),
+                            "critic_lr" => Ok(TD3Field::critic_lr),
+                            "max_grad_norm" => Ok(TD3Field::max_grad_norm),
+                            "n_step" => Ok(TD3Field::n_step),
+                            "actor_opt_state" => Ok(TD3Field::actor_opt_state),
+                            "critic_opt_state" => Ok(TD3Field::critic_opt_state),
+                            "huber_delta" => Ok(TD3Field::huber_delta),
+                            "lr_schedule" => Ok(TD3Field::lr_schedule),
+                            "lr_floor" => Ok(TD3Field::lr_floor),
+                            "lr_horizon" => Ok(TD3Field::lr_horizon),
+                            "ou_theta" => Ok(TD3Field::ou_theta),
+                            "ou_sigma" => Ok(TD3Field::ou_sigma),
+                            "use_twin_critic" => Ok(TD3Field::use_twin_critic),
+                            "target_policy_smoothing" => Ok(TD3Field::target_policy_smoothing),
+                            "obs_normalizer" => Ok(TD3Field::obs_normalizer),
+                            "target_update_freq" => Ok(TD3Field::target_update_freq),
+                            "skipped_steps" => Ok(TD3Field::skipped_steps),
+                            "restore_on_non_finite" => Ok(TD3Field::restore_on_non_finite),
+
+                            _ => Err(serde::de::Error::unknown_field(value, td3_fields)),
                         }
                     }
                 }
@@ -944,7 +2254,10 @@ impl<'de> serde::Deserialize<'de> for TD3 {
             }
         }
 
-        struct TD3Visitor;
+        struct TD3Visitor {
+            device: tch::Device,
+            fields: usize,
+        }
 
         impl<'de> serde::de::Visitor<'de> for TD3Visitor {
             type Value = TD3;
@@ -957,16 +2270,16 @@ impl<'de> serde::Deserialize<'de> for TD3 {
             where
                 V: serde::de::SeqAccess<'de>,
             {
-                let actor: Actor = seq.next_element()?
+                let actor: Actor = seq.next_element_seed(ActorSeed(self.device))?
                     .ok_or_else(|| serde::de::Error::invalid_length(0, &self))?;
-                let actor_target: Actor = seq.next_element()?
+                let actor_target: Actor = seq.next_element_seed(ActorSeed(self.device))?
                     .ok_or_else(|| serde::de::Error::invalid_length(1, &self))?;
-                let critic: Critic = seq.next_element()?
+                let critic: Critic = seq.next_element_seed(CriticSeed(self.device))?
                     .ok_or_else(|| serde::de::Error::invalid_length(2, &self))?;
-                let critic_target: Critic = seq.next_element()?
+                let critic_target: Critic = seq.next_element_seed(CriticSeed(self.device))?
                     .ok_or_else(|| serde::de::Error::invalid_length(3, &self))?;
 
-                let action_dim = seq.next_element()?
+                let action_dim: i64 = seq.next_element()?
                     .ok_or_else(|| serde::de::Error::invalid_length(4, &self))?;
                 let state_dim = seq.next_element()?
                     .ok_or_else(|| serde::de::Error::invalid_length(5, &self))?;
@@ -985,8 +2298,51 @@ impl<'de> serde::Deserialize<'de> for TD3 {
                 let total_it = seq.next_element()?
                     .ok_or_else(|| serde::de::Error::invalid_length(12, &self))?;
 
-                let actor_opt: Box<dyn MilkshakeOptimizer> = Box::new(ADAM::new(0.0003f64, actor.vs.clone()));
-                let critic_opt: Box<dyn MilkshakeOptimizer> = Box::new(ADAM::new(0.0003f64, critic.vs.clone()));
+                // checkpoints from before these were configurable used the TD3Builder defaults
+                let actor_lr: f64 = next_field(&mut seq, 13, self.fields)?.unwrap_or(0.0003);
+                let critic_lr: f64 = next_field(&mut seq, 14, self.fields)?.unwrap_or(0.0003);
+                let max_grad_norm: Option<f64> = next_field(&mut seq, 15, self.fields)?.unwrap_or(None);
+                let n_step: i64 = next_field(&mut seq, 16, self.fields)?.unwrap_or(1);
+
+                // older checkpoints have no optimizer state, those optimizers start fresh
+                let actor_opt_state: Option<Vec<u8>> = next_field(&mut seq, 17, self.fields)?.unwrap_or(None);
+                let critic_opt_state: Option<Vec<u8>> = next_field(&mut seq, 18, self.fields)?.unwrap_or(None);
+                let huber_delta: Option<f64> = next_field(&mut seq, 19, self.fields)?.unwrap_or(None);
+                let lr_schedule: Option<String> = next_field(&mut seq, 20, self.fields)?.unwrap_or(None);
+                let lr_floor: Option<f64> = next_field(&mut seq, 21, self.fields)?.unwrap_or(None);
+                let lr_horizon: Option<i64> = next_field(&mut seq, 22, self.fields)?.unwrap_or(None);
+
+                let lr_schedule = parse_lr_schedule(lr_schedule, lr_floor)?;
+                let lr_horizon = lr_horizon.unwrap_or(1_000_000);
+
+                let ou_theta: Option<f64> = next_field(&mut seq, 23, self.fields)?.unwrap_or(None);
+                let ou_sigma: Option<f64> = next_field(&mut seq, 24, self.fields)?.unwrap_or(None);
+                let noise_process = noise_process_from_params(ou_theta, ou_sigma);
+
+                // checkpoints from before the ddpg switches were full td3
+                let use_twin_critic: bool = next_field::<_, Option<bool>>(&mut seq, 25, self.fields)?.flatten().unwrap_or(true);
+                let target_policy_smoothing: bool = next_field::<_, Option<bool>>(&mut seq, 26, self.fields)?.flatten().unwrap_or(true);
+
+                // count, mean and m2 of the state normalizer, older saves never normalized
+                let obs_normalizer: Option<(f64, Vec<f64>, Vec<f64>)> = next_field::<_, Option<Option<(f64, Vec<f64>, Vec<f64>)>>>(&mut seq, 27, self.fields)?.flatten().unwrap_or(None);
+
+                // older saves updated the targets together with the actor
+                let target_update_freq: i64 = next_field::<_, Option<i64>>(&mut seq, 28, self.fields)?.flatten().unwrap_or(policy_freq);
+
+                // older saves did not count skipped steps
+                let skipped_steps: i64 = next_field::<_, Option<i64>>(&mut seq, 29, self.fields)?.flatten().unwrap_or(0);
+                let restore_on_non_finite: bool = next_field::<_, Option<bool>>(&mut seq, 30, self.fields)?.flatten().unwrap_or(false);
+
+                let mut actor_opt: Box<dyn MilkshakeOptimizer> = Box::new(ADAM::new(actor_lr, actor.vs.clone(), max_grad_norm));
+                let mut critic_opt: Box<dyn MilkshakeOptimizer> = Box::new(ADAM::new(critic_lr, critic.vs.clone(), max_grad_norm));
+
+                if let Some(state) = actor_opt_state {
+                    actor_opt.load_state(state.as_slice()).map_err(<V::Error as serde::de::Error>::custom)?;
+                }
+
+                if let Some(state) = critic_opt_state {
+                    critic_opt.load_state(state.as_slice()).map_err(<V::Error as serde::de::Error>::custom)?;
+                }
 
                 Ok(
                     TD3 {
@@ -1005,6 +2361,26 @@ impl<'de> serde::Deserialize<'de> for TD3 {
                         noise_clip,
                         policy_freq,
                         total_it,
+                        actor_lr,
+                        critic_lr,
+                        max_grad_norm,
+                        n_step,
+                        critic_loss: LossKind::from_huber_delta(huber_delta),
+                        lr_schedule,
+                        lr_horizon,
+                        noise_process,
+                        noise_state: vec![0f64; action_dim as usize],
+                        use_twin_critic,
+                        target_policy_smoothing,
+                        obs_normalizer: obs_normalizer.map(RunningNormalizer::from_stats),
+                        target_update_freq,
+                        skipped_steps,
+                        restore_on_non_finite,
+                        snapshot_freq: 100,
+                        last_good: None,
+                        amp: false,
+                        device: self.device,
+                        logger: None,
                     }
                 )
             }
@@ -1027,6 +2403,24 @@ impl<'de> serde::Deserialize<'de> for TD3 {
                 let mut noise_clip = None;
                 let mut policy_freq = None;
                 let mut total_it = None;
+                let mut actor_lr = None;
+                let mut critic_lr = None;
+                let mut max_grad_norm = None;
+                let mut n_step = None;
+                let mut actor_opt_state: Option<Option<Vec<u8>>> = None;
+                let mut critic_opt_state: Option<Option<Vec<u8>>> = None;
+                let mut huber_delta: Option<Option<f64>> = None;
+                let mut lr_schedule: Option<Option<String>> = None;
+                let mut lr_floor: Option<Option<f64>> = None;
+                let mut lr_horizon: Option<Option<i64>> = None;
+                let mut ou_theta: Option<Option<f64>> = None;
+                let mut ou_sigma: Option<Option<f64>> = None;
+                let mut use_twin_critic: Option<Option<bool>> = None;
+                let mut target_policy_smoothing: Option<Option<bool>> = None;
+                let mut obs_normalizer: Option<Option<Option<(f64, Vec<f64>, Vec<f64>)>>> = None;
+                let mut target_update_freq: Option<Option<i64>> = None;
+                let mut skipped_steps: Option<Option<i64>> = None;
+                let mut restore_on_non_finite: Option<Option<bool>> = None;
 
                 while let Some(key) = map.next_key()? {
                     match key {
@@ -1035,7 +2429,7 @@ impl<'de> serde::Deserialize<'de> for TD3 {
                                 return Err(serde::de::Error::duplicate_field("actor"));
                             }
 
-                            actor = Some(map.next_value()?);
+                            actor = Some(map.next_value_seed(ActorSeed(self.device))?);
                         }
 
                         TD3Field::actor_target => {
@@ -1043,7 +2437,7 @@ impl<'de> serde::Deserialize<'de> for TD3 {
                                 return Err(serde::de::Error::duplicate_field("actor_target"));
                             }
 
-                            actor_target = Some(map.next_value()?);
+                            actor_target = Some(map.next_value_seed(ActorSeed(self.device))?);
                         }
 
                         TD3Field::critic => {
@@ -1051,7 +2445,7 @@ impl<'de> serde::Deserialize<'de> for TD3 {
                                 return Err(serde::de::Error::duplicate_field("critic"));
                             }
 
-                            critic = Some(map.next_value()?);
+                            critic = Some(map.next_value_seed(CriticSeed(self.device))?);
                         }
 
                         TD3Field::critic_target => {
@@ -1059,7 +2453,7 @@ impl<'de> serde::Deserialize<'de> for TD3 {
                                 return Err(serde::de::Error::duplicate_field("critic_target"));
                             }
 
-                            critic_target = Some(map.next_value()?);
+                            critic_target = Some(map.next_value_seed(CriticSeed(self.device))?);
                         }
 
                         TD3Field::action_dim => {
@@ -1133,6 +2527,150 @@ impl<'de> serde::Deserialize<'de> for TD3 {
 
                             total_it = Some(map.next_value()?);
                         }
+
+                        TD3Field::actor_lr => {
+                            if actor_lr.is_some() {
+                                return Err(serde::de::Error::duplicate_field("actor_lr"));
+                            }
+
+                            actor_lr = Some(map.next_value()?);
+                        }
+
+                        TD3Field::critic_lr => {
+                            if critic_lr.is_some() {
+                                return Err(serde::de::Error::duplicate_field("critic_lr"));
+                            }
+
+                            critic_lr = Some(map.next_value()?);
+                        }
+
+                        TD3Field::max_grad_norm => {
+                            if max_grad_norm.is_some() {
+                                return Err(serde::de::Error::duplicate_field("max_grad_norm"));
+                            }
+
+                            max_grad_norm = Some(map.next_value()?);
+                        }
+
+                        TD3Field::n_step => {
+                            if n_step.is_some() {
+                                return Err(serde::de::Error::duplicate_field("n_step"));
+                            }
+
+                            n_step = Some(map.next_value()?);
+                        }
+
+                        TD3Field::actor_opt_state => {
+                            if actor_opt_state.is_some() {
+                                return Err(serde::de::Error::duplicate_field("actor_opt_state"));
+                            }
+
+                            actor_opt_state = Some(map.next_value()?);
+                        }
+
+                        TD3Field::critic_opt_state => {
+                            if critic_opt_state.is_some() {
+                                return Err(serde::de::Error::duplicate_field("critic_opt_state"));
+                            }
+
+                            critic_opt_state = Some(map.next_value()?);
+                        }
+
+                        TD3Field::huber_delta => {
+                            if huber_delta.is_some() {
+                                return Err(serde::de::Error::duplicate_field("huber_delta"));
+                            }
+
+                            huber_delta = Some(map.next_value()?);
+                        }
+
+                        TD3Field::lr_schedule => {
+                            if lr_schedule.is_some() {
+                                return Err(serde::de::Error::duplicate_field("lr_schedule"));
+                            }
+
+                            lr_schedule = Some(map.next_value()?);
+                        }
+
+                        TD3Field::lr_floor => {
+                            if lr_floor.is_some() {
+                                return Err(serde::de::Error::duplicate_field("lr_floor"));
+                            }
+
+                            lr_floor = Some(map.next_value()?);
+                        }
+
+                        TD3Field::lr_horizon => {
+                            if lr_horizon.is_some() {
+                                return Err(serde::de::Error::duplicate_field("lr_horizon"));
+                            }
+
+                            lr_horizon = Some(map.next_value()?);
+                        }
+
+                        TD3Field::ou_theta => {
+                            if ou_theta.is_some() {
+                                return Err(serde::de::Error::duplicate_field("ou_theta"));
+                            }
+
+                            ou_theta = Some(map.next_value()?);
+                        }
+
+                        TD3Field::ou_sigma => {
+                            if ou_sigma.is_some() {
+                                return Err(serde::de::Error::duplicate_field("ou_sigma"));
+                            }
+
+                            ou_sigma = Some(map.next_value()?);
+                        }
+
+                        TD3Field::use_twin_critic => {
+                            if use_twin_critic.is_some() {
+                                return Err(serde::de::Error::duplicate_field("use_twin_critic"));
+                            }
+
+                            use_twin_critic = Some(map.next_value()?);
+                        }
+
+                        TD3Field::target_policy_smoothing => {
+                            if target_policy_smoothing.is_some() {
+                                return Err(serde::de::Error::duplicate_field("target_policy_smoothing"));
+                            }
+
+                            target_policy_smoothing = Some(map.next_value()?);
+                        }
+
+                        TD3Field::obs_normalizer => {
+                            if obs_normalizer.is_some() {
+                                return Err(serde::de::Error::duplicate_field("obs_normalizer"));
+                            }
+
+                            obs_normalizer = Some(map.next_value()?);
+                        }
+
+                        TD3Field::target_update_freq => {
+                            if target_update_freq.is_some() {
+                                return Err(serde::de::Error::duplicate_field("target_update_freq"));
+                            }
+
+                            target_update_freq = Some(map.next_value()?);
+                        }
+
+                        TD3Field::skipped_steps => {
+                            if skipped_steps.is_some() {
+                                return Err(serde::de::Error::duplicate_field("skipped_steps"));
+                            }
+
+                            skipped_steps = Some(map.next_value()?);
+                        }
+
+                        TD3Field::restore_on_non_finite => {
+                            if restore_on_non_finite.is_some() {
+                                return Err(serde::de::Error::duplicate_field("restore_on_non_finite"));
+                            }
+
+                            restore_on_non_finite = Some(map.next_value()?);
+                        }
                     }
                 }
 
@@ -1141,10 +2679,7 @@ impl<'de> serde::Deserialize<'de> for TD3 {
                 let critic: Critic = critic.ok_or_else(|| serde::de::Error::missing_field("critic"))?;
                 let critic_target: Critic = critic_target.ok_or_else(|| serde::de::Error::missing_field("critic_target"))?;
 
-                let actor_opt: Box<dyn MilkshakeOptimizer> = Box::new(ADAM::new(0.0003f64, actor.vs.clone()));
-                let critic_opt: Box<dyn MilkshakeOptimizer> = Box::new(ADAM::new(0.0003f64, critic.vs.clone()));
-
-                let action_dim = action_dim.ok_or_else(|| serde::de::Error::missing_field("action_dim"))?;
+                let action_dim: i64 = action_dim.ok_or_else(|| serde::de::Error::missing_field("action_dim"))?;
                 let state_dim = state_dim.ok_or_else(|| serde::de::Error::missing_field("state_dim"))?;
                 let max_action = max_action.ok_or_else(|| serde::de::Error::missing_field("max_action"))?;
                 let tau = tau.ok_or_else(|| serde::de::Error::missing_field("tau"))?;
@@ -1154,6 +2689,44 @@ impl<'de> serde::Deserialize<'de> for TD3 {
                 let policy_freq = policy_freq.ok_or_else(|| serde::de::Error::missing_field("policy_freq"))?;
                 let total_it = total_it.ok_or_else(|| serde::de::Error::missing_field("total_it"))?;
 
+                // checkpoints from before these were configurable used the TD3Builder defaults
+                let actor_lr: f64 = actor_lr.unwrap_or(0.0003);
+                let critic_lr: f64 = critic_lr.unwrap_or(0.0003);
+                let max_grad_norm: Option<f64> = max_grad_norm.unwrap_or(None);
+                let n_step: i64 = n_step.unwrap_or(1);
+
+                // older checkpoints have no optimizer state, those optimizers start fresh
+                let actor_opt_state: Option<Vec<u8>> = actor_opt_state.unwrap_or(None);
+                let critic_opt_state: Option<Vec<u8>> = critic_opt_state.unwrap_or(None);
+                let huber_delta: Option<f64> = huber_delta.unwrap_or(None);
+
+                // checkpoints from before schedules existed trained with a constant rate
+                let lr_schedule = parse_lr_schedule(lr_schedule.unwrap_or(None), lr_floor.unwrap_or(None))?;
+                let lr_horizon: i64 = lr_horizon.unwrap_or(None).unwrap_or(1_000_000);
+
+                let noise_process = noise_process_from_params(ou_theta.unwrap_or(None), ou_sigma.unwrap_or(None));
+
+                let use_twin_critic: bool = use_twin_critic.unwrap_or(None).unwrap_or(true);
+                let target_policy_smoothing: bool = target_policy_smoothing.unwrap_or(None).unwrap_or(true);
+
+                let obs_normalizer: Option<(f64, Vec<f64>, Vec<f64>)> = obs_normalizer.unwrap_or(None).unwrap_or(None);
+
+                let target_update_freq: i64 = target_update_freq.unwrap_or(None).unwrap_or(policy_freq);
+
+                let skipped_steps: i64 = skipped_steps.unwrap_or(None).unwrap_or(0);
+                let restore_on_non_finite: bool = restore_on_non_finite.unwrap_or(None).unwrap_or(false);
+
+                let mut actor_opt: Box<dyn MilkshakeOptimizer> = Box::new(ADAM::new(actor_lr, actor.vs.clone(), max_grad_norm));
+                let mut critic_opt: Box<dyn MilkshakeOptimizer> = Box::new(ADAM::new(critic_lr, critic.vs.clone(), max_grad_norm));
+
+                if let Some(state) = actor_opt_state {
+                    actor_opt.load_state(state.as_slice()).map_err(<V::Error as serde::de::Error>::custom)?;
+                }
+
+                if let Some(state) = critic_opt_state {
+                    critic_opt.load_state(state.as_slice()).map_err(<V::Error as serde::de::Error>::custom)?;
+                }
+
                 Ok(
                     TD3 {
                         actor,
@@ -1171,11 +2744,31 @@ impl<'de> serde::Deserialize<'de> for TD3 {
                         noise_clip,
                         policy_freq,
                         total_it,
+                        actor_lr,
+                        critic_lr,
+                        max_grad_norm,
+                        n_step,
+                        critic_loss: LossKind::from_huber_delta(huber_delta),
+                        lr_schedule,
+                        lr_horizon,
+                        noise_process,
+                        noise_state: vec![0f64; action_dim as usize],
+                        use_twin_critic,
+                        target_policy_smoothing,
+                        obs_normalizer: obs_normalizer.map(RunningNormalizer::from_stats),
+                        target_update_freq,
+                        skipped_steps,
+                        restore_on_non_finite,
+                        snapshot_freq: 100,
+                        last_good: None,
+                        amp: false,
+                        device: self.device,
+                        logger: None,
                     }
                 )
             }
         }
 
-        deserializer.deserialize_struct("TD3", TD3_FIELDS, TD3Visitor)
+        deserializer.deserialize_struct("TD3", td3_fields, TD3Visitor { device: self.0, fields: self.1 })
     }
 }
@@ -1,3 +1,3299 @@
 #[cfg(test)]
 mod tests {
+    use crate::environment::actionrepeat::ActionRepeat;
+    use crate::environment::halfcheetahenv::{HalfCheetahConfig, HalfCheetahEnv};
+    use crate::environment::normalize::{NormalizeAction, NormalizeObservation};
+    use crate::environment::stockenv::{portfolio_weights, RewardKind, StockEnv};
+    use crate::environment::timelimit::TimeLimit;
+    use crate::environment::vecenv::VecEnv;
+    use crate::environment::{Dtype, Environment, Restart, Spec, Terminate, Trajectory, Transition};
+    use crate::optimizer::adam::ADAM;
+    use crate::optimizer::MilkshakeOptimizer;
+    use crate::replay_buffer::ReplayBuffer;
+    use crate::sac::SAC;
+    use crate::stockframe::{stockframe_columns, CorporateAction, IndicatorConfig, StockFrame, Timeframe, WarmupStrategy};
+    use crate::td3::{snapshot_vs, soft_update, Activation, Actor, ActorHead, Critic, LossKind, LrSchedule, MilkshakeNetwork, NetworkKind, NoiseProcess, TD3};
+
+    fn make_td3(state_dim: i64, action_dim: i64) -> TD3 {
+        TD3::new(state_dim, action_dim, 1f64).expect("Failed to create TD3 Policy")
+    }
+
+    fn fill_buffer(state_dim: i64, action_dim: i64, size: usize) -> ReplayBuffer {
+        let mut rng = <rand::prelude::StdRng as rand::prelude::SeedableRng>::seed_from_u64(0);
+        let uniform = rand::distributions::Uniform::from(-1f64..1f64);
+        let mut sample = |len: i64| -> Vec<f64> {
+            (0..len)
+                .map(|_| rand::prelude::Distribution::sample(&uniform, &mut rng))
+                .collect()
+        };
+
+        let mut replay_buffer = ReplayBuffer::new(state_dim, action_dim, Some(size as i64));
+        for _ in 0..size {
+            let state = sample(state_dim);
+            let action = sample(action_dim);
+            let next_state = sample(state_dim);
+            let reward = sample(1)[0];
+
+            replay_buffer.add(state, action, next_state, reward, 0f64);
+        }
+
+        replay_buffer
+    }
+
+    // counts resets so tests can check how many episodes were run
+    struct CountingEnv {
+        inner: HalfCheetahEnv,
+        resets: usize,
+    }
+
+    impl Environment for CountingEnv {
+        fn action_spec(&self) -> Spec {
+            self.inner.action_spec()
+        }
+
+        fn observation_spec(&self) -> Spec {
+            self.inner.observation_spec()
+        }
+
+        fn step(&mut self, action: Vec<f64>) -> Box<dyn Trajectory> {
+            self.inner.step(action)
+        }
+
+        fn reset(&mut self) -> Box<dyn Trajectory> {
+            self.resets += 1;
+            self.inner.reset()
+        }
+    }
+
+    // replays a fixed observation stream and records the actions it receives
+    struct StreamEnv {
+        observations: Vec<Vec<f64>>,
+        idx: usize,
+        actions: std::rc::Rc<std::cell::RefCell<Vec<Vec<f64>>>>,
+        action_bounds: (f64, f64),
+    }
+
+    impl Environment for StreamEnv {
+        fn action_spec(&self) -> Spec {
+            Spec::new(self.action_bounds.0, self.action_bounds.1, 2)
+        }
+
+        fn observation_spec(&self) -> Spec {
+            Spec::new(f64::NEG_INFINITY, f64::INFINITY, self.observations[0].len() as u32)
+        }
+
+        fn step(&mut self, action: Vec<f64>) -> Box<dyn Trajectory> {
+            self.actions.borrow_mut().push(action);
+            self.idx = (self.idx + 1) % self.observations.len();
+
+            Box::new(Transition {
+                observation: self.observations[self.idx].clone(),
+                reward: 0f64,
+            })
+        }
+
+        fn reset(&mut self) -> Box<dyn Trajectory> {
+            self.idx = 0;
+
+            Box::new(Restart {
+                observation: self.observations[0].clone(),
+            })
+        }
+    }
+
+    // offline frame with one symbol per close series, one bar per minute and no indicators yet
+    fn synthetic_stockframe(closes: Vec<Vec<f64>>) -> StockFrame {
+        let mut frame = polars::prelude::DataFrame::default();
+        let mut tickers = Vec::new();
+
+        for (idx, close) in closes.iter().enumerate() {
+            let symbol = format!("SYM{}", idx);
+            let len = close.len();
+            let series = |name: &str, values: Vec<f64>| {
+                <polars::prelude::Series as polars::prelude::NamedFrom<Vec<f64>, _>>::new(name, values)
+            };
+
+            let mut columns = vec![
+                <polars::prelude::Series as polars::prelude::NamedFrom<Vec<String>, _>>::new(
+                    "symbol",
+                    vec![symbol.clone(); len],
+                ),
+                <polars::prelude::Series as polars::prelude::NamedFrom<Vec<i64>, _>>::new(
+                    "timestamp",
+                    (0..len as i64).map(|minute| 1704205800000 + minute * 60000).collect(),
+                )
+                .cast(&polars::prelude::DataType::Datetime(polars::prelude::TimeUnit::Milliseconds, None))
+                .unwrap(),
+                series("open", close.clone()),
+                series("high", close.iter().map(|price| price * 1.01).collect()),
+                series("low", close.iter().map(|price| price * 0.99).collect()),
+                series("close", close.clone()),
+                series("volume", vec![100f64; len]),
+                series("vwap", close.clone()),
+                series("trade_count", vec![10f64; len]),
+            ];
+
+            for name in &stockframe_columns[9..] {
+                columns.push(polars::prelude::Series::full_null(name, len, &polars::prelude::DataType::Float64));
+            }
+
+            frame = frame.vstack(&polars::prelude::DataFrame::new(columns).unwrap()).unwrap();
+            tickers.push(symbol);
+        }
+
+        StockFrame {
+            columns: stockframe_columns.iter().map(|s| String::from(*s)).collect(),
+            tickers,
+            timeframe: Timeframe::Min,
+            frame: std::cell::RefCell::new(frame),
+        }
+    }
+
+    fn indicator(stockframe: &StockFrame, symbol: &str, name: &str) -> Vec<Option<f64>> {
+        polars::prelude::IntoLazy::lazy(stockframe.frame.borrow().clone())
+            .filter(polars::prelude::col("symbol").eq(polars::prelude::lit(symbol)))
+            .sort(["timestamp"], Default::default())
+            .collect()
+            .unwrap()
+            .column(name)
+            .unwrap()
+            .f64()
+            .unwrap()
+            .into_iter()
+            .collect()
+    }
+
+    fn temp_file(name: &str) -> String {
+        std::env::temp_dir()
+            .join("milkshake_tests")
+            .join(name)
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn td3_save_load_round_trip() {
+        let mut td3 = make_td3(4, 2);
+        let mut replay_buffer = fill_buffer(4, 2, 64);
+
+        for _ in 0..4 {
+            td3.train(&mut replay_buffer, Some(16));
+        }
+
+        let filename = temp_file("td3_round_trip.json");
+        td3.save(filename.clone()).expect("Failed to save td3");
+        let loaded = TD3::load(filename).expect("Failed to load td3");
+
+        let state = vec![0.1, -0.2, 0.3, -0.4];
+        assert_eq!(td3.select_action(state.clone()), loaded.select_action(state));
+    }
+
+    #[test]
+    fn td3_warm_starts_actor_from_checkpoint() {
+        let mut pretrained = make_td3(4, 2);
+        let mut replay_buffer = fill_buffer(4, 2, 64);
+
+        for _ in 0..4 {
+            pretrained.train(&mut replay_buffer, Some(16));
+        }
+
+        let filename = temp_file("td3_pretrained_actor.json");
+        pretrained.save(filename.clone()).expect("Failed to save td3");
+
+        let mut td3 = make_td3(4, 2);
+        let critic_before = td3.critic.qs[0].layers[0].layer.ws.copy();
+        td3.load_actor_weights(filename).expect("Failed to load actor weights");
+
+        let state = vec![0.1, -0.2, 0.3, -0.4];
+        assert_eq!(td3.select_action(state.clone()), pretrained.select_action(state));
+
+        for (actor, target) in td3
+            .actor
+            .vs
+            .borrow()
+            .trainable_variables()
+            .iter()
+            .zip(td3.actor_target.vs.borrow().trainable_variables().iter())
+        {
+            assert!(actor.equal(target));
+        }
+
+        assert!(td3.critic.qs[0].layers[0].layer.ws.equal(&critic_before));
+    }
+
+    #[test]
+    fn td3_rejects_actor_with_mismatched_dims() {
+        let filename = temp_file("td3_mismatched_actor.json");
+        make_td3(4, 2).save(filename.clone()).expect("Failed to save td3");
+
+        let mut td3 = make_td3(4, 3);
+        assert!(td3.load_actor_weights(filename).is_err());
+    }
+
+    #[test]
+    fn td3_observation_normalizer_tracks_state_mean() {
+        let mut td3 = TD3::builder()
+            .state_dim(4)
+            .action_dim(2)
+            .max_action(1f64)
+            .normalize_observations(true)
+            .build()
+            .expect("Failed to create TD3 Policy");
+
+        let means = [3f64, -2f64, 0.5, 10f64];
+        let normal = rand_distr::Normal::new(0f64, 2f64).unwrap();
+        let mut rng = <rand::prelude::StdRng as rand::prelude::SeedableRng>::seed_from_u64(0);
+
+        for _ in 0..5000 {
+            let state: Vec<f64> = means
+                .iter()
+                .map(|mean| mean + rand::prelude::Distribution::sample(&normal, &mut rng))
+                .collect();
+            td3.sample_action(state, 0.1, &mut rng);
+        }
+
+        let normalizer = td3.obs_normalizer.as_ref().unwrap();
+        assert_eq!(normalizer.count, 5000f64);
+
+        for (mean, expected) in normalizer.mean.iter().zip(means.iter()) {
+            assert!((mean - expected).abs() < 0.2);
+        }
+
+        for variance in normalizer.variance() {
+            assert!((variance - 4f64).abs() < 0.5);
+        }
+
+        // evaluation reads the statistics without moving them
+        td3.select_action(vec![100f64; 4]);
+        assert_eq!(td3.obs_normalizer.as_ref().unwrap().count, 5000f64);
+
+        let filename = temp_file("td3_normalizer.json");
+        td3.save(filename.clone()).expect("Failed to save td3");
+        let loaded = TD3::load(filename).expect("Failed to load td3");
+
+        assert_eq!(loaded.obs_normalizer.as_ref().unwrap().mean, td3.obs_normalizer.as_ref().unwrap().mean);
+        assert_eq!(loaded.select_action(vec![1f64; 4]), td3.select_action(vec![1f64; 4]));
+
+        // off by default
+        assert!(make_td3(4, 2).obs_normalizer.is_none());
+    }
+
+    #[test]
+    fn td3_normalizer_counts_each_state_once() {
+        let mut td3 = TD3::builder()
+            .state_dim(4)
+            .action_dim(2)
+            .max_action(1f64)
+            .normalize_observations(true)
+            .build()
+            .expect("Failed to create TD3 Policy");
+        let mut rng = <rand::prelude::StdRng as rand::prelude::SeedableRng>::seed_from_u64(0);
+        let mut replay_buffer = fill_buffer(4, 2, 512);
+
+        td3.sample_action(vec![1f64, 2f64, 3f64, 4f64], 0.1, &mut rng);
+        td3.train_accumulated(&mut replay_buffer, Some(256), 2);
+
+        // the replayed batches are read through the statistics, not added to them
+        let normalizer = td3.obs_normalizer.as_ref().unwrap();
+        assert_eq!(normalizer.count, 1f64);
+        assert_eq!(normalizer.mean, vec![1f64, 2f64, 3f64, 4f64]);
+    }
+
+    #[test]
+    fn td3_smoothing_noise_is_zero_mean() {
+        let td3 = make_td3(4, 2);
+        let noise = td3.smoothing_noise(&tch::Tensor::zeros([10000, 2], (tch::Kind::Float, tch::Device::Cpu)));
+
+        assert!(noise.mean(tch::Kind::Float).double_value(&[]).abs() < 0.01);
+        assert!(noise.min().double_value(&[]) < 0f64);
+        assert!(noise.max().double_value(&[]) > 0f64);
+        assert!(noise.abs().max().double_value(&[]) <= td3.noise_clip);
+    }
+
+    #[test]
+    fn target_update_freq_is_independent_of_policy_freq() {
+        let mut td3 = TD3::builder()
+            .state_dim(4)
+            .action_dim(2)
+            .max_action(1f64)
+            .policy_freq(2)
+            .target_update_freq(1)
+            .build()
+            .expect("Failed to create TD3 Policy");
+        let mut replay_buffer = fill_buffer(4, 2, 64);
+
+        let weights = |vs: &std::rc::Rc<std::cell::RefCell<tch::nn::VarStore>>| -> Vec<tch::Tensor> {
+            vs.borrow().trainable_variables().iter().map(|var| var.copy()).collect()
+        };
+        let moved = |before: &Vec<tch::Tensor>, after: &Vec<tch::Tensor>| {
+            before.iter().zip(after.iter()).any(|(before, after)| !before.equal(after))
+        };
+
+        for it in 1..=4 {
+            let actor = weights(&td3.actor.vs);
+            let actor_target = weights(&td3.actor_target.vs);
+            let critic_target = weights(&td3.critic_target.vs);
+
+            td3.train(&mut replay_buffer, Some(16));
+
+            assert!(moved(&critic_target, &weights(&td3.critic_target.vs)));
+            assert_eq!(moved(&actor, &weights(&td3.actor.vs)), it % 2 == 0);
+
+            // the actor target keeps tracking the actor between actor updates
+            if it == 3 {
+                assert!(moved(&actor_target, &weights(&td3.actor_target.vs)));
+            }
+        }
+    }
+
+    #[test]
+    fn td3_skips_updates_with_non_finite_loss() {
+        let mut td3 = make_td3(4, 2);
+        let filled = fill_buffer(4, 2, 64);
+
+        let mut poisoned = ReplayBuffer::new(4, 2, Some(64));
+        for idx in 0..64 {
+            let (state, action, next_state, _, _) = filled.transition(idx);
+            poisoned.add(state, action, next_state, f64::NAN, 0f64);
+        }
+
+        let weights = |td3: &TD3| -> Vec<Vec<tch::Tensor>> {
+            [&td3.actor.vs, &td3.actor_target.vs, &td3.critic.vs, &td3.critic_target.vs]
+                .iter()
+                .map(|vs| snapshot_vs(&vs.borrow()))
+                .collect()
+        };
+        let unchanged = |before: &Vec<Vec<tch::Tensor>>, after: &Vec<Vec<tch::Tensor>>| {
+            before.iter().flatten().zip(after.iter().flatten()).all(|(before, after)| before.equal(after))
+        };
+
+        let before = weights(&td3);
+        for _ in 0..2 {
+            td3.train(&mut poisoned, Some(16));
+        }
+
+        assert_eq!(td3.skipped_steps, 2);
+        assert_eq!(td3.total_it, 2);
+        assert!(unchanged(&before, &weights(&td3)));
+    }
+
+    #[test]
+    fn td3_restores_last_good_weights_after_non_finite_loss() {
+        let mut td3 = TD3::builder()
+            .state_dim(4)
+            .action_dim(2)
+            .max_action(1f64)
+            .restore_on_non_finite(true)
+            .build()
+            .expect("Failed to create TD3 Policy");
+        let mut replay_buffer = fill_buffer(4, 2, 64);
+
+        td3.train(&mut replay_buffer, Some(16));
+        assert_eq!(td3.skipped_steps, 0);
+        let good = snapshot_vs(&td3.critic.vs.borrow());
+
+        // corrupt the critic the way a bad update would
+        tch::no_grad(|| {
+            for var in td3.critic.vs.borrow_mut().trainable_variables().iter_mut() {
+                let _ = var.fill_(f64::NAN);
+            }
+        });
+
+        td3.train(&mut replay_buffer, Some(16));
+        assert_eq!(td3.skipped_steps, 1);
+
+        let restored = snapshot_vs(&td3.critic.vs.borrow());
+        assert!(good.iter().zip(restored.iter()).all(|(good, restored)| good.equal(restored)));
+    }
+
+    #[test]
+    fn td3_snapshots_every_snapshot_freq_steps_with_optimizer_state() {
+        let mut td3 = TD3::builder()
+            .state_dim(4)
+            .action_dim(2)
+            .max_action(1f64)
+            .restore_on_non_finite(true)
+            .snapshot_freq(4)
+            .build()
+            .expect("Failed to create TD3 Policy");
+        let mut replay_buffer = fill_buffer(4, 2, 64);
+
+        // the first clean step is snapshotted, the next ones only on multiples of snapshot_freq
+        td3.train(&mut replay_buffer, Some(16));
+        let good = snapshot_vs(&td3.critic.vs.borrow());
+        let good_opt = td3.critic_opt.save_state().unwrap();
+
+        for _ in 0..2 {
+            td3.train(&mut replay_buffer, Some(16));
+        }
+
+        let snapshot = td3.last_good.as_ref().unwrap();
+        assert!(good.iter().zip(snapshot.critic.iter()).all(|(good, saved)| good.equal(saved)));
+        assert_eq!(snapshot.critic_opt_state, good_opt);
+
+        tch::no_grad(|| {
+            for var in td3.critic.vs.borrow_mut().trainable_variables().iter_mut() {
+                let _ = var.fill_(f64::NAN);
+            }
+        });
+        td3.train(&mut replay_buffer, Some(16));
+        assert_eq!(td3.skipped_steps, 1);
+
+        // weights and adam moments go back to the same step
+        let restored = snapshot_vs(&td3.critic.vs.borrow());
+        assert!(good.iter().zip(restored.iter()).all(|(good, restored)| good.equal(restored)));
+        assert_eq!(td3.critic_opt.save_state().unwrap(), good_opt);
+    }
+
+    #[test]
+    fn td3_builder_sets_named_fields() {
+        let td3 = TD3::builder()
+            .state_dim(6)
+            .action_dim(3)
+            .max_action(2f64)
+            .actor_shape(vec![32, 16])
+            .critic_shapes(vec![24], vec![8, 8])
+            .tau(0.01)
+            .discount(0.95)
+            .policy_noise(0.3)
+            .noise_clip(0.4)
+            .policy_freq(3)
+            .lr(0.001)
+            .build()
+            .expect("Failed to build TD3 Policy");
+
+        assert_eq!(td3.state_dim, 6);
+        assert_eq!(td3.action_dim, 3);
+        assert_eq!(td3.max_action, 2f64);
+        assert_eq!(td3.tau, 0.01);
+        assert_eq!(td3.discount, 0.95);
+        assert_eq!(td3.policy_noise, 0.3);
+        assert_eq!(td3.noise_clip, 0.4);
+        assert_eq!(td3.policy_freq, 3);
+        assert_eq!(td3.actor_lr, 0.001);
+        assert_eq!(td3.critic_lr, 0.001);
+
+        let widths = |network: &MilkshakeNetwork| -> Vec<i64> {
+            network.layers.iter().map(|layer| layer.output).collect()
+        };
+        assert_eq!(widths(&td3.actor.actor), vec![32, 16, 3]);
+        assert_eq!(widths(&td3.critic.qs[0]), vec![24, 1]);
+        assert_eq!(widths(&td3.critic.qs[1]), vec![8, 8, 1]);
+
+        // unset options fall back to the defaults
+        assert_eq!(td3.target_update_freq, 3);
+        assert!(td3.use_twin_critic);
+
+        assert!(TD3::builder().state_dim(6).max_action(1f64).build().is_err());
+    }
+
+    #[test]
+    fn td3_rejects_empty_actor_shape() {
+        let td3 = TD3::builder()
+            .state_dim(4)
+            .action_dim(2)
+            .max_action(1f64)
+            .actor_shape(vec![])
+            .build();
+
+        assert!(td3.is_err());
+    }
+
+    #[test]
+    fn td3_rejects_zero_action_dim() {
+        let td3 = TD3::new(4, 0, 1f64);
+
+        assert!(td3.is_err());
+    }
+
+    #[test]
+    fn td3_rejected_config_leaves_no_log() {
+        let log_path = temp_file("rejected_td3_log.csv");
+        let _ = std::fs::remove_file(&log_path);
+
+        let td3 = TD3::builder().state_dim(4).action_dim(0).max_action(1f64).log_path(log_path.clone()).build();
+
+        assert!(td3.is_err());
+        assert!(!std::path::Path::new(&log_path).exists());
+    }
+
+    #[test]
+    fn td3_delays_actor_updates_by_policy_freq() {
+        let mut td3 = TD3::builder()
+            .state_dim(4)
+            .action_dim(2)
+            .max_action(1f64)
+            .policy_freq(2)
+            .build()
+            .expect("Failed to create TD3 Policy");
+        let mut replay_buffer = fill_buffer(4, 2, 64);
+
+        let snapshot = |td3: &TD3| -> Vec<tch::Tensor> {
+            td3.actor.vs.borrow().trainable_variables().iter().map(|var| var.copy()).collect()
+        };
+
+        for call in 1..=6 {
+            let before = snapshot(&td3);
+            td3.train(&mut replay_buffer, Some(16));
+            let after = snapshot(&td3);
+
+            let changed = before.iter().zip(after.iter()).any(|(before, after)| !before.equal(after));
+            assert_eq!(changed, call % 2 == 0);
+        }
+
+        assert_eq!(td3.total_it, 6);
+    }
+
+    #[test]
+    fn td3_logs_one_row_per_train_step() {
+        let filename = temp_file("td3_metrics.csv");
+        let mut td3 = TD3::builder()
+            .state_dim(4)
+            .action_dim(2)
+            .max_action(1f64)
+            .log_path(filename.clone())
+            .build()
+            .expect("Failed to create TD3 Policy");
+        let mut replay_buffer = fill_buffer(4, 2, 64);
+
+        for _ in 0..4 {
+            td3.train(&mut replay_buffer, Some(16));
+        }
+
+        let data = std::fs::read_to_string(filename).expect("Failed to read training metrics");
+        let rows: Vec<&str> = data.lines().collect();
+
+        assert_eq!(rows[0], "total_it,critic_loss,actor_loss,target_q");
+        assert_eq!(rows.len(), 5);
+
+        for (idx, row) in rows[1..].iter().enumerate() {
+            let fields: Vec<&str> = row.split(',').collect();
+            assert_eq!(fields.len(), 4);
+            assert_eq!(fields[0], (idx + 1).to_string());
+            // the actor is only updated every policy_freq = 2 steps
+            assert_eq!(fields[2].is_empty(), (idx + 1) % 2 != 0);
+        }
+    }
+
+    #[test]
+    fn td3_inference_does_not_track_gradients() {
+        let td3 = make_td3(4, 2);
+        let state = tch::Tensor::rand([8, 4], (tch::Kind::Float, **crate::device));
+
+        assert!(td3.actor.forward(&state).requires_grad());
+        assert!(!td3.infer(&state).requires_grad());
+    }
+
+    #[test]
+    fn td3_batch_actions_match_single_actions() {
+        let td3 = make_td3(4, 2);
+        let states = vec![
+            vec![0.1, -0.2, 0.3, -0.4],
+            vec![1.0, 0.5, -0.5, 0.0],
+            vec![-0.3, 0.8, 0.2, 0.6],
+        ];
+
+        let batch = td3.select_actions_batch(&states);
+        assert_eq!(batch.len(), states.len());
+
+        for (state, batch_action) in states.iter().zip(batch.iter()) {
+            let action = td3.select_action(state.clone());
+            assert_eq!(action.len(), batch_action.len());
+
+            for (single, batched) in action.iter().zip(batch_action.iter()) {
+                assert!((single - batched).abs() < 1e-6);
+            }
+        }
+    }
+
+    #[test]
+    fn td3_json_checkpoint_nests_networks_as_objects() {
+        let td3 = make_td3(4, 2);
+
+        let filename = temp_file("td3_structure.json");
+        td3.save(filename.clone()).expect("Failed to save td3");
+
+        let data = std::fs::read_to_string(filename).expect("Failed to read td3 save file");
+        let json: serde_json::Value = serde_json::from_str(data.as_str()).expect("Save file is not json");
+
+        assert!(json.is_object());
+        assert!(json["actor"].is_object());
+        assert!(json["critic"].is_object());
+    }
+
+    #[test]
+    fn td3_loads_checkpoint_without_optimizer_settings() {
+        let td3 = make_td3(4, 2);
+
+        let filename = temp_file("td3_without_optimizer_settings.json");
+        td3.save(filename.clone()).expect("Failed to save td3");
+
+        // checkpoints from before learning rates, clipping and n-step returns were saved
+        let data = std::fs::read_to_string(filename.clone()).expect("Failed to read td3 save file");
+        let mut json: serde_json::Value = serde_json::from_str(data.as_str()).expect("Save file is not json");
+        for field in ["actor_lr", "critic_lr", "max_grad_norm", "n_step"] {
+            json.as_object_mut().unwrap().remove(field);
+        }
+        std::fs::write(filename.clone(), json.to_string()).expect("Failed to write td3 save file");
+
+        let loaded = TD3::load(filename).expect("Failed to load td3");
+        assert_eq!(loaded.actor_lr, 0.0003);
+        assert_eq!(loaded.critic_lr, 0.0003);
+        assert_eq!(loaded.max_grad_norm, None);
+        assert_eq!(loaded.n_step, 1);
+    }
+
+    #[test]
+    fn saved_weights_are_stored_as_f32() {
+        let td3 = make_td3(4, 2);
+
+        for vs in [&td3.actor.vs, &td3.critic.vs] {
+            let num_params = vs
+                .borrow()
+                .trainable_variables()
+                .iter()
+                .map(|var| var.numel())
+                .sum::<usize>();
+
+            let mut cursor = std::io::Cursor::new(Vec::<u8>::new());
+            vs.borrow().save_to_stream(&mut cursor).expect("Failed to save varstore");
+
+            let tensors = tch::Tensor::load_multi_from_stream(std::io::Cursor::new(cursor.into_inner()))
+                .expect("Failed to read saved varstore");
+            assert!(tensors.iter().all(|(_, tensor)| tensor.kind() == tch::Kind::Float));
+
+            let data_bytes = tensors.iter().map(|(_, tensor)| tensor.numel() * tensor.kind().elt_size_in_bytes()).sum::<usize>();
+            assert_eq!(data_bytes, num_params * 4);
+        }
+    }
+
+    #[test]
+    fn td3_binary_checkpoint_is_smaller_than_json() {
+        let mut td3 = make_td3(4, 2);
+        let mut replay_buffer = fill_buffer(4, 2, 64);
+        td3.train(&mut replay_buffer, Some(16));
+
+        let json_filename = temp_file("td3_size.json");
+        let bin_filename = temp_file("td3_size.bin");
+        let json_len = td3.save(json_filename).expect("Failed to save td3 as json");
+        let bin_len = td3.save(bin_filename.clone()).expect("Failed to save td3 as binary");
+
+        assert!(bin_len < json_len);
+
+        let loaded = TD3::load(bin_filename).expect("Failed to load binary td3");
+        let state = vec![0.1, -0.2, 0.3, -0.4];
+        assert_eq!(td3.select_action(state.clone()), loaded.select_action(state));
+    }
+
+    #[test]
+    fn td3_loads_binary_checkpoint_from_before_the_last_field() {
+        let mut td3 = make_td3(4, 2);
+        td3.restore_on_non_finite = true;
+
+        let filename = temp_file("td3_current_layout.bin");
+        td3.save(filename.clone()).expect("Failed to save td3 as binary");
+
+        // the layout before restore_on_non_finite was appended, its Some(true) is the last two bytes of the body
+        let data = std::fs::read(&filename).unwrap();
+        let body = &data[8..data.len() - 2];
+        let older_fields = crate::td3::td3_fields.len() as u32 - 1;
+
+        let with_header = temp_file("td3_older_layout.bin");
+        std::fs::write(&with_header, [&crate::td3::td3_bin_magic[..], &older_fields.to_le_bytes()[..], body].concat()).unwrap();
+
+        // and as it was written before .bin files had a header
+        let headerless = temp_file("td3_headerless_layout.bin");
+        std::fs::write(&headerless, body).unwrap();
+
+        let state = vec![0.1, -0.2, 0.3, -0.4];
+        for filename in [with_header, headerless] {
+            let loaded = TD3::load(filename).expect("Failed to load older binary td3");
+
+            assert!(!loaded.restore_on_non_finite);
+            assert_eq!(loaded.total_it, td3.total_it);
+            assert_eq!(loaded.select_action(state.clone()), td3.select_action(state.clone()));
+        }
+
+        assert!(TD3::load(filename).unwrap().restore_on_non_finite);
+    }
+
+    #[test]
+    fn td3_resumes_training_from_checkpoint() {
+        let mut td3 = make_td3(4, 2);
+        let mut replay_buffer = fill_buffer(4, 2, 64);
+
+        for _ in 0..3 {
+            td3.train(&mut replay_buffer, Some(16));
+        }
+
+        let filename = temp_file("td3_resume.json");
+        td3.save(filename.clone()).expect("Failed to save td3");
+        let mut loaded = TD3::load(filename).expect("Failed to load td3");
+        assert_eq!(loaded.total_it, td3.total_it);
+
+        // the cloned buffer samples the same batch, the seed fixes the target policy noise
+        let mut resumed_buffer = replay_buffer.clone();
+
+        tch::manual_seed(0);
+        td3.train(&mut replay_buffer, Some(16));
+        tch::manual_seed(0);
+        loaded.train(&mut resumed_buffer, Some(16));
+
+        let pairs = [
+            (td3.actor.vs.clone(), loaded.actor.vs.clone()),
+            (td3.critic.vs.clone(), loaded.critic.vs.clone()),
+        ];
+
+        for (uninterrupted, resumed) in pairs {
+            let uninterrupted = uninterrupted.borrow().trainable_variables();
+            let resumed = resumed.borrow().trainable_variables();
+
+            for (a, b) in uninterrupted.iter().zip(resumed.iter()) {
+                assert!(a.allclose(b, 1e-6, 1e-6, false));
+            }
+        }
+    }
+
+    #[test]
+    fn critic_serialize_round_trip() {
+        let critic = Critic::new(4, 2, vec![vec![16, 16], vec![16, 16]], None, None, None);
+
+        let data = serde_json::to_string(&critic).expect("Failed to serialize critic");
+        let loaded: Critic = serde_json::from_str(data.as_str()).expect("Failed to deserialize critic");
+
+        let xs = tch::Tensor::rand([8, 6], (tch::Kind::Float, **crate::device));
+        let q1 = critic.Q1(&xs);
+        let loaded_q1 = loaded.Q1(&xs);
+
+        assert!(q1.allclose(&loaded_q1, 1e-6, 1e-6, false));
+    }
+
+    #[test]
+    fn actor_serialize_preserves_weight_values() {
+        let actor = Actor::new(4, 2, vec![16, 16], 1f64, None, None, None);
+
+        // values that would be garbled if the bytes were read back with the wrong dtype
+        tch::no_grad(|| {
+            for (idx, mut var) in actor.vs.borrow().trainable_variables().into_iter().enumerate() {
+                let _ = var.fill_(0.125 * (idx + 1) as f64);
+            }
+        });
+
+        let data = serde_json::to_string(&actor).expect("Failed to serialize actor");
+        let loaded: Actor = serde_json::from_str(data.as_str()).expect("Failed to deserialize actor");
+
+        let loaded_variables = loaded.vs.borrow().trainable_variables();
+        assert_eq!(loaded_variables.len(), 6);
+
+        for (idx, var) in loaded_variables.iter().enumerate() {
+            assert_eq!(var.kind(), tch::Kind::Float);
+            assert_eq!(var.min().double_value(&[]), 0.125 * (idx + 1) as f64);
+            assert_eq!(var.max().double_value(&[]), 0.125 * (idx + 1) as f64);
+        }
+    }
+
+    #[test]
+    fn actor_forward_uses_every_hidden_layer() {
+        let mut actor = Actor::new(4, 2, vec![8, 16, 32], 1f64, None, None, None);
+        assert_eq!(actor.actor.layers.len(), 4);
+
+        let state = tch::Tensor::rand([3, 4], (tch::Kind::Float, **crate::device));
+        let before = actor.forward(&state);
+
+        // zeroing a middle hidden layer must change the output if that layer is actually applied
+        tch::no_grad(|| {
+            let _ = actor.actor.layers[2].layer.ws.zero_();
+            let _ = actor.actor.layers[2].layer.bs.as_mut().unwrap().fill_(1f64);
+        });
+
+        let after = actor.forward(&state);
+
+        assert_eq!(after.size(), vec![3, 2]);
+        assert!(!before.allclose(&after, 1e-6, 1e-6, false));
+    }
+
+    #[test]
+    fn actor_layers_span_state_to_action() {
+        let actor = Actor::new(17, 6, vec![64, 64], 1f64, None, None, None);
+        let layers = &actor.actor.layers;
+
+        assert_eq!(layers.len(), 3);
+        assert_eq!(layers.first().unwrap().input, 17);
+        assert_eq!(layers.first().unwrap().layer.ws.size()[1], 17);
+        assert_eq!(layers.last().unwrap().output, 6);
+        assert_eq!(layers.last().unwrap().layer.ws.size()[0], 6);
+    }
+
+    #[test]
+    fn soft_update_with_unit_tau_copies_online_network() {
+        let actor = Actor::new(4, 2, vec![16, 16], 1f64, None, None, None);
+        let actor_target = Actor::new(4, 2, vec![16, 16], 1f64, None, None, None);
+
+        soft_update(&actor.vs.borrow(), &mut actor_target.vs.borrow_mut(), 1f64);
+
+        let state = tch::Tensor::rand([8, 4], (tch::Kind::Float, **crate::device));
+        assert!(actor.forward(&state).allclose(&actor_target.forward(&state), 1e-6, 1e-6, false));
+    }
+
+    #[test]
+    fn critic_dropout_only_applies_in_training() {
+        let critic = Critic::new(4, 2, vec![vec![64, 64], vec![64, 64]], None, Some(0.5), None);
+
+        let state = tch::Tensor::rand([8, 4], (tch::Kind::Float, **crate::device));
+        let action = tch::Tensor::rand([8, 2], (tch::Kind::Float, **crate::device));
+
+        let train_a = critic.forward_t(&state, &action, true);
+        let train_b = critic.forward_t(&state, &action, true);
+        assert!(!train_a.allclose(&train_b, 1e-6, 1e-6, false));
+
+        let eval_a = critic.forward(&state, &action);
+        let eval_b = critic.forward(&state, &action);
+        assert!(eval_a.allclose(&eval_b, 1e-6, 1e-6, false));
+    }
+
+    #[test]
+    fn critic_target_takes_elementwise_min_of_twin_q() {
+        let critic = Critic::new(4, 2, vec![vec![16, 16], vec![16, 16]], None, None, None);
+
+        let state = tch::Tensor::rand([32, 4], (tch::Kind::Float, **crate::device));
+        let action = tch::Tensor::rand([32, 2], (tch::Kind::Float, **crate::device));
+
+        let q = critic.forward(&state, &action);
+        assert_eq!(q.size(), vec![32, 2]);
+        let (q1, q2) = (q.narrow(1, 0, 1), q.narrow(1, 1, 1));
+
+        // independently initialized heads, the two estimates should not agree
+        assert!(!q1.allclose(&q2, 1e-6, 1e-6, false));
+
+        let min_q = critic.min_over_subset(&q, 2);
+
+        for idx in 0..32 {
+            let expected = q1.double_value(&[idx, 0]).min(q2.double_value(&[idx, 0]));
+            assert_eq!(min_q.double_value(&[idx, 0]), expected);
+        }
+    }
+
+    #[test]
+    fn critic_ensemble_returns_one_column_per_q_network() {
+        let critic = Critic::new(4, 2, vec![vec![16, 16]; 4], None, None, None);
+
+        let state = tch::Tensor::rand([32, 4], (tch::Kind::Float, **crate::device));
+        let action = tch::Tensor::rand([32, 2], (tch::Kind::Float, **crate::device));
+
+        let q = critic.forward(&state, &action);
+        assert_eq!(q.size(), vec![32, 4]);
+
+        // a redq subset never undercuts the minimum of the whole ensemble
+        let min_q = critic.min_over_subset(&q, 4);
+        let subset_min_q = critic.min_over_subset(&q, 2);
+        assert_eq!(subset_min_q.size(), vec![32, 1]);
+        assert!(bool::try_from(subset_min_q.ge_tensor(&min_q).all()).unwrap());
+
+        // networks past the twin pair survive a save file
+        let data = serde_json::to_string(&critic).expect("Failed to serialize critic");
+        let loaded: Critic = serde_json::from_str(data.as_str()).expect("Failed to deserialize critic");
+        assert_eq!(loaded.qs.len(), 4);
+        assert!(q.allclose(&loaded.forward(&state, &action), 1e-6, 1e-6, false));
+    }
+
+    #[test]
+    fn td3_trains_a_critic_ensemble() {
+        let mut td3 = TD3::builder()
+            .state_dim(4)
+            .action_dim(2)
+            .max_action(1f64)
+            .num_critics(4)
+            .build()
+            .expect("Failed to build TD3 Policy");
+        let mut replay_buffer = fill_buffer(4, 2, 64);
+
+        let weights = |td3: &TD3| -> Vec<tch::Tensor> { td3.critic.qs.iter().map(|q| q.layers[0].layer.ws.copy()).collect() };
+
+        let before = weights(&td3);
+        td3.train(&mut replay_buffer, Some(16));
+        let after = weights(&td3);
+
+        assert_eq!(td3.critic.qs.len(), 4);
+        assert_eq!(td3.critic_target.qs.len(), 4);
+        assert!(before.iter().zip(after.iter()).all(|(before, after)| !before.equal(after)));
+    }
+
+    #[test]
+    fn critic_loss_is_scalar() {
+        let critic = Critic::new(4, 2, vec![vec![16, 16], vec![16, 16]], None, None, None);
+
+        let state = tch::Tensor::rand([8, 4], (tch::Kind::Float, **crate::device));
+        let action = tch::Tensor::rand([8, 2], (tch::Kind::Float, **crate::device));
+        let target_q = tch::Tensor::rand([8, 1], (tch::Kind::Float, **crate::device));
+
+        assert_eq!(critic.loss(&state, &action, &target_q, LossKind::Mse, true, None).dim(), 0);
+    }
+
+    #[test]
+    fn single_critic_loss_uses_only_q1() {
+        let critic = Critic::new(4, 2, vec![vec![16, 16], vec![16, 16]], None, None, None);
+
+        let state = tch::Tensor::rand([8, 4], (tch::Kind::Float, **crate::device));
+        let action = tch::Tensor::rand([8, 2], (tch::Kind::Float, **crate::device));
+        let target_q = tch::Tensor::rand([8, 1], (tch::Kind::Float, **crate::device));
+
+        let q = critic.forward(&state, &action);
+        let (q1, q2) = (q.narrow(1, 0, 1), q.narrow(1, 1, 1));
+        let q1_loss = LossKind::Mse.apply(&q1, &target_q).double_value(&[]);
+        let q2_loss = LossKind::Mse.apply(&q2, &target_q).double_value(&[]);
+
+        let single = critic.loss(&state, &action, &target_q, LossKind::Mse, false, None).double_value(&[]);
+        let twin = critic.loss(&state, &action, &target_q, LossKind::Mse, true, None).double_value(&[]);
+
+        assert!((single - q1_loss).abs() < 1e-6);
+        assert!((twin - (q1_loss + q2_loss)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn ddpg_mode_never_updates_q2() {
+        let mut td3 = TD3::builder()
+            .state_dim(4)
+            .action_dim(2)
+            .max_action(1f64)
+            .use_twin_critic(false)
+            .target_policy_smoothing(false)
+            .build()
+            .expect("Failed to create TD3 Policy");
+        let mut replay_buffer = fill_buffer(4, 2, 64);
+
+        let q2_weights = |td3: &TD3| -> Vec<tch::Tensor> {
+            td3.critic.qs[1].layers.iter().map(|layer| layer.layer.ws.copy()).collect()
+        };
+
+        let before = q2_weights(&td3);
+        for _ in 0..4 {
+            td3.train(&mut replay_buffer, Some(16));
+        }
+        let after = q2_weights(&td3);
+
+        assert!(before.iter().zip(after.iter()).all(|(before, after)| before.equal(after)));
+    }
+
+    #[test]
+    fn amp_training_keeps_weights_finite() {
+        let builder = TD3::builder().state_dim(4).action_dim(2).max_action(1f64).amp(true);
+
+        // off cuda amp falls back to fp32 training
+        let td3 = builder.clone().compute_device(tch::Device::Cpu).build().expect("Failed to build TD3 Policy");
+        assert!(!td3.amp);
+
+        if !tch::Cuda::is_available() {
+            return;
+        }
+
+        let mut td3 = builder.compute_device(tch::Device::Cuda(0)).build().expect("Failed to build TD3 Policy");
+        assert!(td3.amp);
+
+        let mut replay_buffer = fill_buffer(4, 2, 64);
+        for _ in 0..2 {
+            td3.train(&mut replay_buffer, Some(16));
+        }
+
+        assert_eq!(td3.skipped_steps, 0);
+        for vs in [&td3.actor.vs, &td3.critic.vs] {
+            for var in vs.borrow().trainable_variables() {
+                assert!(bool::try_from(var.isfinite().all()).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn norms_are_finite_and_nonzero_after_training() {
+        let mut td3 = make_td3(4, 2);
+        let mut replay_buffer = fill_buffer(4, 2, 64);
+
+        // the second call is the first to update the actor
+        td3.train(&mut replay_buffer, Some(16));
+        td3.train(&mut replay_buffer, Some(16));
+
+        let weights_before = td3.weight_norms();
+        let grad_norms = td3.grad_norms();
+        let weight_norms = td3.weight_norms();
+
+        for norms in [&grad_norms, &weight_norms] {
+            assert_eq!(norms.critics.len(), 2);
+            for norm in std::iter::once(norms.actor).chain(norms.critics.iter().copied()) {
+                assert!(norm.is_finite() && norm > 0f64);
+            }
+        }
+
+        assert_eq!(weight_norms, weights_before);
+        assert_eq!(td3.grad_norms(), grad_norms);
+    }
+
+    #[test]
+    fn frozen_actor_layer_keeps_its_weights() {
+        let mut td3 = make_td3(4, 2);
+        let mut replay_buffer = fill_buffer(4, 2, 64);
+
+        // train first so adam carries momentum into the frozen layer
+        for _ in 0..4 {
+            td3.train(&mut replay_buffer, Some(16));
+        }
+
+        td3.set_layer_trainable(NetworkKind::Actor, 0, false);
+
+        let actor_weights = |td3: &TD3| -> Vec<tch::Tensor> {
+            td3.actor.actor.layers.iter().map(|layer| layer.layer.ws.copy()).collect()
+        };
+
+        let frozen_bias = td3.actor.actor.layers[0].layer.bs.as_ref().unwrap().copy();
+        let before = actor_weights(&td3);
+        for _ in 0..4 {
+            td3.train(&mut replay_buffer, Some(16));
+        }
+        let after = actor_weights(&td3);
+
+        assert!(after[0].equal(&before[0]));
+        assert!(td3.actor.actor.layers[0].layer.bs.as_ref().unwrap().equal(&frozen_bias));
+        assert!(before[1..].iter().zip(after[1..].iter()).all(|(before, after)| !before.equal(after)));
+
+        td3.set_layer_trainable(NetworkKind::Actor, 0, true);
+        td3.train(&mut replay_buffer, Some(16));
+        td3.train(&mut replay_buffer, Some(16));
+        assert!(!actor_weights(&td3)[0].equal(&before[0]));
+    }
+
+    #[test]
+    fn huber_critic_loss_damps_large_td_errors() {
+        let critic = Critic::new(4, 2, vec![vec![16, 16], vec![16, 16]], None, None, None);
+
+        let state = tch::Tensor::rand([8, 4], (tch::Kind::Float, **crate::device));
+        let action = tch::Tensor::rand([8, 2], (tch::Kind::Float, **crate::device));
+        let target_q = tch::Tensor::full([8, 1], 100f64, (tch::Kind::Float, **crate::device));
+
+        let mse = critic.loss(&state, &action, &target_q, LossKind::Mse, true, None).double_value(&[]);
+        let huber = critic.loss(&state, &action, &target_q, LossKind::Huber(1f64), true, None).double_value(&[]);
+
+        assert!(huber < mse);
+    }
+
+    #[test]
+    fn importance_weights_scale_down_the_critic_loss() {
+        let critic = Critic::new(4, 2, vec![vec![16, 16], vec![16, 16]], None, None, None);
+
+        let state = tch::Tensor::rand([4, 4], (tch::Kind::Float, **crate::device));
+        let action = tch::Tensor::rand([4, 2], (tch::Kind::Float, **crate::device));
+
+        // only the first sample has a td error, and it is the one a prioritized buffer would oversample
+        let offset = tch::Tensor::from_slice(&[10f32, 0f32, 0f32, 0f32]).view([4, 1]).to_device(**crate::device);
+        let target_q = critic.forward(&state, &action).narrow(1, 0, 1).detach() + offset;
+        let weights = tch::Tensor::from_slice(&[0.1f32, 1f32, 1f32, 1f32]).to_device(**crate::device);
+        let ones = tch::Tensor::ones([4], (tch::Kind::Float, **crate::device));
+
+        for loss_kind in [LossKind::Mse, LossKind::Huber(1f64)] {
+            let unweighted = critic.loss(&state, &action, &target_q, loss_kind, false, None).double_value(&[]);
+            let weighted =
+                critic.loss(&state, &action, &target_q, loss_kind, false, Some(&weights)).double_value(&[]);
+            let uniform = critic.loss(&state, &action, &target_q, loss_kind, false, Some(&ones)).double_value(&[]);
+
+            assert!((uniform - unweighted).abs() < 1e-6);
+            assert!(weighted < unweighted);
+            assert!((weighted - 0.1 * unweighted).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn td3_train_refreshes_prioritized_buffer() {
+        let mut td3 = make_td3(4, 2);
+        let filled = fill_buffer(4, 2, 64);
+
+        let mut replay_buffer = ReplayBuffer::prioritized(4, 2, Some(64), None, None);
+        for idx in 0..64 {
+            let (state, action, next_state, reward, _) = filled.transition(idx);
+            replay_buffer.add(state, action, next_state, reward, 0f64);
+        }
+
+        // every transition enters at the same max priority until it has been trained on
+        assert!(replay_buffer.priorities.as_ref().unwrap().iter().all(|priority| *priority == 1f64));
+
+        td3.train(&mut replay_buffer, Some(16));
+
+        let priorities = replay_buffer.priorities.as_ref().unwrap();
+        assert!(priorities.iter().any(|priority| *priority != 1f64));
+        assert!(priorities.iter().all(|priority| *priority > 0f64));
+
+        // the uniform path has no priorities to touch
+        let mut uniform_buffer = fill_buffer(4, 2, 64);
+        td3.train(&mut uniform_buffer, Some(16));
+        assert!(uniform_buffer.priorities.is_none());
+    }
+
+    #[test]
+    fn td3_targets_match_online_networks_on_construction() {
+        let td3 = make_td3(4, 2);
+
+        let state = tch::Tensor::rand([8, 4], (tch::Kind::Float, **crate::device));
+        let action = tch::Tensor::rand([8, 2], (tch::Kind::Float, **crate::device));
+
+        assert!(td3.actor.forward(&state).allclose(&td3.actor_target.forward(&state), 1e-6, 1e-6, false));
+
+        let q = td3.critic.forward(&state, &action);
+        let target_q = td3.critic_target.forward(&state, &action);
+        assert!(q.allclose(&target_q, 1e-6, 1e-6, false));
+    }
+
+    #[test]
+    fn ou_noise_is_autocorrelated() {
+        // lag one autocorrelation of the noise added to a fixed state's action
+        let autocorrelation = |noise_process: NoiseProcess| -> f64 {
+            let mut td3 = TD3::builder()
+                .state_dim(4)
+                .action_dim(2)
+                .max_action(100f64)
+                .noise_process(noise_process)
+                .build()
+                .expect("Failed to create TD3 Policy");
+
+            let state = vec![0.1, -0.2, 0.3, -0.4];
+            let action = td3.select_action(state.clone());
+            let mut rng = <rand::prelude::StdRng as rand::prelude::SeedableRng>::seed_from_u64(0);
+
+            let noise: Vec<f64> = (0..2000)
+                .map(|_| td3.sample_action(state.clone(), 0.1, &mut rng)[0] - action[0])
+                .collect();
+
+            let mean = noise.iter().sum::<f64>() / noise.len() as f64;
+            let variance = noise.iter().map(|x| (x - mean).powi(2)).sum::<f64>();
+            let covariance = noise
+                .windows(2)
+                .map(|pair| (pair[0] - mean) * (pair[1] - mean))
+                .sum::<f64>();
+
+            covariance / variance
+        };
+
+        let ou = autocorrelation(NoiseProcess::OrnsteinUhlenbeck { theta: 0.15, sigma: 0.05 });
+        let gaussian = autocorrelation(NoiseProcess::Gaussian);
+
+        assert!(ou > 0.5);
+        assert!(gaussian.abs() < 0.2);
+    }
+
+    #[test]
+    fn td3_sample_action_noise() {
+        let mut td3 = make_td3(4, 2);
+        let state = vec![0.1, -0.2, 0.3, -0.4];
+
+        let mut rng = <rand::prelude::StdRng as rand::prelude::SeedableRng>::seed_from_u64(0);
+        assert_eq!(td3.sample_action(state.clone(), 0f64, &mut rng), td3.select_action(state.clone()));
+
+        let mut rng = <rand::prelude::StdRng as rand::prelude::SeedableRng>::seed_from_u64(42);
+        let noisy = td3.sample_action(state.clone(), 0.5, &mut rng);
+
+        let mut rng = <rand::prelude::StdRng as rand::prelude::SeedableRng>::seed_from_u64(42);
+        assert_eq!(noisy, td3.sample_action(state, 0.5, &mut rng));
+        assert!(noisy.iter().all(|act| act.abs() <= td3.max_action));
+    }
+
+    #[test]
+    fn actor_optimizer_does_not_touch_critic() {
+        let actor = Actor::new(4, 2, vec![16, 16], 1f64, None, None, None);
+        let critic = Critic::new(4, 2, vec![vec![16, 16], vec![16, 16]], None, None, None);
+        let mut actor_opt = ADAM::new(0.1, actor.vs.clone(), None);
+
+        let critic_before: Vec<tch::Tensor> = critic
+            .vs
+            .borrow()
+            .trainable_variables()
+            .iter()
+            .map(|var| var.copy())
+            .collect();
+
+        // the actor loss backpropagates through the critic, only the actor may be stepped
+        let state = tch::Tensor::rand([8, 4], (tch::Kind::Float, **crate::device));
+        let loss = -1 * critic
+            .Q1(&tch::Tensor::cat(&[&state, &actor.forward(&state)], 1))
+            .mean(tch::Kind::Float);
+        actor_opt.tell(vec![actor.vs.clone()], vec![loss]);
+
+        for (before, after) in critic_before.iter().zip(critic.vs.borrow().trainable_variables().iter()) {
+            assert!(before.equal(after));
+        }
+    }
+
+    #[test]
+    fn td3_distinct_learning_rates() {
+        let td3 = TD3::builder()
+            .state_dim(4)
+            .action_dim(2)
+            .max_action(1f64)
+            .actor_lr(1e-3)
+            .critic_lr(1e-4)
+            .build()
+            .expect("Failed to create TD3 Policy");
+
+        assert_eq!(td3.actor_lr, 1e-3);
+        assert_eq!(td3.critic_lr, 1e-4);
+    }
+
+    #[test]
+    fn td3_lr_schedule_decays_to_floor() {
+        let mut td3 = TD3::builder()
+            .state_dim(4)
+            .action_dim(2)
+            .max_action(1f64)
+            .lr_schedule(LrSchedule::Cosine(0.1), 8)
+            .build()
+            .expect("Failed to create TD3 Policy");
+        let mut replay_buffer = fill_buffer(4, 2, 64);
+
+        let mut actor_lrs = vec![td3.current_lr().0.unwrap()];
+        for _ in 0..10 {
+            td3.train(&mut replay_buffer, Some(16));
+            actor_lrs.push(td3.current_lr().0.unwrap());
+        }
+
+        assert!(actor_lrs.windows(2).all(|pair| pair[1] <= pair[0]));
+        assert!((actor_lrs.last().unwrap() - 0.1 * td3.actor_lr).abs() < 1e-12);
+        assert!((td3.current_lr().1.unwrap() - 0.1 * td3.critic_lr).abs() < 1e-12);
+    }
+
+    #[test]
+    fn adam_clips_gradient_norm() {
+        let actor = Actor::new(4, 2, vec![16, 16], 1f64, None, None, None);
+        let mut actor_opt = ADAM::new(3e-4, actor.vs.clone(), Some(1f64));
+
+        let state = tch::Tensor::rand([8, 4], (tch::Kind::Float, **crate::device));
+        let loss = 1e6 * actor.forward(&state).sum(tch::Kind::Float);
+        actor_opt.tell(vec![actor.vs.clone()], vec![loss]);
+
+        let grad_norm = actor
+            .vs
+            .borrow()
+            .trainable_variables()
+            .iter()
+            .map(|var| var.grad().norm().double_value(&[]).powi(2))
+            .sum::<f64>()
+            .sqrt();
+
+        assert!(grad_norm <= 1f64 + 1e-4);
+    }
+
+    #[test]
+    fn seeded_halfcheetah_episodes_match() {
+        let rollout = || -> Vec<Vec<f64>> {
+            crate::seed(7);
+            let mut env = HalfCheetahEnv::new(None, None, None, None, None, None, Some(10));
+
+            let mut observations = vec![env.reset().observation()];
+            for _ in 0..10 {
+                observations.push(env.step(vec![0f64; 6]).observation());
+            }
+
+            observations
+        };
+
+        assert_eq!(rollout(), rollout());
+    }
+
+    #[test]
+    fn replay_buffer_resize_keeps_transitions() {
+        let mut replay_buffer = ReplayBuffer::new(1, 1, Some(4));
+
+        // wraps once, slots hold rewards 4, 5, 2, 3 with the oldest at ptr 2
+        for idx in 0..6 {
+            replay_buffer.add(vec![idx as f64], vec![0f64], vec![idx as f64 + 1f64], idx as f64, 0f64);
+        }
+        assert!(replay_buffer.is_full());
+
+        replay_buffer.resize(8);
+
+        assert_eq!(replay_buffer.max_size, 8);
+        assert_eq!(replay_buffer.len(), 4);
+        assert_eq!(replay_buffer.ptr, 4);
+        assert_eq!(replay_buffer.rewards()[..4].to_vec(), vec![2f64, 3f64, 4f64, 5f64]);
+
+        // sampled rows come from the reordered storage
+        let batch = replay_buffer.sample_batch(64);
+        let rewards = Vec::<f32>::try_from(&batch.rewards.to_device(tch::Device::Cpu)).unwrap();
+        let states = Vec::<f32>::try_from(&batch.states.to_device(tch::Device::Cpu).view([-1])).unwrap();
+        for (reward, state) in rewards.iter().zip(states.iter()) {
+            assert!((2f32..=5f32).contains(reward));
+            assert_eq!(reward, state);
+        }
+
+        for idx in 6..10 {
+            replay_buffer.add(vec![idx as f64], vec![0f64], vec![idx as f64 + 1f64], idx as f64, 0f64);
+        }
+
+        assert!(replay_buffer.is_full());
+        assert_eq!(replay_buffer.rewards(), (2..10).map(|idx| idx as f64).collect::<Vec<f64>>());
+        assert_eq!(replay_buffer.ptr, 0);
+
+        // shrinking keeps only the newest transitions
+        replay_buffer.resize(3);
+
+        assert_eq!(replay_buffer.len(), 3);
+        assert_eq!(replay_buffer.rewards(), vec![7f64, 8f64, 9f64]);
+        assert_eq!(replay_buffer.ptr, 0);
+    }
+
+    #[test]
+    fn replay_buffer_save_load_round_trip() {
+        let replay_buffer = fill_buffer(4, 2, 100);
+        let mut partial_buffer = ReplayBuffer::new(4, 2, Some(100));
+        for idx in 0..30 {
+            let (state, action, next_state, reward, _) = replay_buffer.transition(idx);
+            partial_buffer.add(state, action, next_state, reward, 0f64);
+        }
+
+        let filename = temp_file("replay_buffer.bin");
+        partial_buffer.save(filename.clone()).expect("Failed to save replay buffer");
+        let loaded = ReplayBuffer::load(filename).expect("Failed to load replay buffer");
+
+        assert_eq!(loaded.max_size, partial_buffer.max_size);
+        assert_eq!(loaded.ptr, partial_buffer.ptr);
+        assert_eq!(loaded.size, partial_buffer.size);
+
+        partial_buffer.rng.replace(<rand::prelude::StdRng as rand::prelude::SeedableRng>::seed_from_u64(3));
+        loaded.rng.replace(<rand::prelude::StdRng as rand::prelude::SeedableRng>::seed_from_u64(3));
+
+        for (expected, actual) in partial_buffer.sample(16).iter().zip(loaded.sample(16).iter()) {
+            assert!(expected.equal(actual));
+        }
+    }
+
+    #[test]
+    fn replay_buffer_overwrites_oldest_when_full() {
+        let mut replay_buffer = ReplayBuffer::new(1, 1, Some(5));
+        assert!(replay_buffer.is_empty());
+
+        for idx in 0..8 {
+            replay_buffer.add(vec![idx as f64], vec![0f64], vec![idx as f64], idx as f64, 0f64);
+            assert_eq!(replay_buffer.len(), std::cmp::min(idx + 1, 5));
+        }
+
+        assert!(replay_buffer.is_full());
+        assert_eq!(replay_buffer.ptr, 3);
+
+        // transitions 0, 1 and 2 were overwritten by 5, 6 and 7
+        assert_eq!(replay_buffer.rewards(), vec![5f64, 6f64, 7f64, 3f64, 4f64]);
+    }
+
+    #[test]
+    fn replay_buffer_samples_only_filled_slots() {
+        let mut replay_buffer = ReplayBuffer::new(1, 1, Some(100));
+        for _ in 0..3 {
+            replay_buffer.add(vec![1f64], vec![1f64], vec![1f64], 1f64, 0f64);
+        }
+
+        assert!(!replay_buffer.is_full());
+
+        // every unfilled slot holds a zero reward
+        let reward = &replay_buffer.sample(256)[3];
+        assert_eq!(reward.min().double_value(&[]), 1f64);
+    }
+
+    #[test]
+    fn sampling_without_replacement_has_unique_indices() {
+        let mut replay_buffer = fill_buffer(4, 2, 32);
+        replay_buffer.with_replacement = false;
+
+        for _ in 0..10 {
+            let mut indices = replay_buffer.sample_batch(32).indices;
+            indices.sort();
+            indices.dedup();
+            assert_eq!(indices.len(), 32);
+        }
+
+        // more than the buffer holds still works, duplicates are unavoidable then
+        assert_eq!(replay_buffer.sample_batch(64).indices.len(), 64);
+    }
+
+    #[test]
+    fn prioritized_replay_favors_high_priority() {
+        let mut replay_buffer = ReplayBuffer::prioritized(1, 1, Some(4), Some(1f64), None);
+        for idx in 0..4 {
+            replay_buffer.add(vec![idx as f64], vec![0f64], vec![0f64], 0f64, 0f64);
+        }
+
+        replay_buffer.update_priorities(&[0, 1, 2, 3], &[1f64, 1f64, 1f64, 10f64]);
+        replay_buffer.rng.replace(<rand::prelude::StdRng as rand::prelude::SeedableRng>::seed_from_u64(0));
+
+        let mut counts = [0usize; 4];
+        for _ in 0..50 {
+            let batch = replay_buffer.sample_batch(100);
+            for (idx, weight) in batch.indices.iter().zip(Vec::<f64>::try_from(&batch.weights).unwrap()) {
+                counts[*idx] += 1;
+
+                // the most sampled transition gets the smallest correction
+                if *idx == 3 {
+                    assert!(weight < 1f64);
+                }
+            }
+        }
+
+        for count in &counts[..3] {
+            assert!(counts[3] > 5 * count);
+        }
+    }
+
+    #[test]
+    fn sample_batch_indices_in_range() {
+        let mut replay_buffer = ReplayBuffer::new(4, 2, Some(100));
+        let filled = fill_buffer(4, 2, 10);
+        for idx in 0..10 {
+            let (state, action, next_state, reward, _) = filled.transition(idx);
+            replay_buffer.add(state, action, next_state, reward, 0f64);
+        }
+
+        let batch = replay_buffer.sample_batch(64);
+
+        assert_eq!(batch.indices.len(), 64);
+        assert!(batch.indices.iter().all(|idx| *idx < replay_buffer.len()));
+        assert_eq!(batch.states.size(), vec![64, 4]);
+        assert_eq!(batch.weights.sum(tch::Kind::Float).double_value(&[]), 64f64);
+    }
+
+    #[test]
+    fn replay_buffer_scales_and_clips_rewards() {
+        let mut replay_buffer = ReplayBuffer::new(2, 1, Some(8));
+        replay_buffer.set_reward_transform(Some(0.1), Some((-1f64, 1f64)));
+
+        replay_buffer.add(vec![0f64; 2], vec![0f64], vec![0f64; 2], 1e6, 0f64);
+        replay_buffer.add(vec![0f64; 2], vec![0f64], vec![0f64; 2], -1e6, 0f64);
+        replay_buffer.add(vec![0f64; 2], vec![0f64], vec![0f64; 2], 5f64, 0f64);
+
+        assert_eq!(replay_buffer.rewards()[..3], [1f64, -1f64, 0.5]);
+
+        // sampled batches hold the clamped values too
+        let rewards = &replay_buffer.sample(64)[3];
+        assert!(rewards.max().double_value(&[]) <= 1f64);
+        assert!(rewards.min().double_value(&[]) >= -1f64);
+    }
+
+    #[test]
+    fn n_step_windows_stop_at_terminal() {
+        let mut replay_buffer = ReplayBuffer::new(1, 1, Some(10));
+        replay_buffer.set_n_step(3, 0.5);
+
+        // states encode the episode in the hundreds, the first episode terminates after two steps
+        let episodes = [vec![1f64, 2f64], vec![100f64, 200f64, 400f64, 800f64]];
+        for (episode, rewards) in episodes.iter().enumerate() {
+            for (t, reward) in rewards.iter().enumerate() {
+                let state = (100 * episode + t) as f64;
+                let done = match episode == 0 && t == rewards.len() - 1 {
+                    true => 1f64,
+                    false => 0f64,
+                };
+
+                replay_buffer.add(vec![state], vec![0f64], vec![state + 1f64], *reward, done);
+            }
+        }
+
+        replay_buffer.end_episode();
+
+        assert_eq!(replay_buffer.len(), 6);
+        for idx in 0..replay_buffer.len() {
+            let episode = (replay_buffer.transition(idx).0[0] / 100f64).floor();
+            assert_eq!(((replay_buffer.transition(idx).2[0] - 1f64) / 100f64).floor(), episode);
+        }
+
+        assert_eq!(replay_buffer.rewards()[..2].to_vec(), vec![1f64 + 0.5 * 2f64, 2f64]);
+        assert_eq!(replay_buffer.not_dones()[..2].to_vec(), vec![0f64, 0f64]);
+        assert_eq!(replay_buffer.n_steps()[..2].to_vec(), vec![2f64, 1f64]);
+        assert_eq!(replay_buffer.rewards()[2], 100f64 + 0.5 * 200f64 + 0.25 * 400f64);
+
+        // the truncated second episode still bootstraps, but only from as many steps ahead as its windows hold
+        assert_eq!(replay_buffer.not_dones()[2..6].to_vec(), vec![1f64; 4]);
+        assert_eq!(replay_buffer.n_steps()[2..6].to_vec(), vec![3f64, 3f64, 2f64, 1f64]);
+    }
+
+    #[test]
+    fn n_step_rewards_are_discounted_sums() {
+        let mut replay_buffer = ReplayBuffer::new(1, 1, Some(10));
+        replay_buffer.set_n_step(3, 0.5);
+
+        let rewards = [1f64, 2f64, 4f64, 8f64, 16f64];
+        for (idx, reward) in rewards.iter().enumerate() {
+            let done = match idx == rewards.len() - 1 {
+                true => 1f64,
+                false => 0f64,
+            };
+
+            replay_buffer.add(vec![idx as f64], vec![0f64], vec![idx as f64 + 1f64], *reward, done);
+        }
+
+        assert_eq!(replay_buffer.len(), 5);
+        assert_eq!(
+            replay_buffer.rewards()[..5].to_vec(),
+            vec![
+                1f64 + 0.5 * 2f64 + 0.25 * 4f64,
+                2f64 + 0.5 * 4f64 + 0.25 * 8f64,
+                4f64 + 0.5 * 8f64 + 0.25 * 16f64,
+                8f64 + 0.5 * 16f64,
+                16f64,
+            ]
+        );
+
+        // next_state is n steps ahead, or the terminal state when the episode ends first
+        assert_eq!(replay_buffer.transition(0).2, vec![3f64]);
+        assert_eq!(replay_buffer.transition(3).2, vec![5f64]);
+        assert_eq!(replay_buffer.not_dones()[..5].to_vec(), vec![1f64, 1f64, 0f64, 0f64, 0f64]);
+        assert_eq!(replay_buffer.n_steps()[..5].to_vec(), vec![3f64, 3f64, 3f64, 2f64, 1f64]);
+    }
+
+    #[test]
+    fn td3_evaluate_runs_every_episode() {
+        let td3 = make_td3(18, 6);
+        let mut env = CountingEnv {
+            inner: HalfCheetahEnv::new(None, None, None, None, None, None, Some(20)),
+            resets: 0,
+        };
+
+        let avg_reward = td3.evaluate(&mut env, 3);
+
+        assert_eq!(env.resets, 3);
+        assert!(avg_reward.is_finite());
+    }
+
+    #[test]
+    #[should_panic(expected = "episodes must be positive")]
+    fn td3_evaluate_rejects_zero_episodes() {
+        let td3 = make_td3(18, 6);
+        let mut env = HalfCheetahEnv::new(None, None, None, None, None, None, Some(20));
+
+        td3.evaluate(&mut env, 0);
+    }
+
+    #[test]
+    fn train_agent_smoke() {
+        let mut env = HalfCheetahEnv::new(None, None, None, None, None, None, Some(5));
+        let mut eval_env = HalfCheetahEnv::new(None, None, None, None, None, None, Some(5));
+        let mut td3 = make_td3(18, 6);
+        let mut replay_buffer = ReplayBuffer::new(18, 6, Some(100));
+
+        let mut callbacks = 0;
+        let history = crate::trainer::train_agent(
+            &mut env,
+            &mut eval_env,
+            &mut td3,
+            &mut replay_buffer,
+            30,
+            10,
+            10,
+            0.1,
+            |_, _, _| callbacks += 1,
+            |_, _| false,
+            None,
+            None,
+        );
+        let evals = history.evals;
+
+        assert_eq!(callbacks, 3);
+        assert_eq!(evals.iter().map(|eval| eval.0).collect::<Vec<u32>>(), vec![0, 10, 20, 30]);
+        assert!(evals.iter().all(|eval| eval.1.is_finite()));
+        assert_eq!(replay_buffer.len(), 30);
+    }
+
+    #[test]
+    fn train_agent_stops_when_asked() {
+        let mut env = HalfCheetahEnv::new(None, None, None, None, None, None, Some(5));
+        let mut eval_env = HalfCheetahEnv::new(None, None, None, None, None, None, Some(5));
+        let mut td3 = make_td3(18, 6);
+        let mut replay_buffer = ReplayBuffer::new(18, 6, Some(100));
+
+        let history = crate::trainer::train_agent(
+            &mut env,
+            &mut eval_env,
+            &mut td3,
+            &mut replay_buffer,
+            30,
+            10,
+            10,
+            0.1,
+            |_, _, _| {},
+            |_, _| true,
+            None,
+            None,
+        );
+        let evals = history.evals;
+
+        // the initial evaluation plus the one that stopped training
+        assert_eq!(evals.iter().map(|eval| eval.0).collect::<Vec<u32>>(), vec![0, 10]);
+        assert_eq!(replay_buffer.len(), 10);
+    }
+
+    #[test]
+    fn train_agent_checkpoints_and_prunes() {
+        let mut env = HalfCheetahEnv::new(None, None, None, None, None, None, Some(5));
+        let mut eval_env = HalfCheetahEnv::new(None, None, None, None, None, None, Some(5));
+        let mut td3 = make_td3(18, 6);
+        let mut replay_buffer = ReplayBuffer::new(18, 6, Some(100));
+
+        let dir = temp_file("train_agent_checkpoints");
+        let _ = std::fs::remove_dir_all(&dir);
+
+        crate::trainer::train_agent(
+            &mut env,
+            &mut eval_env,
+            &mut td3,
+            &mut replay_buffer,
+            30,
+            10,
+            30,
+            0.1,
+            |_, _, _| {},
+            |_, _| false,
+            Some(crate::trainer::Checkpoint::new(4, dir.clone(), Some(2))),
+            None,
+        );
+
+        // checkpoints at 4, 8, .., 28, only the last two are kept
+        let mut files = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<String>>();
+        files.sort();
+
+        assert_eq!(files, vec!["checkpoint_24.bin", "checkpoint_28.bin"]);
+        assert_eq!(TD3::load(format!("{}/checkpoint_28.bin", dir)).unwrap().total_it, td3.total_it - 2);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // replays scripted eval returns and saves the return it last reported
+    struct ScriptedPolicy {
+        returns: Vec<f64>,
+        evals: std::cell::Cell<usize>,
+    }
+
+    impl crate::trainer::Policy for ScriptedPolicy {
+        fn sample_action(&mut self, _state: Vec<f64>, _expl_noise: f64, _rng: &mut rand::prelude::StdRng) -> Vec<f64> {
+            vec![0f64; 2]
+        }
+
+        fn train(&mut self, _replay_buffer: &mut ReplayBuffer, _batch_size: Option<i64>) {}
+
+        fn evaluate(&self, _env: &mut dyn Environment, _episodes: i64) -> f64 {
+            let idx = self.evals.get();
+            self.evals.set(idx + 1);
+            self.returns[idx]
+        }
+
+        fn save(&self, filename: String) -> anyhow::Result<()> {
+            std::fs::create_dir_all(std::path::Path::new(&filename).parent().unwrap())?;
+            std::fs::write(filename, serde_json::to_string(&self.returns[self.evals.get() - 1])?)?;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn train_agent_keeps_the_best_model() {
+        let stream = || StreamEnv {
+            observations: vec![vec![0f64]],
+            idx: 0,
+            actions: Default::default(),
+            action_bounds: (-1f64, 1f64),
+        };
+        let mut policy = ScriptedPolicy {
+            returns: vec![1f64, 3f64, 5f64, 2f64, 4f64],
+            evals: std::cell::Cell::new(0),
+        };
+        let mut replay_buffer = ReplayBuffer::new(1, 2, Some(100));
+
+        let dir = temp_file("train_agent_best_model");
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let history = crate::trainer::train_agent(
+            &mut stream(),
+            &mut stream(),
+            &mut policy,
+            &mut replay_buffer,
+            40,
+            0,
+            10,
+            0.1,
+            |_, _, _| {},
+            |_, _| false,
+            None,
+            Some(dir.clone()),
+        );
+
+        assert_eq!(history.evals.len(), 5);
+        assert_eq!(history.best_timestep, 20);
+        assert_eq!(history.best_return, 5f64);
+
+        // the regression at 30 and the partial recovery at 40 leave the peak in place
+        let best_model = std::fs::read_to_string(format!("{}/best_model.json", dir)).unwrap();
+        assert_eq!(best_model.parse::<f64>().unwrap(), 5f64);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn patience_stopper_waits_for_stalled_evals() {
+        let mut stopper = crate::trainer::PatienceStopper::new(2, None);
+
+        assert!(!stopper.should_stop(1f64, 10));
+        assert!(!stopper.should_stop(2f64, 20));
+        assert!(!stopper.should_stop(1.5, 30));
+        assert!(!stopper.should_stop(3f64, 40));
+        assert!(!stopper.should_stop(3f64, 50));
+        assert!(stopper.should_stop(2f64, 60));
+    }
+
+    #[test]
+    fn sac_trains_on_halfcheetah_smoke() {
+        let mut env = HalfCheetahEnv::new(None, None, None, None, None, None, Some(5));
+        let mut eval_env = HalfCheetahEnv::new(None, None, None, None, None, None, Some(5));
+        let mut sac = SAC::new(
+            18, 6, 1f64, None, None, None, None, None, None, None, None, None, None, None, None,
+        )
+        .expect("Failed to create SAC Policy");
+        let mut replay_buffer = ReplayBuffer::new(18, 6, Some(100));
+
+        let history = crate::trainer::train_agent(
+            &mut env,
+            &mut eval_env,
+            &mut sac,
+            &mut replay_buffer,
+            30,
+            10,
+            10,
+            0.1,
+            |_, _, _| {},
+            |_, _| false,
+            None,
+            None,
+        );
+
+        assert_eq!(sac.total_it, 20);
+        assert!(history.evals.iter().all(|eval| eval.1.is_finite()));
+        assert!(sac.alpha().is_finite() && sac.alpha() > 0f64);
+
+        let action = sac.select_action(vec![0f64; 18]);
+        assert_eq!(action.len(), 6);
+        assert!(action.iter().all(|x| x.abs() <= 1f64));
+    }
+
+    #[test]
+    fn td3_runs_on_requested_device() {
+        let td3 = TD3::builder()
+            .state_dim(4)
+            .action_dim(2)
+            .max_action(1f64)
+            .compute_device(tch::Device::Cpu)
+            .build()
+            .expect("Failed to create TD3 Policy");
+
+        assert_eq!(td3.device, tch::Device::Cpu);
+        assert_eq!(td3.actor.vs.borrow().device(), tch::Device::Cpu);
+        assert_eq!(td3.critic_target.vs.borrow().device(), tch::Device::Cpu);
+
+        let state = tch::Tensor::rand([8, 4], (tch::Kind::Float, tch::Device::Cpu));
+        assert_eq!(td3.actor.forward(&state).device(), tch::Device::Cpu);
+        assert_eq!(td3.select_action(vec![0.1, -0.2, 0.3, -0.4]).len(), 2);
+
+        let mut replay_buffer = fill_buffer(4, 2, 64);
+        let mut td3 = td3;
+        td3.train(&mut replay_buffer, Some(16));
+    }
+
+    #[test]
+    fn td3_loads_onto_requested_device() {
+        let td3 = make_td3(4, 2);
+
+        for filename in [temp_file("td3_load_on.json"), temp_file("td3_load_on.bin")] {
+            td3.save(filename.clone()).expect("Failed to save TD3 Policy");
+            let loaded = TD3::load_on(filename, Some(tch::Device::Cpu)).expect("Failed to load TD3 Policy");
+
+            assert_eq!(loaded.device, tch::Device::Cpu);
+            for vs in [&loaded.actor.vs, &loaded.actor_target.vs, &loaded.critic.vs, &loaded.critic_target.vs] {
+                assert_eq!(vs.borrow().device(), tch::Device::Cpu);
+            }
+        }
+    }
+
+    #[test]
+    fn gradient_accumulation_matches_a_full_batch() {
+        let build = || {
+            tch::manual_seed(0);
+            TD3::builder()
+                .state_dim(4)
+                .action_dim(2)
+                .max_action(1f64)
+                .target_policy_smoothing(false)
+                .build()
+                .expect("Failed to build TD3 Policy")
+        };
+
+        // every sample is the same transition so both splits see identical data
+        let mut replay_buffer = ReplayBuffer::new(4, 2, Some(64));
+        for _ in 0..64 {
+            replay_buffer.add(vec![0.1, -0.2, 0.3, -0.4], vec![0.5, -0.5], vec![0.2, 0.1, -0.1, 0.0], 1f64, 0f64);
+        }
+
+        let mut full = build();
+        let mut accumulated = build();
+        let before = full.critic.qs[0].layers[0].layer.ws.copy();
+
+        full.train(&mut replay_buffer, Some(64));
+        accumulated.train_accumulated(&mut replay_buffer, Some(32), 2);
+
+        let full_ws = &full.critic.qs[0].layers[0].layer.ws;
+        let accumulated_ws = &accumulated.critic.qs[0].layers[0].layer.ws;
+
+        assert!(!full_ws.equal(&before));
+        assert!((full_ws - accumulated_ws).abs().max().double_value(&[]) < 1e-6);
+        assert_eq!(accumulated.total_it, 1);
+    }
+
+    #[test]
+    fn td3_q_value_returns_both_critic_estimates() {
+        let env = HalfCheetahEnv::new(None, None, None, None, None, None, None);
+        let state_dim = env.observation_spec().shape as i64;
+        let action_dim = env.action_spec().shape as i64;
+        let td3 = make_td3(state_dim, action_dim);
+
+        let (q1, q2) = td3.q_value(vec![0.1; state_dim as usize], vec![-0.2; action_dim as usize]);
+
+        assert!(q1.is_finite());
+        assert!(q2.is_finite());
+    }
+
+    #[test]
+    fn vecenv_steps_every_sub_env() {
+        let envs = (0..4)
+            .map(|_| HalfCheetahEnv::new(None, None, None, None, None, None, None))
+            .collect();
+        let mut vec_env = VecEnv::new(envs);
+
+        let observations = vec_env.reset();
+        assert_eq!(observations.len(), 4);
+
+        let vec_step = vec_env.step(vec![vec![0.5; 6]; 4]);
+
+        assert_eq!(vec_step.observations.len(), 4);
+        assert!(vec_step.observations.iter().all(|observation| observation.len() == 18));
+        assert_eq!(vec_step.rewards.len(), 4);
+        assert!(vec_step.dones.iter().all(|done| !done));
+        assert_eq!(vec_env.steps, vec![1; 4]);
+        assert!(vec_env.envs.iter().all(|env| env.step == 1));
+    }
+
+    #[test]
+    fn halfcheetah_reset_restarts_episode() {
+        let mut env = HalfCheetahEnv::new(None, None, None, None, None, None, None);
+        env.reset();
+
+        for _ in 0..5 {
+            env.step(vec![0.5; 6]);
+        }
+
+        assert_eq!(env.step, 5);
+
+        let ts = env.reset();
+
+        assert_eq!(env.step, 0);
+        assert!(ts.reward().is_none());
+        assert_eq!(ts.observation().len(), env.observation_spec().shape as usize);
+    }
+
+    #[test]
+    fn normalize_observation_standardizes_stream() {
+        let mut rng = <rand::prelude::StdRng as rand::prelude::SeedableRng>::seed_from_u64(0);
+        let normal = rand_distr::Normal::new(5f64, 3f64).unwrap();
+        let observations = (0..4000)
+            .map(|_| {
+                vec![
+                    rand::prelude::Distribution::sample(&normal, &mut rng),
+                    100f64 + 0.1 * rand::prelude::Distribution::sample(&normal, &mut rng),
+                ]
+            })
+            .collect::<Vec<Vec<f64>>>();
+
+        let mut env = NormalizeObservation::new(
+            Box::new(StreamEnv {
+                observations,
+                idx: 0,
+                actions: Default::default(),
+                action_bounds: (-1f64, 1f64),
+            }),
+            None,
+            None,
+        );
+
+        env.reset();
+        let normalized = (0..3999)
+            .map(|_| env.step(vec![0f64; 2]).observation())
+            .collect::<Vec<Vec<f64>>>();
+
+        // the statistics have settled by the second half of the stream
+        let tail = &normalized[2000..];
+        for dim in 0..2 {
+            let mean = tail.iter().map(|obs| obs[dim]).sum::<f64>() / tail.len() as f64;
+            let var = tail.iter().map(|obs| (obs[dim] - mean).powi(2)).sum::<f64>() / tail.len() as f64;
+
+            assert!(mean.abs() < 0.1);
+            assert!((var - 1f64).abs() < 0.1);
+        }
+    }
+
+    #[test]
+    fn normalize_observation_clips_outliers() {
+        let mut rng = <rand::prelude::StdRng as rand::prelude::SeedableRng>::seed_from_u64(0);
+        let normal = rand_distr::Normal::new(0f64, 1f64).unwrap();
+        let mut observations = (0..1000)
+            .map(|_| vec![rand::prelude::Distribution::sample(&normal, &mut rng)])
+            .collect::<Vec<Vec<f64>>>();
+
+        // a single erroneous bar
+        observations.push(vec![1e6]);
+        observations.push(vec![0.5]);
+
+        let mut env = NormalizeObservation::new(
+            Box::new(StreamEnv {
+                observations,
+                idx: 0,
+                actions: Default::default(),
+                action_bounds: (-1f64, 1f64),
+            }),
+            None,
+            Some(5f64),
+        );
+
+        assert_eq!(env.observation_spec().max, 5f64);
+        assert_eq!(env.observation_spec().min, -5f64);
+
+        env.reset();
+        for _ in 0..999 {
+            env.step(vec![0f64]);
+        }
+
+        let outlier = env.step(vec![0f64]).observation();
+        assert_eq!(outlier, vec![5f64]);
+
+        // ordinary values are untouched by the clip
+        env.frozen = true;
+        let observation = env.step(vec![0f64]).observation();
+        assert_eq!(observation, env.normalize(&[0.5]));
+        assert!(observation[0].abs() < 5f64);
+
+        env.obs_clip = None;
+        assert_eq!(observation, env.normalize(&[0.5]));
+    }
+
+    #[test]
+    fn action_repeat_steps_the_inner_env_up_to_k_times() {
+        let config = HalfCheetahConfig {
+            max_episode_steps: 6,
+            seed: Some(0),
+            ..Default::default()
+        };
+        let mut env = ActionRepeat::new(Box::new(HalfCheetahEnv::from_config(config.clone())), 4);
+        let mut reference = HalfCheetahEnv::from_config(config);
+
+        env.reset();
+        reference.reset();
+
+        let ts = env.step(vec![0f64; 6]);
+        let rewards = (0..4).map(|_| reference.step(vec![0f64; 6]).reward().unwrap()).collect::<Vec<f64>>();
+
+        assert_eq!(env.last_repeats, 4);
+        assert!(!ts.is_terminal());
+        assert!((ts.reward().unwrap() - rewards.iter().sum::<f64>()).abs() < 1e-6);
+        assert_eq!(ts.observation().len(), 18);
+
+        // the episode ends two steps into the second repeat
+        let ts = env.step(vec![0f64; 6]);
+
+        assert_eq!(env.last_repeats, 2);
+        assert!(ts.is_terminal());
+        assert_eq!(ts.discount(), 1f64);
+    }
+
+    #[test]
+    fn time_limit_truncates_at_max_steps() {
+        let mut env = TimeLimit::new(
+            Box::new(StreamEnv {
+                observations: (0..10).map(|idx| vec![idx as f64]).collect(),
+                idx: 0,
+                actions: Default::default(),
+                action_bounds: (-1f64, 1f64),
+            }),
+            5,
+        );
+
+        for _ in 0..2 {
+            env.reset();
+
+            for _ in 0..4 {
+                assert!(!env.step(vec![0f64; 2]).is_terminal());
+            }
+
+            let ts = env.step(vec![0f64; 2]);
+
+            // a truncation, not a true terminal, so the last state still bootstraps
+            assert!(ts.as_any().downcast_ref::<Terminate>().is_some());
+            assert_eq!(ts.discount(), 1f64);
+            assert_eq!(ts.observation(), vec![5f64]);
+            assert_eq!(env.step, 5);
+        }
+    }
+
+    #[test]
+    fn normalize_action_maps_to_env_bounds() {
+        let actions: std::rc::Rc<std::cell::RefCell<Vec<Vec<f64>>>> = Default::default();
+        let mut env = NormalizeAction::new(Box::new(StreamEnv {
+            observations: vec![vec![0f64]],
+            idx: 0,
+            actions: actions.clone(),
+            action_bounds: (-2f64, 4f64),
+        }));
+
+        assert_eq!(env.action_spec().min, -1f64);
+        assert_eq!(env.action_spec().max, 1f64);
+
+        env.reset();
+        env.step(vec![-1f64, 1f64]);
+        env.step(vec![0f64, 0.5]);
+
+        assert_eq!(*actions.borrow(), vec![vec![-2f64, 4f64], vec![1f64, 2.5]]);
+    }
+
+    #[test]
+    fn rsi_matches_wilder_smoothing() {
+        let mut stockframe = synthetic_stockframe(vec![vec![
+            44.0, 44.5, 43.5, 44.5, 45.5, 45.0, 46.0, 45.5, 47.0, 46.5,
+        ]]);
+
+        stockframe
+            .calc_technical_indicators(Some(IndicatorConfig {
+                rsi_period: 3,
+                ..Default::default()
+            }))
+            .unwrap();
+
+        let rsi = indicator(&stockframe, "SYM0", "rsi");
+        let expected = [60.0, 75.0, 58.536585, 75.0, 57.790368, 79.233449, 63.184218];
+
+        // the warmup window is left null instead of shifting the values forward
+        assert_eq!(rsi[..3].to_vec(), vec![None; 3]);
+        for (actual, expected) in rsi[3..].iter().zip(expected.iter()) {
+            assert!((actual.unwrap() - expected).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn macd_follows_trend_per_symbol() {
+        let rising = (0..60).map(|idx| 100f64 + idx as f64).collect::<Vec<f64>>();
+        let falling = (0..60).map(|idx| 1000f64 - 10f64 * idx as f64).collect::<Vec<f64>>();
+        let mut stockframe = synthetic_stockframe(vec![rising, falling]);
+
+        stockframe.calc_technical_indicators(None).unwrap();
+
+        // 26 period slow ema plus 9 period signal line
+        let warmup = 33;
+
+        let macd = indicator(&stockframe, "SYM0", "macd");
+        assert!(macd[..25].iter().all(|val| val.is_none()));
+        assert!(macd[warmup..].iter().all(|val| val.unwrap() > 0f64));
+
+        let macd = indicator(&stockframe, "SYM1", "macd");
+        assert!(macd[warmup..].iter().all(|val| val.unwrap() < 0f64));
+
+        let signal = indicator(&stockframe, "SYM0", "macdsignal");
+        let hist = indicator(&stockframe, "SYM0", "macdhist");
+        let macd = indicator(&stockframe, "SYM0", "macd");
+        for idx in warmup..60 {
+            assert!((macd[idx].unwrap() - signal[idx].unwrap() - hist[idx].unwrap()).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn bollinger_bands_track_volatility() {
+        let flat = vec![50f64; 40];
+        let calm = (0..40).map(|idx| 50f64 + (idx % 2) as f64).collect::<Vec<f64>>();
+        let volatile = (0..40).map(|idx| 50f64 + 10f64 * (idx % 2) as f64).collect::<Vec<f64>>();
+        let mut stockframe = synthetic_stockframe(vec![flat, calm, volatile]);
+
+        stockframe.calc_technical_indicators(None).unwrap();
+
+        let band = |symbol: &str, name: &str| indicator(&stockframe, symbol, name);
+
+        assert!(band("SYM0", "bband_up")[..19].iter().all(|val| val.is_none()));
+
+        for idx in 19..40 {
+            for name in ["bband_up", "bband_mid", "bband_low"] {
+                assert!((band("SYM0", name)[idx].unwrap() - 50f64).abs() < 1e-9);
+            }
+
+            let calm_width = band("SYM1", "bband_up")[idx].unwrap() - band("SYM1", "bband_low")[idx].unwrap();
+            let volatile_width =
+                band("SYM2", "bband_up")[idx].unwrap() - band("SYM2", "bband_low")[idx].unwrap();
+
+            assert!(calm_width > 0f64);
+            assert!(volatile_width > calm_width);
+        }
+    }
+
+    #[test]
+    fn lazy_indicators_match_the_eager_pass() {
+        let stockframe = synthetic_stockframe(vec![
+            (0..80).map(|idx| 100f64 + (idx as f64 / 4f64).sin() * 5f64).collect(),
+            (0..80).map(|idx| 20f64 + (idx as f64 / 7f64).cos()).collect(),
+            (0..80).map(|idx| 300f64 - idx as f64).collect(),
+        ]);
+
+        let config = IndicatorConfig {
+            rsi_period: 10,
+            macd_fast: 6,
+            macd_slow: 13,
+            macd_signal: 5,
+            bband_period: 15,
+            bband_dev: 2.5,
+            ..Default::default()
+        };
+
+        let mut lazy = stockframe.clone();
+        lazy.calc_technical_indicators(Some(config)).unwrap();
+
+        let mut eager = stockframe.clone();
+        eager.calc_technical_indicators_eager(Some(config)).unwrap();
+
+        // the eager pass keeps group_by order, the lazy one sorts
+        let eager_frame = eager
+            .frame
+            .borrow()
+            .sort(["symbol", "timestamp"], vec![false, false], false)
+            .unwrap();
+
+        assert_eq!(lazy.frame.borrow().get_column_names(), eager_frame.get_column_names());
+        assert!(lazy.frame.borrow().equals_missing(&eager_frame));
+    }
+
+    #[test]
+    fn indicators_leave_out_bars_with_null_inputs() {
+        let closes = (0..40).map(|idx| 50f64 + (idx as f64 * 0.7).sin() * 5f64).collect::<Vec<f64>>();
+
+        let mut gapped = synthetic_stockframe(vec![closes.clone()]);
+        let mut close = indicator(&gapped, "SYM0", "close");
+        close[20] = None;
+        let series = <polars::prelude::Series as polars::prelude::NamedFrom<Vec<Option<f64>>, _>>::new("close", close);
+        gapped.frame.borrow_mut().with_column(series).unwrap();
+        gapped.calc_technical_indicators(None).unwrap();
+
+        // the other bars match a frame that never had the null bar
+        let mut reference = synthetic_stockframe(vec![[&closes[..20], &closes[21..]].concat()]);
+        reference.calc_technical_indicators(None).unwrap();
+
+        for name in ["rsi", "bband_mid"] {
+            let values = indicator(&gapped, "SYM0", name);
+
+            assert!(values[20].is_none());
+            assert_eq!([&values[..20], &values[21..]].concat(), indicator(&reference, "SYM0", name));
+        }
+    }
+
+    #[test]
+    fn sma_period_sets_the_warmup_length() {
+        let closes = (0..60).map(|idx| 100f64 + idx as f64).collect::<Vec<f64>>();
+
+        for sma_period in [5, 30] {
+            let mut stockframe = synthetic_stockframe(vec![closes.clone()]);
+            stockframe
+                .calc_technical_indicators(Some(IndicatorConfig {
+                    sma_period,
+                    ..Default::default()
+                }))
+                .unwrap();
+
+            let sma = indicator(&stockframe, "SYM0", "sma");
+            let leading_nulls = sma.iter().take_while(|val| val.is_none()).count();
+
+            assert_eq!(leading_nulls, sma_period as usize - 1);
+            assert!(sma[leading_nulls..].iter().all(|val| val.is_some()));
+        }
+    }
+
+    #[test]
+    fn technical_indicators_fill_every_column() {
+        let mut stockframe = synthetic_stockframe(vec![
+            (0..60).map(|idx| 100f64 + (idx as f64).sin()).collect(),
+            (0..60).map(|idx| 20f64 + (idx as f64 / 3f64).cos()).collect(),
+        ]);
+
+        stockframe.calc_technical_indicators(None).unwrap();
+
+        let frame = stockframe.frame.borrow();
+        assert_eq!(frame.get_column_names(), stockframe_columns.to_vec());
+        assert_eq!(frame.height(), 120);
+
+        // every indicator has settled by the last bar of each symbol
+        for symbol in ["SYM0", "SYM1"] {
+            for name in &stockframe_columns[9..] {
+                assert!(indicator(&stockframe, symbol, name).last().unwrap().is_some());
+            }
+        }
+    }
+
+    #[test]
+    fn stockenv_trading_costs_reduce_round_trip_reward() {
+        let round_trip = |commission_fixed: f64, commission_pct: f64, slippage: f64| -> f64 {
+            let stockframe = synthetic_stockframe(vec![vec![50f64; 10], vec![20f64; 10]]);
+            let mut env = StockEnv::from_stockframe(
+                stockframe,
+                1,
+                Some(commission_fixed),
+                Some(commission_pct),
+                Some(slippage),
+                None,
+                None,
+                None,
+                None,
+            );
+
+            env.reset();
+            let buy = env.step(vec![1f64, -1f64]).reward().unwrap();
+            let holding = env.state[env.feature_length as usize];
+            assert!(holding > 0f64);
+
+            // swing the allocation over to the other symbol
+            let swap = env.step(vec![-1f64, 1f64]).reward().unwrap();
+            assert!(env.state[env.feature_length as usize] < holding);
+
+            buy + swap
+        };
+
+        // prices never move so only the costs can change the outcome
+        let baseline = round_trip(0f64, 0f64, 0f64);
+        assert_eq!(baseline, 0f64);
+        assert!(round_trip(1f64, 0.001, 0.000001) < baseline);
+    }
+
+    #[test]
+    fn stockenv_buys_within_cash_at_low_prices() {
+        let stockframe = synthetic_stockframe(vec![vec![0.01; 10]]);
+        let mut env = StockEnv::from_stockframe(stockframe, 1, Some(1f64), Some(0.001), None, Some(1e9), None, None, None);
+
+        env.reset();
+        env.buy(0, 1e12);
+
+        // the closed form lands on the largest order the cash covers
+        let price = env.price(0);
+        let holding = env.state[env.feature_length as usize];
+        assert!(holding > 0f64);
+        assert!(env.state[0] >= 0f64);
+        assert!(env.buy_cost(price, holding + 1f64) > env.state[0] + env.buy_cost(price, holding));
+
+        // the same dollar order slips by the same fraction whatever the share price
+        let cheap = env.fill_price(0.01, 100000f64, true) / 0.01;
+        let dear = env.fill_price(100f64, 10f64, true) / 100f64;
+        assert!((cheap - dear).abs() < 1e-12);
+    }
+
+    #[test]
+    fn stockenv_observation_stacks_window_of_bars() {
+        let window = 4;
+        let stockframe = synthetic_stockframe(vec![vec![50f64; 10], vec![20f64; 10]]);
+        let mut env = StockEnv::from_stockframe(stockframe, window, None, None, None, None, None, None, None);
+
+        let num_tickers = env.tickers.len();
+        let num_features = env.feature_length as usize - 1 - num_tickers;
+        let account_length = 1 + 2 * num_tickers;
+
+        let observation = env.reset().observation();
+        assert_eq!(observation.len() - account_length, window * num_features);
+        assert_eq!(env.observation_spec().shape as usize, observation.len());
+
+        // the first bar pads every slot of the window
+        let first_bar = &observation[(1 + num_tickers)..(1 + num_tickers + num_features)];
+        let market = &observation[(1 + num_tickers)..(observation.len() - num_tickers)];
+        for bar in market.chunks(num_features) {
+            assert_eq!(bar, first_bar);
+        }
+
+        for _ in 0..(window + 2) {
+            let observation = env.step(vec![0f64, 0f64]).observation();
+            assert_eq!(observation.len() - account_length, window * num_features);
+        }
+
+        assert_eq!(env.history.len(), window);
+    }
+
+    #[test]
+    fn stockenv_allocates_normalized_weights_across_symbols() {
+        let stockframe = synthetic_stockframe(vec![vec![50f64; 10], vec![20f64; 10]]);
+        let mut env = StockEnv::from_stockframe(
+            stockframe,
+            1,
+            Some(0f64),
+            Some(0f64),
+            Some(0f64),
+            None,
+            None,
+            None,
+            None,
+        );
+        assert_eq!(env.action_spec().shape as usize, 2);
+
+        let weights = portfolio_weights(&[0.5, -0.25]);
+        assert!((weights.iter().sum::<f64>() - 1f64).abs() < 1e-12);
+        assert!(weights[0] > weights[1]);
+
+        env.reset();
+        let reward = env.step(vec![0.5, -0.25]).reward().unwrap();
+        assert!((env.weights.iter().sum::<f64>() - 1f64).abs() < 1e-12);
+        assert_eq!(env.weights, weights);
+
+        // both symbols end up held and flat prices leave the log return at zero
+        let holdings = &env.state[(env.feature_length as usize)..];
+        assert!(holdings.iter().all(|shares| *shares > 0f64));
+        assert_eq!(reward, 0f64);
+    }
+
+    #[test]
+    fn stockenv_clamps_positions_to_max_position() {
+        let stockframe = synthetic_stockframe(vec![vec![50f64; 10], vec![20f64; 10]]);
+        let mut env = StockEnv::from_stockframe(
+            stockframe,
+            1,
+            Some(0f64),
+            Some(0f64),
+            Some(0f64),
+            Some(50000f64),
+            Some(0.25),
+            None,
+            None,
+        );
+
+        let ts = env.reset();
+        assert_eq!(ts.observation()[0], 50000f64);
+
+        // asks for almost the whole account in the first symbol
+        let reward = env.step(vec![10f64, -10f64]).reward().unwrap();
+        let holdings = env.state[(env.feature_length as usize)..].to_vec();
+
+        assert_eq!(holdings[0], (0.25 * 50000f64 / 50f64).floor());
+        assert!(holdings[0] * 50f64 <= 0.25 * 50000f64);
+        assert!(env.weights.iter().all(|weight| *weight <= 0.25));
+
+        // the rest stays in cash and flat prices leave the log return at zero
+        assert_eq!(env.state[0] + env.holdings_value(), 50000f64);
+        assert!(env.state[0] >= 0.75 * 50000f64 - 1e-9);
+        assert_eq!(reward, 0f64);
+    }
+
+    #[test]
+    fn stockenv_builds_observations_from_selected_columns() {
+        let window = 3;
+        let stockframe = synthetic_stockframe(vec![(0..30).map(|x| 100f64 + x as f64).collect()]);
+        let mut env = StockEnv::from_stockframe(
+            stockframe,
+            window,
+            None,
+            None,
+            None,
+            None,
+            None,
+            Some(vec![String::from("close"), String::from("rsi")]),
+            None,
+        );
+
+        // balance, unrealized pnl and holdings of the one symbol around two features per bar
+        let observation = env.reset().observation();
+        assert_eq!(observation.len(), 3 + window * 2);
+        assert_eq!(env.observation_spec().shape as usize, observation.len());
+        assert_eq!(observation[2], 100f64);
+
+        let observation = env.step(vec![0f64]).observation();
+        assert_eq!(observation.len(), 3 + window * 2);
+        assert_eq!(observation[2 + (window - 1) * 2], 101f64);
+    }
+
+    #[test]
+    #[should_panic(expected = "not a market feature")]
+    fn stockenv_rejects_unknown_feature_columns() {
+        let stockframe = synthetic_stockframe(vec![vec![50f64; 10]]);
+        StockEnv::from_stockframe(
+            stockframe,
+            1,
+            None,
+            None,
+            None,
+            None,
+            None,
+            Some(vec![String::from("close"), String::from("not_a_column")]),
+            None,
+        );
+    }
+
+    #[test]
+    fn stockenv_steps_every_symbol_on_one_timeline() {
+        // the second symbol stops quoting after six bars
+        let stockframe = synthetic_stockframe(vec![vec![50f64; 10], vec![20f64, 21f64, 22f64, 23f64, 24f64, 25f64]]);
+        let mut env = StockEnv::from_stockframe(stockframe, 1, None, None, None, None, None, None, None);
+
+        let observation_len = env.reset().observation().len();
+        let holding = env.feature_length as usize + 1;
+        let mut terminals = vec![];
+        let mut held = 0f64;
+
+        for step in 1..=10 {
+            // buy into the second symbol while it quotes, then ask to sell all of it
+            let weight = if step <= 5 { 0.5 } else { 0f64 };
+            let num_trades = env.num_trades;
+            let ts = env.step(vec![0f64, weight]);
+            assert_eq!(ts.observation().len(), observation_len);
+
+            if ts.is_terminal() {
+                terminals.push(step);
+            } else if step <= 5 {
+                assert!(env.tradable(1));
+                assert_eq!(env.price(1), 20f64 + step as f64);
+                held = env.state[holding];
+            } else {
+                // the padded bars carry the last close forward for valuation but are never traded on
+                assert_eq!(env.data.height(), 2);
+                assert!(!env.tradable(1));
+                assert_eq!(env.price(1), 25f64);
+                assert_eq!(env.state[holding], held);
+                assert_eq!(env.num_trades, num_trades);
+            }
+        }
+
+        assert!(held > 0f64);
+
+        // one episode across both symbols, ended by the shared timeline running out
+        assert_eq!(terminals, vec![10]);
+    }
+
+    #[test]
+    fn stockenv_logs_one_trade_per_symbol_per_step() {
+        let stockframe = synthetic_stockframe(vec![vec![50f64; 10], vec![20f64; 10]]);
+        let mut env = StockEnv::from_stockframe(
+            stockframe,
+            1,
+            Some(0f64),
+            Some(0f64),
+            Some(0f64),
+            None,
+            None,
+            None,
+            None,
+        );
+
+        env.reset();
+        assert!(env.trades.is_empty());
+
+        let steps = 4;
+        for _ in 0..steps {
+            env.step(vec![0.5, -0.25]);
+        }
+
+        assert_eq!(env.trades.len(), steps * env.tickers.len());
+
+        for (step, records) in env.trades.chunks(env.tickers.len()).enumerate() {
+            // the first step trades on the bar after the reset bar
+            let timestamp = env.timeline[step + 1];
+
+            for (idx, record) in records.iter().enumerate() {
+                assert_eq!(record.timestamp, timestamp);
+                assert_eq!(record.symbol, env.tickers[idx]);
+                assert!(record.position > 0f64);
+            }
+        }
+
+        assert_eq!(env.trades[0].action, 0.5);
+        assert_eq!(env.trades[1].price, 20f64);
+
+        let path = temp_file("trades.csv");
+        env.export_trades(path.as_str()).unwrap();
+
+        let csv = std::fs::read_to_string(path.as_str()).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[0], "timestamp,symbol,action,price,position,reward");
+        assert_eq!(lines.len(), 1 + env.trades.len());
+        assert!(lines[1].starts_with("2024-01-02T14:31:00Z,SYM0,0.5,50,"));
+
+        // a new episode starts a new log
+        env.reset();
+        assert!(env.trades.is_empty());
+    }
+
+    // serves flat bars at fixed closes and fills every order in full, nothing answers while down is set
+    #[cfg(feature = "live")]
+    struct MockBroker {
+        closes: std::collections::HashMap<String, f64>,
+        minute: i64,
+        orders: std::rc::Rc<std::cell::RefCell<Vec<crate::environment::livestockenv::Order>>>,
+        down: std::rc::Rc<std::cell::Cell<bool>>,
+    }
+
+    #[cfg(feature = "live")]
+    impl crate::environment::livestockenv::Broker for MockBroker {
+        fn latest_bars(&mut self, tickers: &[String]) -> anyhow::Result<Vec<crate::environment::livestockenv::Bar>> {
+            if self.down.get() {
+                anyhow::bail!("Connection refused");
+            }
+
+            let timestamp = polars::export::chrono::DateTime::from_timestamp_millis(
+                1704205800000 + self.minute * 60000,
+            )
+            .unwrap()
+            .naive_utc();
+            self.minute += 1;
+
+            Ok(tickers
+                .iter()
+                .map(|ticker| {
+                    let close = self.closes[ticker];
+
+                    crate::environment::livestockenv::Bar {
+                        symbol: ticker.clone(),
+                        timestamp,
+                        open: close,
+                        high: close,
+                        low: close,
+                        close,
+                        volume: 100f64,
+                        vwap: close,
+                        trade_count: 10f64,
+                    }
+                })
+                .collect())
+        }
+
+        fn submit_order(
+            &mut self,
+            order: &crate::environment::livestockenv::Order,
+        ) -> anyhow::Result<crate::environment::livestockenv::Fill> {
+            if self.down.get() {
+                anyhow::bail!("Connection refused");
+            }
+
+            self.orders.borrow_mut().push(order.clone());
+
+            Ok(crate::environment::livestockenv::Fill {
+                symbol: order.symbol.clone(),
+                qty: order.qty,
+                price: self.closes[&order.symbol],
+            })
+        }
+    }
+
+    #[cfg(feature = "live")]
+    fn live_stockenv(
+        log_name: &str,
+    ) -> (
+        crate::environment::livestockenv::LiveStockEnv,
+        std::rc::Rc<std::cell::RefCell<Vec<crate::environment::livestockenv::Order>>>,
+        std::rc::Rc<std::cell::Cell<bool>>,
+    ) {
+        let orders = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+        let down = std::rc::Rc::new(std::cell::Cell::new(false));
+        let broker = MockBroker {
+            closes: [(String::from("AAA"), 100f64), (String::from("BBB"), 50f64)].into_iter().collect(),
+            minute: 0,
+            orders: orders.clone(),
+            down: down.clone(),
+        };
+
+        let mut env = crate::environment::livestockenv::LiveStockEnv::new(
+            Box::new(broker),
+            vec![String::from("BBB"), String::from("AAA")],
+            2,
+            None,
+            Some(10000f64),
+            Some(5),
+            None,
+            Some(temp_file(log_name)),
+        )
+        .unwrap();
+        env.poll_interval = std::time::Duration::ZERO;
+        env.poll_backoff = std::time::Duration::ZERO;
+
+        (env, orders, down)
+    }
+
+    #[cfg(feature = "live")]
+    #[test]
+    fn live_stockenv_submits_rebalancing_orders() {
+        use crate::environment::livestockenv::{Order, Side};
+
+        let (mut env, orders, _) = live_stockenv("live_rebalancing_events.csv");
+
+        let ts = env.reset();
+        assert_eq!(ts.observation().len(), env.observation_spec().shape as usize);
+        assert_eq!(env.tickers, vec![String::from("AAA"), String::from("BBB")]);
+
+        // nearly everything into AAA, the rounding leaves one share of cash
+        let ts = env.step(vec![10f64, -10f64]);
+        assert_eq!(
+            *orders.borrow(),
+            vec![Order { symbol: String::from("AAA"), qty: 99f64, side: Side::Buy }]
+        );
+        assert_eq!(env.holdings, vec![99f64, 0f64]);
+        assert_eq!(env.balance, 100f64);
+        assert_eq!(ts.reward(), Some(0f64));
+
+        // sells go out before buys so the cash is there
+        env.step(vec![-10f64, 10f64]);
+        assert_eq!(
+            orders.borrow()[1..].to_vec(),
+            vec![
+                Order { symbol: String::from("AAA"), qty: 99f64, side: Side::Sell },
+                Order { symbol: String::from("BBB"), qty: 199f64, side: Side::Buy },
+            ]
+        );
+        assert_eq!(env.holdings, vec![0f64, 199f64]);
+        assert_eq!(env.num_trades, 3);
+    }
+
+    #[cfg(feature = "live")]
+    #[test]
+    fn live_stockenv_ends_episode_when_the_feed_drops() {
+        let (mut env, orders, down) = live_stockenv("live_outage_events.csv");
+        env.poll_retries = 2;
+
+        env.reset();
+        env.step(vec![10f64, -10f64]);
+        assert_eq!(env.holdings, vec![99f64, 0f64]);
+
+        // the orders and every poll fail, the books keep what actually filled
+        down.set(true);
+        let ts = env.step(vec![-10f64, 10f64]);
+        assert!(ts.is_terminal());
+        assert_eq!(ts.discount(), 1f64);
+        assert_eq!(env.holdings, vec![99f64, 0f64]);
+        assert_eq!(env.balance, 100f64);
+        assert_eq!(orders.borrow().len(), 1);
+
+        let log = std::fs::read_to_string(&env.logger.path).unwrap();
+        let events = |event: &str| log.lines().filter(|line| line.split(',').nth(1) == Some(event)).count();
+        assert_eq!(events("order_failed"), 2);
+        assert_eq!(events("poll_failed"), 3);
+        assert_eq!(events("episode_ended"), 1);
+
+        // once the feed is back the next step resets and sells the position off
+        down.set(false);
+        let ts = env.step(vec![0f64, 0f64]);
+        assert!(!ts.is_terminal());
+        assert_eq!(env.holdings, vec![0f64, 0f64]);
+        assert_eq!(env.balance, 10000f64);
+    }
+
+    #[cfg(feature = "live")]
+    #[test]
+    fn live_stockenv_reset_gives_up_on_a_dead_feed() {
+        let (mut env, _, down) = live_stockenv("live_dead_feed_events.csv");
+        env.poll_retries = 1;
+        env.reset_rounds = 2;
+
+        // bad keys or a ticker without bars fail every poll the same way
+        down.set(true);
+        assert!(env.try_reset().is_err());
+        assert!(env.episode_ended);
+
+        // every round logs its polls, a bounded number of them
+        let log = std::fs::read_to_string(&env.logger.path).unwrap();
+        let polls = log.lines().filter(|line| line.split(',').nth(1) == Some("poll_failed")).count();
+        assert_eq!(polls, 4);
+    }
+
+    #[test]
+    fn stockframe_loads_bundled_csv() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/bars.csv");
+
+        let mut stockframe = StockFrame::from_csv(path, None, None).expect("Failed to load csv");
+        assert_eq!(stockframe.frame.borrow().shape(), (10, stockframe_columns.len()));
+        assert_eq!(stockframe.tickers, vec![String::from("AAA"), String::from("BBB")]);
+
+        // the alpaca pipeline runs unchanged on top of it
+        stockframe.parse_dt_column().unwrap();
+        stockframe.fill_nulls().unwrap();
+        assert_eq!(stockframe.get_min_timestamp().timestamp_millis(), 1704205800000);
+        assert_eq!(stockframe.get_max_timestamp().timestamp_millis(), 1704206040000);
+
+        let filtered = StockFrame::from_csv(path, Some(vec![String::from("BBB")]), None).unwrap();
+        assert_eq!(filtered.frame.borrow().shape(), (5, stockframe_columns.len()));
+    }
+
+    #[test]
+    fn stockframe_csv_requires_ohlcv_columns() {
+        let filename = temp_file("missing_close.csv");
+        std::fs::create_dir_all(std::path::Path::new(&filename).parent().unwrap()).unwrap();
+        std::fs::write(
+            &filename,
+            "timestamp,symbol,open,high,low,volume\n2024-01-02T14:30:00Z,AAA,1,1,1,100\n",
+        )
+        .unwrap();
+
+        let err = StockFrame::from_csv(&filename, None, None).err().expect("csv without close loaded");
+        assert!(err.to_string().contains("close"));
+    }
+
+    #[test]
+    fn stockframe_reuses_cached_bars() {
+        let cache_dir = temp_file("stockframe_cache");
+        let _ = std::fs::remove_dir_all(&cache_dir);
+
+        let fetches = std::cell::Cell::new(0);
+        let fetch = |_start, _end, _tickers: &Vec<String>, _timeframe| {
+            fetches.set(fetches.get() + 1);
+            let path = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/bars.csv");
+            let stockframe = StockFrame::from_csv(path, None, None).unwrap();
+            stockframe.frame.into_inner().select(&stockframe_columns[..9]).unwrap()
+        };
+
+        let tickers = Some(vec![String::from("AAA"), String::from("BBB")]);
+        let start = polars::export::chrono::NaiveDate::from_ymd_opt(2024, 1, 2)
+            .unwrap()
+            .and_hms_opt(14, 30, 0);
+        let end = polars::export::chrono::NaiveDate::from_ymd_opt(2024, 1, 2)
+            .unwrap()
+            .and_hms_opt(14, 35, 0);
+
+        let cached = |force_refresh| {
+            StockFrame::with_fetcher(
+                tickers.clone(),
+                start,
+                end,
+                None,
+                Some(cache_dir.clone()),
+                force_refresh,
+                fetch,
+            )
+        };
+
+        let first = cached(None);
+        let second = cached(None);
+        assert_eq!(fetches.get(), 1);
+        assert!(first.frame.borrow().equals_missing(&second.frame.borrow()));
+
+        cached(Some(true));
+        assert_eq!(fetches.get(), 2);
+    }
+
+    // every indicator is null for the first warmup bars and equal to the bar index afterwards
+    #[test]
+    fn stockframe_reports_missing_columns_as_errors() {
+        let stockframe = synthetic_stockframe(vec![vec![50f64; 30]]);
+        let without_close = stockframe.frame.borrow().drop("close").unwrap();
+        stockframe.frame.replace(without_close);
+
+        let mut broken = stockframe.clone();
+        let err = broken.calc_technical_indicators(None).unwrap_err();
+        assert!(err.to_string().contains("close"));
+
+        let mut broken = stockframe.clone();
+        assert!(broken.fill_nulls().is_err());
+
+        let mut broken = stockframe.clone();
+        assert!(broken.add_rolling_zscore("close", 5).is_err());
+    }
+
+    #[test]
+    fn stockframe_to_tensor_stacks_bars_by_symbol() {
+        let stockframe = synthetic_stockframe(vec![vec![50f64, 51f64, 52f64], vec![20f64, 21f64, 22f64]]);
+
+        let tensor = stockframe.to_tensor(&["close", "volume"]).unwrap();
+
+        assert_eq!(tensor.size(), vec![6, 2]);
+        assert_eq!(tensor.kind(), tch::Kind::Float);
+
+        let values = Vec::<f32>::try_from(&tensor.to_device(tch::Device::Cpu).view([-1])).unwrap();
+        assert_eq!(values[..4].to_vec(), vec![50f32, 100f32, 51f32, 100f32]);
+        assert_eq!(values[6..8].to_vec(), vec![20f32, 100f32]);
+
+        // indicators are still null before calc_technical_indicators
+        assert!(stockframe.to_tensor(&["close", "rsi"]).is_err());
+    }
+
+    #[test]
+    fn split_adjustment_keeps_close_continuous() {
+        // a 2:1 split halves the quoted price at the tenth bar
+        let closes = (0..20).map(|idx| if idx < 10 { 100f64 + idx as f64 } else { 55f64 + (idx - 10) as f64 * 0.5 }).collect::<Vec<f64>>();
+        let mut stockframe = synthetic_stockframe(vec![closes, vec![20f64; 20]]);
+
+        let ex_date = polars::export::chrono::DateTime::from_timestamp_millis(1704205800000 + 10 * 60000)
+            .unwrap()
+            .naive_utc();
+        stockframe
+            .adjust_prices(&[CorporateAction::Split {
+                symbol: String::from("SYM0"),
+                ex_date,
+                ratio: 2f64,
+            }])
+            .unwrap();
+
+        let column = |symbol: &str, name: &str| -> Vec<f64> {
+            polars::prelude::IntoLazy::lazy(stockframe.frame.borrow().clone())
+                .filter(polars::prelude::col("symbol").eq(polars::prelude::lit(symbol)))
+                .collect()
+                .unwrap()
+                .column(name)
+                .unwrap()
+                .f64()
+                .unwrap()
+                .into_no_null_iter()
+                .collect()
+        };
+
+        let close = column("SYM0", "close");
+        assert_eq!(close[9], 54.5);
+        assert_eq!(close[10], 55f64);
+        assert!(close.windows(2).all(|pair| (pair[1] - pair[0]).abs() <= 0.5 + 1e-9));
+
+        let volume = column("SYM0", "volume");
+        assert_eq!(volume[9], 200f64);
+        assert_eq!(volume[10], 100f64);
+
+        // other symbols are untouched
+        assert!(column("SYM1", "close").iter().all(|close| *close == 20f64));
+        assert!(column("SYM1", "volume").iter().all(|volume| *volume == 100f64));
+    }
+
+    #[test]
+    fn split_by_date_partitions_bars_chronologically() {
+        let stockframe = synthetic_stockframe(vec![vec![50f64; 60], vec![20f64; 60]]);
+        let rows = stockframe.frame.borrow().height();
+
+        // half an hour after the first bar
+        let cutoff = polars::export::chrono::DateTime::from_timestamp_millis(1704205800000 + 30 * 60000)
+            .unwrap()
+            .naive_utc();
+        let (train, test) = stockframe.split_by_date(cutoff);
+
+        assert_eq!(train.frame.borrow().height() + test.frame.borrow().height(), rows);
+        assert_eq!(train.frame.borrow().height(), 60);
+        assert!(train.get_max_timestamp() < cutoff);
+        assert_eq!(test.get_min_timestamp(), cutoff);
+
+        for half in [&train, &test] {
+            for symbol in ["SYM0", "SYM1"] {
+                let timestamps: Vec<i64> = polars::prelude::IntoLazy::lazy(half.frame.borrow().clone())
+                    .filter(polars::prelude::col("symbol").eq(polars::prelude::lit(symbol)))
+                    .collect()
+                    .unwrap()
+                    .column("timestamp")
+                    .unwrap()
+                    .cast(&polars::prelude::DataType::Int64)
+                    .unwrap()
+                    .i64()
+                    .unwrap()
+                    .into_no_null_iter()
+                    .collect();
+
+                assert_eq!(timestamps.len(), 30);
+                assert!(timestamps.windows(2).all(|pair| pair[0] < pair[1]));
+            }
+        }
+
+        // both halves still build a working environment
+        for half in [train, test] {
+            let mut env = StockEnv::from_stockframe(half, 1, None, None, None, None, None, None, None);
+            env.reset();
+            assert!(!env.step(vec![0f64, 0f64]).is_terminal());
+        }
+    }
+
+    #[test]
+    fn rolling_zscore_standardizes_the_latest_value() {
+        let mut stockframe =
+            synthetic_stockframe(vec![(1..=30).map(|x| x as f64).collect(), vec![50f64; 30]]);
+
+        let name = stockframe.add_rolling_zscore("close", 5).unwrap();
+        assert_eq!(name, "close_zscore_5");
+
+        // last window is 26..=30, mean 28 and sample variance (4 + 1 + 0 + 1 + 4) / 4
+        let zscores = indicator(&stockframe, "SYM0", name.as_str());
+        let expected = (30f64 - 28f64) / 2.5f64.sqrt();
+        assert!((zscores.last().unwrap().unwrap() - expected).abs() < 1e-12);
+
+        // a linear series scores the same in every full window
+        assert!(zscores[..4].iter().all(|z| z.is_none()));
+        assert!(zscores[4..].iter().all(|z| (z.unwrap() - expected).abs() < 1e-12));
+
+        // a flat series has no variance and scores zero rather than nan
+        let flat = indicator(&stockframe, "SYM1", name.as_str());
+        assert!(flat[4..].iter().all(|z| *z == Some(0f64)));
+    }
+
+    #[test]
+    fn resample_aggregates_minute_bars_into_five_minute_bars() {
+        let closes: Vec<f64> = (0..120).map(|minute| 100f64 + (minute % 7) as f64).collect();
+        let mut stockframe = synthetic_stockframe(vec![closes.clone()]);
+
+        stockframe.resample(Timeframe::FiveMin).unwrap();
+        assert_eq!(stockframe.timeframe, Timeframe::FiveMin);
+
+        let frame = stockframe.frame.borrow().clone();
+        assert_eq!(frame.height(), 24);
+        assert_eq!(frame.get_column_names(), stockframe_columns.to_vec());
+
+        let column = |name: &str| -> Vec<f64> {
+            frame.column(name).unwrap().f64().unwrap().into_no_null_iter().collect()
+        };
+
+        let timestamps: Vec<i64> = frame
+            .column("timestamp")
+            .unwrap()
+            .datetime()
+            .unwrap()
+            .into_no_null_iter()
+            .collect();
+
+        for (idx, bucket) in closes.chunks(5).enumerate() {
+            let max = bucket.iter().cloned().fold(f64::MIN, f64::max);
+            let min = bucket.iter().cloned().fold(f64::MAX, f64::min);
+
+            assert_eq!(timestamps[idx], 1704205800000 + idx as i64 * 300000);
+            assert_eq!(column("open")[idx], bucket[0]);
+            assert!((column("high")[idx] - max * 1.01).abs() < 1e-9);
+            assert!((column("low")[idx] - min * 0.99).abs() < 1e-9);
+            assert_eq!(column("close")[idx], bucket[4]);
+            assert_eq!(column("volume")[idx], 500f64);
+            assert_eq!(column("trade_count")[idx], 50f64);
+        }
+
+        // indicators were recomputed on the coarser bars, the sma window has not filled in 24 bars
+        assert!(indicator(&stockframe, "SYM0", "rsi").iter().any(|value| value.is_some()));
+        assert!(indicator(&stockframe, "SYM0", "sma").iter().all(|value| value.is_none()));
+    }
+
+    fn warmup_stockframe(len: usize, warmup: usize) -> StockFrame {
+        let stockframe = synthetic_stockframe(vec![vec![10f64; len]]);
+        let values: Vec<Option<f64>> = (0..len)
+            .map(|idx| match idx < warmup {
+                true => None,
+                false => Some(idx as f64),
+            })
+            .collect();
+
+        for name in &stockframe_columns[9..] {
+            let series = <polars::prelude::Series as polars::prelude::NamedFrom<Vec<Option<f64>>, _>>::new(
+                name,
+                values.clone(),
+            );
+            stockframe.frame.borrow_mut().with_column(series).unwrap();
+        }
+
+        stockframe
+    }
+
+    #[test]
+    fn clean_warmup_strategies() {
+        let mut dropped = warmup_stockframe(10, 3);
+        dropped.clean(Some(WarmupStrategy::DropNullRows)).unwrap();
+        assert_eq!(dropped.frame.borrow().height(), 7);
+        assert_eq!(indicator(&dropped, "SYM0", "rsi")[0], Some(3f64));
+
+        let mut zeroed = warmup_stockframe(10, 3);
+        zeroed.clean(Some(WarmupStrategy::ZeroFill)).unwrap();
+        assert_eq!(zeroed.frame.borrow().height(), 10);
+        assert_eq!(indicator(&zeroed, "SYM0", "rsi")[..4], [Some(0f64), Some(0f64), Some(0f64), Some(3f64)]);
+
+        let mut filled = warmup_stockframe(10, 3);
+        filled.clean(Some(WarmupStrategy::ForwardFill)).unwrap();
+        assert_eq!(filled.frame.borrow().height(), 10);
+        // the warmup bars have nothing to carry forward, later values must not leak back into them
+        assert_eq!(indicator(&filled, "SYM0", "rsi")[..4], [Some(0f64), Some(0f64), Some(0f64), Some(3f64)]);
+
+        let mut gapped = warmup_stockframe(10, 3);
+        let mut values = indicator(&gapped, "SYM0", "rsi");
+        values[5] = None;
+        let series = <polars::prelude::Series as polars::prelude::NamedFrom<Vec<Option<f64>>, _>>::new("rsi", values);
+        gapped.frame.borrow_mut().with_column(series).unwrap();
+        gapped.clean(Some(WarmupStrategy::ForwardFill)).unwrap();
+        assert_eq!(indicator(&gapped, "SYM0", "rsi")[5], Some(4f64));
+    }
+
+    #[test]
+    fn fill_date_range_steps_by_timeframe() {
+        let filename = temp_file("hourly_bars.csv");
+        std::fs::create_dir_all(std::path::Path::new(&filename).parent().unwrap()).unwrap();
+        std::fs::write(
+            &filename,
+            "timestamp,symbol,open,high,low,close,volume\n\
+             2024-01-02T14:00:00Z,AAA,1,1,1,1,100\n\
+             2024-01-02T19:00:00Z,AAA,2,2,2,2,100\n",
+        )
+        .unwrap();
+
+        let mut stockframe = StockFrame::from_csv(&filename, None, Some(Timeframe::Hour)).unwrap();
+        stockframe.parse_dt_column().unwrap();
+        stockframe.fill_date_range().unwrap();
+
+        // 14:00 through 19:00 on the hour
+        assert_eq!(stockframe.frame.borrow().shape().0, 6);
+        assert_eq!(Timeframe::Hour.as_alpaca(), "1Hour");
+    }
+
+    #[test]
+    fn stockenv_episode_stats_track_drawdown() {
+        let stockframe = synthetic_stockframe(vec![vec![10f64, 12f64, 9f64, 11f64, 6f64, 8f64]]);
+        let mut env = StockEnv::from_stockframe(
+            stockframe,
+            1,
+            Some(0f64),
+            Some(0f64),
+            Some(0f64),
+            None,
+            None,
+            None,
+            None,
+        );
+
+        env.reset();
+        loop {
+            let ts = env.step(vec![1f64]);
+            if ts.is_terminal() {
+                break;
+            }
+        }
+
+        // 833 shares bought at 12 leave 4 in cash, the account bottoms out at 4 + 833 * 6
+        let stats = env.episode_stats().expect("stats missing after Terminate");
+        assert_eq!(stats.num_trades, 1);
+        assert!((stats.max_drawdown - (10000f64 - 5002f64) / 10000f64).abs() < 1e-12);
+        assert!((stats.total_return - (6668f64 / 10000f64 - 1f64)).abs() < 1e-12);
+        assert!(stats.sharpe < 0f64);
+    }
+
+    #[test]
+    fn stockenv_log_return_reward_of_doubling_price() {
+        let stockframe = synthetic_stockframe(vec![vec![50f64, 50f64, 100f64]]);
+        let mut env = StockEnv::from_stockframe(
+            stockframe,
+            1,
+            Some(0f64),
+            Some(0f64),
+            Some(0f64),
+            None,
+            None,
+            None,
+            Some(RewardKind::LogReturn),
+        );
+
+        env.reset();
+
+        // buying in at 50 leaves the account value unchanged
+        let reward = env.step(vec![1f64]).reward().unwrap();
+        assert!(reward.abs() < 1e-12);
+
+        let reward = env.step(vec![1f64]).reward().unwrap();
+        assert!((reward - 2f64.ln()).abs() < 1e-12);
+    }
+
+    #[test]
+    fn only_terminate_is_terminal() {
+        let restart: Box<dyn Trajectory> = Box::new(Restart { observation: vec![0f64] });
+        let transition: Box<dyn Trajectory> = Box::new(Transition { observation: vec![0f64], reward: 1f64 });
+        let terminate: Box<dyn Trajectory> =
+            Box::new(Terminate { observation: vec![0f64], reward: 1f64, discount: 0f64 });
+
+        assert_eq!(terminate.discount(), 0f64);
+        assert!(!restart.is_terminal());
+        assert!(!transition.is_terminal());
+        assert!(terminate.is_terminal());
+
+        // the downcast path keeps working
+        assert!(terminate.as_any().downcast_ref::<Terminate>().is_some());
+    }
+
+    #[test]
+    fn halfcheetah_time_limit_keeps_bootstrapping() {
+        let mut env = HalfCheetahEnv::new(None, None, None, None, None, None, Some(3));
+        env.reset();
+
+        let mut ts = env.step(vec![0f64; 6]);
+        assert_eq!(ts.discount(), 1f64);
+
+        while !ts.is_terminal() {
+            ts = env.step(vec![0f64; 6]);
+        }
+
+        assert_eq!(env.step, 3);
+        assert_eq!(ts.discount(), 1f64);
+    }
+
+    #[test]
+    fn replay_buffer_samples_on_storage_device() {
+        let mut replay_buffer = ReplayBuffer::with_device(3, 2, Some(16), Some(tch::Device::Cpu), Some(true));
+        for idx in 0..16 {
+            let val = idx as f64;
+            replay_buffer.add(vec![val; 3], vec![-val; 2], vec![val + 1f64; 3], val, 0f64);
+        }
+
+        let batch = replay_buffer.sample_batch(8);
+
+        for tensor in [&batch.states, &batch.actions, &batch.next_states, &batch.rewards, &batch.not_dones, &batch.n_steps, &batch.weights] {
+            assert_eq!(tensor.device(), tch::Device::Cpu);
+        }
+
+        assert_eq!(batch.states.size(), vec![8, 3]);
+        assert_eq!(batch.actions.size(), vec![8, 2]);
+        assert_eq!(batch.rewards.size(), vec![8]);
+
+        // rows come back intact from the packed storage
+        for (row, idx) in batch.indices.iter().enumerate() {
+            let val = *idx as f64;
+            assert_eq!(batch.states.double_value(&[row as i64, 0]), val);
+            assert_eq!(batch.actions.double_value(&[row as i64, 1]), -val);
+            assert_eq!(batch.next_states.double_value(&[row as i64, 2]), val + 1f64);
+            assert_eq!(batch.rewards.double_value(&[row as i64]), val);
+            assert_eq!(batch.not_dones.double_value(&[row as i64]), 1f64);
+            assert_eq!(batch.n_steps.double_value(&[row as i64]), 1f64);
+        }
+    }
+
+    #[test]
+    fn replay_buffer_end_episode_flushes_n_step_windows() {
+        let mut replay_buffer = ReplayBuffer::new(1, 1, Some(10));
+        replay_buffer.set_n_step(3, 0.5);
+
+        replay_buffer.add(vec![0f64], vec![0f64], vec![1f64], 1f64, 0f64);
+        replay_buffer.add(vec![1f64], vec![0f64], vec![2f64], 2f64, 0f64);
+        assert_eq!(replay_buffer.len(), 0);
+
+        // a truncated episode keeps not_done at one
+        replay_buffer.end_episode();
+        assert_eq!(replay_buffer.len(), 2);
+        assert_eq!(replay_buffer.rewards()[..2].to_vec(), vec![1f64 + 0.5 * 2f64, 2f64]);
+        assert_eq!(replay_buffer.not_dones()[..2].to_vec(), vec![1f64, 1f64]);
+        assert_eq!(replay_buffer.n_steps()[..2].to_vec(), vec![2f64, 1f64]);
+    }
+
+    #[test]
+    fn halfcheetah_records_one_frame_per_step() {
+        let mut env = HalfCheetahEnv::from_config(HalfCheetahConfig {
+            record: true,
+            ..Default::default()
+        });
+        env.reset();
+
+        for _ in 0..7 {
+            env.step(vec![0.5; 6]);
+        }
+
+        let frame_len = (env.model.nq + env.model.nv) as usize;
+        assert_eq!(env.frames.len(), 7);
+        assert!(env.frames.iter().all(|frame| frame.len() == frame_len));
+        assert_eq!(env.frames.last().unwrap(), &env.render());
+
+        env.reset();
+        assert!(env.frames.is_empty());
+    }
+
+    #[test]
+    fn seeded_halfcheetah_envs_reset_identically() {
+        let mut first = HalfCheetahEnv::from_config(HalfCheetahConfig {
+            seed: Some(3),
+            ..Default::default()
+        });
+        let mut second = HalfCheetahEnv::new(None, None, None, None, None, None, None);
+        second.seed(3);
+
+        let observation = first.reset().observation();
+        assert_eq!(observation, second.reset().observation());
+
+        // the noise is actually applied, a different seed starts elsewhere
+        let mut other = HalfCheetahEnv::new(None, None, None, None, None, None, None);
+        other.seed(4);
+        assert_ne!(observation, other.reset().observation());
+    }
+
+    #[test]
+    fn halfcheetah_config_sets_max_episode_steps() {
+        let mut env = HalfCheetahEnv::from_config(HalfCheetahConfig {
+            max_episode_steps: 10,
+            ..Default::default()
+        });
+        env.reset();
+
+        for _ in 0..9 {
+            assert!(!env.step(vec![0f64; 6]).is_terminal());
+        }
+
+        assert!(env.step(vec![0f64; 6]).is_terminal());
+        assert_eq!(env.step, 10);
+    }
+
+    #[test]
+    fn halfcheetah_specs_report_float() {
+        let env = HalfCheetahEnv::new(None, None, None, None, None, None, None);
+
+        assert_eq!(env.action_spec().dtype, Dtype::Float);
+        assert_eq!(env.observation_spec().dtype, Dtype::Float);
+        assert_eq!(env.observation_spec().dtype.kind(), tch::Kind::Float);
+
+        // wrappers keep the dtype of the env they wrap
+        let normalized = NormalizeObservation::new(Box::new(env), None, None);
+        assert_eq!(normalized.observation_spec().dtype, Dtype::Float);
+    }
+
+    #[test]
+    fn halfcheetah_action_bounds_match_shape() {
+        let env = HalfCheetahEnv::new(None, None, None, None, None, None, None);
+        let spec = env.action_spec();
+
+        assert_eq!(spec.low.len(), spec.shape as usize);
+        assert_eq!(spec.high.len(), spec.shape as usize);
+        assert!(spec.low.iter().all(|low| *low == -1f64));
+        assert!(spec.high.iter().all(|high| *high == 1f64));
+    }
+
+    #[test]
+    fn halfcheetah_observation_spec_matches_step() {
+        let mut env = HalfCheetahEnv::new(None, None, None, None, None, None, None);
+        env.reset();
+
+        let ts = env.step(vec![0.1; env.action_spec().shape as usize]);
+        assert_eq!(ts.observation().len(), env.observation_spec().shape as usize);
+    }
+
+    #[test]
+    fn actor_activation_changes_output() {
+        let relu = Actor::new(4, 2, vec![16, 16], 1f64, Some(Activation::Relu), None, None);
+        let tanh = Actor::new(4, 2, vec![16, 16], 1f64, Some(Activation::Tanh), None, None);
+        tanh.vs.borrow_mut().copy(&relu.vs.borrow()).unwrap();
+
+        let state = tch::Tensor::rand([8, 4], (tch::Kind::Float, **crate::device)) * 4f64 - 2f64;
+        assert!(!relu.forward(&state).allclose(&tanh.forward(&state), 1e-6, 1e-6, false));
+
+        // the choice survives a save file
+        let data = serde_json::to_string(&tanh).expect("Failed to serialize actor");
+        let loaded: Actor = serde_json::from_str(data.as_str()).expect("Failed to deserialize actor");
+        assert_eq!(loaded.actor.activation, Activation::Tanh);
+        assert!(tanh.forward(&state).allclose(&loaded.forward(&state), 1e-6, 1e-6, false));
+    }
+
+    #[test]
+    fn discrete_actor_selects_index_in_range() {
+        let actor = Actor::new(4, 1, vec![16, 16], 1f64, None, Some(ActorHead::Discrete(3)), None);
+        let state = tch::Tensor::rand([32, 4], (tch::Kind::Float, **crate::device)) * 4f64 - 2f64;
+
+        let probabilities = actor.forward(&state);
+        assert_eq!(probabilities.size(), vec![32, 3]);
+        assert!(probabilities.sum_dim_intlist(-1, false, tch::Kind::Float).allclose(
+            &tch::Tensor::ones([32], (tch::Kind::Float, **crate::device)),
+            1e-5,
+            1e-5,
+            false
+        ));
+
+        for sample in [false, true] {
+            let indices = actor.select_action(&state, sample).to_device(tch::Device::Cpu);
+            assert_eq!(indices.kind(), tch::Kind::Int64);
+            assert_eq!(indices.size(), vec![32]);
+
+            for index in Vec::<i64>::try_from(&indices).unwrap() {
+                assert!((0..3).contains(&index));
+            }
+        }
+
+        // the head survives a save file
+        let data = serde_json::to_string(&actor).expect("Failed to serialize actor");
+        let loaded: Actor = serde_json::from_str(data.as_str()).expect("Failed to deserialize actor");
+        assert_eq!(loaded.head, ActorHead::Discrete(3));
+    }
 }
@@ -0,0 +1,301 @@
+extern crate rand;
+
+use crate::environment::Environment;
+use crate::replay_buffer::ReplayBuffer;
+use crate::sac::SAC;
+use crate::td3::TD3;
+
+// what the interaction loop needs from an agent
+pub trait Policy {
+    fn sample_action(
+        &mut self,
+        state: Vec<f64>,
+        expl_noise: f64,
+        rng: &mut rand::prelude::StdRng,
+    ) -> Vec<f64>;
+    fn train(&mut self, replay_buffer: &mut ReplayBuffer, batch_size: Option<i64>);
+    fn evaluate(&self, env: &mut dyn Environment, episodes: i64) -> f64;
+
+    // only agents with stateful exploration noise have anything to reset
+    fn reset_noise(&mut self) {}
+
+    fn save(&self, _filename: String) -> anyhow::Result<()> {
+        anyhow::bail!("{} does not support saving", std::any::type_name::<Self>())
+    }
+}
+
+impl Policy for TD3 {
+    fn sample_action(
+        &mut self,
+        state: Vec<f64>,
+        expl_noise: f64,
+        rng: &mut rand::prelude::StdRng,
+    ) -> Vec<f64> {
+        TD3::sample_action(self, state, expl_noise, rng)
+    }
+
+    fn train(&mut self, replay_buffer: &mut ReplayBuffer, batch_size: Option<i64>) {
+        TD3::train(self, replay_buffer, batch_size)
+    }
+
+    fn evaluate(&self, env: &mut dyn Environment, episodes: i64) -> f64 {
+        TD3::evaluate(self, env, episodes)
+    }
+
+    fn reset_noise(&mut self) {
+        TD3::reset_noise(self)
+    }
+
+    fn save(&self, filename: String) -> anyhow::Result<()> {
+        TD3::save(self, filename).map(|_| ())
+    }
+}
+
+// sac explores by sampling its own policy, expl_noise and the driver's rng go unused
+impl Policy for SAC {
+    fn sample_action(
+        &mut self,
+        state: Vec<f64>,
+        _expl_noise: f64,
+        _rng: &mut rand::prelude::StdRng,
+    ) -> Vec<f64> {
+        SAC::sample_action(self, state)
+    }
+
+    fn train(&mut self, replay_buffer: &mut ReplayBuffer, batch_size: Option<i64>) {
+        SAC::train(self, replay_buffer, batch_size)
+    }
+
+    fn evaluate(&self, env: &mut dyn Environment, episodes: i64) -> f64 {
+        SAC::evaluate(self, env, episodes)
+    }
+}
+
+// mean undiscounted return of a deterministic policy, every episode starts from a reset
+pub fn evaluate_policy(env: &mut dyn Environment, episodes: i64, act: impl Fn(Vec<f64>) -> Vec<f64>) -> f64 {
+    assert!(episodes > 0, "episodes must be positive");
+
+    let mut total_reward = 0f64;
+
+    for _ in 0..episodes {
+        let mut ts = env.reset();
+
+        while !ts.is_terminal() {
+            ts = env.step(act(ts.observation()));
+            total_reward += ts.reward().unwrap_or(0f64);
+        }
+    }
+
+    total_reward / episodes as f64
+}
+
+// what train_agent hands back, every (timestep, eval return) pair and the best of them
+pub struct TrainingHistory {
+    pub evals: Vec<(u32, f64)>,
+    pub best_timestep: u32,
+    pub best_return: f64,
+}
+
+// interaction loop shared by the cli and tests
+// on_eval is called after every evaluation with everything collected so far,
+// then should_stop with the eval return and timestep, returning true ends training early
+// checkpoint saves the agent on its own interval, None never checkpoints
+// best_model_dir gets a best_model.json every time an evaluation beats the best so far, None skips saving
+pub fn train_agent<P: Policy>(
+    env: &mut dyn Environment,
+    eval_env: &mut dyn Environment,
+    policy: &mut P,
+    replay_buffer: &mut ReplayBuffer,
+    max_timesteps: u32,
+    start_timesteps: u32,
+    eval_freq: u32,
+    expl_noise: f64,
+    mut on_eval: impl FnMut(u32, &P, &Vec<(u32, f64)>),
+    mut should_stop: impl FnMut(f64, u32) -> bool,
+    mut checkpoint: Option<Checkpoint>,
+    best_model_dir: Option<String>,
+) -> TrainingHistory {
+    let best_model_path = best_model_dir.map(|dir| std::path::Path::new(&dir).join("best_model.json"));
+    let save_best = |t: u32, policy: &P| {
+        if let Some(path) = &best_model_path {
+            if let Err(err) = policy.save(path.to_string_lossy().into_owned()) {
+                println!("Failed to save best model at T: {}: {}", t, err);
+            }
+        }
+    };
+
+    let initial_return = policy.evaluate(eval_env, 10);
+    let mut evals = vec![(0, initial_return)];
+    let (mut best_timestep, mut best_return) = (0, initial_return);
+    save_best(0, policy);
+
+    let mut ts = env.reset();
+    let mut episode_reward = 0f64;
+    let mut episode_timesteps = 0;
+    let mut episode_num = 0;
+
+    let mut rng = crate::new_rng();
+    let action_spec = env.action_spec();
+    let uniforms: Vec<rand::distributions::Uniform<f64>> = action_spec
+        .low
+        .iter()
+        .zip(action_spec.high.iter())
+        .map(|(low, high)| rand::distributions::Uniform::from(*low..*high))
+        .collect();
+
+    let mut action: Vec<f64>;
+    for t in 0..max_timesteps {
+        episode_timesteps += 1;
+
+        if t < start_timesteps {
+            action = uniforms
+                .iter()
+                .map(|uniform| rand::prelude::Distribution::sample(uniform, &mut rng))
+                .collect();
+        } else {
+            action = policy.sample_action(ts.observation(), expl_noise, &mut rng);
+        }
+
+        let next_ts = env.step(action.clone());
+        let done = next_ts.is_terminal();
+
+        // the buffer stores 1 - done as not_done, so a time limit still bootstraps
+        replay_buffer.add(
+            ts.observation(),
+            action,
+            next_ts.observation(),
+            next_ts.reward().unwrap_or(0f64),
+            1f64 - next_ts.discount(),
+        );
+
+        if done {
+            replay_buffer.end_episode();
+        }
+
+        episode_reward += next_ts.reward().unwrap_or(0f64);
+        ts = next_ts;
+
+        if t >= start_timesteps {
+            policy.train(replay_buffer, None);
+        }
+
+        if done {
+            println!(
+                "Total T: {} Episode Num: {} Episode T: {} Reward: {:.3}",
+                t + 1,
+                episode_num + 1,
+                episode_timesteps,
+                episode_reward
+            );
+
+            ts = env.reset();
+            policy.reset_noise();
+            episode_reward = 0f64;
+            episode_timesteps = 0;
+            episode_num += 1;
+        }
+
+        if let Some(checkpoint) = checkpoint.as_mut() {
+            if (t + 1) % checkpoint.freq == 0 {
+                if let Err(err) = checkpoint.save(t + 1, policy) {
+                    println!("Failed to checkpoint at T: {}: {}", t + 1, err);
+                }
+            }
+        }
+
+        if (t + 1) % eval_freq == 0 {
+            let eval_return = policy.evaluate(eval_env, 10);
+            evals.push((t + 1, eval_return));
+
+            if eval_return > best_return {
+                best_timestep = t + 1;
+                best_return = eval_return;
+                save_best(t + 1, policy);
+            }
+
+            on_eval(t + 1, policy, &evals);
+
+            if should_stop(eval_return, t + 1) {
+                println!("Stopping early at T: {} with eval return {:.3}", t + 1, eval_return);
+                break;
+            }
+        }
+    }
+
+    TrainingHistory {
+        evals,
+        best_timestep,
+        best_return,
+    }
+}
+
+// stops once patience evaluations in a row fail to beat the best return by more than min_delta
+pub struct PatienceStopper {
+    pub patience: u32,
+    pub min_delta: f64,
+    pub best: f64,
+    pub evals_since_best: u32,
+}
+
+impl PatienceStopper {
+    pub fn new(patience: u32, min_delta: Option<f64>) -> Self {
+        assert!(patience > 0, "patience must be positive");
+
+        PatienceStopper {
+            patience,
+            min_delta: min_delta.unwrap_or(0f64),
+            best: f64::NEG_INFINITY,
+            evals_since_best: 0,
+        }
+    }
+
+    pub fn should_stop(&mut self, eval_return: f64, _timestep: u32) -> bool {
+        if eval_return > self.best + self.min_delta {
+            self.best = eval_return;
+            self.evals_since_best = 0;
+        } else {
+            self.evals_since_best += 1;
+        }
+
+        self.evals_since_best >= self.patience
+    }
+}
+
+// saves the agent to dir/checkpoint_{timestep}.bin every freq timesteps,
+// only the newest keep checkpoints written by this run stay on disk (5 by default)
+pub struct Checkpoint {
+    pub freq: u32,
+    pub dir: String,
+    pub keep: usize,
+    pub saved: std::collections::VecDeque<std::path::PathBuf>,
+}
+
+impl Checkpoint {
+    pub fn new(freq: u32, dir: String, keep: Option<usize>) -> Self {
+        assert!(freq > 0, "checkpoint freq must be positive");
+
+        let keep = keep.unwrap_or(5);
+        assert!(keep > 0, "keep at least one checkpoint");
+
+        Checkpoint {
+            freq,
+            dir,
+            keep,
+            saved: std::collections::VecDeque::new(),
+        }
+    }
+
+    pub fn save<P: Policy>(&mut self, timestep: u32, policy: &P) -> anyhow::Result<()> {
+        let path = std::path::Path::new(&self.dir).join(format!("checkpoint_{}.bin", timestep));
+        policy.save(path.to_string_lossy().into_owned())?;
+        self.saved.push_back(path);
+
+        while self.saved.len() > self.keep {
+            if let Some(oldest) = self.saved.pop_front() {
+                std::fs::remove_file(&oldest)?;
+            }
+        }
+
+        Ok(())
+    }
+}